@@ -0,0 +1,226 @@
+//! Decodes the responses pond-planner and pond-duckling return.
+//!
+//! Both answer with an [`ArrowIpcResponse`]: a status code, HTTP-style headers
+//! and a body holding an Arrow IPC stream on success or a JSON error.
+//! [`decode_response`] turns one back into record batches, and
+//! [`decode_arrow_ipc`] reads a bare Arrow IPC stream.
+
+use arrow_array::RecordBatch;
+use arrow_ipc::reader::StreamReader;
+use arrow_schema::ArrowError;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use thiserror::Error;
+
+/// The media type of a successful response's body.
+pub const ARROW_STREAM_CONTENT_TYPE: &str = "application/vnd.apache.arrow.stream";
+
+/// The envelope the planner and workers return.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArrowIpcResponse {
+    pub status_code: u16,
+    pub headers: serde_json::Value,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+}
+
+impl ArrowIpcResponse {
+    /// The value of the header `name`, matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .as_object()?
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .and_then(|(_, value)| value.as_str())
+    }
+}
+
+/// The JSON body of an error response.
+#[derive(Deserialize)]
+struct ErrorBody {
+    error_type: String,
+    message: String,
+}
+
+/// Why a response could not be decoded.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// The query failed; `error_type` and `message` come from the JSON body,
+    /// or the body's text when it isn't one.
+    #[error("Query failed with status {status_code} ({error_type}): {message}")]
+    Status {
+        status_code: u16,
+        error_type: String,
+        message: String,
+    },
+    /// The body isn't an Arrow IPC stream, e.g. JSON or CSV was requested.
+    #[error("Expected {ARROW_STREAM_CONTENT_TYPE}, got {0}")]
+    ContentType(String),
+    #[error("Unsupported Content-Encoding: {0}")]
+    ContentEncoding(String),
+    #[error("Failed to decompress the body: {0}")]
+    Decompression(#[from] std::io::Error),
+    #[error("Arrow error: {0}")]
+    Arrow(#[from] ArrowError),
+}
+
+/// Reads back the record batches of an Arrow IPC stream, such as the body
+/// of a successful response once decompressed.
+pub fn decode_arrow_ipc(bytes: &[u8]) -> Result<Vec<RecordBatch>, ArrowError> {
+    StreamReader::try_new(bytes, None)?.collect()
+}
+
+/// Reads the Arrow IPC stream in `resp` back into record batches, undoing any
+/// zstd or lz4 `Content-Encoding` first.
+///
+/// Fails with [`Error::Status`] when the response isn't a success and with
+/// [`Error::ContentType`] when its body isn't an Arrow stream.
+pub fn decode_response(resp: &ArrowIpcResponse) -> Result<Vec<RecordBatch>, Error> {
+    if resp.status_code != 200 {
+        let (error_type, message) = match serde_json::from_slice::<ErrorBody>(&resp.body) {
+            Ok(body) => (body.error_type, body.message),
+            Err(_) => (
+                "Unknown".to_string(),
+                String::from_utf8_lossy(&resp.body).into_owned(),
+            ),
+        };
+        return Err(Error::Status {
+            status_code: resp.status_code,
+            error_type,
+            message,
+        });
+    }
+
+    let content_type = resp.header("Content-Type").unwrap_or_default();
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    if !media_type.eq_ignore_ascii_case(ARROW_STREAM_CONTENT_TYPE) {
+        return Err(Error::ContentType(content_type.to_string()));
+    }
+
+    let body = match resp.header("Content-Encoding") {
+        None => resp.body.clone(),
+        Some(encoding) if encoding.eq_ignore_ascii_case("zstd") => {
+            zstd::decode_all(resp.body.as_slice())?
+        }
+        Some(encoding) if encoding.eq_ignore_ascii_case("lz4") => {
+            let mut body = Vec::new();
+            lz4_flex::frame::FrameDecoder::new(resp.body.as_slice()).read_to_end(&mut body)?;
+            body
+        }
+        Some(encoding) => return Err(Error::ContentEncoding(encoding.to_string())),
+    };
+
+    Ok(decode_arrow_ipc(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{Int64Array, StringArray};
+    use arrow_ipc::writer::StreamWriter;
+    use arrow_schema::{DataType, Field, Schema};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn stream() -> (RecordBatch, Vec<u8>) {
+        let schema = Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, true),
+        ]);
+        let batch = RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(Int64Array::from(vec![1, 2])),
+                Arc::new(StringArray::from(vec![Some("a"), None])),
+            ],
+        )
+        .unwrap();
+        let mut body = Vec::new();
+        let mut writer = StreamWriter::try_new(&mut body, &batch.schema()).unwrap();
+        writer.write(&batch).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        (batch, body)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (batch, _) = stream();
+        let mut bytes = Vec::new();
+        let mut writer = StreamWriter::try_new(&mut bytes, &batch.schema()).unwrap();
+        writer.write(&batch).unwrap();
+        writer.write(&batch.slice(1, 1)).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        let decoded = decode_arrow_ipc(&bytes).unwrap();
+        assert_eq!(decoded, [batch.clone(), batch.slice(1, 1)]);
+        assert_eq!(decoded[0].schema(), batch.schema());
+
+        assert!(decode_arrow_ipc(b"not arrow").is_err());
+    }
+
+    #[test]
+    fn test_decode_compressed_responses() {
+        let (batch, body) = stream();
+        let zstd = zstd::encode_all(body.as_slice(), 0).unwrap();
+        let mut lz4 = lz4_flex::frame::FrameEncoder::new(Vec::new());
+        std::io::Write::write_all(&mut lz4, &body).unwrap();
+
+        for (encoding, body) in [
+            (None, body.clone()),
+            (Some("zstd"), zstd),
+            (Some("lz4"), lz4.finish().unwrap()),
+        ] {
+            let mut headers = json!({ "content-type": ARROW_STREAM_CONTENT_TYPE });
+            if let Some(encoding) = encoding {
+                headers["Content-Encoding"] = json!(encoding);
+            }
+            let resp = ArrowIpcResponse {
+                status_code: 200,
+                headers,
+                body,
+            };
+            assert_eq!(
+                decode_response(&resp).unwrap(),
+                std::slice::from_ref(&batch)
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_rejects_errors_and_other_formats() {
+        let resp = ArrowIpcResponse {
+            status_code: 413,
+            headers: json!({ "Content-Type": "application/json" }),
+            body: br#"{"error_type":"ResultTooLarge","message":"Result exceeds 10 rows"}"#.to_vec(),
+        };
+        let err = decode_response(&resp).unwrap_err();
+        assert!(matches!(
+            &err,
+            Error::Status { status_code: 413, error_type, .. } if error_type == "ResultTooLarge"
+        ));
+
+        let resp = ArrowIpcResponse {
+            status_code: 200,
+            headers: json!({ "Content-Type": "text/csv; charset=utf-8" }),
+            body: b"id\n1\n".to_vec(),
+        };
+        let err = decode_response(&resp).unwrap_err();
+        assert!(matches!(&err, Error::ContentType(ct) if ct == "text/csv; charset=utf-8"));
+
+        let (_, body) = stream();
+        let resp = ArrowIpcResponse {
+            status_code: 200,
+            headers: json!({
+                "Content-Type": ARROW_STREAM_CONTENT_TYPE,
+                "Content-Encoding": "br",
+            }),
+            body,
+        };
+        assert!(matches!(
+            decode_response(&resp),
+            Err(Error::ContentEncoding(encoding)) if encoding == "br"
+        ));
+    }
+}
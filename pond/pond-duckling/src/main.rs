@@ -1,16 +1,748 @@
+use arrow::datatypes::Schema;
 use arrow::ipc::writer::StreamWriter;
+use arrow::json::writer::JsonArray;
 use arrow::record_batch::RecordBatch;
-use duckdb::Connection;
+use aws_config::BehaviorVersion;
+use duckdb::types::Value;
+use duckdb::{params_from_iter, Connection, InterruptHandle};
 use http::StatusCode;
-use lambda_runtime::tracing;
+use lambda_runtime::tracing::{self, Instrument};
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use lz4_flex::frame::FrameEncoder;
+use pond_parser::{QueryError, QueryPolicy, QueryWrapper};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::io::Cursor;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::OnceCell;
+
+mod metrics;
 
 #[derive(Deserialize)]
 struct Request {
+    /// The SQL to run. Without it the worker runs the plan fragment, if any.
     query: Option<String>,
+    #[serde(flatten)]
+    fragment: PlanFragment,
+    /// Values for the query's `?` placeholders, in order.
+    #[serde(default)]
+    params: Vec<serde_json::Value>,
+    /// Secrets Manager secret holding S3 credentials for private buckets.
+    secret_arn: Option<String>,
+    /// Credentials created as DuckDB secrets for this invocation; see
+    /// [`DuckDbSecret`].
+    duckdb_secrets: Option<Vec<DuckDbSecret>>,
+    /// Threads DuckDB may use for this query; see [`duckdb_threads`].
+    duckdb_threads: Option<u32>,
+    /// Whether to report CloudWatch metrics for this query; on unless `false`.
+    metrics_enabled: Option<bool>,
+    /// How long the query may run, overriding `POND_QUERY_TIMEOUT_SECS`.
+    timeout_secs: Option<u64>,
+    /// Takes precedence over `accept`.
+    response_format: Option<ResponseFormat>,
+    /// An HTTP `Accept` value such as `text/csv`, used when `response_format`
+    /// isn't given.
+    accept: Option<String>,
+    /// Runs the query under `EXPLAIN ANALYZE` and returns the profiled plan as
+    /// plain text, or its error as plain text with a 500.
+    explain_analyze: Option<bool>,
+    /// Compresses the response body. Takes precedence over `accept_encoding`.
+    compression: Option<ContentEncoding>,
+    /// An HTTP `Accept-Encoding` value such as `zstd, gzip`, used when
+    /// `compression` isn't given.
+    accept_encoding: Option<String>,
+    /// Returns a result past `POND_MAX_RESULT_ROWS` or `POND_MAX_RESULT_BYTES`
+    /// cut short and flagged by `X-Pond-Result-Truncated`, rather than failing
+    /// with a 413.
+    truncate: Option<bool>,
+}
+
+/// The part of a distributed aggregation the planner hands one worker: the
+/// partial aggregate over `table`, reading `partition` in place of the
+/// table's source.
+#[derive(Deserialize, Debug, Default)]
+struct PlanFragment {
+    /// The FROM relation, alias included.
+    table: Option<String>,
+    group_column: Option<String>,
+    /// The aggregate, e.g. `SUM(amount)`, when `aggregates` is empty.
+    agg_function: Option<String>,
+    /// SELECT items computing the partial aggregate, e.g. a SUM and a COUNT
+    /// for an AVG.
+    #[serde(default)]
+    aggregates: Vec<String>,
+    where_clause: Option<String>,
+    /// A further filter the planner pushes down, applied along with
+    /// `where_clause`.
+    push_down_filter: Option<String>,
+    /// The prefix this worker reads, e.g. `s3://bucket/data/2024/*`.
+    partition: Option<String>,
+}
+
+impl PlanFragment {
+    /// The SQL computing this fragment's partial aggregate, or `None` when
+    /// there is no fragment.
+    fn to_sql(&self) -> Result<Option<String>, QueryError> {
+        let Some(table) = &self.table else {
+            return Ok(None);
+        };
+        let mut items = match (&self.aggregates[..], &self.agg_function) {
+            ([], Some(agg_function)) => vec![agg_function.clone()],
+            ([], None) => {
+                return Err(QueryError::Other(
+                    "Plan fragment has no aggregate".to_string(),
+                ))
+            }
+            (aggregates, _) => aggregates.to_vec(),
+        };
+        let group_column = self.group_column.as_deref().map(quote_ident);
+        if let Some(group_column) = &group_column {
+            items.insert(0, group_column.clone());
+        }
+
+        let mut sql = format!("SELECT {} FROM {}", items.join(", "), table);
+        match (&self.where_clause, &self.push_down_filter) {
+            (Some(where_clause), Some(filter)) => {
+                sql.push_str(&format!(" WHERE ({}) AND ({})", where_clause, filter));
+            }
+            (Some(filter), None) | (None, Some(filter)) => {
+                sql.push_str(" WHERE ");
+                sql.push_str(filter);
+            }
+            (None, None) => {}
+        }
+        if let Some(group_column) = &group_column {
+            sql.push_str(" GROUP BY ");
+            sql.push_str(group_column);
+        }
+
+        let mut wrapper = QueryWrapper::parse(&sql)?;
+        if let Some(partition) = &self.partition {
+            let source = wrapper.source()?;
+            if *partition != source {
+                wrapper.normalize_table_names(&HashMap::from([(source, partition.clone())]))?;
+            }
+        }
+        Ok(Some(wrapper.sql().to_string()))
+    }
+}
+
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// How the result is encoded in the response body.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ResponseFormat {
+    /// An Arrow IPC stream, which the planner expects.
+    #[default]
+    Arrow,
+    /// A Parquet file written by DuckDB.
+    Parquet,
+    /// A JSON array with an object per row.
+    Json,
+    /// CSV with a header row.
+    Csv,
+}
+
+impl ResponseFormat {
+    /// The first media type in `accept` with a format, ignoring parameters
+    /// such as `q`. `None` when there is none, e.g. for `*/*`.
+    fn from_accept(accept: &str) -> Option<Self> {
+        accept.split(',').find_map(|range| {
+            let media_type = range.split(';').next().unwrap_or_default();
+            match media_type.trim().to_ascii_lowercase().as_str() {
+                "application/vnd.apache.arrow.stream" => Some(Self::Arrow),
+                "application/vnd.apache.parquet" => Some(Self::Parquet),
+                "application/json" => Some(Self::Json),
+                "text/csv" => Some(Self::Csv),
+                _ => None,
+            }
+        })
+    }
+
+    fn headers(self) -> serde_json::Value {
+        match self {
+            Self::Arrow => json!({
+                "Content-Type": "application/vnd.apache.arrow.stream",
+            }),
+            Self::Parquet => json!({
+                "Content-Type": "application/octet-stream",
+                "Content-Disposition": "attachment; filename=\"result.parquet\"",
+            }),
+            Self::Json => json!({
+                "Content-Type": "application/json",
+            }),
+            Self::Csv => json!({
+                "Content-Type": "text/csv; charset=utf-8",
+            }),
+        }
+    }
+}
+
+/// A compression of the response body, named in its `Content-Encoding`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ContentEncoding {
+    /// A zstd frame.
+    Zstd,
+    /// An LZ4 frame.
+    Lz4,
+}
+
+impl ContentEncoding {
+    /// The first coding in `accept_encoding` the worker can produce, skipping
+    /// any refused with `q=0`. `None` when there is none, e.g. for `gzip`.
+    fn from_accept_encoding(accept_encoding: &str) -> Option<Self> {
+        accept_encoding.split(',').find_map(|coding| {
+            let mut parts = coding.split(';');
+            let name = parts.next().unwrap_or_default();
+            let refused = parts.any(|param| {
+                let q = param.trim().strip_prefix("q=");
+                q.and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+            });
+            match name.trim().to_ascii_lowercase().as_str() {
+                _ if refused => None,
+                "zstd" => Some(Self::Zstd),
+                "lz4" => Some(Self::Lz4),
+                _ => None,
+            }
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+            Self::Lz4 => "lz4",
+        }
+    }
+
+    fn encode(self, body: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            // Level 0 is zstd's default, currently 3.
+            Self::Zstd => Ok(zstd::encode_all(body, 0)?),
+            Self::Lz4 => {
+                let mut encoder = FrameEncoder::new(Vec::new());
+                encoder.write_all(body)?;
+                Ok(encoder.finish()?)
+            }
+        }
+    }
+}
+
+/// How long a query may run when neither the request nor
+/// `POND_QUERY_TIMEOUT_SECS` says otherwise.
+const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Kept back from the invocation deadline to send the timeout response.
+const DEADLINE_MARGIN: Duration = Duration::from_secs(1);
+
+/// How long a timed-out statement gets to stop once interrupted, within
+/// [`DEADLINE_MARGIN`].
+const INTERRUPT_GRACE: Duration = Duration::from_millis(500);
+
+/// How often a timed-out statement is interrupted again while it stops, in
+/// case the first interrupt came before the statement started.
+const INTERRUPT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The query ran past its timeout.
+#[derive(Debug)]
+struct QueryTimeout(Duration);
+
+impl std::fmt::Display for QueryTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Query timed out after {:?}", self.0)
+    }
+}
+
+impl std::error::Error for QueryTimeout {}
+
+/// Kept under Lambda's 6MB cap on synchronous responses, which also has to
+/// fit the headers and the body's encoding.
+const DEFAULT_MAX_RESULT_BYTES: usize = 4 * 1024 * 1024;
+
+/// How large a result may get before it is cut short or the query fails with
+/// [`ResultTooLarge`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ResultLimits {
+    max_rows: Option<usize>,
+    /// Counted as the Arrow memory of the batches, which runs close to their
+    /// encoded size.
+    max_bytes: Option<usize>,
+    /// Whether to return the rows within the limits instead of failing.
+    truncate: bool,
+}
+
+impl ResultLimits {
+    /// No limits, for callers that size the result themselves.
+    #[cfg(test)]
+    const NONE: Self = Self {
+        max_rows: None,
+        max_bytes: None,
+        truncate: false,
+    };
+
+    /// `POND_MAX_RESULT_ROWS`, unlimited by default, and
+    /// `POND_MAX_RESULT_BYTES`, or [`DEFAULT_MAX_RESULT_BYTES`]. Either may be
+    /// `0` to lift it.
+    fn from_env(var: impl Fn(&str) -> Option<String>, truncate: bool) -> Self {
+        let limit = |name| var(name).and_then(|value| value.trim().parse::<usize>().ok());
+        let max_bytes = limit("POND_MAX_RESULT_BYTES").unwrap_or(DEFAULT_MAX_RESULT_BYTES);
+        Self {
+            max_rows: limit("POND_MAX_RESULT_ROWS").filter(|&max| max > 0),
+            max_bytes: Some(max_bytes).filter(|&max| max > 0),
+            truncate,
+        }
+    }
+}
+
+/// The result grew past [`ResultLimits`] and truncation wasn't asked for.
+#[derive(Debug)]
+struct ResultTooLarge(String);
+
+impl std::fmt::Display for ResultTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Result exceeds {}; add a LIMIT to the query or request truncation",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ResultTooLarge {}
+
+/// The JSON shape expected in the secret named by `Request::secret_arn`.
+#[derive(Deserialize)]
+struct S3Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    region: String,
+}
+
+// Hand-written so credentials can't reach the logs through `{:?}`.
+impl std::fmt::Debug for S3Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Credentials")
+            .field("access_key_id", &"<redacted>")
+            .field("secret_access_key", &"<redacted>")
+            .field("region", &self.region)
+            .finish()
+    }
+}
+
+impl S3Credentials {
+    /// Fetches and parses the secret. Errors name the secret but never include
+    /// its value.
+    async fn fetch(secret_arn: &str) -> Result<Self, Error> {
+        let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+        let client = aws_sdk_secretsmanager::Client::new(&config);
+        let output = client
+            .get_secret_value()
+            .secret_id(secret_arn)
+            .send()
+            .await
+            .map_err(|err| format!("Failed to fetch secret {}: {}", secret_arn, err))?;
+        let secret = output
+            .secret_string()
+            .ok_or_else(|| format!("Secret {} has no string value", secret_arn))?;
+        serde_json::from_str(secret)
+            .map_err(|_| format!("Secret {} is not valid S3 credentials JSON", secret_arn).into())
+    }
+
+    /// Configures DuckDB's S3 access with these credentials, replacing those
+    /// from the environment. Expects httpfs to be loaded.
+    fn apply(&self, conn: &Connection) -> Result<(), Error> {
+        set_option(conn, "s3_access_key_id", &self.access_key_id)?;
+        set_option(conn, "s3_secret_access_key", &self.secret_access_key)?;
+        set_option(conn, "s3_region", &self.region)?;
+        // The role's session token doesn't belong with these keys.
+        set_option(conn, "s3_session_token", "")
+    }
+}
+
+/// Credentials for one invocation, created with DuckDB's secrets API as
+/// `CREATE TEMPORARY SECRET pond_request_<n> (TYPE <type>, PROVIDER
+/// <provider>, <option> '<value>', ...)`, e.g. type `s3` with provider
+/// `config` and options `KEY_ID`, `SECRET` and `REGION`.
+///
+/// Temporary secrets live in memory for as long as the connection, and the
+/// connection outlives the invocation to be reused by the next one, so
+/// [`acquire_connection`] drops any left by an earlier invocation.
+#[derive(Deserialize)]
+struct DuckDbSecret {
+    /// One of [`SECRET_TYPES`].
+    #[serde(rename = "type")]
+    type_: String,
+    /// How DuckDB gets the credentials, e.g. `config` for ones given in
+    /// `options` or `credential_chain` to find them itself.
+    provider: String,
+    /// Named as DuckDB names them for `type_` and `provider`; `SCOPE`
+    /// restricts the secret to paths under a prefix.
+    #[serde(default)]
+    options: HashMap<String, String>,
+}
+
+// Hand-written so option values, which hold credentials, stay out of the logs.
+impl std::fmt::Debug for DuckDbSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut options: Vec<&String> = self.options.keys().collect();
+        options.sort();
+        f.debug_struct("DuckDbSecret")
+            .field("type_", &self.type_)
+            .field("provider", &self.provider)
+            .field("options", &options)
+            .finish()
+    }
+}
+
+/// The secret types [`DuckDbSecret`] may create.
+const SECRET_TYPES: [&str; 4] = ["s3", "r2", "gcs", "azure"];
+
+/// Prefix of the names [`DuckDbSecret`]s are created under.
+const REQUEST_SECRET_PREFIX: &str = "pond_request_";
+
+impl DuckDbSecret {
+    /// The `CREATE TEMPORARY SECRET` statement creating this secret as `name`.
+    /// The type, provider and option names are checked against what DuckDB
+    /// accepts unquoted, since only values can be quoted.
+    fn create_statement(&self, name: &str) -> Result<String, String> {
+        let type_ = self.type_.to_lowercase();
+        if !SECRET_TYPES.contains(&type_.as_str()) {
+            return Err(format!(
+                "Unsupported secret type {}; expected one of {}",
+                self.type_,
+                SECRET_TYPES.join(", ")
+            ));
+        }
+        let is_word = |word: &str| {
+            !word.is_empty()
+                && !word.starts_with(|c: char| c.is_ascii_digit())
+                && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+        if !is_word(&self.provider) {
+            return Err(format!("Invalid secret provider {}", self.provider));
+        }
+
+        let mut options: Vec<(&String, &String)> = self.options.iter().collect();
+        options.sort();
+        let mut items = vec![
+            format!("TYPE {}", type_),
+            format!("PROVIDER {}", self.provider),
+        ];
+        for (option, value) in options {
+            if !is_word(option) || ["type", "provider"].contains(&option.to_lowercase().as_str()) {
+                return Err(format!("Invalid secret option {}", option));
+            }
+            items.push(format!("{} '{}'", option, value.replace('\'', "''")));
+        }
+        Ok(format!(
+            "CREATE OR REPLACE TEMPORARY SECRET {} ({})",
+            name,
+            items.join(", ")
+        ))
+    }
+}
+
+/// Creates `secrets` on `conn`, reporting a failure without DuckDB's message,
+/// which could echo an option's value.
+fn create_secrets(conn: &Connection, secrets: &[DuckDbSecret]) -> Result<(), String> {
+    for (index, secret) in secrets.iter().enumerate() {
+        let name = format!("{}{}", REQUEST_SECRET_PREFIX, index);
+        conn.execute_batch(&secret.create_statement(&name)?)
+            .map_err(|_| format!("Failed to create {} secret {}", secret.type_, index))?;
+    }
+    Ok(())
+}
+
+/// Drops the secrets an earlier invocation created on `conn`.
+fn drop_request_secrets(conn: &Connection) -> Result<(), Error> {
+    let names = conn
+        .prepare("SELECT name FROM duckdb_secrets() WHERE starts_with(name, ?)")?
+        .query_map([REQUEST_SECRET_PREFIX], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    for name in names {
+        conn.execute_batch(&format!("DROP TEMPORARY SECRET {}", name))?;
+    }
+    Ok(())
+}
+
+/// Configures S3 access from the Lambda environment: the execution role's
+/// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`,
+/// `AWS_REGION`, and for S3-compatible storage `AWS_ENDPOINT_URL_S3` (or
+/// `AWS_ENDPOINT_URL`) and `POND_S3_URL_STYLE`. Settings without a variable
+/// are reset, undoing credentials an earlier invocation applied. The s3_*
+/// settings belong to httpfs, so it must be loaded.
+fn configure_s3(conn: &Connection) -> Result<(), Error> {
+    let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+    let settings = [
+        (
+            "s3_region",
+            var("AWS_REGION").or_else(|| var("AWS_DEFAULT_REGION")),
+        ),
+        ("s3_access_key_id", var("AWS_ACCESS_KEY_ID")),
+        ("s3_secret_access_key", var("AWS_SECRET_ACCESS_KEY")),
+        ("s3_session_token", var("AWS_SESSION_TOKEN")),
+        ("s3_url_style", var("POND_S3_URL_STYLE")),
+    ];
+    for (setting, value) in settings {
+        match value {
+            Some(value) => set_option(conn, setting, &value)?,
+            None => reset_option(conn, setting)?,
+        }
+    }
+
+    if let Some(endpoint) = var("AWS_ENDPOINT_URL_S3").or_else(|| var("AWS_ENDPOINT_URL")) {
+        // DuckDB wants the bare host and a separate switch for plain HTTP.
+        let (host, use_ssl) = match endpoint.split_once("://") {
+            Some((scheme, host)) => (host, !scheme.eq_ignore_ascii_case("http")),
+            None => (endpoint.as_str(), true),
+        };
+        set_option(conn, "s3_endpoint", host.trim_end_matches('/'))?;
+        conn.execute_batch(&format!("SET s3_use_ssl = {}", use_ssl))?;
+    } else {
+        reset_option(conn, "s3_endpoint")?;
+        reset_option(conn, "s3_use_ssl")?;
+    }
+    Ok(())
+}
+
+/// Share of the Lambda's memory DuckDB may use by default, leaving the rest
+/// for the runtime and the result, which is buffered in full before it is sent.
+const DEFAULT_MEMORY_LIMIT_PERCENT: u64 = 60;
+
+/// DuckDB resource settings from the environment: `POND_DUCKDB_MEMORY_LIMIT`
+/// (e.g. `1.5GB`) or else a share of `AWS_LAMBDA_FUNCTION_MEMORY_SIZE`,
+/// `POND_DUCKDB_THREADS`, and `POND_DUCKDB_TEMP_DIRECTORY` or else a directory
+/// under `/tmp`, since DuckDB's default spills into the read-only working
+/// directory.
+fn resource_settings(var: impl Fn(&str) -> Option<String>) -> Vec<(&'static str, String)> {
+    let mut settings = Vec::new();
+    let memory_limit = var("POND_DUCKDB_MEMORY_LIMIT").or_else(|| {
+        let lambda_mb: u64 = var("AWS_LAMBDA_FUNCTION_MEMORY_SIZE")?.parse().ok()?;
+        Some(format!(
+            "{}MB",
+            lambda_mb * DEFAULT_MEMORY_LIMIT_PERCENT / 100
+        ))
+    });
+    if let Some(memory_limit) = memory_limit {
+        settings.push(("memory_limit", memory_limit));
+    }
+    if let Some(threads) = var("POND_DUCKDB_THREADS") {
+        settings.push(("threads", threads));
+    }
+    let temp_directory = var("POND_DUCKDB_TEMP_DIRECTORY").unwrap_or_else(|| {
+        std::env::temp_dir()
+            .join("pond-duckdb")
+            .to_string_lossy()
+            .into_owned()
+    });
+    settings.push(("temp_directory", temp_directory));
+    settings
+}
+
+/// Applies [`resource_settings`] to a freshly opened connection.
+fn configure_resources(conn: &Connection) -> Result<(), Error> {
+    let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+    for (setting, value) in resource_settings(var) {
+        set_option(conn, setting, &value)?;
+    }
+    Ok(())
+}
+
+/// Runs `RESET setting`.
+fn reset_option(conn: &Connection, setting: &str) -> Result<(), Error> {
+    conn.execute_batch(&format!("RESET {}", setting))
+        .map_err(|_| format!("Failed to reset {}", setting).into())
+}
+
+/// The connection warm invocations reuse, put back by the last one to finish
+/// with it. A Lambda environment serves one invocation at a time, so one slot
+/// is enough.
+static WARM_CONNECTION: Mutex<Option<Connection>> = Mutex::new(None);
+
+/// The DuckDB extensions named in `POND_DUCKDB_EXTENSIONS` (e.g.
+/// `httpfs,parquet,spatial`), or httpfs and parquet. Queries can't autoload
+/// extensions once [`restrict_access`] has run, so every one they need must
+/// be listed.
+fn extensions_from_env() -> Vec<String> {
+    match std::env::var("POND_DUCKDB_EXTENSIONS") {
+        Ok(names) => names
+            .split(',')
+            .map(|name| name.trim().to_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect(),
+        Err(_) => vec!["httpfs".to_string(), "parquet".to_string()],
+    }
+}
+
+/// Where queries may read from: the prefixes in
+/// `POND_DUCKDB_ALLOWED_DIRECTORIES` (e.g. `s3://,https://`), or S3 and
+/// HTTPS, plus the directory Parquet results are written to.
+fn allowed_directories_from_env() -> Vec<String> {
+    let mut directories: Vec<String> = match std::env::var("POND_DUCKDB_ALLOWED_DIRECTORIES") {
+        Ok(prefixes) => prefixes
+            .split(',')
+            .map(|prefix| prefix.trim().to_string())
+            .filter(|prefix| !prefix.is_empty())
+            .collect(),
+        Err(_) => vec!["s3://".to_string(), "https://".to_string()],
+    };
+    directories.push(format!("{}/", results_directory().display()));
+    directories
+}
+
+/// Turns off DuckDB's access to the local filesystem and network outside
+/// `allowed_directories`, along with ATTACH and installing or loading
+/// extensions. This can't be undone for the life of the connection, and
+/// `temp_directory` can't be changed afterwards either.
+fn restrict_access(conn: &Connection, allowed_directories: &[String]) -> Result<(), Error> {
+    let list = allowed_directories
+        .iter()
+        .map(|directory| format!("'{}'", directory.replace('\'', "''")))
+        .collect::<Vec<_>>()
+        .join(", ");
+    conn.execute_batch(&format!("SET allowed_directories = [{}]", list))?;
+    conn.execute_batch("SET enable_external_access = false")?;
+    Ok(())
+}
+
+/// The parsed query, or the error response for a query that isn't a single
+/// pure read or can't be parsed well enough to tell.
+fn check_read_only(query: &str) -> Result<QueryWrapper, ArrowIpcResponse> {
+    let wrapper = QueryWrapper::parse(query)
+        .map_err(|err| error_response(StatusCode::BAD_REQUEST, "InvalidQuery", err.to_string()))?;
+    wrapper
+        .validate(&QueryPolicy::read_only())
+        .map_err(|err| error_response(StatusCode::FORBIDDEN, "PolicyViolation", err.to_string()))?;
+    Ok(wrapper)
+}
+
+/// Fails naming the first of `extensions` DuckDB doesn't know, so a typo is
+/// caught at startup instead of failing queries.
+fn check_extensions(conn: &Connection, extensions: &[String]) -> Result<(), Error> {
+    for name in extensions {
+        let known = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && conn.query_row(
+                "SELECT COUNT(*) > 0 FROM duckdb_extensions() WHERE extension_name = ?",
+                [name],
+                |row| row.get::<_, bool>(0),
+            )?;
+        if !known {
+            return Err(format!(
+                "Unknown DuckDB extension in POND_DUCKDB_EXTENSIONS: {}",
+                name
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Installs `extensions`, which [`check_extensions`] accepted.
+fn install_extensions(conn: &Connection, extensions: &[String]) -> Result<(), Error> {
+    for name in extensions {
+        conn.execute_batch(&format!("INSTALL {}", name))?;
+    }
+    Ok(())
+}
+
+/// Loads `extensions`, which [`install_extensions`] installed.
+fn load_extensions<'a>(
+    conn: &Connection,
+    extensions: impl IntoIterator<Item = &'a String>,
+) -> Result<(), Error> {
+    for name in extensions {
+        conn.execute_batch(&format!("LOAD {}", name))?;
+    }
+    Ok(())
+}
+
+/// The extensions from [`extensions_from_env`], checked and installed by the
+/// first invocation. Installed extensions live on disk, so connections opened
+/// by later invocations only load them.
+static INSTALLED_EXTENSIONS: OnceCell<HashSet<String>> = OnceCell::const_new();
+
+/// [`INSTALLED_EXTENSIONS`], installing them on first use.
+async fn installed_extensions() -> Result<&'static HashSet<String>, Error> {
+    INSTALLED_EXTENSIONS
+        .get_or_try_init(|| async {
+            let extensions = extensions_from_env();
+            let conn = Connection::open_in_memory()?;
+            check_extensions(&conn, &extensions)?;
+            install_extensions(&conn, &extensions)?;
+            Ok(extensions.into_iter().collect())
+        })
+        .await
+}
+
+/// The warm connection, or a new one with `extensions` loaded,
+/// resource settings applied and access restricted, with S3 settings applied
+/// from the environment and request secrets dropped so no earlier
+/// invocation's credentials carry over. S3 settings need httpfs and are
+/// skipped without it.
+fn acquire_connection(extensions: &HashSet<String>) -> Result<Connection, Error> {
+    let warm = WARM_CONNECTION
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+    let conn = match warm {
+        Some(conn) => {
+            drop_request_secrets(&conn)?;
+            conn
+        }
+        None => {
+            let conn = Connection::open_in_memory()?;
+            load_extensions(&conn, extensions)?;
+            configure_resources(&conn)?;
+            restrict_access(&conn, &allowed_directories_from_env())?;
+            conn
+        }
+    };
+    if extensions.contains("httpfs") {
+        configure_s3(&conn)?;
+    }
+    Ok(conn)
+}
+
+/// Keeps `conn` for the next invocation, unless another connection got there
+/// first.
+fn release_connection(conn: Connection) {
+    let mut slot = WARM_CONNECTION
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if slot.is_none() {
+        *slot = Some(conn);
+    }
+}
+
+/// Runs `query` in a transaction that is then rolled back, so tables and
+/// views it creates don't outlive the invocation, and releases the
+/// connection. One that can't roll back is closed instead.
+fn run_and_release<T>(
+    conn: Connection,
+    query: impl FnOnce(&Connection) -> Result<T, Error>,
+) -> Result<T, Error> {
+    conn.execute_batch("BEGIN TRANSACTION")?;
+    let result = query(&conn);
+    if conn.execute_batch("ROLLBACK").is_ok() {
+        release_connection(conn);
+    }
+    result
+}
+
+/// Runs `SET setting = 'value'`.
+fn set_option(conn: &Connection, setting: &str, value: &str) -> Result<(), Error> {
+    let statement = format!("SET {} = '{}'", setting, value.replace('\'', "''"));
+    // DuckDB's message could echo the statement, so report only the setting.
+    conn.execute_batch(&statement)
+        .map_err(|_| format!("Failed to set {}", setting).into())
 }
 
 #[derive(Serialize)]
@@ -21,10 +753,10 @@ struct ArrowIpcResponse {
     body: Vec<u8>,
 }
 
-fn convert_to_arrow_ipc(rbs: &[RecordBatch]) -> Result<Vec<u8>, Error> {
+fn convert_to_arrow_ipc(schema: &Schema, rbs: &[RecordBatch]) -> Result<Vec<u8>, Error> {
     let mut buffer = Cursor::new(Vec::new());
     {
-        let mut writer = StreamWriter::try_new(&mut buffer, &rbs[0].schema())?;
+        let mut writer = StreamWriter::try_new(&mut buffer, schema)?;
         for batch in rbs {
             writer.write(batch)?;
         }
@@ -33,33 +765,1294 @@ fn convert_to_arrow_ipc(rbs: &[RecordBatch]) -> Result<Vec<u8>, Error> {
     Ok(buffer.into_inner())
 }
 
-async fn function_handler(event: LambdaEvent<Request>) -> Result<ArrowIpcResponse, Error> {
-    let query = event.payload.query.unwrap_or_else(||
-        "SELECT * FROM read_parquet('https://shell.duckdb.org/data/tpch/0_01/parquet/customer.parquet') LIMIT 5".to_string()
-    );
+/// A request parameter that has no DuckDB counterpart.
+#[derive(Debug)]
+struct InvalidParam {
+    index: usize,
+    kind: &'static str,
+}
 
-    // Create an in-memory DuckDB database
-    let conn = Connection::open_in_memory()?;
+impl std::fmt::Display for InvalidParam {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Parameter {} is {}, expected null, a boolean, a number or a string",
+            self.index + 1,
+            self.kind
+        )
+    }
+}
 
+impl std::error::Error for InvalidParam {}
+
+/// Converts JSON parameters to the DuckDB values bound to `?` placeholders.
+/// Integers bind as BIGINT (UBIGINT above `i64::MAX`), other numbers as
+/// DOUBLE and strings as VARCHAR, which DuckDB casts to the column's type.
+fn bind_params(params: &[serde_json::Value]) -> Result<Vec<Value>, InvalidParam> {
+    params
+        .iter()
+        .enumerate()
+        .map(|(index, param)| match param {
+            serde_json::Value::Null => Ok(Value::Null),
+            serde_json::Value::Bool(value) => Ok(Value::Boolean(*value)),
+            serde_json::Value::Number(number) => Ok(number
+                .as_i64()
+                .map(Value::BigInt)
+                .or_else(|| number.as_u64().map(Value::UBigInt))
+                .unwrap_or_else(|| Value::Double(number.as_f64().unwrap_or(f64::NAN)))),
+            serde_json::Value::String(value) => Ok(Value::Text(value.clone())),
+            serde_json::Value::Array(_) => Err(InvalidParam {
+                index,
+                kind: "an array",
+            }),
+            serde_json::Value::Object(_) => Err(InvalidParam {
+                index,
+                kind: "an object",
+            }),
+        })
+        .collect()
+}
+
+/// An encoded query result.
+#[derive(Debug)]
+struct ResultBody {
+    bytes: Vec<u8>,
+    /// The rows encoded, when the result was read as record batches.
+    rows: Option<usize>,
+    /// Whether rows were left out to stay within [`ResultLimits`].
+    truncated: bool,
+}
+
+impl ResultBody {
+    /// A body of `batches`, encoded as `bytes`.
+    fn of_batches(bytes: Vec<u8>, batches: &[RecordBatch], truncated: bool) -> Self {
+        Self {
+            bytes,
+            rows: Some(batches.iter().map(RecordBatch::num_rows).sum()),
+            truncated,
+        }
+    }
+
+    /// A body DuckDB encoded, whose rows aren't counted.
+    fn complete(bytes: Vec<u8>) -> Self {
+        Self {
+            bytes,
+            rows: None,
+            truncated: false,
+        }
+    }
+}
+
+/// Runs `query` with `params` bound, returning its schema and batches and
+/// whether they were cut short at `limits`. Batches are counted as they are
+/// converted, and conversion stops at the first past a limit.
+fn query_batches(
+    conn: &Connection,
+    query: &str,
+    params: &[Value],
+    limits: &ResultLimits,
+) -> Result<(Schema, Vec<RecordBatch>, bool), Error> {
     // Execute the query using arrow
-    let mut stmt = conn.prepare(&query)?;
-    let rbs: Vec<RecordBatch> = stmt.query_arrow([])?.collect();
+    let mut stmt = conn.prepare(query)?;
+    let arrow = stmt.query_arrow(params_from_iter(params))?;
+    // Taken from the statement so a query with no rows still has a schema.
+    let schema = arrow.get_schema();
+    let mut rbs: Vec<RecordBatch> = Vec::new();
+    let (mut rows, mut bytes) = (0, 0);
+    for batch in arrow {
+        let kept_rows = rows;
+        rows += batch.num_rows();
+        bytes += batch.get_array_memory_size();
+        let over_rows = limits.max_rows.filter(|&max| rows > max);
+        let over_bytes = limits.max_bytes.filter(|&max| bytes > max);
+        if over_rows.is_none() && over_bytes.is_none() {
+            rbs.push(batch);
+            continue;
+        }
+        if !limits.truncate {
+            let limit = match over_bytes {
+                Some(max) => format!("{} bytes", max),
+                None => format!("{} rows", over_rows.unwrap_or_default()),
+            };
+            return Err(ResultTooLarge(limit).into());
+        }
+        // A batch past the byte limit is left out whole.
+        if let (Some(max), None) = (over_rows, over_bytes) {
+            rbs.push(batch.slice(0, max - kept_rows));
+        }
+        return Ok((schema.as_ref().clone(), rbs, true));
+    }
+    Ok((schema.as_ref().clone(), rbs, false))
+}
+
+/// Runs `query` with `params` bound and encodes its result as an Arrow IPC
+/// stream. A query with no rows yields a stream holding just the schema.
+fn query_to_arrow_ipc(
+    conn: &Connection,
+    query: &str,
+    params: &[Value],
+    limits: &ResultLimits,
+) -> Result<ResultBody, Error> {
+    let (schema, rbs, truncated) = query_batches(conn, query, params, limits)?;
 
     // Convert RecordBatches to Arrow IPC format
-    let arrow_ipc_data = convert_to_arrow_ipc(&rbs)?;
+    let bytes = convert_to_arrow_ipc(&schema, &rbs)?;
+    Ok(ResultBody::of_batches(bytes, &rbs, truncated))
+}
+
+/// Runs `query` with `params` bound and encodes its result as a JSON array of
+/// row objects, with `null` for null values. No rows yield `[]`.
+fn query_to_json(
+    conn: &Connection,
+    query: &str,
+    params: &[Value],
+    limits: &ResultLimits,
+) -> Result<ResultBody, Error> {
+    let (_, rbs, truncated) = query_batches(conn, query, params, limits)?;
+    let mut writer = arrow::json::WriterBuilder::new()
+        .with_explicit_nulls(true)
+        .build::<_, JsonArray>(Vec::new());
+    for batch in &rbs {
+        writer.write(batch)?;
+    }
+    writer.finish()?;
+    Ok(ResultBody::of_batches(writer.into_inner(), &rbs, truncated))
+}
+
+/// Runs `query` with `params` bound and encodes its result as CSV. The header
+/// row is written even when there are no rows.
+fn query_to_csv(
+    conn: &Connection,
+    query: &str,
+    params: &[Value],
+    limits: &ResultLimits,
+) -> Result<ResultBody, Error> {
+    let (schema, rbs, truncated) = query_batches(conn, query, params, limits)?;
+    let mut writer = arrow::csv::WriterBuilder::new()
+        .with_header(true)
+        .build(Vec::new());
+    if rbs.is_empty() {
+        writer.write(&RecordBatch::new_empty(schema.into()))?;
+    }
+    for batch in &rbs {
+        writer.write(batch)?;
+    }
+    Ok(ResultBody::of_batches(writer.into_inner(), &rbs, truncated))
+}
+
+/// Threads a query gets when neither the request nor the environment says.
+/// Lambda CPUs are shared and DuckDB would otherwise claim every core.
+const DEFAULT_DUCKDB_THREADS: u32 = 1;
+
+/// The requested thread count, or `POND_DEFAULT_DUCKDB_THREADS`, or
+/// `POND_DUCKDB_THREADS`, or [`DEFAULT_DUCKDB_THREADS`]. DuckDB needs at
+/// least one.
+fn duckdb_threads(
+    requested: Option<u32>,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<u32, String> {
+    let from_env = |name| var(name).and_then(|value| value.trim().parse::<u32>().ok());
+    let threads = requested
+        .or_else(|| from_env("POND_DEFAULT_DUCKDB_THREADS"))
+        .or_else(|| from_env("POND_DUCKDB_THREADS"))
+        .unwrap_or(DEFAULT_DUCKDB_THREADS);
+    if threads == 0 {
+        return Err("duckdb_threads must be at least 1".to_string());
+    }
+    Ok(threads)
+}
+
+/// Runs `PRAGMA threads`, which lasts until the next request sets it again.
+fn set_threads(conn: &Connection, threads: u32) -> Result<(), Error> {
+    conn.execute_batch(&format!("PRAGMA threads={}", threads))
+        .map_err(|_| "Failed to set threads".into())
+}
+
+/// The requested timeout, or `POND_QUERY_TIMEOUT_SECS`, or the default,
+/// capped so the timeout response still goes out before `deadline`.
+fn query_timeout(requested_secs: Option<u64>, deadline: SystemTime) -> Duration {
+    let timeout = requested_secs
+        .or_else(|| {
+            std::env::var("POND_QUERY_TIMEOUT_SECS")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+        })
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_QUERY_TIMEOUT);
+    let remaining = deadline
+        .duration_since(SystemTime::now())
+        .unwrap_or_default()
+        .saturating_sub(DEADLINE_MARGIN);
+    timeout.min(remaining)
+}
+
+/// Runs `query` (a closure owning the connection) on a blocking thread,
+/// failing with [`QueryTimeout`] once `timeout` has passed.
+///
+/// A timed-out statement is cancelled through `interrupt`, the connection's
+/// handle, and its thread waited for so the connection goes back to the pool
+/// idle. One that hasn't stopped within [`INTERRUPT_GRACE`] is left to finish
+/// in the background.
+async fn query_with_timeout<T, F>(
+    timeout: Duration,
+    interrupt: &InterruptHandle,
+    query: F,
+) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    let mut run = tokio::task::spawn_blocking(query);
+    match tokio::time::timeout(timeout, &mut run).await {
+        Ok(result) => result?,
+        Err(_) => {
+            let stopped = tokio::time::timeout(INTERRUPT_GRACE, async {
+                loop {
+                    interrupt.interrupt();
+                    if tokio::time::timeout(INTERRUPT_INTERVAL, &mut run)
+                        .await
+                        .is_ok()
+                    {
+                        break;
+                    }
+                }
+            })
+            .await;
+            if stopped.is_err() {
+                tracing::warn!("Timed-out query didn't stop when interrupted");
+            }
+            Err(QueryTimeout(timeout).into())
+        }
+    }
+}
+
+/// A JSON error body in place of Arrow IPC, which the planner reports with the
+/// partition that failed.
+fn error_response(status: StatusCode, error_type: &str, message: String) -> ArrowIpcResponse {
+    ArrowIpcResponse {
+        status_code: status.as_u16(),
+        headers: json!({
+            "Content-Type": "application/json",
+        }),
+        body: json!({
+            "error_type": error_type,
+            "message": message,
+        })
+        .to_string()
+        .into_bytes(),
+    }
+}
+
+/// Runs `query` under `EXPLAIN ANALYZE` with `params` bound and returns the
+/// profiled plan DuckDB renders.
+fn query_to_explain_analyze(
+    conn: &Connection,
+    query: &str,
+    params: &[Value],
+) -> Result<Vec<u8>, Error> {
+    let mut stmt = conn.prepare(&format!("EXPLAIN ANALYZE {}", query))?;
+    let mut rows = stmt.query(params_from_iter(params))?;
+    let mut plan = String::new();
+    while let Some(row) = rows.next()? {
+        // Rows are (explain_key, explain_value); the value holds the plan.
+        plan.push_str(&row.get::<_, String>(1)?);
+    }
+    Ok(plan.into_bytes())
+}
+
+/// A plain-text response, as `explain_analyze` answers with.
+fn text_response(status: StatusCode, body: String) -> ArrowIpcResponse {
+    ArrowIpcResponse {
+        status_code: status.as_u16(),
+        headers: json!({
+            "Content-Type": "text/plain; charset=utf-8",
+        }),
+        body: body.into_bytes(),
+    }
+}
+
+/// The directory under `/tmp` Parquet results are written to, the only local
+/// one DuckDB may access.
+fn results_directory() -> PathBuf {
+    std::env::temp_dir().join("pond-results")
+}
+
+/// Where to write a Parquet result. `/tmp` outlives the invocation and is
+/// shared by every invocation a warm environment serves, so the name is
+/// derived from the query and the invocation's request ID.
+fn parquet_path(query: &str, request_id: &str) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    query.hash(&mut hasher);
+    request_id.hash(&mut hasher);
+    results_directory().join(format!("pond-result-{:016x}.parquet", hasher.finish()))
+}
+
+/// Runs `query` through DuckDB's `COPY ... (FORMAT PARQUET)` and returns the
+/// file's bytes. The file is removed afterwards, whether or not this succeeds.
+/// A file can't be cut short, so one past `max_bytes` fails with
+/// [`ResultTooLarge`] whether or not truncation was asked for.
+fn query_to_parquet(
+    conn: &Connection,
+    query: &str,
+    params: &[Value],
+    path: &Path,
+    max_bytes: Option<usize>,
+) -> Result<Vec<u8>, Error> {
+    let copy = format!(
+        "COPY ({}) TO '{}' (FORMAT PARQUET)",
+        query.trim().trim_end_matches(';'),
+        path.display()
+    );
+    if let Some(directory) = path.parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+    let result = conn
+        .prepare(&copy)
+        .and_then(|mut stmt| stmt.execute(params_from_iter(params)))
+        .map_err(Error::from)
+        .and_then(|_| match (std::fs::metadata(path)?.len(), max_bytes) {
+            (len, Some(max)) if len > max as u64 => {
+                Err(ResultTooLarge(format!("{} bytes", max)).into())
+            }
+            _ => Ok(std::fs::read(path)?),
+        });
+    let _ = std::fs::remove_file(path);
+    result
+}
+
+/// DuckDB error classes caused by the query itself rather than by storage,
+/// memory or DuckDB.
+const QUERY_ERROR_PREFIXES: &[&str] = &[
+    "Parser Error",
+    "Binder Error",
+    "Catalog Error",
+    "Conversion Error",
+    "Invalid Input Error",
+    "Not implemented Error",
+    "Syntax Error",
+    "Constraint Error",
+    "Out of Range Error",
+];
+
+/// The error response for a query that failed: 400 when DuckDB rejected the
+/// query or its parameters, 500 when running it failed.
+fn query_error_response(err: Error) -> ArrowIpcResponse {
+    let message = err.to_string();
+    let (status, error_type) = match err.downcast_ref::<duckdb::Error>() {
+        Some(duckdb::Error::InvalidParameterCount(..)) => {
+            (StatusCode::BAD_REQUEST, "InvalidParams")
+        }
+        Some(_)
+            if QUERY_ERROR_PREFIXES
+                .iter()
+                .any(|prefix| message.starts_with(prefix)) =>
+        {
+            (StatusCode::BAD_REQUEST, "InvalidQuery")
+        }
+        Some(_) => (StatusCode::INTERNAL_SERVER_ERROR, "QueryFailed"),
+        None => (StatusCode::INTERNAL_SERVER_ERROR, "InternalError"),
+    };
+    error_response(status, error_type, message)
+}
+
+async fn function_handler(event: LambdaEvent<Request>) -> Result<ArrowIpcResponse, Error> {
+    let started = Instant::now();
+    let Request {
+        query,
+        fragment,
+        params,
+        secret_arn,
+        duckdb_secrets,
+        duckdb_threads: requested_threads,
+        metrics_enabled,
+        timeout_secs,
+        response_format,
+        accept,
+        explain_analyze,
+        compression,
+        accept_encoding,
+        truncate,
+    } = event.payload;
+    let explain_analyze = explain_analyze.unwrap_or(false);
+    let format = response_format
+        .or_else(|| accept.as_deref().and_then(ResponseFormat::from_accept))
+        .unwrap_or_default();
+    let encoding = compression.or_else(|| {
+        accept_encoding
+            .as_deref()
+            .and_then(ContentEncoding::from_accept_encoding)
+    });
+    let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+    let limits = ResultLimits::from_env(var, truncate.unwrap_or(false));
+    let timeout = query_timeout(timeout_secs, event.context.deadline());
+    let query = match query.map(Ok).or_else(|| fragment.to_sql().transpose()) {
+        Some(Ok(query)) => query,
+        Some(Err(err)) => {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                "InvalidQuery",
+                err.to_string(),
+            ));
+        }
+        None => "SELECT * FROM read_parquet('https://shell.duckdb.org/data/tpch/0_01/parquet/customer.parquet') LIMIT 5".to_string(),
+    };
+    let wrapper = match check_read_only(&query) {
+        Ok(wrapper) => wrapper,
+        Err(response) => {
+            tracing::info!(
+                query_hash = %QueryWrapper::create_hash_string(&query),
+                status = response.status_code,
+                "Query rejected"
+            );
+            return Ok(response);
+        }
+    };
+    let span = tracing::Span::current();
+    span.record("query_hash", wrapper.hash());
+    tracing::info!(query = %wrapper.redacted_sql(&[]), "Query parsed");
+
+    let params = match bind_params(&params) {
+        Ok(params) => params,
+        Err(err) => {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                "InvalidParams",
+                err.to_string(),
+            ));
+        }
+    };
+
+    let threads = match duckdb_threads(requested_threads, var) {
+        Ok(threads) => threads,
+        Err(message) => {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                "InvalidThreads",
+                message,
+            ));
+        }
+    };
+
+    let conn = acquire_connection(installed_extensions().await?)?;
+    if let Err(err) = set_threads(&conn, threads) {
+        release_connection(conn);
+        return Err(err);
+    }
+    if let Some(secret_arn) = &secret_arn {
+        let applied = match S3Credentials::fetch(secret_arn).await {
+            Ok(credentials) => credentials.apply(&conn),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = applied {
+            release_connection(conn);
+            return Err(err);
+        }
+    }
+    if let Err(message) = create_secrets(&conn, duckdb_secrets.as_deref().unwrap_or_default()) {
+        release_connection(conn);
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            "InvalidSecret",
+            message,
+        ));
+    }
+
+    let parquet_path = parquet_path(&query, &event.context.request_id);
+    let interrupt = conn.interrupt_handle();
+    let run = move || {
+        run_and_release(conn, |conn| match format {
+            _ if explain_analyze => {
+                query_to_explain_analyze(conn, &query, &params).map(ResultBody::complete)
+            }
+            ResponseFormat::Arrow => query_to_arrow_ipc(conn, &query, &params, &limits),
+            ResponseFormat::Parquet => {
+                query_to_parquet(conn, &query, &params, &parquet_path, limits.max_bytes)
+                    .map(ResultBody::complete)
+            }
+            ResponseFormat::Json => query_to_json(conn, &query, &params, &limits),
+            ResponseFormat::Csv => query_to_csv(conn, &query, &params, &limits),
+        })
+    };
+    tracing::info!(format = ?format, "Executing query");
+    let executing = Instant::now();
+    let result = query_with_timeout(timeout, &interrupt, run).await;
+    span.record("elapsed_ms", executing.elapsed().as_millis() as u64);
+    match &result {
+        Ok(ResultBody {
+            rows: Some(rows), ..
+        }) => {
+            span.record("rows", *rows as u64);
+            tracing::info!("Query finished");
+        }
+        Ok(_) => tracing::info!("Query finished"),
+        Err(err) => tracing::warn!(error = %err, "Query failed"),
+    }
+    let ResultBody {
+        bytes: body,
+        truncated,
+        ..
+    } = match result {
+        Ok(body) => body,
+        Err(err) if explain_analyze => {
+            return Ok(text_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                err.to_string(),
+            ));
+        }
+        Err(err) if err.is::<QueryTimeout>() => {
+            return Ok(error_response(
+                StatusCode::GATEWAY_TIMEOUT,
+                "QueryTimeout",
+                err.to_string(),
+            ));
+        }
+        Err(err) if err.is::<ResultTooLarge>() => {
+            return Ok(error_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "ResultTooLarge",
+                err.to_string(),
+            ));
+        }
+        Err(err) => return Ok(query_error_response(err)),
+    };
+
+    if metrics_enabled.unwrap_or(true) {
+        metrics::QueryMetrics {
+            latency: started.elapsed(),
+            result_bytes: body.len(),
+        }
+        .emit();
+    }
+
+    if explain_analyze {
+        return Ok(text_response(
+            StatusCode::OK,
+            String::from_utf8_lossy(&body).into_owned(),
+        ));
+    }
+
+    let mut headers = format.headers();
+    if truncated {
+        headers["X-Pond-Result-Truncated"] = json!("true");
+    }
+    let body = match encoding {
+        Some(encoding) => {
+            headers["Content-Encoding"] = json!(encoding.name());
+            encoding.encode(&body)?
+        }
+        None => body,
+    };
 
     // Return the custom response
     Ok(ArrowIpcResponse {
         status_code: StatusCode::OK.as_u16(),
-        headers: json!({
-            "Content-Type": "application/vnd.apache.arrow.stream",
-        }),
-        body: arrow_ipc_data,
+        headers,
+        body,
     })
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     tracing::init_default_subscriber();
-    run(service_fn(function_handler)).await
+    // Fails at startup on an unknown extension instead of on every query.
+    installed_extensions().await?;
+    run(service_fn(|event: LambdaEvent<Request>| {
+        // The rest is recorded as the invocation gets to it.
+        let span = tracing::info_span!(
+            "invocation",
+            request_id = %event.context.request_id,
+            query_hash = tracing::field::Empty,
+            rows = tracing::field::Empty,
+            elapsed_ms = tracing::field::Empty,
+        );
+        function_handler(event).instrument(span)
+    }))
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::ipc::reader::StreamReader;
+
+    #[test]
+    fn test_empty_result_is_a_well_formed_stream() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE t (id INTEGER, name VARCHAR)")
+            .unwrap();
+
+        let body = query_to_arrow_ipc(&conn, "SELECT * FROM t WHERE 1=0", &[], &ResultLimits::NONE)
+            .unwrap()
+            .bytes;
+        let reader = StreamReader::try_new(Cursor::new(body), None).unwrap();
+        let names: Vec<_> = reader
+            .schema()
+            .fields()
+            .iter()
+            .map(|field| field.name().clone())
+            .collect();
+        assert_eq!(names, ["id", "name"]);
+        let rows: usize = reader.map(|batch| batch.unwrap().num_rows()).sum();
+        assert_eq!(rows, 0);
+    }
+
+    #[test]
+    fn test_json_and_csv_results() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE t (id INTEGER, name VARCHAR); \
+             INSERT INTO t VALUES (1, 'a,b'), (2, NULL)",
+        )
+        .unwrap();
+
+        let query = "SELECT * FROM t ORDER BY id";
+        let body = query_to_json(&conn, query, &[], &ResultLimits::NONE)
+            .unwrap()
+            .bytes;
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            json!([{"id": 1, "name": "a,b"}, {"id": 2, "name": null}])
+        );
+        let body = query_to_csv(&conn, query, &[], &ResultLimits::NONE)
+            .unwrap()
+            .bytes;
+        assert_eq!(String::from_utf8(body).unwrap(), "id,name\n1,\"a,b\"\n2,\n");
+
+        let empty = "SELECT * FROM t WHERE id > ?";
+        let body = query_to_json(&conn, empty, &[Value::Int(5)], &ResultLimits::NONE)
+            .unwrap()
+            .bytes;
+        assert_eq!(body, b"[]");
+        let body = query_to_csv(&conn, empty, &[Value::Int(5)], &ResultLimits::NONE)
+            .unwrap()
+            .bytes;
+        assert_eq!(body, b"id,name\n");
+    }
+
+    #[test]
+    fn test_plan_fragment_reads_its_partition() {
+        let root = std::env::temp_dir().join(format!("pond-fragment-{}", std::process::id()));
+        for (dir, rows) in [("a", "eu,1\nus,2\neu,3\n"), ("b", "eu,100\n")] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+            std::fs::write(
+                root.join(dir).join("sales.csv"),
+                format!("region,amount\n{}", rows),
+            )
+            .unwrap();
+        }
+
+        let fragment: PlanFragment = serde_json::from_value(json!({
+            "table": format!("read_csv('{}/*/*.csv') AS s", root.display()),
+            "group_column": "region",
+            "agg_function": "AVG",
+            "aggregates": ["SUM(s.amount) AS \"sum\"", "COUNT(s.amount) AS \"count\""],
+            "where_clause": "s.amount < 50",
+            "partition": format!("{}/a/*", root.display()),
+        }))
+        .unwrap();
+        let query = fragment.to_sql().unwrap().unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        let body = query_to_json(
+            &conn,
+            &format!("{} ORDER BY 1", query),
+            &[],
+            &ResultLimits::NONE,
+        )
+        .unwrap()
+        .bytes;
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            json!([
+                {"region": "eu", "sum": 4, "count": 2},
+                {"region": "us", "sum": 2, "count": 1},
+            ])
+        );
+
+        let fragment = PlanFragment {
+            table: Some("t".to_string()),
+            agg_function: Some("COUNT(*)".to_string()),
+            ..PlanFragment::default()
+        };
+        assert_eq!(
+            fragment.to_sql().unwrap().as_deref(),
+            Some("SELECT COUNT(*) FROM t")
+        );
+        let fragment = PlanFragment {
+            where_clause: Some("a > 1".to_string()),
+            push_down_filter: Some("b = 2 OR b = 3".to_string()),
+            ..fragment
+        };
+        assert_eq!(
+            fragment.to_sql().unwrap().as_deref(),
+            Some("SELECT COUNT(*) FROM t WHERE (a > 1) AND (b = 2 OR b = 3)")
+        );
+        assert!(PlanFragment::default().to_sql().unwrap().is_none());
+        let fragment = PlanFragment {
+            table: Some("t".to_string()),
+            ..PlanFragment::default()
+        };
+        assert!(fragment.to_sql().is_err());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_duckdb_secrets() {
+        let secret: DuckDbSecret = serde_json::from_value(json!({
+            "type": "S3",
+            "provider": "config",
+            "options": {"KEY_ID": "AKIA", "SECRET": "it's", "SCOPE": "s3://bucket"},
+        }))
+        .unwrap();
+        assert_eq!(
+            secret.create_statement("pond_request_0").unwrap(),
+            "CREATE OR REPLACE TEMPORARY SECRET pond_request_0 (TYPE s3, PROVIDER config, \
+             KEY_ID 'AKIA', SCOPE 's3://bucket', SECRET 'it''s')"
+        );
+        assert!(!format!("{:?}", secret).contains("AKIA"));
+
+        for (type_, provider, option) in [
+            ("ftp", "config", "KEY_ID"),
+            ("gcs", "config; DROP TABLE t", "KEY_ID"),
+            ("azure", "config", "ACCOUNT_NAME 'x', TYPE"),
+            ("azure", "config", "type"),
+        ] {
+            let secret = DuckDbSecret {
+                type_: type_.to_string(),
+                provider: provider.to_string(),
+                options: HashMap::from([(option.to_string(), "x".to_string())]),
+            };
+            assert!(secret.create_statement("s").is_err(), "{:?}", secret);
+        }
+
+        // Nothing to drop without any secrets, or httpfs.
+        drop_request_secrets(&Connection::open_in_memory().unwrap()).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs DuckDB's httpfs extension, which is downloaded on first use"]
+    fn test_request_secrets_are_dropped() {
+        // Secrets outlive the invocation on a warm connection until dropped.
+        let conn = Connection::open_in_memory().unwrap();
+        let secret = DuckDbSecret {
+            type_: "s3".to_string(),
+            provider: "config".to_string(),
+            options: HashMap::from([("KEY_ID".to_string(), "AKIA".to_string())]),
+        };
+        create_secrets(&conn, &[secret]).unwrap();
+        let count = |conn: &Connection| {
+            conn.query_row("SELECT count(*) FROM duckdb_secrets()", [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(count(&conn), 1);
+        drop_request_secrets(&conn).unwrap();
+        assert_eq!(count(&conn), 0);
+    }
+
+    #[test]
+    fn test_explain_analyze() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE t AS SELECT range AS id FROM range(100)")
+            .unwrap();
+
+        let plan = query_to_explain_analyze(
+            &conn,
+            "SELECT COUNT(*) FROM t WHERE id > ?",
+            &[Value::Int(10)],
+        )
+        .unwrap();
+        let plan = String::from_utf8(plan).unwrap();
+        assert!(plan.contains("Query Profiling Information"), "{}", plan);
+        assert!(plan.contains("TABLE_SCAN"), "{}", plan);
+
+        let err = query_to_explain_analyze(&conn, "SELECT * FROM missing", &[]).unwrap_err();
+        assert!(err.to_string().contains("missing"), "{}", err);
+    }
+
+    #[test]
+    fn test_only_reads_are_run() {
+        assert!(check_read_only("SELECT * FROM read_parquet('s3://b/*.parquet')").is_ok());
+        assert!(check_read_only("SELECT 42").is_ok());
+        for query in [
+            "ATTACH '/tmp/other.db'",
+            "COPY (SELECT 1) TO '/tmp/out.csv'",
+            "INSTALL spatial",
+            "SET enable_external_access = true",
+            "SELECT 1; DROP TABLE t",
+        ] {
+            let response = check_read_only(query).err().unwrap();
+            assert_eq!(response.status_code, 403, "{}", query);
+            let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+            assert_eq!(body["error_type"], "PolicyViolation");
+        }
+        assert_eq!(check_read_only("SELEC 1").err().unwrap().status_code, 400);
+    }
+
+    #[test]
+    fn test_restricted_connection_reads_only_allowed_directories() {
+        let root = std::env::temp_dir().join(format!("pond-restricted-{}", std::process::id()));
+        for dir in ["allowed", "other"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+            std::fs::write(root.join(dir).join("t.csv"), "id\n1\n").unwrap();
+        }
+        let conn = Connection::open_in_memory().unwrap();
+        restrict_access(&conn, &[format!("{}/", root.join("allowed").display())]).unwrap();
+
+        let read = |dir: &str| {
+            let path = root.join(dir).join("t.csv");
+            query_to_json(
+                &conn,
+                &format!("SELECT * FROM read_csv('{}')", path.display()),
+                &[],
+                &ResultLimits::NONE,
+            )
+            .map(|body| body.bytes)
+        };
+        assert_eq!(read("allowed").unwrap(), br#"[{"id":1}]"#);
+        assert!(read("other").is_err());
+        let attach = format!("ATTACH '{}'", root.join("other").join("x.db").display());
+        assert!(conn.execute_batch(&attach).is_err());
+        assert!(conn
+            .execute_batch("SET enable_external_access = true")
+            .is_err());
+        set_option(&conn, "memory_limit", "1GB").unwrap();
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_format_from_accept() {
+        assert_eq!(
+            ResponseFormat::from_accept("text/csv"),
+            Some(ResponseFormat::Csv)
+        );
+        assert_eq!(
+            ResponseFormat::from_accept("text/html, Application/JSON;q=0.9, */*;q=0.1"),
+            Some(ResponseFormat::Json)
+        );
+        assert_eq!(
+            ResponseFormat::from_accept("application/vnd.apache.arrow.stream"),
+            Some(ResponseFormat::Arrow)
+        );
+        assert_eq!(ResponseFormat::from_accept("*/*"), None);
+    }
+
+    #[test]
+    fn test_encoding_from_accept_encoding() {
+        assert_eq!(
+            ContentEncoding::from_accept_encoding("gzip, ZSTD;q=0.8, lz4"),
+            Some(ContentEncoding::Zstd)
+        );
+        assert_eq!(
+            ContentEncoding::from_accept_encoding("zstd;q=0, lz4;q=0.5"),
+            Some(ContentEncoding::Lz4)
+        );
+        assert_eq!(ContentEncoding::from_accept_encoding("gzip, br"), None);
+    }
+
+    #[test]
+    fn test_compressed_body_decodes_to_the_stream() {
+        let conn = Connection::open_in_memory().unwrap();
+        let query = "SELECT i, 'row ' || i AS name FROM range(10000) t(i)";
+        let body = query_to_arrow_ipc(&conn, query, &[], &ResultLimits::NONE)
+            .unwrap()
+            .bytes;
+
+        let zstd = ContentEncoding::Zstd.encode(&body).unwrap();
+        assert!(zstd.len() < body.len());
+        assert_eq!(zstd::decode_all(zstd.as_slice()).unwrap(), body);
+
+        let lz4 = ContentEncoding::Lz4.encode(&body).unwrap();
+        assert!(lz4.len() < body.len());
+        let mut decoded = Vec::new();
+        std::io::Read::read_to_end(
+            &mut lz4_flex::frame::FrameDecoder::new(lz4.as_slice()),
+            &mut decoded,
+        )
+        .unwrap();
+        assert_eq!(decoded, body);
+    }
+
+    #[test]
+    fn test_client_decodes_the_response() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT i, 'row ' || i AS name FROM range(3) t(i)")
+            .unwrap();
+        let rbs: Vec<_> = stmt.query_arrow([]).unwrap().collect();
+        let schema = rbs[0].schema();
+
+        let response = ArrowIpcResponse {
+            status_code: 200,
+            headers: ResponseFormat::Arrow.headers(),
+            body: convert_to_arrow_ipc(&schema, &rbs).unwrap(),
+        };
+        let json = serde_json::to_vec(&response).unwrap();
+        let decoded = pond_client::decode_response(&serde_json::from_slice(&json).unwrap());
+        assert_eq!(decoded.unwrap(), rbs);
+    }
+
+    #[test]
+    fn test_slow_query_times_out() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        let interrupt = conn.interrupt_handle();
+        let (finished, outcome) = std::sync::mpsc::channel();
+        let started = Instant::now();
+        let err = runtime
+            .block_on(query_with_timeout(
+                Duration::from_millis(200),
+                &interrupt,
+                move || {
+                    let result = query_to_arrow_ipc(
+                        &conn,
+                        "SELECT SUM(a.range * b.range) FROM range(1000000) a, range(1000000) b",
+                        &[],
+                        &ResultLimits::NONE,
+                    );
+                    finished
+                        .send(result.as_ref().err().map(ToString::to_string))
+                        .unwrap();
+                    result
+                },
+            ))
+            .unwrap_err();
+        assert!(err.is::<QueryTimeout>());
+        // The cross join was interrupted and its thread has returned.
+        let message = outcome.try_recv().unwrap().unwrap();
+        assert!(message.contains("Interrupted"), "{}", message);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(runtime);
+
+        let response = error_response(StatusCode::GATEWAY_TIMEOUT, "QueryTimeout", err.to_string());
+        assert_eq!(response.status_code, 504);
+        let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(body["error_type"], "QueryTimeout");
+        assert_eq!(body["message"], "Query timed out after 200ms");
+    }
+
+    #[test]
+    #[ignore = "needs DuckDB's parquet extension, which is downloaded on first use"]
+    fn test_parquet_response() {
+        let conn = Connection::open_in_memory().unwrap();
+        let path = parquet_path("SELECT 42 AS answer;", "test-request");
+        let body = query_to_parquet(&conn, "SELECT 42 AS answer;", &[], &path, None).unwrap();
+        assert!(body.starts_with(b"PAR1") && body.ends_with(b"PAR1"));
+        assert!(!path.try_exists().unwrap());
+
+        let hashes = "SELECT md5(range::VARCHAR) AS h FROM range(10000)";
+        let err = query_to_parquet(&conn, hashes, &[], &path, Some(1024)).unwrap_err();
+        assert!(err.is::<ResultTooLarge>());
+        assert!(!path.try_exists().unwrap());
+    }
+
+    #[test]
+    fn test_parquet_paths_differ_per_invocation() {
+        let query = "SELECT 1";
+        assert_eq!(parquet_path(query, "a"), parquet_path(query, "a"));
+        assert_ne!(parquet_path(query, "a"), parquet_path(query, "b"));
+        assert_ne!(parquet_path(query, "a"), parquet_path("SELECT 2", "a"));
+
+        // A failed COPY leaves nothing behind either.
+        let conn = Connection::open_in_memory().unwrap();
+        let path = parquet_path("SELECT * FROM missing", "test-request");
+        assert!(query_to_parquet(&conn, "SELECT * FROM missing", &[], &path, None).is_err());
+        assert!(!path.try_exists().unwrap());
+    }
+
+    #[test]
+    fn test_query_errors_are_classified() {
+        let conn = Connection::open_in_memory().unwrap();
+        let failed = |query: &str| {
+            let err = query_to_arrow_ipc(&conn, query, &[], &ResultLimits::NONE).unwrap_err();
+            let response = query_error_response(err);
+            let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+            (
+                response.status_code,
+                body["error_type"].as_str().unwrap().to_string(),
+            )
+        };
+        assert_eq!(failed("SELEC 1"), (400, "InvalidQuery".to_string()));
+        assert_eq!(
+            failed("SELECT * FROM missing"),
+            (400, "InvalidQuery".to_string())
+        );
+        assert_eq!(
+            failed("SELECT * FROM read_csv('/nonexistent/pond/*.csv')"),
+            (500, "QueryFailed".to_string())
+        );
+
+        let response = query_error_response("worker misconfigured".into());
+        assert_eq!(response.status_code, 500);
+        let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(body["error_type"], "InternalError");
+        assert_eq!(body["message"], "worker misconfigured");
+    }
+
+    #[test]
+    fn test_params_are_bound_positionally() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE t (id INTEGER, name VARCHAR); \
+             INSERT INTO t VALUES (42, 'answer'), (7, 'x''; DROP TABLE t; --')",
+        )
+        .unwrap();
+        let request: Request = serde_json::from_value(json!({
+            "query": "SELECT name FROM t WHERE id = ? OR name = ? ORDER BY id",
+            "params": [42, "x'; DROP TABLE t; --"],
+        }))
+        .unwrap();
+        let params = bind_params(&request.params).unwrap();
+
+        let body = query_to_arrow_ipc(
+            &conn,
+            request.query.as_deref().unwrap(),
+            &params,
+            &ResultLimits::NONE,
+        )
+        .unwrap()
+        .bytes;
+        let names: Vec<String> = StreamReader::try_new(Cursor::new(body), None)
+            .unwrap()
+            .flat_map(|batch| {
+                let batch = batch.unwrap();
+                let column = batch
+                    .column(0)
+                    .as_any()
+                    .downcast_ref::<arrow::array::StringArray>()
+                    .unwrap()
+                    .clone();
+                column
+                    .iter()
+                    .map(|name| name.unwrap().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(names, ["x'; DROP TABLE t; --", "answer"]);
+
+        assert_eq!(
+            bind_params(&[json!(null), json!(true), json!(u64::MAX), json!(1.5)]).unwrap(),
+            [
+                Value::Null,
+                Value::Boolean(true),
+                Value::UBigInt(u64::MAX),
+                Value::Double(1.5)
+            ]
+        );
+        assert_eq!(
+            bind_params(&[json!(1), json!([1, 2])])
+                .unwrap_err()
+                .to_string(),
+            "Parameter 2 is an array, expected null, a boolean, a number or a string"
+        );
+
+        let err = query_to_arrow_ipc(
+            &conn,
+            "SELECT * FROM t WHERE id = ?",
+            &[],
+            &ResultLimits::NONE,
+        )
+        .unwrap_err();
+        let response = query_error_response(err);
+        assert_eq!(response.status_code, 400);
+        let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(body["error_type"], "InvalidParams");
+    }
+
+    /// An environment holding only `vars`.
+    fn env(
+        vars: &'static [(&'static str, &'static str)],
+    ) -> impl Fn(&str) -> Option<String> + Copy {
+        move |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    #[test]
+    fn test_resource_settings() {
+        let settings = resource_settings(env(&[("AWS_LAMBDA_FUNCTION_MEMORY_SIZE", "512")]));
+        assert_eq!(settings[0], ("memory_limit", "307MB".to_string()));
+        assert_eq!(settings[1].0, "temp_directory");
+
+        let settings = resource_settings(env(&[
+            ("AWS_LAMBDA_FUNCTION_MEMORY_SIZE", "10240"),
+            ("POND_DUCKDB_MEMORY_LIMIT", "8GB"),
+            ("POND_DUCKDB_THREADS", "6"),
+            ("POND_DUCKDB_TEMP_DIRECTORY", "/tmp/spill"),
+        ]));
+        assert_eq!(
+            settings,
+            [
+                ("memory_limit", "8GB".to_string()),
+                ("threads", "6".to_string()),
+                ("temp_directory", "/tmp/spill".to_string()),
+            ]
+        );
+
+        let conn = Connection::open_in_memory().unwrap();
+        for (setting, value) in settings {
+            set_option(&conn, setting, &value).unwrap();
+        }
+        let threads: i64 = conn
+            .query_row("SELECT current_setting('threads')", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(threads, 6);
+        assert!(set_option(&conn, "memory_limit", "lots").is_err());
+    }
+
+    #[test]
+    fn test_duckdb_threads() {
+        assert_eq!(duckdb_threads(None, env(&[])), Ok(1));
+        assert_eq!(
+            duckdb_threads(None, env(&[("POND_DUCKDB_THREADS", "6")])),
+            Ok(6)
+        );
+        let lambda_default = env(&[
+            ("POND_DEFAULT_DUCKDB_THREADS", "2"),
+            ("POND_DUCKDB_THREADS", "6"),
+        ]);
+        assert_eq!(duckdb_threads(None, lambda_default), Ok(2));
+        assert_eq!(duckdb_threads(Some(4), lambda_default), Ok(4));
+        assert!(duckdb_threads(Some(0), env(&[])).is_err());
+
+        let conn = Connection::open_in_memory().unwrap();
+        for threads in [3, 1] {
+            set_threads(&conn, threads).unwrap();
+            let setting: i64 = conn
+                .query_row("SELECT current_setting('threads')", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(setting, i64::from(threads));
+        }
+    }
+
+    #[test]
+    fn test_result_limits() {
+        let limits = ResultLimits::from_env(|_| None, false);
+        assert_eq!(limits.max_rows, None);
+        assert_eq!(limits.max_bytes, Some(DEFAULT_MAX_RESULT_BYTES));
+
+        let limits = ResultLimits::from_env(
+            |name| match name {
+                "POND_MAX_RESULT_ROWS" => Some("1000".to_string()),
+                _ => Some("0".to_string()),
+            },
+            true,
+        );
+        assert_eq!(
+            limits,
+            ResultLimits {
+                max_rows: Some(1000),
+                max_bytes: None,
+                truncate: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_results_past_the_limits() {
+        let conn = Connection::open_in_memory().unwrap();
+        let query = "SELECT range AS i FROM range(10000)";
+        let rows = |bytes: &[u8]| -> usize {
+            StreamReader::try_new(Cursor::new(bytes), None)
+                .unwrap()
+                .map(|batch| batch.unwrap().num_rows())
+                .sum()
+        };
+
+        let mut limits = ResultLimits {
+            max_rows: Some(2500),
+            ..ResultLimits::NONE
+        };
+        let err = query_to_arrow_ipc(&conn, query, &[], &limits).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Result exceeds 2500 rows; add a LIMIT to the query or request truncation"
+        );
+        limits.truncate = true;
+        let body = query_to_arrow_ipc(&conn, query, &[], &limits).unwrap();
+        assert!(body.truncated);
+        assert_eq!(body.rows, Some(2500));
+        assert_eq!(rows(&body.bytes), 2500);
+
+        let limits = ResultLimits {
+            max_bytes: Some(1024),
+            truncate: true,
+            ..ResultLimits::NONE
+        };
+        let body = query_to_arrow_ipc(&conn, query, &[], &limits).unwrap();
+        assert!(body.truncated);
+        assert_eq!(rows(&body.bytes), 0);
+        let body = query_to_arrow_ipc(&conn, "SELECT 1 AS i", &[], &limits).unwrap();
+        assert!(!body.truncated);
+    }
+
+    #[test]
+    fn test_released_connection_is_reused_without_query_state() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE kept (id INTEGER)")
+            .unwrap();
+        run_and_release(conn, |conn| {
+            conn.execute_batch("CREATE TABLE scratch AS SELECT 1 AS id")
+                .map_err(Error::from)
+        })
+        .unwrap();
+
+        let warm = WARM_CONNECTION.lock().unwrap().take().unwrap();
+        let tables: Vec<String> = warm
+            .prepare("SELECT table_name FROM information_schema.tables ORDER BY 1")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(tables, ["kept"]);
+
+        // A failed query still gives the connection back.
+        assert!(run_and_release(warm, |conn| query_to_arrow_ipc(
+            conn,
+            "SELEC 1",
+            &[],
+            &ResultLimits::NONE
+        ))
+        .is_err());
+        assert!(WARM_CONNECTION.lock().unwrap().take().is_some());
+    }
+
+    #[test]
+    fn test_unknown_extensions_are_rejected() {
+        let conn = Connection::open_in_memory().unwrap();
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        check_extensions(&conn, &names(&["httpfs", "json", "spatial"])).unwrap();
+        for bad in ["spatail", "json; ATTACH 'x'"] {
+            let err = check_extensions(&conn, &names(&["json", bad])).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "Unknown DuckDB extension in POND_DUCKDB_EXTENSIONS: {}",
+                    bad
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_timeout_leaves_room_before_the_deadline() {
+        let deadline = SystemTime::now() + Duration::from_secs(10);
+        assert_eq!(query_timeout(Some(5), deadline), Duration::from_secs(5));
+        let capped = query_timeout(Some(30), deadline);
+        assert!(capped <= Duration::from_secs(9) && capped > Duration::from_secs(8));
+        assert_eq!(
+            query_timeout(Some(30), SystemTime::UNIX_EPOCH),
+            Duration::ZERO
+        );
+    }
 }
@@ -0,0 +1,55 @@
+use aws_config::BehaviorVersion;
+use aws_sdk_cloudwatch::types::{MetricDatum, StandardUnit};
+use lambda_runtime::tracing;
+use std::time::Duration;
+
+const NAMESPACE: &str = "Pond/Duckling";
+
+/// What one successful invocation reports to CloudWatch.
+pub(crate) struct QueryMetrics {
+    pub(crate) latency: Duration,
+    pub(crate) result_bytes: usize,
+}
+
+impl QueryMetrics {
+    fn data(&self) -> Vec<MetricDatum> {
+        [
+            (
+                "QueryLatencyMs",
+                self.latency.as_secs_f64() * 1000.0,
+                StandardUnit::Milliseconds,
+            ),
+            ("ResultBytes", self.result_bytes as f64, StandardUnit::Bytes),
+            ("QueryCount", 1.0, StandardUnit::Count),
+        ]
+        .into_iter()
+        .map(|(name, value, unit)| {
+            MetricDatum::builder()
+                .metric_name(name)
+                .value(value)
+                .unit(unit)
+                .build()
+        })
+        .collect()
+    }
+
+    /// Sends the metrics in one `PutMetricData` call on a background task, so
+    /// the response isn't held up. Failures are logged and otherwise ignored;
+    /// a task still running when Lambda freezes the environment finishes on
+    /// the next invocation, or never if the environment is recycled.
+    pub(crate) fn emit(self) {
+        tokio::spawn(async move {
+            let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+            let client = aws_sdk_cloudwatch::Client::new(&config);
+            let result = client
+                .put_metric_data()
+                .namespace(NAMESPACE)
+                .set_metric_data(Some(self.data()))
+                .send()
+                .await;
+            if let Err(err) = result {
+                tracing::warn!("Failed to put metrics: {}", err);
+            }
+        });
+    }
+}
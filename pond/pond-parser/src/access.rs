@@ -0,0 +1,213 @@
+use crate::{QueryError, QueryWrapper};
+use regex::Regex;
+use sqlparser::ast::{Expr, Query as SqlQuery, TableFactor, Value, Visit, Visitor};
+use std::collections::HashSet;
+use std::ops::ControlFlow;
+
+impl QueryWrapper {
+    /// Checks that the query only reads from allowed buckets and tables.
+    ///
+    /// Every relation in the statement is considered, including those inside
+    /// joins, CTEs, subqueries and reader functions such as `read_parquet`.
+    /// File paths are reduced to their bucket or host (`s3://bucket`,
+    /// `https://example.com`) and checked against `allowed_buckets`; local
+    /// paths are checked as-is. Bare table names, other than references to a
+    /// CTE, are checked against `allowed_tables`. Both lists accept `*` and `?`
+    /// globs, e.g. `s3://tenant-acme-*`.
+    ///
+    /// The returned [`QueryError::AccessDenied`] lists every violating source.
+    pub fn check_access(
+        &self,
+        allowed_buckets: &[&str],
+        allowed_tables: &[&str],
+    ) -> Result<(), QueryError> {
+        let mut sources = Sources::default();
+        for statement in std::iter::once(&self.ast).chain(&self.trailing) {
+            let _ = statement.visit(&mut sources);
+        }
+
+        let bucket_patterns = globs(allowed_buckets);
+        let table_patterns = globs(allowed_tables);
+
+        let mut denied = Vec::new();
+        for path in &sources.paths {
+            let bucket = path_source(path);
+            if !bucket_patterns
+                .iter()
+                .any(|pattern| pattern.is_match(&bucket))
+            {
+                denied.push(bucket);
+            }
+        }
+        for table in &sources.tables {
+            if sources.ctes.contains(table) {
+                continue;
+            }
+            if !table_patterns.iter().any(|pattern| pattern.is_match(table)) {
+                denied.push(table.clone());
+            }
+        }
+
+        let mut seen = HashSet::new();
+        denied.retain(|source| seen.insert(source.clone()));
+        if denied.is_empty() {
+            Ok(())
+        } else {
+            Err(QueryError::AccessDenied(denied))
+        }
+    }
+}
+
+/// Relations referenced anywhere in a statement.
+#[derive(Default)]
+struct Sources {
+    /// Quoted file paths and URLs, in the order they appear.
+    paths: Vec<String>,
+    /// Bare table names, lowercased.
+    tables: Vec<String>,
+    /// Names defined by WITH clauses, lowercased.
+    ctes: HashSet<String>,
+}
+
+impl Visitor for Sources {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &SqlQuery) -> ControlFlow<()> {
+        if let Some(with) = &query.with {
+            for cte in &with.cte_tables {
+                self.ctes.insert(cte.alias.name.value.to_lowercase());
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_table_factor(&mut self, table_factor: &TableFactor) -> ControlFlow<()> {
+        let TableFactor::Table { name, args, .. } = table_factor else {
+            return ControlFlow::Continue(());
+        };
+
+        match (args, name.0.as_slice()) {
+            // A reader function: check every string argument that looks like a path.
+            (Some(args), _) => {
+                let _ = sqlparser::ast::visit_expressions(&args.args, |expr| {
+                    if let Expr::Value(Value::SingleQuotedString(path)) = expr {
+                        if is_path(path) {
+                            self.paths.push(path.clone());
+                        }
+                    }
+                    ControlFlow::<()>::Continue(())
+                });
+            }
+            // `FROM 's3://bucket/file.parquet'`
+            (None, [ident]) if ident.quote_style == Some('\'') => {
+                self.paths.push(ident.value.clone());
+            }
+            (None, idents) => {
+                let table = idents
+                    .iter()
+                    .map(|ident| ident.value.to_lowercase())
+                    .collect::<Vec<_>>()
+                    .join(".");
+                self.tables.push(table);
+            }
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// Reader functions also take options such as `'auto'` or a delimiter, so only
+/// URLs and strings with a path separator or file extension are treated as sources.
+fn is_path(value: &str) -> bool {
+    value.contains("://") || value.contains('/') || value.contains('.')
+}
+
+/// `s3://bucket/key` becomes `s3://bucket`; paths without a scheme are kept whole.
+pub(crate) fn path_source(path: &str) -> String {
+    match path.split_once("://") {
+        Some((scheme, rest)) => {
+            let host = rest.split('/').next().unwrap_or_default();
+            format!("{}://{}", scheme.to_lowercase(), host)
+        }
+        None => path.to_string(),
+    }
+}
+
+/// Compiles `*`/`?` glob patterns into anchored, case-insensitive regexes.
+fn globs(patterns: &[&str]) -> Vec<Regex> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            let regex = regex::escape(pattern)
+                .replace(r"\*", ".*")
+                .replace(r"\?", ".");
+            Regex::new(&format!("(?i)^{}$", regex)).expect("escaped glob is a valid regex")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn denied(sql: &str, buckets: &[&str], tables: &[&str]) -> Vec<String> {
+        match QueryWrapper::parse(sql)
+            .unwrap()
+            .check_access(buckets, tables)
+        {
+            Ok(()) => Vec::new(),
+            Err(QueryError::AccessDenied(sources)) => sources,
+            Err(err) => panic!("unexpected error for {}: {}", sql, err),
+        }
+    }
+
+    #[test]
+    fn test_check_access_allows_matching_sources() {
+        let sql = "WITH recent AS (SELECT * FROM read_parquet('s3://tenant-acme-logs/*.parquet')) \
+                   SELECT r.id, u.name FROM recent r \
+                   JOIN 's3://tenant-acme-users/users.parquet' u ON r.user_id = u.id \
+                   JOIN regions g ON u.region = g.code";
+        assert!(denied(sql, &["s3://tenant-acme-*"], &["regions"]).is_empty());
+    }
+
+    #[test]
+    fn test_check_access_names_every_violation() {
+        let sql = "SELECT * FROM 's3://tenant-acme-logs/a.parquet' a \
+                   JOIN 's3://tenant-other/b.parquet' b ON a.id = b.id \
+                   JOIN secrets s ON a.id = s.id \
+                   JOIN read_csv('s3://tenant-other/c.csv', delim = ',') c ON a.id = c.id";
+        assert_eq!(
+            denied(sql, &["s3://tenant-acme-*"], &[]),
+            vec!["s3://tenant-other", "secrets"]
+        );
+    }
+
+    #[test]
+    fn test_check_access_inside_in_subquery() {
+        let sql = "SELECT * FROM 's3://tenant-acme-logs/a.parquet' \
+                   WHERE user_id IN (SELECT id FROM read_parquet('s3://tenant-other/users.parquet'))";
+        assert_eq!(
+            denied(sql, &["s3://tenant-acme-*"], &[]),
+            vec!["s3://tenant-other"]
+        );
+    }
+
+    #[test]
+    fn test_check_access_rejects_other_schemes() {
+        let sql = "SELECT * FROM read_json_auto('https://example.com/data.json') \
+                   UNION ALL SELECT * FROM '/etc/passwd.csv'";
+        assert_eq!(
+            denied(sql, &["s3://*"], &[]),
+            vec!["https://example.com", "/etc/passwd.csv"]
+        );
+    }
+
+    #[test]
+    fn test_check_access_table_globs() {
+        let sql = "SELECT * FROM analytics.events e JOIN Analytics.Users u ON e.uid = u.id";
+        assert!(denied(sql, &[], &["analytics.*"]).is_empty());
+        assert_eq!(
+            denied(sql, &[], &["analytics.events"]),
+            vec!["analytics.users"]
+        );
+    }
+}
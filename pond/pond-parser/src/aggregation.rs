@@ -0,0 +1,79 @@
+use crate::decompose::is_aggregate_call;
+use crate::QueryWrapper;
+use sqlparser::ast::{Expr, GroupByExpr, Query, SetExpr, Visit, Visitor};
+use std::ops::ControlFlow;
+
+impl QueryWrapper {
+    /// Whether the query aggregates anywhere, i.e. whether partition results
+    /// need a merge step. Cheaper than [`analyze`](Self::analyze): it stops at
+    /// the first aggregate call, GROUP BY or HAVING it finds.
+    pub fn has_aggregation(&self) -> bool {
+        std::iter::once(&self.ast)
+            .chain(&self.trailing)
+            .any(|statement| statement.visit(&mut AggregationFinder).is_break())
+    }
+}
+
+/// Stops at the first aggregate call, GROUP BY or HAVING, subqueries and
+/// CTEs included.
+struct AggregationFinder;
+
+impl Visitor for AggregationFinder {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<()> {
+        if groups(&query.body) {
+            return ControlFlow::Break(());
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<()> {
+        match expr {
+            Expr::Function(func) if is_aggregate_call(func) => ControlFlow::Break(()),
+            _ => ControlFlow::Continue(()),
+        }
+    }
+}
+
+/// Whether any SELECT making up `body` has a GROUP BY or HAVING clause.
+/// Nested queries are visited separately.
+fn groups(body: &SetExpr) -> bool {
+    match body {
+        SetExpr::Select(select) => {
+            select.having.is_some()
+                || !matches!(
+                    &select.group_by,
+                    GroupByExpr::Expressions(exprs, modifiers)
+                        if exprs.is_empty() && modifiers.is_empty()
+                )
+        }
+        SetExpr::SetOperation { left, right, .. } => groups(left) || groups(right),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_has_aggregation() {
+        let aggregates = |sql: &str| QueryWrapper::parse(sql).unwrap().has_aggregation();
+        assert!(aggregates("SELECT COUNT(*) FROM t"));
+        assert!(aggregates("SELECT a FROM t GROUP BY a"));
+        assert!(aggregates("SELECT 1 FROM t HAVING 1 = 1"));
+        assert!(aggregates("SELECT a + SUM(b) FROM t"));
+        assert!(aggregates("SELECT * FROM (SELECT MAX(a) FROM t)"));
+        assert!(aggregates(
+            "WITH c AS (SELECT a FROM t GROUP BY a) SELECT * FROM c"
+        ));
+        assert!(aggregates(
+            "SELECT a FROM t UNION SELECT b FROM u GROUP BY b"
+        ));
+        assert!(!aggregates(
+            "SELECT a, UPPER(b) FROM t WHERE a > 1 ORDER BY a"
+        ));
+        assert!(!aggregates("SELECT ROW_NUMBER() OVER (ORDER BY a) FROM t"));
+    }
+}
@@ -0,0 +1,380 @@
+use crate::{QueryError, QueryWrapper};
+use duckdb::types::{ToSql, ToSqlOutput, Value as DuckValue};
+use sqlparser::ast::{
+    CastKind, DataType, Expr, GroupByExpr, Query as SqlQuery, SetExpr, TableFactor, Value,
+    VisitMut, VisitorMut,
+};
+use std::collections::HashSet;
+use std::ops::ControlFlow;
+
+impl QueryWrapper {
+    /// Returns the SQL with every placeholder replaced by a literal.
+    ///
+    /// `?` placeholders take the parameters in order; `$1`, `$2`, ... name them
+    /// by position and may repeat. The number of parameters must match the
+    /// placeholders exactly. Strings are single-quoted with embedded quotes
+    /// doubled, blobs become `'\xNN'::BLOB` and non-finite floats are cast from
+    /// their text form, as DuckDB expects. Nested types such as lists and
+    /// structs are rejected.
+    pub fn bind_parameters(&self, params: &[&dyn ToSql]) -> Result<String, QueryError> {
+        let literals = params
+            .iter()
+            .map(|param| literal(*param))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut statements: Vec<_> = std::iter::once(&self.ast)
+            .chain(&self.trailing)
+            .cloned()
+            .collect();
+        let mut next = 0;
+        let mut highest = 0;
+        let mut numbered = false;
+        let mut failure = None;
+        let _ = sqlparser::ast::visit_expressions_mut(&mut statements, |expr| {
+            let Expr::Value(Value::Placeholder(placeholder)) = expr else {
+                return ControlFlow::Continue(());
+            };
+            let index = if placeholder == "?" {
+                next += 1;
+                next
+            } else {
+                match placeholder
+                    .strip_prefix('$')
+                    .and_then(|n| n.parse::<usize>().ok())
+                    .filter(|n| *n > 0)
+                {
+                    Some(n) => {
+                        numbered = true;
+                        n
+                    }
+                    None => {
+                        failure = Some(format!("Unsupported placeholder {}", placeholder));
+                        return ControlFlow::Break(());
+                    }
+                }
+            };
+            highest = highest.max(index);
+            if let Some(literal) = literals.get(index - 1) {
+                *expr = literal.clone();
+            }
+            ControlFlow::Continue(())
+        });
+
+        if let Some(message) = failure {
+            return Err(QueryError::Other(message));
+        }
+        if next > 0 && numbered {
+            return Err(QueryError::Other(
+                "Cannot mix ? and $N placeholders".to_string(),
+            ));
+        }
+        if highest != literals.len() {
+            return Err(QueryError::Other(format!(
+                "Query has {} placeholder(s) but {} parameter(s) were given",
+                highest,
+                literals.len()
+            )));
+        }
+
+        Ok(statements
+            .iter()
+            .map(|statement| statement.to_string())
+            .collect::<Vec<_>>()
+            .join("; "))
+    }
+
+    /// Returns the SQL with each literal replaced by a `$1`, `$2`, ...
+    /// placeholder in order, and the literals as SQL, e.g. `'abc'` or `42`.
+    ///
+    /// Queries that differ only in their literals come out the same, so the
+    /// SQL (or its hash) can key a plan cache. Literals that shape the plan
+    /// rather than filter rows stay: table function arguments such as
+    /// `read_parquet`'s path, INTERVAL values, and positions such as
+    /// `GROUP BY 1`. Placeholders already in the query are left as they are,
+    /// so this is meant for queries without any.
+    pub fn to_parameterized(&self) -> (String, Vec<String>) {
+        let mut statements: Vec<_> = std::iter::once(&self.ast)
+            .chain(&self.trailing)
+            .cloned()
+            .collect();
+        let mut parameterizer = Parameterizer::default();
+        let _ = statements.visit(&mut parameterizer);
+        let sql = statements
+            .iter()
+            .map(|statement| statement.to_string())
+            .collect::<Vec<_>>()
+            .join("; ");
+        (sql, parameterizer.literals)
+    }
+}
+
+#[derive(Default)]
+struct Parameterizer {
+    literals: Vec<String>,
+    /// How many table functions and INTERVALs the visit is inside, whose
+    /// literals are kept.
+    kept: usize,
+    /// GROUP BY and ORDER BY positions such as the `1` in `ORDER BY 1`, by
+    /// address, as they are only recognizable from the enclosing query.
+    positions: HashSet<*const Expr>,
+}
+
+impl Parameterizer {
+    fn keeps_literals(table_factor: &TableFactor) -> bool {
+        matches!(
+            table_factor,
+            TableFactor::Table { args: Some(_), .. } | TableFactor::Function { .. }
+        )
+    }
+}
+
+impl VisitorMut for Parameterizer {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &mut SqlQuery) -> ControlFlow<()> {
+        let order_by = query.order_by.iter().flat_map(|order_by| &order_by.exprs);
+        let group_by = match query.body.as_ref() {
+            SetExpr::Select(select) => match &select.group_by {
+                GroupByExpr::Expressions(exprs, _) => exprs.as_slice(),
+                GroupByExpr::All(_) => &[],
+            },
+            _ => &[],
+        };
+        let positions = order_by.map(|order| &order.expr).chain(group_by);
+        self.positions.extend(
+            positions
+                .filter(|expr| matches!(expr, Expr::Value(Value::Number(..))))
+                .map(|expr| expr as *const Expr),
+        );
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_table_factor(&mut self, table_factor: &mut TableFactor) -> ControlFlow<()> {
+        if Self::keeps_literals(table_factor) {
+            self.kept += 1;
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn post_visit_table_factor(&mut self, table_factor: &mut TableFactor) -> ControlFlow<()> {
+        if Self::keeps_literals(table_factor) {
+            self.kept -= 1;
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &mut Expr) -> ControlFlow<()> {
+        let address = expr as *const Expr;
+        match expr {
+            Expr::Interval(_) => self.kept += 1,
+            Expr::Value(Value::Placeholder(_)) => {}
+            Expr::Value(value) if self.kept == 0 && !self.positions.contains(&address) => {
+                let placeholder = Value::Placeholder(format!("${}", self.literals.len() + 1));
+                self.literals
+                    .push(std::mem::replace(value, placeholder).to_string());
+            }
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn post_visit_expr(&mut self, expr: &mut Expr) -> ControlFlow<()> {
+        if let Expr::Interval(_) = expr {
+            self.kept -= 1;
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+fn literal(param: &dyn ToSql) -> Result<Expr, QueryError> {
+    let value = match param.to_sql()? {
+        ToSqlOutput::Borrowed(value) => value.to_owned(),
+        ToSqlOutput::Owned(value) => value,
+        _ => return Err(QueryError::Other("Unsupported parameter".to_string())),
+    };
+
+    let number = |text: String| Expr::Value(Value::Number(text, false));
+    Ok(match value {
+        DuckValue::Null => Expr::Value(Value::Null),
+        DuckValue::Boolean(value) => Expr::Value(Value::Boolean(value)),
+        DuckValue::TinyInt(value) => number(value.to_string()),
+        DuckValue::SmallInt(value) => number(value.to_string()),
+        DuckValue::Int(value) => number(value.to_string()),
+        DuckValue::BigInt(value) => number(value.to_string()),
+        DuckValue::HugeInt(value) => number(value.to_string()),
+        DuckValue::UTinyInt(value) => number(value.to_string()),
+        DuckValue::USmallInt(value) => number(value.to_string()),
+        DuckValue::UInt(value) => number(value.to_string()),
+        DuckValue::UBigInt(value) => number(value.to_string()),
+        DuckValue::Float(value) => float(value as f64),
+        DuckValue::Double(value) => float(value),
+        DuckValue::Decimal(value) => number(value.to_string()),
+        DuckValue::Text(value) | DuckValue::Enum(value) => {
+            Expr::Value(Value::SingleQuotedString(value))
+        }
+        DuckValue::Blob(bytes) => {
+            let escaped: String = bytes
+                .iter()
+                .map(|byte| format!("\\x{:02X}", byte))
+                .collect();
+            cast(escaped, DataType::Blob(None))
+        }
+        other => {
+            return Err(QueryError::Other(format!(
+                "Unsupported parameter type: {:?}",
+                other.data_type()
+            )))
+        }
+    })
+}
+
+fn float(value: f64) -> Expr {
+    if value.is_finite() {
+        // `{:?}` keeps the fractional part (`1.0`, not `1`) so DuckDB reads a DOUBLE.
+        Expr::Value(Value::Number(format!("{:?}", value), false))
+    } else {
+        cast(value.to_string(), DataType::Double)
+    }
+}
+
+fn cast(text: String, data_type: DataType) -> Expr {
+    Expr::Cast {
+        kind: CastKind::DoubleColon,
+        expr: Box::new(Expr::Value(Value::SingleQuotedString(text))),
+        data_type,
+        format: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use duckdb::Connection;
+
+    #[test]
+    fn test_bind_positional_parameters() {
+        let wrapper =
+            QueryWrapper::parse("SELECT * FROM sales WHERE region = ? AND amount > ? AND ok = ?")
+                .unwrap();
+        let sql = wrapper
+            .bind_parameters(&[&"O'Brien", &42i64, &true])
+            .unwrap();
+        assert_eq!(
+            sql,
+            "SELECT * FROM sales WHERE region = 'O''Brien' AND amount > 42 AND ok = true"
+        );
+    }
+
+    #[test]
+    fn test_bind_numbered_parameters() {
+        let wrapper = QueryWrapper::parse("SELECT $2, $1, $2").unwrap();
+        let sql = wrapper.bind_parameters(&[&1.5f64, &"x"]).unwrap();
+        assert_eq!(sql, "SELECT 'x', 1.5, 'x'");
+    }
+
+    #[test]
+    fn test_bind_parameter_count_mismatch() {
+        let wrapper = QueryWrapper::parse("SELECT ? + ?").unwrap();
+        assert!(matches!(
+            wrapper.bind_parameters(&[&1i32]),
+            Err(QueryError::Other(_))
+        ));
+        assert!(matches!(
+            wrapper.bind_parameters(&[&1i32, &2i32, &3i32]),
+            Err(QueryError::Other(_))
+        ));
+        assert!(matches!(
+            QueryWrapper::parse("SELECT $1, ?")
+                .unwrap()
+                .bind_parameters(&[&1i32, &2i32]),
+            Err(QueryError::Other(_))
+        ));
+    }
+
+    #[test]
+    fn test_to_parameterized() {
+        let parameterized = |sql: &str| QueryWrapper::parse(sql).unwrap().to_parameterized();
+        let (sql, literals) = parameterized(
+            "SELECT region, SUM(amount) * 1.5 FROM sales \
+             WHERE id = 1 AND name = 'O''Brien' AND ok = true GROUP BY 1 ORDER BY 2 DESC LIMIT 10",
+        );
+        assert_eq!(
+            sql,
+            "SELECT region, SUM(amount) * $1 FROM sales \
+             WHERE id = $2 AND name = $3 AND ok = $4 GROUP BY 1 ORDER BY 2 DESC LIMIT $5"
+        );
+        assert_eq!(literals, ["1.5", "1", "'O''Brien'", "true", "10"]);
+
+        assert_eq!(
+            parameterized("SELECT * FROM t WHERE id = 2").0,
+            parameterized("SELECT * FROM t WHERE id = 7").0
+        );
+
+        let (sql, literals) = parameterized(
+            "SELECT * FROM read_parquet('s3://b/*.parquet') \
+             WHERE ts > now() - INTERVAL '1 day' AND id IN (SELECT id FROM t WHERE x = 'y')",
+        );
+        assert_eq!(
+            sql,
+            "SELECT * FROM read_parquet('s3://b/*.parquet') \
+             WHERE ts > now() - INTERVAL '1 day' AND id IN (SELECT id FROM t WHERE x = $1)"
+        );
+        assert_eq!(literals, ["'y'"]);
+    }
+
+    #[test]
+    fn test_parameterized_sql_runs_in_duckdb() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE t (id INTEGER, name VARCHAR); \
+             INSERT INTO t VALUES (1, 'a'), (2, 'b'), (3, 'b')",
+        )
+        .unwrap();
+        let (sql, literals) =
+            QueryWrapper::parse("SELECT COUNT(*) FROM t WHERE name = 'b' AND id > 2")
+                .unwrap()
+                .to_parameterized();
+        assert_eq!(literals, ["'b'", "2"]);
+        let count: i64 = conn
+            .query_row(&sql, duckdb::params!["b", 2], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_bound_literals_execute_in_duckdb() {
+        let wrapper = QueryWrapper::parse("SELECT ?, ?, ?, ?, ?, ?").unwrap();
+        let blob: Vec<u8> = vec![0x00, 0x27, 0xff];
+        let null: Option<i32> = None;
+        let sql = wrapper
+            .bind_parameters(&[&"it's", &blob, &f64::NAN, &0.25f32, &null, &-7i8])
+            .unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        let (text, bytes, nan, quarter, missing, small): (
+            String,
+            Vec<u8>,
+            f64,
+            f64,
+            Option<i32>,
+            i64,
+        ) = conn
+            .query_row(&sql, [], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                ))
+            })
+            .unwrap();
+        assert_eq!(text, "it's");
+        assert_eq!(bytes, blob);
+        assert!(nan.is_nan());
+        assert_eq!(quarter, 0.25);
+        assert_eq!(missing, None);
+        assert_eq!(small, -7);
+    }
+}
@@ -0,0 +1,284 @@
+use crate::{PrefixScanner, QueryError, QueryWrapper, ScanConfig};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Remembers prefix scan results per source for a while, so repeated queries
+/// over the same data skip the glob.
+///
+/// Entries expire `ttl` after they were scanned; beyond `max_entries` the
+/// least recently used is dropped. The cache is `Sync`, so one `Arc` can be
+/// kept in a static or a handler's state to last across invocations of a
+/// warm Lambda. Two concurrent misses for the same source both scan.
+///
+/// ```
+/// use pond_parser::PrefixCache;
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// let cache = Arc::new(PrefixCache::new(Duration::from_secs(3600), 256));
+/// ```
+#[derive(Debug)]
+pub struct PrefixCache {
+    ttl: Duration,
+    max_entries: usize,
+    bypass: AtomicBool,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    by_source: HashMap<String, Entry>,
+    /// Bumped on every hit or insert to order entries by last use.
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    prefixes: Vec<String>,
+    scanned_at: Instant,
+    last_used: u64,
+}
+
+impl PrefixCache {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            bypass: AtomicBool::new(false),
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// While set, every lookup scans and nothing is read from the cache.
+    /// Fresh results are still stored.
+    pub fn set_bypass(&self, bypass: bool) {
+        self.bypass.store(bypass, Ordering::Relaxed);
+    }
+
+    /// Forgets the prefixes cached for `source`.
+    pub fn invalidate(&self, source: &str) {
+        self.entries().by_source.remove(&cache_key(source));
+    }
+
+    /// The cached prefixes for `source` if they are fresh, otherwise the
+    /// result of `scan`, which is cached when it succeeds.
+    pub async fn get_or_scan<F, Fut>(
+        &self,
+        source: &str,
+        scan: F,
+    ) -> Result<Vec<String>, QueryError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Vec<String>, QueryError>>,
+    {
+        let key = cache_key(source);
+        if !self.bypass.load(Ordering::Relaxed) {
+            if let Some(prefixes) = self.lookup(&key) {
+                return Ok(prefixes);
+            }
+        }
+        let prefixes = scan().await?;
+        self.insert(key, prefixes.clone());
+        Ok(prefixes)
+    }
+
+    fn lookup(&self, key: &str) -> Option<Vec<String>> {
+        let mut entries = self.entries();
+        entries.clock += 1;
+        let clock = entries.clock;
+        match entries.by_source.get_mut(key) {
+            Some(entry) if entry.scanned_at.elapsed() < self.ttl => {
+                entry.last_used = clock;
+                Some(entry.prefixes.clone())
+            }
+            Some(_) => {
+                entries.by_source.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, key: String, prefixes: Vec<String>) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let mut entries = self.entries();
+        entries.clock += 1;
+        let last_used = entries.clock;
+        entries.by_source.insert(
+            key,
+            Entry {
+                prefixes,
+                scanned_at: Instant::now(),
+                last_used,
+            },
+        );
+        while entries.by_source.len() > self.max_entries {
+            let oldest = entries
+                .by_source
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.by_source.remove(&oldest);
+            }
+        }
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, Entries> {
+        // The map stays consistent even if a holder panicked.
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Sources differing only in surrounding whitespace or the scheme's case
+/// share an entry.
+fn cache_key(source: &str) -> String {
+    let source = source.trim();
+    match source.split_once("://") {
+        Some((scheme, rest)) => format!("{}://{}", scheme.to_lowercase(), rest),
+        None => source.to_string(),
+    }
+}
+
+impl QueryWrapper {
+    /// Like [`list_of_prefixes`](Self::list_of_prefixes), answering from
+    /// `cache` when it holds fresh prefixes for this query's source.
+    pub async fn list_of_prefixes_cached(
+        &mut self,
+        cache: &PrefixCache,
+    ) -> Result<&Vec<String>, QueryError> {
+        if self.list_of_prefixes.is_none() {
+            let source = self.source()?;
+            let prefixes = cache
+                .get_or_scan(&source, || async {
+                    PrefixScanner::shared()
+                        .scan(self, &ScanConfig::from_env())
+                        .await
+                })
+                .await?;
+            self.list_of_prefixes = Some(prefixes);
+        }
+        Ok(self.list_of_prefixes.as_ref().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    /// A scan that counts its calls and returns one prefix per call.
+    async fn counting_scan(calls: &AtomicUsize) -> Result<Vec<String>, QueryError> {
+        let call = calls.fetch_add(1, Ordering::SeqCst) + 1;
+        Ok(vec![format!("s3://bucket/scan-{}/*", call)])
+    }
+
+    #[tokio::test]
+    async fn test_hit_within_ttl_skips_the_scan() {
+        let cache = PrefixCache::new(Duration::from_secs(60), 8);
+        let calls = AtomicUsize::new(0);
+        let source = "s3://bucket/data/*.parquet";
+
+        let first = cache
+            .get_or_scan(source, || counting_scan(&calls))
+            .await
+            .unwrap();
+        let second = cache
+            .get_or_scan(" S3://bucket/data/*.parquet", || counting_scan(&calls))
+            .await
+            .unwrap();
+        assert_eq!(first, second);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        cache.set_bypass(true);
+        cache
+            .get_or_scan(source, || counting_scan(&calls))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        cache.set_bypass(false);
+        cache.invalidate(source);
+        let refreshed = cache
+            .get_or_scan(source, || counting_scan(&calls))
+            .await
+            .unwrap();
+        assert_eq!(refreshed, vec!["s3://bucket/scan-3/*".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_expiry_triggers_a_refresh() {
+        let cache = PrefixCache::new(Duration::from_millis(50), 8);
+        let calls = AtomicUsize::new(0);
+        let source = "s3://bucket/data/*.parquet";
+
+        cache
+            .get_or_scan(source, || counting_scan(&calls))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        let refreshed = cache
+            .get_or_scan(source, || counting_scan(&calls))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(refreshed, vec!["s3://bucket/scan-2/*".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_least_recently_used_is_evicted() {
+        let cache = PrefixCache::new(Duration::from_secs(60), 2);
+        let calls = AtomicUsize::new(0);
+        for source in ["s3://a/*", "s3://b/*"] {
+            cache
+                .get_or_scan(source, || counting_scan(&calls))
+                .await
+                .unwrap();
+        }
+        // Touch `a` so `b` is the least recently used when `c` arrives.
+        cache
+            .get_or_scan("s3://a/*", || counting_scan(&calls))
+            .await
+            .unwrap();
+        cache
+            .get_or_scan("s3://c/*", || counting_scan(&calls))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        cache
+            .get_or_scan("s3://a/*", || counting_scan(&calls))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        cache
+            .get_or_scan("s3://b/*", || counting_scan(&calls))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_list_of_prefixes_cached() {
+        let cache = PrefixCache::new(Duration::from_secs(60), 8);
+        let source = "s3://bucket/data/*.parquet";
+        cache
+            .get_or_scan(source, || async {
+                Ok(vec!["s3://bucket/data/*".to_string()])
+            })
+            .await
+            .unwrap();
+
+        // Served from the cache, so no DuckDB scan of the unreachable bucket.
+        let mut wrapper = QueryWrapper::parse(&format!("SELECT * FROM '{}'", source)).unwrap();
+        let prefixes = wrapper.list_of_prefixes_cached(&cache).await.unwrap();
+        assert_eq!(prefixes, &vec!["s3://bucket/data/*".to_string()]);
+    }
+}
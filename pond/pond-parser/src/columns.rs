@@ -0,0 +1,427 @@
+use crate::decompose::is_aggregate_call;
+use crate::{normalized_ident, QueryWrapper, SortRequirement};
+use sqlparser::ast::{
+    Expr, Function, FunctionArg, FunctionArgExpr, FunctionArguments, Ident, JoinOperator, Offset,
+    SelectItem, Statement, TableFactor, Value,
+};
+use std::collections::HashSet;
+use std::ops::ControlFlow;
+
+impl QueryWrapper {
+    /// The outer SELECT's output columns in projection order, each with
+    /// whether it may be NULL. Without a schema this is an estimate:
+    ///
+    /// - Names are the alias, else the column name for (possibly qualified)
+    ///   identifiers, else the function name for calls, else the SQL text.
+    ///   Wildcards come back as `("*", true)`.
+    /// - Base columns are assumed NOT NULL unless they can come from the
+    ///   optional side of an outer join. `COUNT` never returns NULL; other
+    ///   aggregates and expressions are nullable when an input is, as are NULL
+    ///   literals, CASE without ELSE and scalar subqueries.
+    ///
+    /// Set operations and non-queries have no single projection and yield an
+    /// empty list.
+    pub fn estimated_output_columns(&self) -> Vec<(String, bool)> {
+        let Some(select) = self.outer_select() else {
+            return Vec::new();
+        };
+        let nullability = Nullability::of(select);
+        select
+            .projection
+            .iter()
+            .map(|item| match item {
+                SelectItem::UnnamedExpr(expr) => (column_name(expr), nullability.expr(expr)),
+                SelectItem::ExprWithAlias { expr, alias } => {
+                    (alias.value.clone(), nullability.expr(expr))
+                }
+                SelectItem::Wildcard(_) | SelectItem::QualifiedWildcard(..) => {
+                    ("*".to_string(), true)
+                }
+            })
+            .collect()
+    }
+
+    /// The outer ORDER BY's sort keys, each with `true` when ascending. Keys
+    /// are named like [`estimated_output_columns`](Self::estimated_output_columns):
+    /// the column name for (possibly qualified) identifiers, the output column
+    /// for positions such as `ORDER BY 2`, else the SQL text.
+    pub fn order_by_columns(&self) -> Vec<(String, bool)> {
+        self.order_by()
+            .iter()
+            .map(|order| {
+                let name = match &order.expr {
+                    Expr::Value(Value::Number(position, _)) => self
+                        .output_column(position)
+                        .unwrap_or_else(|| order.expr.to_string()),
+                    Expr::Identifier(_) | Expr::CompoundIdentifier(_) => column_name(&order.expr),
+                    expr => expr.to_string(),
+                };
+                (name, order.asc != Some(false))
+            })
+            .collect()
+    }
+
+    /// See [`QueryAnalysis::sort_requirement`](crate::QueryAnalysis::sort_requirement).
+    /// A LIMIT or OFFSET that isn't a literal number bounds nothing, so the
+    /// sort is taken to be full.
+    pub(crate) fn sort_requirement(&self) -> SortRequirement {
+        let Statement::Query(query) = &self.ast else {
+            return SortRequirement::None;
+        };
+        let keys = self.sort_keys();
+        if keys.is_empty() {
+            return SortRequirement::None;
+        }
+        let offset = match &query.offset {
+            None => Some(0),
+            Some(Offset { value, .. }) => literal(value),
+        };
+        match (query.limit.as_ref().and_then(literal), offset) {
+            (Some(limit), Some(offset)) => SortRequirement::TopK {
+                k: limit.saturating_add(offset),
+                keys,
+            },
+            _ => SortRequirement::FullSort { keys },
+        }
+    }
+
+    fn sort_keys(&self) -> Vec<(String, bool)> {
+        self.order_by()
+            .iter()
+            .map(|order| {
+                let expr = self.output_expr(&order.expr).unwrap_or(&order.expr);
+                let name = match expr {
+                    Expr::Identifier(ident) => normalized_ident(ident),
+                    Expr::CompoundIdentifier(idents) => idents
+                        .last()
+                        .map_or_else(|| expr.to_string(), normalized_ident),
+                    _ => expr.to_string(),
+                };
+                (name, order.asc != Some(false))
+            })
+            .collect()
+    }
+
+    /// The outer SELECT item `expr` refers to in an ORDER BY, by 1-based
+    /// position or by alias.
+    fn output_expr(&self, expr: &Expr) -> Option<&Expr> {
+        let projection = self.projection();
+        match expr {
+            Expr::Value(Value::Number(position, _)) => {
+                let index = position.parse::<usize>().ok()?.checked_sub(1)?;
+                match projection.get(index)? {
+                    SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                        Some(expr)
+                    }
+                    SelectItem::Wildcard(_) | SelectItem::QualifiedWildcard(..) => None,
+                }
+            }
+            Expr::Identifier(name) => projection.iter().find_map(|item| match item {
+                SelectItem::ExprWithAlias { expr, alias }
+                    if normalized_ident(alias) == normalized_ident(name) =>
+                {
+                    Some(expr)
+                }
+                _ => None,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The name of the 1-based `position` in the outer SELECT list.
+    fn output_column(&self, position: &str) -> Option<String> {
+        let index = position.parse::<usize>().ok()?.checked_sub(1)?;
+        match self.projection().get(index)? {
+            SelectItem::UnnamedExpr(expr) => Some(column_name(expr)),
+            SelectItem::ExprWithAlias { alias, .. } => Some(alias.value.clone()),
+            SelectItem::Wildcard(_) | SelectItem::QualifiedWildcard(..) => None,
+        }
+    }
+}
+
+fn literal(expr: &Expr) -> Option<u64> {
+    match expr {
+        Expr::Value(Value::Number(n, _)) => n.parse().ok(),
+        _ => None,
+    }
+}
+
+pub(crate) fn column_name(expr: &Expr) -> String {
+    match expr {
+        Expr::Identifier(ident) => ident.value.clone(),
+        Expr::CompoundIdentifier(idents) => idents
+            .last()
+            .map_or_else(|| expr.to_string(), |ident| ident.value.clone()),
+        Expr::Function(func) => func.name.to_string(),
+        _ => expr.to_string(),
+    }
+}
+
+/// Which relations of a SELECT can produce NULL-extended rows.
+struct Nullability {
+    /// Lowercased names and aliases of relations on the optional side of an
+    /// outer join.
+    optional: HashSet<String>,
+}
+
+impl Nullability {
+    fn of(select: &sqlparser::ast::Select) -> Self {
+        let mut optional = HashSet::new();
+        for from in &select.from {
+            let mut seen = vec![relation_name(&from.relation)];
+            for join in &from.joins {
+                let joined = relation_name(&join.relation);
+                match join.join_operator {
+                    JoinOperator::LeftOuter(_) | JoinOperator::OuterApply => {
+                        optional.extend(joined.clone());
+                    }
+                    JoinOperator::RightOuter(_) => {
+                        optional.extend(seen.iter().flatten().cloned());
+                    }
+                    JoinOperator::FullOuter(_) => {
+                        optional.extend(seen.iter().flatten().cloned());
+                        optional.extend(joined.clone());
+                    }
+                    _ => {}
+                }
+                seen.push(joined);
+            }
+        }
+        Self { optional }
+    }
+
+    fn column(&self, qualifier: Option<&Ident>) -> bool {
+        match qualifier {
+            Some(relation) => self.optional.contains(&normalized_ident(relation)),
+            // Could come from any relation.
+            None => !self.optional.is_empty(),
+        }
+    }
+
+    fn expr(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::Identifier(_) => self.column(None),
+            Expr::CompoundIdentifier(idents) => {
+                let qualifier = idents.len().checked_sub(2).map(|i| &idents[i]);
+                self.column(qualifier)
+            }
+            Expr::Value(Value::Null) => true,
+            Expr::Value(_) => false,
+            Expr::Function(func) => self.function(func),
+            Expr::Case {
+                results,
+                else_result,
+                ..
+            } => match else_result {
+                Some(else_result) => {
+                    results.iter().any(|result| self.expr(result)) || self.expr(else_result)
+                }
+                None => true,
+            },
+            Expr::Subquery(_) => true,
+            Expr::Nested(expr) | Expr::UnaryOp { expr, .. } | Expr::Cast { expr, .. } => {
+                self.expr(expr)
+            }
+            Expr::BinaryOp { left, right, .. } => self.expr(left) || self.expr(right),
+            _ => self.any_input(expr),
+        }
+    }
+
+    fn function(&self, func: &Function) -> bool {
+        let name = func.name.to_string().to_lowercase();
+        let args = function_args(func);
+        match name.as_str() {
+            "count" if is_aggregate_call(func) => false,
+            "coalesce" | "ifnull" => args.iter().all(|arg| self.expr(arg)),
+            "nullif" => true,
+            _ => args.iter().any(|arg| self.expr(arg)),
+        }
+    }
+
+    /// Whether any column, NULL or call inside `expr` is nullable, for
+    /// expressions not handled above.
+    fn any_input(&self, expr: &Expr) -> bool {
+        sqlparser::ast::visit_expressions(expr, |inner| {
+            let leaf = matches!(
+                inner,
+                Expr::Identifier(_)
+                    | Expr::CompoundIdentifier(_)
+                    | Expr::Value(Value::Null)
+                    | Expr::Subquery(_)
+                    | Expr::Function(_)
+            );
+            if leaf && !std::ptr::eq(inner, expr) && self.expr(inner) {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .is_break()
+    }
+}
+
+fn function_args(func: &Function) -> Vec<&Expr> {
+    let FunctionArguments::List(list) = &func.args else {
+        return Vec::new();
+    };
+    list.args
+        .iter()
+        .filter_map(|arg| match arg {
+            FunctionArg::Unnamed(FunctionArgExpr::Expr(expr))
+            | FunctionArg::Named {
+                arg: FunctionArgExpr::Expr(expr),
+                ..
+            } => Some(expr),
+            _ => None,
+        })
+        .collect()
+}
+
+pub(crate) fn relation_name(relation: &TableFactor) -> Option<String> {
+    match relation {
+        TableFactor::Table {
+            alias: Some(alias), ..
+        }
+        | TableFactor::Derived {
+            alias: Some(alias), ..
+        }
+        | TableFactor::Function {
+            alias: Some(alias), ..
+        }
+        | TableFactor::UNNEST {
+            alias: Some(alias), ..
+        } => Some(normalized_ident(&alias.name)),
+        TableFactor::Table { name, .. } => name.0.last().map(normalized_ident),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns(sql: &str) -> Vec<(String, bool)> {
+        QueryWrapper::parse(sql).unwrap().estimated_output_columns()
+    }
+
+    fn expected(pairs: &[(&str, bool)]) -> Vec<(String, bool)> {
+        pairs
+            .iter()
+            .map(|(name, nullable)| (name.to_string(), *nullable))
+            .collect()
+    }
+
+    #[test]
+    fn test_names_in_projection_order() {
+        assert_eq!(
+            columns("SELECT id, t.name, UPPER(city) AS city_upper, LOWER(code), price * 2 FROM t"),
+            expected(&[
+                ("id", false),
+                ("name", false),
+                ("city_upper", false),
+                ("LOWER", false),
+                ("price * 2", false),
+            ])
+        );
+        assert_eq!(columns("SELECT * FROM t"), expected(&[("*", true)]));
+        assert!(columns("SELECT a FROM t UNION SELECT b FROM u").is_empty());
+    }
+
+    #[test]
+    fn test_outer_join_columns_are_nullable() {
+        assert_eq!(
+            columns(
+                "SELECT o.id, c.name, COALESCE(c.name, 'none') AS label, COUNT(c.id) AS n, \
+                 MAX(c.age) AS oldest FROM orders o LEFT JOIN customers c ON o.cid = c.id \
+                 GROUP BY o.id, c.name"
+            ),
+            expected(&[
+                ("id", false),
+                ("name", true),
+                ("label", false),
+                ("n", false),
+                ("oldest", true),
+            ])
+        );
+        assert_eq!(
+            columns("SELECT a.x, b.y FROM a RIGHT JOIN b ON a.id = b.id"),
+            expected(&[("x", true), ("y", false)])
+        );
+        assert_eq!(
+            columns("SELECT a.x, b.y, z FROM a FULL JOIN b ON a.id = b.id"),
+            expected(&[("x", true), ("y", true), ("z", true)])
+        );
+    }
+
+    #[test]
+    fn test_aggregates_and_expressions() {
+        assert_eq!(
+            columns(
+                "SELECT COUNT(*), SUM(amount) AS total, NULL AS nothing, \
+                 CASE WHEN amount > 0 THEN 'pos' END AS sign, \
+                 CASE WHEN amount > 0 THEN 'pos' ELSE 'neg' END AS sign2, \
+                 -CAST(amount AS INTEGER) + 1 AS adjusted FROM sales"
+            ),
+            expected(&[
+                ("COUNT", false),
+                ("total", false),
+                ("nothing", true),
+                ("sign", true),
+                ("sign2", false),
+                ("adjusted", false),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_order_by_columns() {
+        let parsed = QueryWrapper::parse(
+            "SELECT region, SUM(amount) AS total FROM sales s \
+             GROUP BY region ORDER BY total DESC, s.region ASC, 1, lower(region) DESC",
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.order_by_columns(),
+            expected(&[
+                ("total", false),
+                ("region", true),
+                ("region", true),
+                ("lower(region)", false),
+            ])
+        );
+        let parsed = QueryWrapper::parse("SELECT * FROM sales").unwrap();
+        assert!(parsed.order_by_columns().is_empty());
+    }
+
+    #[test]
+    fn test_sort_requirement() {
+        let sort = |sql: &str| {
+            QueryWrapper::parse(sql)
+                .unwrap()
+                .analyze()
+                .sort_requirement()
+                .clone()
+        };
+        assert_eq!(
+            sort("SELECT id, amount AS a FROM sales s ORDER BY a DESC, s.Region LIMIT 5 OFFSET 10"),
+            SortRequirement::TopK {
+                k: 15,
+                keys: expected(&[("amount", false), ("region", true)]),
+            }
+        );
+        assert_eq!(
+            sort("SELECT region, SUM(amount) AS total FROM sales GROUP BY 1 ORDER BY 2, 1 DESC"),
+            SortRequirement::FullSort {
+                keys: expected(&[("SUM(amount)", true), ("region", false)]),
+            }
+        );
+        assert_eq!(
+            sort("SELECT id FROM sales ORDER BY id LIMIT (SELECT 5)"),
+            SortRequirement::FullSort {
+                keys: expected(&[("id", true)])
+            }
+        );
+        assert_eq!(sort("SELECT id FROM sales LIMIT 5"), SortRequirement::None);
+        assert_eq!(sort("INSERT INTO t VALUES (1)"), SortRequirement::None);
+    }
+}
@@ -0,0 +1,166 @@
+use crate::schema::read_metadata;
+use crate::{FileEntry, FileFormat, PrefixScanner, QueryError, QueryWrapper, ScanConfig};
+
+/// Parquet footers read by [`QueryWrapper::estimate_cost`] unless told
+/// otherwise.
+pub const DEFAULT_FOOTER_SAMPLE: usize = 16;
+
+/// How much data a query would scan; see [`QueryWrapper::estimate_cost`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CostEstimate {
+    /// Files matched by the query's sources.
+    pub files: usize,
+    /// Their total size.
+    pub bytes: u64,
+    /// Rows in the parquet files, exact when every footer was read and
+    /// extrapolated by size otherwise. Other formats count no rows.
+    pub estimated_rows: u64,
+    /// Parquet footers actually read.
+    pub sampled: usize,
+}
+
+impl CostEstimate {
+    /// Fails with [`QueryError::ScanTooLarge`] when the scan exceeds
+    /// `max_bytes`.
+    pub fn ensure_max_bytes(&self, max_bytes: u64) -> Result<(), QueryError> {
+        if self.bytes > max_bytes {
+            return Err(QueryError::ScanTooLarge {
+                bytes: self.bytes,
+                max_bytes,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl QueryWrapper {
+    /// Estimates the scan from the files the query's sources match, reading
+    /// at most [`DEFAULT_FOOTER_SAMPLE`] parquet footers, with
+    /// [`ScanConfig::from_env`] and the shared [`PrefixScanner`].
+    pub async fn estimate_cost(&self) -> Result<CostEstimate, QueryError> {
+        self.estimate_cost_with_config(&ScanConfig::from_env(), DEFAULT_FOOTER_SAMPLE)
+            .await
+    }
+
+    /// Like [`estimate_cost`](Self::estimate_cost), reaching object storage as
+    /// `config` describes and reading at most `max_footers` footers.
+    ///
+    /// Files are listed, so `files` and `bytes` are exact. When a source has
+    /// more parquet files than `max_footers`, footers are read from files
+    /// spread evenly over its listing and their rows per byte applied to the
+    /// rest. Remote footers need the `object-store` feature, as for
+    /// [`source_schema`](Self::source_schema).
+    pub async fn estimate_cost_with_config(
+        &self,
+        config: &ScanConfig,
+        max_footers: usize,
+    ) -> Result<CostEstimate, QueryError> {
+        if !self.has_from() {
+            return Ok(CostEstimate::default());
+        }
+        let mut sources: Vec<(String, FileFormat)> = Vec::new();
+        for (source, format) in self.file_sources() {
+            if !sources.iter().any(|(seen, _)| *seen == source) {
+                sources.push((source, format));
+            }
+        }
+        if sources.is_empty() {
+            sources.push((self.source()?, FileFormat::Unknown));
+        }
+
+        let mut estimate = CostEstimate::default();
+        let mut parquet_files = Vec::new();
+        for (source, format) in sources {
+            let files = PrefixScanner::shared()
+                .list_source_files(source, config)
+                .await?;
+            estimate.files += files.len();
+            estimate.bytes += files.iter().map(|file| file.size_bytes).sum::<u64>();
+            if format == FileFormat::Parquet {
+                parquet_files.extend(files);
+            }
+        }
+
+        let sample = spread(&parquet_files, max_footers);
+        let mut sampled_rows: u64 = 0;
+        let mut sampled_bytes: u64 = 0;
+        for file in &sample {
+            let metadata = read_metadata(file, config).await?;
+            sampled_rows += metadata.metadata().file_metadata().num_rows().max(0) as u64;
+            sampled_bytes += file.size_bytes;
+        }
+        estimate.sampled = sample.len();
+        estimate.estimated_rows = if sample.len() == parquet_files.len() {
+            sampled_rows
+        } else {
+            let parquet_bytes: u64 = parquet_files.iter().map(|file| file.size_bytes).sum();
+            (sampled_rows as f64 / sampled_bytes.max(1) as f64 * parquet_bytes as f64) as u64
+        };
+        Ok(estimate)
+    }
+}
+
+/// At most `count` of `files`, evenly spaced so a listing sorted by date or
+/// partition isn't sampled from one end only.
+fn spread(files: &[FileEntry], count: usize) -> Vec<&FileEntry> {
+    if files.len() <= count {
+        return files.iter().collect();
+    }
+    (0..count)
+        .map(|i| &files[i * files.len() / count])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{ArrayRef, Int64Array, RecordBatch};
+    use parquet::arrow::ArrowWriter;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_estimate_cost() {
+        let root = std::env::temp_dir().join(format!("pond-cost-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        for (i, rows) in [10, 10, 10, 10].into_iter().enumerate() {
+            let ids: ArrayRef = Arc::new(Int64Array::from_iter_values(0..rows));
+            let batch = RecordBatch::try_from_iter([("id", ids)]).unwrap();
+            let file = std::fs::File::create(root.join(format!("{}.parquet", i))).unwrap();
+            let mut writer = ArrowWriter::try_new(file, batch.schema(), None).unwrap();
+            writer.write(&batch).unwrap();
+            writer.close().unwrap();
+        }
+        std::fs::write(root.join("notes.csv"), "a,b\n1,2\n").unwrap();
+
+        let query = format!(
+            "SELECT * FROM '{0}/*.parquet' p JOIN read_csv('{0}/notes.csv') n ON true",
+            root.display()
+        );
+        let wrapper = QueryWrapper::parse(&query).unwrap();
+        let exact = wrapper.estimate_cost().await.unwrap();
+        assert_eq!(exact.files, 5);
+        assert_eq!(exact.estimated_rows, 40);
+        assert_eq!(exact.sampled, 4);
+
+        // Identical files extrapolate exactly.
+        let sampled = wrapper
+            .estimate_cost_with_config(&ScanConfig::from_env(), 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            sampled,
+            CostEstimate {
+                sampled: 2,
+                ..exact
+            }
+        );
+
+        assert!(exact.ensure_max_bytes(exact.bytes).is_ok());
+        assert!(matches!(
+            exact.ensure_max_bytes(exact.bytes - 1),
+            Err(QueryError::ScanTooLarge { bytes, .. }) if bytes == exact.bytes
+        ));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,57 @@
+use crate::CteInfo;
+use sqlparser::ast::{CteAsMaterialized, Query, Statement, TableFactor, Visit, Visitor};
+use std::collections::BTreeSet;
+use std::ops::ControlFlow;
+
+/// Appends every CTE `statement` defines to `ctes`, outer queries' first.
+pub(crate) fn collect_ctes(statement: &Statement, ctes: &mut Vec<CteInfo>) {
+    let _ = statement.visit(&mut CteCollector { ctes });
+}
+
+struct CteCollector<'a> {
+    ctes: &'a mut Vec<CteInfo>,
+}
+
+impl Visitor for CteCollector<'_> {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<()> {
+        let Some(with) = &query.with else {
+            return ControlFlow::Continue(());
+        };
+        for cte in &with.cte_tables {
+            let mut relations = RelationCollector::default();
+            let _ = cte.query.visit(&mut relations);
+            self.ctes.push(CteInfo {
+                name: cte.alias.name.value.clone(),
+                referenced_tables: relations.names,
+                is_recursive: with.recursive,
+                materialized_hint: cte
+                    .materialized
+                    .as_ref()
+                    .map(|hint| matches!(hint, CteAsMaterialized::Materialized)),
+            });
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// The names of the relations a query reads, as
+/// [`QueryAnalysis::tables`](crate::QueryAnalysis::tables) reports them.
+#[derive(Default)]
+struct RelationCollector {
+    names: BTreeSet<String>,
+}
+
+impl Visitor for RelationCollector {
+    type Break = ();
+
+    fn pre_visit_table_factor(&mut self, table_factor: &TableFactor) -> ControlFlow<()> {
+        if let TableFactor::Table { name, .. } = table_factor {
+            let name = crate::schema::relation_path(table_factor)
+                .unwrap_or_else(|| crate::normalized_name(&name.0));
+            self.names.insert(name);
+        }
+        ControlFlow::Continue(())
+    }
+}
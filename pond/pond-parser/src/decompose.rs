@@ -0,0 +1,726 @@
+use crate::{QueryError, QueryWrapper};
+use sqlparser::ast::{
+    BinaryOperator, DuplicateTreatment, Expr, Function, FunctionArg, FunctionArgExpr,
+    FunctionArgumentList, FunctionArguments, GroupByExpr, Ident, ObjectName, Query as SqlQuery,
+    Select, SelectItem, SetExpr, Statement, Value, VisitMut, Visitor, VisitorMut,
+};
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::ControlFlow;
+
+/// Relation the final stage reads from; callers load the union of every
+/// partition's partial results under this name before running it.
+pub const PARTIAL_RESULTS_TABLE: &str = "partial_results";
+
+const KEY_PREFIX: &str = "__pond_key_";
+const PARTIAL_PREFIX: &str = "__pond_agg_";
+const MERGE_PREFIX: &str = "__pond_merge_";
+
+/// Aggregates that cannot be computed from per-partition partial results.
+pub(crate) const HOLISTIC_AGGREGATES: &[&str] = &[
+    "approx_count_distinct",
+    "approx_quantile",
+    "arg_max",
+    "arg_min",
+    "array_agg",
+    "group_concat",
+    "list",
+    "median",
+    "mode",
+    "percentile_cont",
+    "percentile_disc",
+    "quantile",
+    "quantile_cont",
+    "quantile_disc",
+    "stddev",
+    "stddev_pop",
+    "stddev_samp",
+    "string_agg",
+    "var_pop",
+    "var_samp",
+    "variance",
+];
+
+/// An aggregate query split into a per-partition stage and a merge stage.
+#[derive(Debug, Clone)]
+pub struct DecomposedQuery {
+    /// Runs once per partition: the original FROM, WHERE and GROUP BY with each
+    /// aggregate rewritten to its partial form.
+    pub partial_sql: String,
+    /// Combines the partial rows in [`PARTIAL_RESULTS_TABLE`] into the original
+    /// answer, applying HAVING, ORDER BY and LIMIT.
+    pub final_sql: String,
+}
+
+/// A construct that prevents a query from being decomposed, with the offending SQL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnsupportedFeature {
+    NotAQuery,
+    SetOperation(String),
+    NoAggregation,
+    SelectDistinct,
+    Wildcard(String),
+    WindowFunction(String),
+    DistinctAggregate(String),
+    HolisticAggregate(String),
+    GroupingModifier(String),
+    Qualify(String),
+    Subquery(String),
+    UngroupedColumn(String),
+}
+
+impl fmt::Display for UnsupportedFeature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotAQuery => write!(f, "statement is not a query"),
+            Self::SetOperation(sql) => write!(f, "set operation `{}`", sql),
+            Self::NoAggregation => write!(f, "query has no aggregation"),
+            Self::SelectDistinct => write!(f, "SELECT DISTINCT"),
+            Self::Wildcard(sql) => write!(f, "wildcard `{}`", sql),
+            Self::WindowFunction(sql) => write!(f, "window function `{}`", sql),
+            Self::DistinctAggregate(sql) => write!(f, "DISTINCT aggregate `{}`", sql),
+            Self::HolisticAggregate(sql) => write!(f, "aggregate `{}`", sql),
+            Self::GroupingModifier(sql) => write!(f, "grouping modifier `{}`", sql),
+            Self::Qualify(sql) => write!(f, "QUALIFY `{}`", sql),
+            Self::Subquery(sql) => write!(f, "subquery `{}`", sql),
+            Self::UngroupedColumn(sql) => write!(f, "ungrouped column `{}`", sql),
+        }
+    }
+}
+
+impl QueryWrapper {
+    /// Splits an aggregate query into a partial stage to run on every partition
+    /// and a final stage that merges the partial results.
+    ///
+    /// COUNT, SUM, MIN and MAX are computed per partition and re-aggregated with
+    /// SUM, SUM, MIN and MAX respectively; AVG is shipped as a SUM and a COUNT and
+    /// divided at merge time. Anything else that needs to see all rows at once
+    /// (DISTINCT aggregates, window functions, MEDIAN and friends) is reported in
+    /// a [`QueryError::Unsupported`] listing every offending fragment.
+    pub fn decompose(&self) -> Result<DecomposedQuery, QueryError> {
+        let query = match &self.ast {
+            Statement::Query(query) => query.as_ref(),
+            _ => return Err(QueryError::Unsupported(vec![UnsupportedFeature::NotAQuery])),
+        };
+        let select = match query.body.as_ref() {
+            SetExpr::Select(select) => select.as_ref(),
+            other => {
+                return Err(QueryError::Unsupported(vec![
+                    UnsupportedFeature::SetOperation(other.to_string()),
+                ]))
+            }
+        };
+
+        let mut rewriter = Rewriter::new(select);
+        rewriter.check_select(select);
+
+        let mut final_items = Vec::with_capacity(select.projection.len());
+        for item in &select.projection {
+            let (expr, alias) = match item {
+                SelectItem::UnnamedExpr(expr) => (expr, output_name(expr)),
+                SelectItem::ExprWithAlias { expr, alias } => (expr, alias.clone()),
+                SelectItem::Wildcard(_) | SelectItem::QualifiedWildcard(..) => {
+                    rewriter
+                        .unsupported
+                        .push(UnsupportedFeature::Wildcard(item.to_string()));
+                    continue;
+                }
+            };
+            let expr = rewriter.rewrite(expr)?;
+            final_items.push(SelectItem::ExprWithAlias { expr, alias });
+        }
+        let having = match &select.having {
+            Some(having) => Some(rewriter.rewrite(having)?),
+            None => None,
+        };
+        let mut order_by = query.order_by.clone();
+        if let Some(order_by) = &mut order_by {
+            for order in &mut order_by.exprs {
+                order.expr = rewriter.rewrite(&order.expr)?;
+            }
+        }
+
+        if rewriter.partials.is_empty() && rewriter.keys.is_empty() {
+            rewriter.unsupported.push(UnsupportedFeature::NoAggregation);
+        }
+        if !rewriter.unsupported.is_empty() {
+            return Err(QueryError::Unsupported(rewriter.unsupported));
+        }
+
+        let partial_sql = rewriter.partial_query(query, select).to_string();
+
+        let mut final_sql = format!(
+            "SELECT {} FROM {}",
+            display_comma_separated(&final_items),
+            PARTIAL_RESULTS_TABLE
+        );
+        if !rewriter.keys.is_empty() {
+            let keys: Vec<String> = (0..rewriter.keys.len()).map(key_name).collect();
+            final_sql.push_str(&format!(" GROUP BY {}", keys.join(", ")));
+        }
+        if let Some(having) = having {
+            final_sql.push_str(&format!(" HAVING {}", having));
+        }
+        if let Some(order_by) = order_by {
+            final_sql.push_str(&format!(" {}", order_by));
+        }
+        if let Some(limit) = &query.limit {
+            final_sql.push_str(&format!(" LIMIT {}", limit));
+        }
+        if let Some(offset) = &query.offset {
+            final_sql.push_str(&format!(" {}", offset));
+        }
+
+        Ok(DecomposedQuery {
+            partial_sql,
+            final_sql,
+        })
+    }
+}
+
+/// Tracks the group keys and partial aggregates discovered while rewriting the
+/// projection, HAVING and ORDER BY into their final-stage form.
+struct Rewriter {
+    keys: Vec<Expr>,
+    partials: Vec<Expr>,
+    merges: Vec<Expr>,
+    aliases: HashSet<String>,
+    unsupported: Vec<UnsupportedFeature>,
+}
+
+impl Rewriter {
+    fn new(select: &Select) -> Self {
+        let mut rewriter = Self {
+            keys: Vec::new(),
+            partials: Vec::new(),
+            merges: Vec::new(),
+            aliases: HashSet::new(),
+            unsupported: Vec::new(),
+        };
+
+        for item in &select.projection {
+            if let SelectItem::ExprWithAlias { alias, .. } = item {
+                rewriter.aliases.insert(alias.value.clone());
+            }
+        }
+
+        match &select.group_by {
+            GroupByExpr::All(modifiers) => {
+                rewriter.push_modifiers(modifiers);
+                for item in &select.projection {
+                    if let SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } =
+                        item
+                    {
+                        if !contains_aggregate(expr) && !matches!(expr, Expr::Value(_)) {
+                            rewriter.keys.push(expr.clone());
+                        }
+                    }
+                }
+            }
+            GroupByExpr::Expressions(exprs, modifiers) => {
+                rewriter.push_modifiers(modifiers);
+                for expr in exprs {
+                    match expr {
+                        Expr::Rollup(_) | Expr::Cube(_) | Expr::GroupingSets(_) => rewriter
+                            .unsupported
+                            .push(UnsupportedFeature::GroupingModifier(expr.to_string())),
+                        _ => {
+                            let key = resolve_group_expr(expr, &select.projection);
+                            rewriter.keys.push(key);
+                        }
+                    }
+                }
+            }
+        }
+
+        rewriter
+    }
+
+    fn push_modifiers<T: fmt::Display>(&mut self, modifiers: &[T]) {
+        for modifier in modifiers {
+            self.unsupported
+                .push(UnsupportedFeature::GroupingModifier(modifier.to_string()));
+        }
+    }
+
+    fn check_select(&mut self, select: &Select) {
+        if select.distinct.is_some() {
+            self.unsupported.push(UnsupportedFeature::SelectDistinct);
+        }
+        if let Some(qualify) = &select.qualify {
+            self.unsupported
+                .push(UnsupportedFeature::Qualify(qualify.to_string()));
+        }
+    }
+
+    /// Rewrites an expression over the original rows into one over the partial
+    /// results: group keys become key columns and aggregates become merges.
+    fn rewrite(&mut self, expr: &Expr) -> Result<Expr, QueryError> {
+        let mut expr = expr.clone();
+        let _ = expr.visit(self);
+
+        let mut failure = None;
+        let _ = sqlparser::ast::visit_expressions_mut(&mut expr, |expr| {
+            if let Expr::Identifier(ident) = expr {
+                if let Some(index) = ident.value.strip_prefix(MERGE_PREFIX) {
+                    match index.parse::<usize>() {
+                        Ok(index) => *expr = self.merges[index].clone(),
+                        Err(_) => failure = Some(expr.to_string()),
+                    }
+                    return ControlFlow::<()>::Continue(());
+                }
+            }
+            if let Some(column) = ungrouped_column(expr, &self.aliases) {
+                self.unsupported
+                    .push(UnsupportedFeature::UngroupedColumn(column));
+            }
+            ControlFlow::Continue(())
+        });
+        if let Some(fragment) = failure {
+            return Err(QueryError::Other(format!(
+                "Invalid merge placeholder: {}",
+                fragment
+            )));
+        }
+        Ok(expr)
+    }
+
+    /// Registers the partial aggregates backing `func` and returns its merge expression.
+    fn merge_for(&mut self, name: &str, func: &Function) -> Expr {
+        match name {
+            "avg" => {
+                let sum = self.partial(renamed_function(func, "SUM"));
+                let count = self.partial(renamed_function(func, "COUNT"));
+                Expr::BinaryOp {
+                    left: Box::new(call("SUM", sum)),
+                    op: BinaryOperator::Divide,
+                    right: Box::new(call("SUM", count)),
+                }
+            }
+            "min" | "max" => {
+                let partial = self.partial(func.clone());
+                call(&name.to_uppercase(), partial)
+            }
+            // COUNT and SUM both merge by summing the partial values.
+            _ => {
+                let partial = self.partial(func.clone());
+                call("SUM", partial)
+            }
+        }
+    }
+
+    /// Returns the partial column for `func`, reusing an identical aggregate.
+    fn partial(&mut self, func: Function) -> Ident {
+        let expr = Expr::Function(func);
+        let index = match self.partials.iter().position(|p| *p == expr) {
+            Some(index) => index,
+            None => {
+                self.partials.push(expr);
+                self.partials.len() - 1
+            }
+        };
+        Ident::new(format!("{}{}", PARTIAL_PREFIX, index))
+    }
+
+    fn partial_query(&self, query: &SqlQuery, select: &Select) -> SqlQuery {
+        let mut projection = Vec::with_capacity(self.keys.len() + self.partials.len());
+        for (index, key) in self.keys.iter().enumerate() {
+            projection.push(SelectItem::ExprWithAlias {
+                expr: key.clone(),
+                alias: Ident::new(key_name(index)),
+            });
+        }
+        for (index, partial) in self.partials.iter().enumerate() {
+            projection.push(SelectItem::ExprWithAlias {
+                expr: partial.clone(),
+                alias: Ident::new(format!("{}{}", PARTIAL_PREFIX, index)),
+            });
+        }
+
+        let mut partial_select = select.clone();
+        partial_select.projection = projection;
+        partial_select.group_by = GroupByExpr::Expressions(self.keys.clone(), vec![]);
+        partial_select.having = None;
+
+        let mut partial = query.clone();
+        partial.body = Box::new(SetExpr::Select(Box::new(partial_select)));
+        partial.order_by = None;
+        partial.limit = None;
+        partial.limit_by = vec![];
+        partial.offset = None;
+        partial.fetch = None;
+        partial
+    }
+}
+
+impl VisitorMut for Rewriter {
+    type Break = ();
+
+    fn pre_visit_expr(&mut self, expr: &mut Expr) -> ControlFlow<()> {
+        if let Some(index) = self.keys.iter().position(|key| key == expr) {
+            *expr = Expr::Identifier(Ident::new(key_name(index)));
+            return ControlFlow::Continue(());
+        }
+
+        let unsupported = match expr {
+            Expr::Subquery(_) | Expr::Exists { .. } | Expr::InSubquery { .. } => {
+                Some(UnsupportedFeature::Subquery(expr.to_string()))
+            }
+            Expr::Function(func) => {
+                let name = function_name(func);
+                if func.over.is_some() {
+                    Some(UnsupportedFeature::WindowFunction(func.to_string()))
+                } else if HOLISTIC_AGGREGATES.contains(&name.as_str()) {
+                    Some(UnsupportedFeature::HolisticAggregate(func.to_string()))
+                } else if is_decomposable(&name) && is_distinct(func) {
+                    Some(UnsupportedFeature::DistinctAggregate(func.to_string()))
+                } else if is_decomposable(&name) {
+                    let merge = self.merge_for(&name, func);
+                    self.merges.push(merge);
+                    let placeholder = format!("{}{}", MERGE_PREFIX, self.merges.len() - 1);
+                    *expr = Expr::Identifier(Ident::new(placeholder));
+                    None
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        // Blank out the offending node so its inner columns aren't also
+        // reported as ungrouped.
+        if let Some(feature) = unsupported {
+            self.unsupported.push(feature);
+            *expr = Expr::Value(Value::Null);
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+fn key_name(index: usize) -> String {
+    format!("{}{}", KEY_PREFIX, index)
+}
+
+fn is_decomposable(name: &str) -> bool {
+    matches!(name, "count" | "sum" | "min" | "max" | "avg")
+}
+
+pub(crate) fn function_name(func: &Function) -> String {
+    func.name
+        .0
+        .last()
+        .map(|ident| ident.value.to_lowercase())
+        .unwrap_or_default()
+}
+
+pub(crate) fn is_distinct(func: &Function) -> bool {
+    matches!(
+        &func.args,
+        FunctionArguments::List(list)
+            if list.duplicate_treatment == Some(DuplicateTreatment::Distinct)
+    )
+}
+
+/// `func` called by another name, e.g. `AVG(x)` as `SUM(x)`.
+pub fn renamed_function(func: &Function, name: &str) -> Function {
+    let mut func = func.clone();
+    func.name = ObjectName(vec![Ident::new(name)]);
+    func
+}
+
+/// A plain one-argument call such as `SUM(__pond_agg_0)`.
+fn call(name: &str, column: Ident) -> Expr {
+    Expr::Function(Function {
+        name: ObjectName(vec![Ident::new(name)]),
+        parameters: FunctionArguments::None,
+        args: FunctionArguments::List(FunctionArgumentList {
+            duplicate_treatment: None,
+            args: vec![FunctionArg::Unnamed(FunctionArgExpr::Expr(
+                Expr::Identifier(column),
+            ))],
+            clauses: vec![],
+        }),
+        filter: None,
+        null_treatment: None,
+        over: None,
+        within_group: vec![],
+    })
+}
+
+/// A plain (non-window) call to an aggregate pond knows about.
+pub(crate) fn is_aggregate_call(func: &Function) -> bool {
+    let name = function_name(func);
+    func.over.is_none() && (is_decomposable(&name) || HOLISTIC_AGGREGATES.contains(&name.as_str()))
+}
+
+/// Whether `expr` calls an aggregate of its own query. Aggregates inside
+/// subqueries belong to those subqueries and don't count.
+pub(crate) fn contains_aggregate(expr: &Expr) -> bool {
+    sqlparser::ast::Visit::visit(expr, &mut OwnAggregateFinder::default()).is_break()
+}
+
+#[derive(Default)]
+struct OwnAggregateFinder {
+    /// How many subqueries deep the visitor is.
+    depth: usize,
+}
+
+impl Visitor for OwnAggregateFinder {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, _query: &SqlQuery) -> ControlFlow<()> {
+        self.depth += 1;
+        ControlFlow::Continue(())
+    }
+
+    fn post_visit_query(&mut self, _query: &SqlQuery) -> ControlFlow<()> {
+        self.depth -= 1;
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<()> {
+        match expr {
+            Expr::Function(func) if self.depth == 0 && is_aggregate_call(func) => {
+                ControlFlow::Break(())
+            }
+            _ => ControlFlow::Continue(()),
+        }
+    }
+}
+
+/// Column references left over after rewriting are neither group keys nor
+/// inside an aggregate, so the merge stage has no way to compute them.
+fn ungrouped_column(expr: &Expr, aliases: &HashSet<String>) -> Option<String> {
+    match expr {
+        Expr::Identifier(ident)
+            if !ident.value.starts_with(KEY_PREFIX)
+                && !ident.value.starts_with(PARTIAL_PREFIX)
+                && !aliases.contains(&ident.value) =>
+        {
+            Some(ident.value.clone())
+        }
+        Expr::CompoundIdentifier(idents) => Some(
+            idents
+                .iter()
+                .map(|i| i.value.as_str())
+                .collect::<Vec<_>>()
+                .join("."),
+        ),
+        _ => None,
+    }
+}
+
+/// Resolves `GROUP BY 1` and `GROUP BY alias` to the projection expression they name.
+fn resolve_group_expr(expr: &Expr, projection: &[SelectItem]) -> Expr {
+    match expr {
+        Expr::Value(Value::Number(n, _)) => {
+            let item = n
+                .parse::<usize>()
+                .ok()
+                .and_then(|position| projection.get(position.checked_sub(1)?));
+            match item {
+                Some(SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. }) => {
+                    expr.clone()
+                }
+                _ => expr.clone(),
+            }
+        }
+        Expr::Identifier(ident) => projection
+            .iter()
+            .find_map(|item| match item {
+                SelectItem::ExprWithAlias { expr, alias } if alias.value == ident.value => {
+                    Some(expr.clone())
+                }
+                _ => None,
+            })
+            .unwrap_or_else(|| expr.clone()),
+        _ => expr.clone(),
+    }
+}
+
+/// Name the final stage gives an unaliased projection item so its output column
+/// keeps a recognisable name: bare columns keep their name, anything else is
+/// named after its SQL text.
+fn output_name(expr: &Expr) -> Ident {
+    match expr {
+        Expr::Identifier(ident) => ident.clone(),
+        Expr::CompoundIdentifier(idents) => idents
+            .last()
+            .cloned()
+            .unwrap_or_else(|| Ident::new(expr.to_string())),
+        _ => Ident::with_quote('"', expr.to_string()),
+    }
+}
+
+fn display_comma_separated<T: fmt::Display>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use duckdb::types::Value as DuckValue;
+    use duckdb::Connection;
+
+    const PARTITIONS: &[&str] = &[
+        "(1, 'east', 10, 1.5), (2, 'west', 20, 2.5), (3, 'east', 30, NULL)",
+        "(4, 'west', 40, 4.0), (5, 'north', 50, 5.5)",
+        "(6, 'east', 60, 6.0), (7, 'west', NULL, 7.5), (8, 'north', 80, 8.0)",
+    ];
+
+    fn rows(conn: &Connection, sql: &str) -> Vec<Vec<String>> {
+        let mut stmt = conn.prepare(sql).unwrap();
+        let mut rows = stmt.query([]).unwrap();
+        let mut result = Vec::new();
+        while let Some(row) = rows.next().unwrap() {
+            let mut values = Vec::new();
+            let mut index = 0;
+            while let Ok(value) = row.get::<_, DuckValue>(index) {
+                values.push(match value {
+                    DuckValue::Null => "NULL".to_string(),
+                    DuckValue::Int(v) => v.to_string(),
+                    DuckValue::BigInt(v) => v.to_string(),
+                    DuckValue::HugeInt(v) => v.to_string(),
+                    DuckValue::Double(v) => format!("{:.6}", v),
+                    DuckValue::Text(v) => v,
+                    other => format!("{:?}", other),
+                });
+                index += 1;
+            }
+            result.push(values);
+        }
+        result
+    }
+
+    /// Runs `query` single-node over all partitions and distributed through
+    /// `decompose()`, returning both result sets.
+    fn run_both(query: &str) -> (Vec<Vec<String>>, Vec<Vec<String>>) {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE all_sales (id INTEGER, region VARCHAR, amount INTEGER, price DOUBLE)",
+        )
+        .unwrap();
+        for (index, values) in PARTITIONS.iter().enumerate() {
+            conn.execute_batch(&format!(
+                "CREATE TABLE sales_{index} (id INTEGER, region VARCHAR, amount INTEGER, price DOUBLE);
+                 INSERT INTO sales_{index} VALUES {values};
+                 INSERT INTO all_sales VALUES {values};"
+            ))
+            .unwrap();
+        }
+
+        conn.execute_batch("CREATE VIEW sales AS SELECT * FROM all_sales")
+            .unwrap();
+        let expected = rows(&conn, query);
+
+        let decomposed = QueryWrapper::parse(query).unwrap().decompose().unwrap();
+        for index in 0..PARTITIONS.len() {
+            conn.execute_batch(&format!(
+                "CREATE OR REPLACE VIEW sales AS SELECT * FROM sales_{index}"
+            ))
+            .unwrap();
+            let load = if index == 0 {
+                format!("CREATE TABLE {} AS ", PARTIAL_RESULTS_TABLE)
+            } else {
+                format!("INSERT INTO {} ", PARTIAL_RESULTS_TABLE)
+            };
+            conn.execute_batch(&format!("{}{}", load, decomposed.partial_sql))
+                .unwrap();
+        }
+        let actual = rows(&conn, &decomposed.final_sql);
+
+        (expected, actual)
+    }
+
+    #[test]
+    fn test_decompose_count_group_by() -> Result<(), QueryError> {
+        let query = "SELECT region, COUNT(*) FROM sales WHERE amount > 10 GROUP BY region";
+        let decomposed = QueryWrapper::parse(query)?.decompose()?;
+        assert_eq!(
+            decomposed.partial_sql,
+            "SELECT region AS __pond_key_0, COUNT(*) AS __pond_agg_0 FROM sales WHERE amount > 10 GROUP BY region"
+        );
+        assert_eq!(
+            decomposed.final_sql,
+            "SELECT __pond_key_0 AS region, SUM(__pond_agg_0) AS \"COUNT(*)\" FROM partial_results GROUP BY __pond_key_0"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_decompose_avg_ships_sum_and_count() -> Result<(), QueryError> {
+        let query = "SELECT region, AVG(price) AS avg_price FROM sales GROUP BY region";
+        let decomposed = QueryWrapper::parse(query)?.decompose()?;
+        assert_eq!(
+            decomposed.partial_sql,
+            "SELECT region AS __pond_key_0, SUM(price) AS __pond_agg_0, COUNT(price) AS __pond_agg_1 FROM sales GROUP BY region"
+        );
+        assert!(decomposed
+            .final_sql
+            .contains("SUM(__pond_agg_0) / SUM(__pond_agg_1) AS avg_price"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decompose_keeps_final_clauses_out_of_partial() -> Result<(), QueryError> {
+        let query = "SELECT region, SUM(amount) AS total FROM sales GROUP BY region HAVING SUM(amount) > 50 ORDER BY total DESC LIMIT 2";
+        let decomposed = QueryWrapper::parse(query)?.decompose()?;
+        assert_eq!(
+            decomposed.partial_sql,
+            "SELECT region AS __pond_key_0, SUM(amount) AS __pond_agg_0 FROM sales GROUP BY region"
+        );
+        assert_eq!(
+            decomposed.final_sql,
+            "SELECT __pond_key_0 AS region, SUM(__pond_agg_0) AS total FROM partial_results GROUP BY __pond_key_0 HAVING SUM(__pond_agg_0) > 50 ORDER BY total DESC LIMIT 2"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_decompose_matches_single_node() {
+        let queries = [
+            "SELECT region, COUNT(*) AS n FROM sales GROUP BY region ORDER BY region",
+            "SELECT region, COUNT(amount), SUM(amount), MIN(price), MAX(price) FROM sales GROUP BY region ORDER BY region",
+            "SELECT region, AVG(price) AS avg_price, AVG(amount) FROM sales GROUP BY region ORDER BY region",
+            "SELECT COUNT(*), SUM(amount), AVG(price) FROM sales WHERE id > 2",
+            "SELECT region, SUM(amount) AS total FROM sales GROUP BY region HAVING COUNT(*) > 2 ORDER BY total DESC",
+            "SELECT region, SUM(amount) * 2 AS doubled FROM sales GROUP BY 1 ORDER BY doubled DESC LIMIT 2",
+            "SELECT upper(region) AS r, MAX(amount) - MIN(amount) AS spread FROM sales GROUP BY r ORDER BY r",
+        ];
+        for query in queries {
+            let (expected, actual) = run_both(query);
+            assert_eq!(expected, actual, "results differ for {}", query);
+        }
+    }
+
+    #[test]
+    fn test_decompose_rejects_unsupported_features() {
+        let query = "SELECT region, COUNT(DISTINCT id), MEDIAN(amount), ROW_NUMBER() OVER (ORDER BY region) FROM sales GROUP BY region";
+        match QueryWrapper::parse(query).unwrap().decompose() {
+            Err(QueryError::Unsupported(features)) => assert_eq!(
+                features,
+                vec![
+                    UnsupportedFeature::DistinctAggregate("COUNT(DISTINCT id)".to_string()),
+                    UnsupportedFeature::HolisticAggregate("MEDIAN(amount)".to_string()),
+                    UnsupportedFeature::WindowFunction(
+                        "ROW_NUMBER() OVER (ORDER BY region)".to_string()
+                    ),
+                ]
+            ),
+            other => panic!("expected unsupported features, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decompose_rejects_non_aggregate_query() {
+        let parsed = QueryWrapper::parse("SELECT id FROM sales").unwrap();
+        assert!(matches!(
+            parsed.decompose(),
+            Err(QueryError::Unsupported(features)) if features.contains(&UnsupportedFeature::UngroupedColumn("id".to_string()))
+        ));
+    }
+}
@@ -0,0 +1,849 @@
+use crate::decompose::{contains_aggregate, function_name, is_distinct, HOLISTIC_AGGREGATES};
+use crate::{is_generator, QueryError, QueryWrapper, SortRequirement, UnsupportedFeature};
+use sqlparser::ast::{
+    BinaryOperator, Expr, Function, GroupByExpr, Join, JoinConstraint, JoinOperator, Query, Select,
+    SelectItem, SetExpr, SetOperator, SetQuantifier, Statement, TableFactor, Visit, Visitor,
+    WindowType,
+};
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::ControlFlow;
+
+/// Whether a query can be fanned out to workers, and how.
+pub type Distributability = Result<Strategy, Vec<Blocker>>;
+
+/// How a distributable query is split across workers.
+///
+/// Row-returning strategies carry the outer ORDER BY as a
+/// [`SortRequirement`]. A [`TopK`](SortRequirement::TopK) is cheap to merge
+/// from each partition's own top rows. A
+/// [`FullSort`](SortRequirement::FullSort), an ORDER BY without a LIMIT, is
+/// allowed rather than blocked but is expensive, since the planner sorts
+/// every row; weigh it against
+/// [`CostEstimate::estimated_rows`](crate::CostEstimate::estimated_rows)
+/// before running it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Strategy {
+    /// Every worker runs the query over its partition and the planner
+    /// concatenates the rows, merging them by `sort`.
+    ParallelScan { sort: SortRequirement },
+    /// Workers compute partial aggregates that the planner merges; see
+    /// [`QueryWrapper::decompose`].
+    PartialAggregate,
+    /// The first relation is partitioned and every joined relation is shipped
+    /// whole to each worker. `aggregate` is set when the joined rows are then
+    /// partially aggregated, in which case the final aggregation orders them
+    /// and `sort` is [`SortRequirement::None`].
+    BroadcastJoin {
+        aggregate: bool,
+        sort: SortRequirement,
+    },
+}
+
+/// A construct that keeps a query on a single node, with the offending SQL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Blocker {
+    NotAQuery,
+    SetOperation(String),
+    RecursiveCte(String),
+    /// A window function not partitioned by every partitioning column.
+    WindowFunction(String),
+    /// A QUALIFY filter over such a window function.
+    Qualify(String),
+    DistinctAggregate(String),
+    HolisticAggregate(String),
+    /// A subquery referencing a column of the enclosing query.
+    CorrelatedSubquery(String),
+    /// An uncorrelated subquery, which each worker would evaluate against its
+    /// own partition instead of the whole table.
+    Subquery(String),
+    /// A join condition that isn't a conjunction of equalities, which would
+    /// turn every worker into a nested loop over the broadcast relation.
+    NonEquiJoin(String),
+    /// A RIGHT or FULL join, or a right semi/anti join, whose unmatched
+    /// broadcast rows would be emitted once per worker.
+    PreservesBroadcastSide(String),
+    /// A ROLLUP, CUBE or GROUPING SETS in the GROUP BY. Each grouping set
+    /// would need its own re-aggregation of the partial results, which the
+    /// final stage doesn't do yet.
+    GroupingModifier(String),
+    /// A PIVOT, whose aggregates have to be finalized on one node. UNPIVOT
+    /// reshapes each row on its own and doesn't block.
+    Pivot(String),
+    /// A table function such as `range` generating its rows, which every
+    /// worker would generate again.
+    Generator(String),
+    /// A query with no FROM clause, such as `SELECT now()`, which every
+    /// worker would answer again.
+    NoFrom,
+    /// The outer SELECT's DISTINCT or DISTINCT ON, whose duplicates across
+    /// partitions would survive the merge.
+    SelectDistinct(String),
+    /// A derived table or CTE that aggregates, removes duplicates or limits
+    /// its rows, which each worker would do over its own partition alone.
+    DerivedTable(String),
+    /// A LIMIT, OFFSET or FETCH that isn't a
+    /// [`TopK`](SortRequirement::TopK), i.e. without an ORDER BY or with a
+    /// bound that isn't a literal number. Every worker would apply it to its
+    /// own partition.
+    RowLimit(String),
+    /// Anything else [`QueryWrapper::decompose`] refuses to split.
+    Aggregation(UnsupportedFeature),
+}
+
+impl fmt::Display for Blocker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotAQuery => write!(f, "statement is not a query"),
+            Self::SetOperation(sql) => write!(f, "set operation `{}`", sql),
+            Self::RecursiveCte(sql) => write!(f, "recursive CTE `{}`", sql),
+            Self::WindowFunction(sql) => write!(f, "window function `{}`", sql),
+            Self::Qualify(sql) => write!(f, "QUALIFY `{}`", sql),
+            Self::DistinctAggregate(sql) => write!(f, "DISTINCT aggregate `{}`", sql),
+            Self::HolisticAggregate(sql) => write!(f, "aggregate `{}`", sql),
+            Self::CorrelatedSubquery(sql) => write!(f, "correlated subquery `{}`", sql),
+            Self::Subquery(sql) => write!(f, "subquery `{}`", sql),
+            Self::NonEquiJoin(sql) => write!(f, "non-equi join `{}`", sql),
+            Self::PreservesBroadcastSide(sql) => {
+                write!(f, "join `{}` preserves the broadcast side", sql)
+            }
+            Self::GroupingModifier(sql) => write!(
+                f,
+                "`{}` needs a re-aggregation per grouping set in the final stage",
+                sql
+            ),
+            Self::Pivot(sql) => write!(f, "PIVOT `{}`", sql),
+            Self::Generator(sql) => write!(f, "generator `{}`", sql),
+            Self::NoFrom => write!(f, "query reads no relation"),
+            Self::SelectDistinct(sql) => write!(f, "SELECT {}", sql),
+            Self::DerivedTable(sql) => write!(f, "derived table `{}`", sql),
+            Self::RowLimit(sql) => write!(f, "`{}` without a top-k merge", sql),
+            Self::Aggregation(feature) => feature.fmt(f),
+        }
+    }
+}
+
+impl QueryWrapper {
+    /// Decides whether the query can run across worker partitions.
+    ///
+    /// Returns the [`Strategy`] to use, or every [`Blocker`] found so the caller
+    /// can explain why the query has to stay on a single node. Nothing is
+    /// known about how the data is partitioned, so any window function or
+    /// QUALIFY keeps the query on one node.
+    pub fn distributability(&self) -> Distributability {
+        self.distributability_partitioned_by(&[])
+    }
+
+    /// Like [`distributability`](Self::distributability), for data
+    /// partitioned so that rows sharing values of `partition_columns` sit in
+    /// the same partition, e.g. the Hive keys a prefix is split on. Window
+    /// functions, in QUALIFY or elsewhere, are then computed per partition
+    /// when their PARTITION BY names every one of those columns.
+    pub fn distributability_partitioned_by(&self, partition_columns: &[&str]) -> Distributability {
+        let query = match &self.ast {
+            Statement::Query(query) => query.as_ref(),
+            _ => return Err(vec![Blocker::NotAQuery]),
+        };
+
+        let mut blockers = Vec::new();
+        if !self.has_from() {
+            blockers.push(Blocker::NoFrom);
+        }
+        if let Some(with) = query.with.as_ref().filter(|with| with.recursive) {
+            blockers.push(Blocker::RecursiveCte(with.to_string()));
+        }
+        blockers.extend(local_relations(&self.ast));
+
+        // Window functions QUALIFY filters on are reported as the QUALIFY.
+        let mut qualified = HashSet::new();
+        if let SetExpr::Select(select) = query.body.as_ref() {
+            if let Some(qualify) = &select.qualify {
+                let _ = sqlparser::ast::visit_expressions(qualify, |expr| {
+                    if let Expr::Function(func) = expr {
+                        if func.over.is_some() && !is_partitioned_by(func, partition_columns) {
+                            qualified.insert(func.to_string());
+                        }
+                    }
+                    ControlFlow::<()>::Continue(())
+                });
+                if !qualified.is_empty() {
+                    blockers.push(Blocker::Qualify(qualify.to_string()));
+                }
+            }
+        }
+        self.check_expressions(partition_columns, &qualified, &mut blockers);
+
+        let select = match query.body.as_ref() {
+            SetExpr::Select(select) => select.as_ref(),
+            other => {
+                blockers.push(Blocker::SetOperation(other.to_string()));
+                return Err(blockers);
+            }
+        };
+
+        let joined = check_joins(select, &mut blockers);
+        let aggregate = is_aggregate_select(select);
+        if let Some(distinct) = &select.distinct {
+            blockers.push(Blocker::SelectDistinct(distinct.to_string()));
+        }
+        if let GroupByExpr::Expressions(exprs, _) = &select.group_by {
+            for expr in exprs {
+                if let Expr::Rollup(_) | Expr::Cube(_) | Expr::GroupingSets(_) = expr {
+                    blockers.push(Blocker::GroupingModifier(expr.to_string()));
+                }
+            }
+        }
+
+        // The final aggregation applies an aggregate query's LIMIT to the
+        // merged groups.
+        let sort = if aggregate {
+            SortRequirement::None
+        } else {
+            self.sort_requirement()
+        };
+        if !aggregate && !matches!(sort, SortRequirement::TopK { .. }) {
+            if let Some(bound) = row_limit(query) {
+                blockers.push(Blocker::RowLimit(bound));
+            }
+        }
+
+        // Only ask decompose() once the cheaper checks pass, so a window
+        // function isn't reported twice.
+        if aggregate && blockers.is_empty() {
+            if let Err(QueryError::Unsupported(features)) = self.decompose() {
+                blockers.extend(features.into_iter().map(Blocker::Aggregation));
+            }
+        }
+
+        if !blockers.is_empty() {
+            return Err(blockers);
+        }
+        Ok(match (joined, aggregate) {
+            (true, aggregate) => Strategy::BroadcastJoin { aggregate, sort },
+            (false, true) => Strategy::PartialAggregate,
+            (false, false) => Strategy::ParallelScan { sort },
+        })
+    }
+
+    fn check_expressions(
+        &self,
+        partition_columns: &[&str],
+        qualified: &HashSet<String>,
+        blockers: &mut Vec<Blocker>,
+    ) {
+        let outer = relation_names(&self.ast);
+        let _ = sqlparser::ast::visit_expressions(&self.ast, |expr| {
+            match expr {
+                Expr::Function(func) => {
+                    let name = function_name(func);
+                    if func.over.is_some() {
+                        let sql = func.to_string();
+                        if !is_partitioned_by(func, partition_columns) && !qualified.contains(&sql)
+                        {
+                            blockers.push(Blocker::WindowFunction(sql));
+                        }
+                    } else if is_distinct(func) {
+                        blockers.push(Blocker::DistinctAggregate(func.to_string()));
+                    } else if HOLISTIC_AGGREGATES.contains(&name.as_str()) {
+                        blockers.push(Blocker::HolisticAggregate(func.to_string()));
+                    }
+                }
+                Expr::Subquery(subquery)
+                | Expr::Exists { subquery, .. }
+                | Expr::InSubquery { subquery, .. } => {
+                    if is_correlated(subquery.as_ref(), &outer) {
+                        blockers.push(Blocker::CorrelatedSubquery(subquery.to_string()));
+                    } else {
+                        blockers.push(Blocker::Subquery(subquery.to_string()));
+                    }
+                }
+                _ => {}
+            }
+            ControlFlow::<()>::Continue(())
+        });
+    }
+}
+
+/// Whether `func` is a window function whose PARTITION BY names every one of
+/// `partition_columns`, so each of its partitions lies within one data
+/// partition. Never true without partitioning columns.
+fn is_partitioned_by(func: &Function, partition_columns: &[&str]) -> bool {
+    let Some(WindowType::WindowSpec(spec)) = &func.over else {
+        return false;
+    };
+    !partition_columns.is_empty()
+        && partition_columns.iter().all(|column| {
+            spec.partition_by.iter().any(|expr| match expr {
+                Expr::Identifier(ident) => ident.value.eq_ignore_ascii_case(column),
+                Expr::CompoundIdentifier(idents) => idents
+                    .last()
+                    .is_some_and(|ident| ident.value.eq_ignore_ascii_case(column)),
+                _ => false,
+            })
+        })
+}
+
+/// Records join blockers and returns whether the query joins at all.
+fn check_joins(select: &Select, blockers: &mut Vec<Blocker>) -> bool {
+    // `FROM a, b` is a cross join with the condition buried in WHERE.
+    for relation in select.from.iter().skip(1) {
+        blockers.push(Blocker::NonEquiJoin(relation.to_string()));
+    }
+
+    let mut joined = select.from.len() > 1;
+    for join in select.from.iter().flat_map(|from| &from.joins) {
+        joined = true;
+        let constraint = match &join.join_operator {
+            JoinOperator::Inner(constraint)
+            | JoinOperator::LeftOuter(constraint)
+            | JoinOperator::LeftSemi(constraint)
+            | JoinOperator::LeftAnti(constraint) => constraint,
+            JoinOperator::RightOuter(constraint)
+            | JoinOperator::FullOuter(constraint)
+            | JoinOperator::RightSemi(constraint)
+            | JoinOperator::RightAnti(constraint) => {
+                blockers.push(Blocker::PreservesBroadcastSide(join_sql(join)));
+                constraint
+            }
+            JoinOperator::CrossJoin | JoinOperator::AsOf { .. } => {
+                blockers.push(Blocker::NonEquiJoin(join_sql(join)));
+                continue;
+            }
+            // Lateral joins are evaluated per left row and need no condition.
+            JoinOperator::CrossApply | JoinOperator::OuterApply => continue,
+        };
+        let equi = match constraint {
+            JoinConstraint::On(expr) => is_equi_condition(expr),
+            JoinConstraint::Using(_) | JoinConstraint::Natural => true,
+            JoinConstraint::None => false,
+        };
+        if !equi {
+            blockers.push(Blocker::NonEquiJoin(join_sql(join)));
+        }
+    }
+    joined
+}
+
+/// `Join` renders with a leading space so it can follow the relation it joins.
+fn join_sql(join: &Join) -> String {
+    join.to_string().trim_start().to_string()
+}
+
+fn is_equi_condition(expr: &Expr) -> bool {
+    match expr {
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => is_equi_condition(left) && is_equi_condition(right),
+        Expr::BinaryOp {
+            op: BinaryOperator::Eq,
+            ..
+        } => true,
+        Expr::Nested(expr) => is_equi_condition(expr),
+        _ => false,
+    }
+}
+
+/// Whether `select` aggregates: it groups, has a HAVING, or its SELECT list
+/// calls an aggregate outside any subquery.
+pub(crate) fn is_aggregate_select(select: &Select) -> bool {
+    let grouped = match &select.group_by {
+        GroupByExpr::All(_) => true,
+        GroupByExpr::Expressions(exprs, _) => !exprs.is_empty(),
+    };
+    grouped
+        || select.having.is_some()
+        || select.projection.iter().any(|item| match item {
+            SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                contains_aggregate(expr)
+            }
+            _ => false,
+        })
+}
+
+/// A subquery is correlated when it qualifies a column with a relation that
+/// only the enclosing query defines.
+fn is_correlated<V: Visit>(subquery: &V, outer: &HashSet<String>) -> bool {
+    let inner = relation_names(subquery);
+    sqlparser::ast::visit_expressions(subquery, |expr| {
+        if let Expr::CompoundIdentifier(idents) = expr {
+            if idents.len() >= 2 {
+                let qualifier = idents[idents.len() - 2].value.to_lowercase();
+                if !inner.contains(&qualifier) && outer.contains(&qualifier) {
+                    return ControlFlow::Break(());
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    })
+    .is_break()
+}
+
+/// Table names and aliases a column could be qualified with anywhere in `node`.
+fn relation_names<V: Visit>(node: &V) -> HashSet<String> {
+    let mut names = RelationNames::default();
+    let _ = node.visit(&mut names);
+    names.0
+}
+
+#[derive(Default)]
+struct RelationNames(HashSet<String>);
+
+impl Visitor for RelationNames {
+    type Break = ();
+
+    fn pre_visit_table_factor(&mut self, table_factor: &TableFactor) -> ControlFlow<()> {
+        let (name, alias) = match table_factor {
+            TableFactor::Table { name, alias, .. } => (name.0.last(), alias),
+            TableFactor::Derived { alias, .. } => (None, alias),
+            _ => return ControlFlow::Continue(()),
+        };
+        for ident in name
+            .into_iter()
+            .chain(alias.iter().map(|alias| &alias.name))
+        {
+            self.0.insert(ident.value.to_lowercase());
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// Every PIVOT and generator anywhere in `node`, which can only be
+/// evaluated on one node.
+/// The query's LIMIT, OFFSET and FETCH as written, or `None` without any.
+fn row_limit(query: &Query) -> Option<String> {
+    let bounds: Vec<String> = query
+        .limit
+        .iter()
+        .map(|limit| format!("LIMIT {}", limit))
+        .chain(query.offset.iter().map(ToString::to_string))
+        .chain(query.fetch.iter().map(ToString::to_string))
+        .collect();
+    (!bounds.is_empty()).then(|| bounds.join(" "))
+}
+
+/// Whether `query` needs all of its input rows at once: it aggregates,
+/// removes duplicates or keeps only some of its rows.
+fn combines_rows(query: &Query) -> bool {
+    query.limit.is_some()
+        || query.offset.is_some()
+        || query.fetch.is_some()
+        || body_combines_rows(&query.body)
+}
+
+fn body_combines_rows(body: &SetExpr) -> bool {
+    match body {
+        SetExpr::Select(select) => select.distinct.is_some() || is_aggregate_select(select),
+        SetExpr::Query(query) => combines_rows(query),
+        // Only UNION ALL keeps every row of both sides as they are.
+        SetExpr::SetOperation {
+            op,
+            set_quantifier,
+            left,
+            right,
+        } => {
+            !matches!(
+                (op, set_quantifier),
+                (
+                    SetOperator::Union,
+                    SetQuantifier::All | SetQuantifier::AllByName
+                )
+            ) || body_combines_rows(left)
+                || body_combines_rows(right)
+        }
+        _ => false,
+    }
+}
+
+fn local_relations<V: Visit>(node: &V) -> Vec<Blocker> {
+    let mut relations = LocalRelations::default();
+    let _ = node.visit(&mut relations);
+    relations.0
+}
+
+#[derive(Default)]
+struct LocalRelations(Vec<Blocker>);
+
+impl Visitor for LocalRelations {
+    type Break = ();
+
+    fn pre_visit_table_factor(&mut self, table_factor: &TableFactor) -> ControlFlow<()> {
+        match table_factor {
+            TableFactor::Pivot { .. } => self.0.push(Blocker::Pivot(table_factor.to_string())),
+            TableFactor::Table {
+                name,
+                args: Some(_),
+                ..
+            } if is_generator(&name.to_string()) => {
+                self.0.push(Blocker::Generator(table_factor.to_string()))
+            }
+            TableFactor::Derived { subquery, .. } if combines_rows(subquery) => {
+                self.0.push(Blocker::DerivedTable(table_factor.to_string()))
+            }
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<()> {
+        // A recursive CTE is already reported as a whole.
+        if let Some(with) = query.with.as_ref().filter(|with| !with.recursive) {
+            for cte in &with.cte_tables {
+                if combines_rows(&cte.query) {
+                    self.0.push(Blocker::DerivedTable(cte.to_string()));
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn distributability(sql: &str) -> Distributability {
+        QueryWrapper::parse(sql).unwrap().distributability()
+    }
+
+    const SCAN: Strategy = Strategy::ParallelScan {
+        sort: SortRequirement::None,
+    };
+
+    fn blockers(sql: &str) -> Vec<Blocker> {
+        distributability(sql).expect_err("query should not be distributable")
+    }
+
+    #[test]
+    fn test_distributability_strategies() {
+        assert_eq!(
+            distributability("SELECT id, amount FROM sales WHERE amount > 10"),
+            Ok(SCAN)
+        );
+        assert_eq!(
+            distributability("SELECT region, SUM(amount) FROM sales GROUP BY region"),
+            Ok(Strategy::PartialAggregate)
+        );
+        assert_eq!(
+            distributability(
+                "SELECT s.id, r.name FROM sales s JOIN regions r ON s.region = r.code"
+            ),
+            Ok(Strategy::BroadcastJoin {
+                aggregate: false,
+                sort: SortRequirement::None,
+            })
+        );
+        assert_eq!(
+            distributability(
+                "SELECT r.name, COUNT(*) FROM sales s LEFT JOIN regions r USING (region) \
+                 GROUP BY r.name"
+            ),
+            Ok(Strategy::BroadcastJoin {
+                aggregate: true,
+                sort: SortRequirement::None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_distributability_of_sorts() {
+        assert_eq!(
+            distributability("SELECT id FROM sales ORDER BY amount DESC LIMIT 5"),
+            Ok(Strategy::ParallelScan {
+                sort: SortRequirement::TopK {
+                    k: 5,
+                    keys: vec![("amount".to_string(), false)],
+                },
+            })
+        );
+        assert_eq!(
+            distributability("SELECT id FROM sales ORDER BY amount"),
+            Ok(Strategy::ParallelScan {
+                sort: SortRequirement::FullSort {
+                    keys: vec![("amount".to_string(), true)],
+                },
+            })
+        );
+        assert_eq!(
+            blockers("SELECT id FROM sales LIMIT 5"),
+            [Blocker::RowLimit("LIMIT 5".to_string())]
+        );
+        assert_eq!(
+            distributability(
+                "SELECT s.id FROM sales s JOIN regions r ON s.region = r.code ORDER BY r.name"
+            ),
+            Ok(Strategy::BroadcastJoin {
+                aggregate: false,
+                sort: SortRequirement::FullSort {
+                    keys: vec![("name".to_string(), true)],
+                },
+            })
+        );
+        // The final aggregation orders the merged groups.
+        assert_eq!(
+            distributability("SELECT region, SUM(amount) FROM sales GROUP BY region ORDER BY 2"),
+            Ok(Strategy::PartialAggregate)
+        );
+    }
+
+    #[test]
+    fn test_distributability_blockers() {
+        let cases = [
+            ("INSERT INTO sales VALUES (1)", Blocker::NotAQuery),
+            (
+                "SELECT id FROM a UNION SELECT id FROM b",
+                Blocker::SetOperation("SELECT id FROM a UNION SELECT id FROM b".to_string()),
+            ),
+            (
+                "WITH RECURSIVE n AS (SELECT 1 AS x UNION ALL SELECT x + 1 FROM n WHERE x < 3) \
+                 SELECT x FROM n ORDER BY x LIMIT 10",
+                Blocker::RecursiveCte(
+                    "WITH RECURSIVE n AS (SELECT 1 AS x UNION ALL SELECT x + 1 FROM n WHERE x < 3)"
+                        .to_string(),
+                ),
+            ),
+            (
+                "SELECT id, ROW_NUMBER() OVER (ORDER BY id) FROM sales",
+                Blocker::WindowFunction("ROW_NUMBER() OVER (ORDER BY id)".to_string()),
+            ),
+            (
+                "SELECT COUNT(DISTINCT region) FROM sales",
+                Blocker::DistinctAggregate("COUNT(DISTINCT region)".to_string()),
+            ),
+            (
+                "SELECT MEDIAN(amount) FROM sales",
+                Blocker::HolisticAggregate("MEDIAN(amount)".to_string()),
+            ),
+            (
+                "SELECT id FROM sales s WHERE EXISTS (SELECT 1 FROM refunds r WHERE r.sale = s.id)",
+                Blocker::CorrelatedSubquery(
+                    "SELECT 1 FROM refunds AS r WHERE r.sale = s.id".to_string(),
+                ),
+            ),
+            (
+                "SELECT id FROM sales WHERE amount > (SELECT AVG(amount) FROM sales)",
+                Blocker::Subquery("SELECT AVG(amount) FROM sales".to_string()),
+            ),
+            (
+                "SELECT s.id FROM sales s JOIN prices p ON s.amount > p.floor",
+                Blocker::NonEquiJoin("JOIN prices AS p ON s.amount > p.floor".to_string()),
+            ),
+            (
+                "SELECT s.id FROM sales s FULL JOIN regions r ON s.region = r.code",
+                Blocker::PreservesBroadcastSide(
+                    "FULL JOIN regions AS r ON s.region = r.code".to_string(),
+                ),
+            ),
+            (
+                "SELECT region, id, SUM(amount) FROM sales GROUP BY region",
+                Blocker::Aggregation(UnsupportedFeature::UngroupedColumn("id".to_string())),
+            ),
+        ];
+
+        for (sql, blocker) in cases {
+            assert_eq!(blockers(sql), vec![blocker], "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_distributability_of_qualify() {
+        let latest = "SELECT * FROM 's3://b/events/*/*.parquet' \
+                      QUALIFY row_number() OVER (PARTITION BY e.user_id ORDER BY ts DESC) = 1";
+        let qualify = "row_number() OVER (PARTITION BY e.user_id ORDER BY ts DESC) = 1";
+        assert_eq!(
+            blockers(latest),
+            vec![Blocker::Qualify(qualify.to_string())]
+        );
+        let parsed = QueryWrapper::parse(latest).unwrap();
+        assert_eq!(
+            parsed.distributability_partitioned_by(&["user_id"]),
+            Ok(SCAN)
+        );
+        assert_eq!(
+            parsed.distributability_partitioned_by(&["user_id", "day"]),
+            Err(vec![Blocker::Qualify(qualify.to_string())])
+        );
+
+        // The window QUALIFY filters on may be in the SELECT list instead.
+        let parsed = QueryWrapper::parse(
+            "SELECT *, rank() OVER (PARTITION BY day, user_id ORDER BY ts) AS r FROM events \
+             QUALIFY r = 1",
+        )
+        .unwrap();
+        assert_eq!(parsed.distributability_partitioned_by(&["day"]), Ok(SCAN));
+        assert_eq!(
+            parsed.distributability(),
+            Err(vec![Blocker::WindowFunction(
+                "rank() OVER (PARTITION BY day, user_id ORDER BY ts)".to_string()
+            )])
+        );
+    }
+
+    #[test]
+    fn test_distributability_of_grouping_modifiers() {
+        for (sql, modifier) in [
+            (
+                "SELECT region, country, SUM(amount) FROM sales GROUP BY ROLLUP (region, country)",
+                "ROLLUP (region, country)",
+            ),
+            (
+                "SELECT region, SUM(amount) FROM sales GROUP BY CUBE (region)",
+                "CUBE (region)",
+            ),
+            (
+                "SELECT region, SUM(amount) FROM sales GROUP BY GROUPING SETS ((region), ())",
+                "GROUPING SETS ((region), ())",
+            ),
+            (
+                "SELECT a, b, c, SUM(amount) FROM sales GROUP BY a, ROLLUP (b, c)",
+                "ROLLUP (b, c)",
+            ),
+        ] {
+            let blockers = blockers(sql);
+            assert_eq!(
+                blockers,
+                [Blocker::GroupingModifier(modifier.to_string())],
+                "{}",
+                sql
+            );
+            assert!(blockers[0].to_string().contains("re-aggregation"));
+        }
+    }
+
+    #[test]
+    fn test_distributability_of_pivots() {
+        assert_eq!(
+            blockers(
+                "SELECT * FROM 's3://b/sales/*.parquet' \
+                 PIVOT (SUM(amount) FOR month IN ('jan', 'feb')) LIMIT 10"
+            ),
+            [
+                Blocker::Pivot(
+                    "'s3://b/sales/*.parquet' PIVOT(SUM(amount) FOR month IN ('jan', 'feb'))"
+                        .to_string()
+                ),
+                Blocker::RowLimit("LIMIT 10".to_string()),
+            ]
+        );
+        assert_eq!(
+            distributability(
+                "SELECT * FROM 's3://b/sales/*.parquet' UNPIVOT (amount FOR month IN (jan, feb))"
+            ),
+            Ok(SCAN)
+        );
+    }
+
+    #[test]
+    fn test_distributability_of_generators() {
+        assert_eq!(
+            blockers("SELECT i FROM range(10) t(i) LIMIT 5"),
+            [
+                Blocker::Generator("range(10) AS t (i)".to_string()),
+                Blocker::RowLimit("LIMIT 5".to_string()),
+            ]
+        );
+        assert_eq!(
+            distributability("SELECT * FROM read_parquet('s3://b/sales/*.parquet')"),
+            Ok(SCAN)
+        );
+    }
+
+    #[test]
+    fn test_distributability_without_from() {
+        assert_eq!(blockers("SELECT 42"), [Blocker::NoFrom]);
+        assert_eq!(blockers("SELECT count(*)"), [Blocker::NoFrom]);
+        assert_eq!(
+            distributability("SELECT (SELECT 1) AS one, id FROM t"),
+            Err(vec![Blocker::Subquery("SELECT 1".to_string())])
+        );
+    }
+
+    #[test]
+    fn test_distributability_reports_every_blocker() {
+        let blockers =
+            blockers("SELECT id, RANK() OVER (ORDER BY amount) FROM sales, regions ORDER BY id");
+        assert_eq!(
+            blockers,
+            vec![
+                Blocker::WindowFunction("RANK() OVER (ORDER BY amount)".to_string()),
+                Blocker::NonEquiJoin("regions".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_distributability_of_select_distinct() {
+        assert_eq!(
+            blockers("SELECT DISTINCT region FROM sales"),
+            [Blocker::SelectDistinct("DISTINCT".to_string())]
+        );
+        assert_eq!(
+            blockers("SELECT DISTINCT ON (region) region, amount FROM sales"),
+            [Blocker::SelectDistinct("DISTINCT ON (region)".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_distributability_of_derived_tables() {
+        let grouped = "SELECT region, SUM(amount) FROM sales GROUP BY region";
+        assert_eq!(
+            blockers(&format!("SELECT * FROM ({}) d", grouped)),
+            [Blocker::DerivedTable(format!("({}) AS d", grouped))]
+        );
+        assert_eq!(
+            blockers(&format!("WITH c AS ({}) SELECT * FROM c", grouped)),
+            [Blocker::DerivedTable(format!("c AS ({})", grouped))]
+        );
+        for inner in [
+            "SELECT COUNT(*) AS n FROM sales",
+            "SELECT DISTINCT region FROM sales",
+            "SELECT id FROM sales LIMIT 10",
+            "SELECT id FROM a UNION SELECT id FROM b",
+        ] {
+            assert_eq!(
+                blockers(&format!("SELECT * FROM ({}) d", inner)),
+                [Blocker::DerivedTable(format!("({}) AS d", inner))],
+                "{}",
+                inner
+            );
+        }
+        // Derived tables that pass each row through on its own are fine.
+        assert_eq!(
+            distributability("SELECT * FROM (SELECT id, amount * 2 AS x FROM sales) d"),
+            Ok(SCAN)
+        );
+        assert_eq!(
+            distributability("WITH c AS (SELECT id FROM sales WHERE amount > 1) SELECT id FROM c"),
+            Ok(SCAN)
+        );
+    }
+
+    #[test]
+    fn test_distributability_of_row_limits() {
+        for (sql, bound) in [
+            ("SELECT id FROM sales OFFSET 5", "OFFSET 5"),
+            (
+                "SELECT id FROM sales LIMIT 5 OFFSET 10",
+                "LIMIT 5 OFFSET 10",
+            ),
+            ("SELECT id FROM sales ORDER BY id LIMIT $1", "LIMIT $1"),
+        ] {
+            assert_eq!(
+                blockers(sql),
+                [Blocker::RowLimit(bound.to_string())],
+                "{}",
+                sql
+            );
+        }
+        assert_eq!(
+            distributability("SELECT id FROM sales ORDER BY id LIMIT 5 OFFSET 10"),
+            Ok(Strategy::ParallelScan {
+                sort: SortRequirement::TopK {
+                    k: 15,
+                    keys: vec![("id".to_string(), true)],
+                },
+            })
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use crate::QueryWrapper;
+use sqlparser::dialect::DuckDbDialect;
+use sqlparser::tokenizer::{Token, Tokenizer};
+use std::hash::{Hash, Hasher};
+
+impl QueryWrapper {
+    /// Whether `other` is the same query written differently: with other
+    /// spacing, comments, or case in keywords and unquoted identifiers, which
+    /// DuckDB doesn't tell apart. Anything else makes them differ, including
+    /// a literal's value or the order of SELECT items.
+    ///
+    /// This is also how wrappers compare with `==` and hash, so concurrent
+    /// duplicates of a query can share one entry in a `HashMap`.
+    pub fn semantically_eq(&self, other: &QueryWrapper) -> bool {
+        self.normalized_tokens() == other.normalized_tokens()
+    }
+
+    /// The tokens of the statements as rendered from the AST, which leaves
+    /// out comments and spacing, with unquoted words lowercased.
+    fn normalized_tokens(&self) -> Vec<Token> {
+        let sql = self.strip_comments();
+        let Ok(tokens) = Tokenizer::new(&DuckDbDialect {}, &sql).tokenize() else {
+            // SQL rendered from a parsed AST tokenizes; compare it whole if not.
+            return vec![Token::make_word(&sql, Some('"'))];
+        };
+        tokens
+            .into_iter()
+            .filter(|token| !matches!(token, Token::Whitespace(_)))
+            .map(|token| match token {
+                Token::Word(mut word) if word.quote_style.is_none() => {
+                    word.value = word.value.to_lowercase();
+                    Token::Word(word)
+                }
+                token => token,
+            })
+            .collect()
+    }
+}
+
+impl PartialEq for QueryWrapper {
+    fn eq(&self, other: &Self) -> bool {
+        self.semantically_eq(other)
+    }
+}
+
+impl Eq for QueryWrapper {}
+
+impl Hash for QueryWrapper {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.normalized_tokens().hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn parse(sql: &str) -> QueryWrapper {
+        QueryWrapper::parse(sql).unwrap()
+    }
+
+    #[test]
+    fn test_semantically_eq() {
+        let query =
+            parse("SELECT region, SUM(amount) FROM sales WHERE day = '2024-01-01' GROUP BY 1");
+        for same in [
+            "select Region,\n  sum(AMOUNT)\nfrom SALES where DAY = '2024-01-01'  group by 1",
+            "SELECT region, SUM(amount) -- totals\nFROM sales /* daily */ WHERE day = '2024-01-01' GROUP BY 1",
+        ] {
+            assert!(query.semantically_eq(&parse(same)), "{}", same);
+        }
+        for different in [
+            // Literals
+            "SELECT region, SUM(amount) FROM sales WHERE day = '2024-01-02' GROUP BY 1",
+            "SELECT region, SUM(amount) FROM sales WHERE DAY = '2024-01-01' GROUP BY 2",
+            // Order of SELECT items
+            "SELECT SUM(amount), region FROM sales WHERE day = '2024-01-01' GROUP BY 1",
+            // Quoted identifiers keep their case.
+            "SELECT \"Region\", SUM(amount) FROM sales WHERE day = '2024-01-01' GROUP BY 1",
+        ] {
+            assert!(!query.semantically_eq(&parse(different)), "{}", different);
+        }
+    }
+
+    #[test]
+    fn test_wrappers_key_a_map() {
+        let mut waiting: HashMap<QueryWrapper, usize> = HashMap::new();
+        for sql in [
+            "SELECT * FROM t WHERE id = 1",
+            "select *  from T where ID = 1",
+            "SELECT * FROM t WHERE id = 2",
+        ] {
+            *waiting.entry(parse(sql)).or_default() += 1;
+        }
+        assert_eq!(waiting.len(), 2);
+        assert_eq!(waiting[&parse("SELECT * FROM t WHERE id = 1")], 2);
+    }
+}
@@ -0,0 +1,49 @@
+use crate::decompose::function_name;
+use crate::QueryWrapper;
+use sqlparser::ast::{Expr, TableFactor, Visit, Visitor};
+use std::collections::BTreeSet;
+use std::ops::ControlFlow;
+
+impl QueryWrapper {
+    /// The lowercased names of every function the batch calls, in subqueries
+    /// and table functions too.
+    pub fn referenced_functions(&self) -> BTreeSet<String> {
+        let mut names = BTreeSet::new();
+        for statement in std::iter::once(&self.ast).chain(&self.trailing) {
+            let _ = statement.visit(&mut FunctionCollector { names: &mut names });
+        }
+        names
+    }
+}
+
+/// Adds the lowercased names of the functions it visits to `names`, table
+/// functions such as `read_parquet` included.
+struct FunctionCollector<'a> {
+    names: &'a mut BTreeSet<String>,
+}
+
+impl Visitor for FunctionCollector<'_> {
+    type Break = ();
+
+    fn pre_visit_table_factor(&mut self, table_factor: &TableFactor) -> ControlFlow<()> {
+        match table_factor {
+            TableFactor::Table {
+                name,
+                args: Some(_),
+                ..
+            }
+            | TableFactor::Function { name, .. } => {
+                self.names.insert(name.to_string().to_lowercase());
+            }
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<()> {
+        if let Expr::Function(func) = expr {
+            self.names.insert(function_name(func));
+        }
+        ControlFlow::Continue(())
+    }
+}
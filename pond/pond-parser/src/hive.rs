@@ -0,0 +1,127 @@
+use crate::{QueryError, QueryWrapper, ScanConfig};
+use std::collections::{BTreeMap, BTreeSet};
+
+impl QueryWrapper {
+    /// The Hive partition keys named by the source's `key=value` directories,
+    /// in path order: `year` and `month` for
+    /// `s3://b/year=2024/month=*/*.parquet`. Read with `hive_partitioning`,
+    /// DuckDB exposes each as a column. Empty when the source names none or
+    /// the query has no single source.
+    pub fn hive_partition_keys(&self) -> Vec<String> {
+        let Ok(source) = self.source() else {
+            return Vec::new();
+        };
+        let mut keys = Vec::new();
+        for (key, _) in hive_pairs(&source) {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        keys
+    }
+
+    /// The distinct values of each Hive partition key, read off the prefixes
+    /// a scan with `config` finds, as
+    /// [`scan_source_for_prefixes_with_config`](Self::scan_source_for_prefixes_with_config)
+    /// returns them. Besides the [`hive_partition_keys`](Self::hive_partition_keys),
+    /// which are present even when nothing matches, this covers `key=value`
+    /// directories the source's wildcards match.
+    pub async fn hive_partition_values(
+        &self,
+        config: &ScanConfig,
+    ) -> Result<BTreeMap<String, BTreeSet<String>>, QueryError> {
+        let mut values: BTreeMap<String, BTreeSet<String>> = self
+            .hive_partition_keys()
+            .into_iter()
+            .map(|key| (key, BTreeSet::new()))
+            .collect();
+        for prefix in self.scan_source_for_prefixes_with_config(config).await? {
+            for (key, value) in hive_pairs(&prefix) {
+                values.entry(key).or_default().insert(value);
+            }
+        }
+        Ok(values)
+    }
+}
+
+/// The `key=value` directories in `path`, in order, leaving out the last
+/// segment (the file name or pattern) and a URL's scheme and bucket. Keys
+/// must be plain names; values are kept as written, wildcards included.
+fn hive_pairs(path: &str) -> Vec<(String, String)> {
+    let path = match path.split_once("://") {
+        Some((_, rest)) => rest.split_once('/').map_or("", |(_, path)| path),
+        None => path,
+    };
+    let Some((directories, _)) = path.rsplit_once('/') else {
+        return Vec::new();
+    };
+    directories
+        .split('/')
+        .filter_map(|segment| segment.split_once('='))
+        .filter(|(key, value)| {
+            !key.is_empty()
+                && !value.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        })
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hive_partition_keys() {
+        let keys = |sql: &str| QueryWrapper::parse(sql).unwrap().hive_partition_keys();
+        assert_eq!(
+            keys("SELECT * FROM 's3://b/year=2024/month=01/*.parquet'"),
+            ["year", "month"]
+        );
+        assert_eq!(
+            keys(
+                "SELECT * FROM read_parquet('s3://b/sales/region=*/day=*/*.parquet', \
+                 hive_partitioning = true)"
+            ),
+            ["region", "day"]
+        );
+        assert_eq!(keys("SELECT * FROM '/data/x=1/x=2/file=3.parquet'"), ["x"]);
+        assert!(keys("SELECT * FROM 's3://b/sales/*/*.parquet'").is_empty());
+        assert!(keys("SELECT 1").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_hive_partition_values() {
+        let root = std::env::temp_dir().join(format!("pond-hive-{}", std::process::id()));
+        for dir in [
+            "year=2023/month=12",
+            "year=2024/month=01",
+            "year=2024/month=02",
+        ] {
+            let dir = root.join("sales").join(dir).join("source=web");
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("a.parquet"), b"PAR1").unwrap();
+        }
+        let wrapper = QueryWrapper::parse(&format!(
+            "SELECT * FROM '{}/sales/year=*/month=*/*/*.parquet'",
+            root.display()
+        ))
+        .unwrap();
+        let values = wrapper
+            .hive_partition_values(&ScanConfig::default())
+            .await
+            .unwrap();
+        let set = |values: &[&str]| values.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            values,
+            BTreeMap::from([
+                ("month".to_string(), set(&["01", "02", "12"])),
+                ("source".to_string(), set(&["web"])),
+                ("year".to_string(), set(&["2023", "2024"])),
+            ])
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,64 @@
+//! Runs parsed queries in DuckDB to check that `QueryWrapper` hands back SQL
+//! that still executes and returns the same rows.
+
+use super::*;
+use duckdb::Connection;
+
+fn connection() -> Connection {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch(
+        "CREATE TABLE sales (id INTEGER, region VARCHAR, amount INTEGER);
+         INSERT INTO sales VALUES
+             (1, 'east', 10), (2, 'west', 20), (3, 'east', 30),
+             (4, 'north', 40), (5, 'west', NULL);",
+    )
+    .unwrap();
+    conn
+}
+
+fn row_count(conn: &Connection, sql: &str) -> usize {
+    let mut stmt = conn.prepare(sql).unwrap();
+    let mut rows = stmt.query([]).unwrap();
+    let mut count = 0;
+    while rows.next().unwrap().is_some() {
+        count += 1;
+    }
+    count
+}
+
+#[test]
+fn test_parsed_sql_round_trips_through_duckdb() {
+    let conn = connection();
+    let cases = [
+        ("SELECT * FROM sales", 5),
+        ("SELECT id FROM sales WHERE region = 'east'", 2),
+        ("SELECT region, SUM(amount) FROM sales GROUP BY region", 3),
+        (
+            "WITH big AS (SELECT * FROM sales WHERE amount > 15) SELECT id FROM big ORDER BY id",
+            3,
+        ),
+        (
+            "SELECT id FROM sales ORDER BY amount DESC NULLS LAST LIMIT 2",
+            2,
+        ),
+    ];
+
+    for (query, expected) in cases {
+        let wrapper = QueryWrapper::parse(query).unwrap();
+        assert_eq!(row_count(&conn, &wrapper.sql), expected, "{}", query);
+        assert_eq!(row_count(&conn, query), expected, "{}", query);
+    }
+}
+
+#[test]
+fn test_mutated_sql_round_trips_through_duckdb() {
+    let conn = connection();
+    let mut wrapper = QueryWrapper::parse("SELECT id FROM sales ORDER BY id LIMIT 2").unwrap();
+    wrapper.set_limit(Some(4)).unwrap();
+    wrapper.set_offset(Some(1)).unwrap();
+    assert_eq!(row_count(&conn, &wrapper.sql), 4);
+
+    // Only the LIMIT goes; OFFSET 1 still skips a row.
+    wrapper.strip_limit().unwrap();
+    assert_eq!(row_count(&conn, &wrapper.sql), 4);
+}
@@ -1,75 +1,682 @@
-use duckdb::{Connection, Result as DuckResult};
 use lazy_static::lazy_static;
 use regex::Regex;
 use sha2::{Digest, Sha256};
 use sqlparser::ast::{
-    Expr, Function, FunctionArg, FunctionArgExpr, FunctionArguments, GroupByExpr, Ident,
-    JoinConstraint, JoinOperator, ObjectName, Query as SqlQuery, Select, SelectItem, SetExpr,
-    Statement, TableFactor, TableWithJoins,
+    BinaryOperator, ExcludeSelectItem, Expr, Function, FunctionArg, FunctionArgExpr,
+    FunctionArguments, GroupByExpr, Ident, JoinConstraint, JoinOperator, Offset, OffsetRows,
+    OrderByExpr, PivotValueSource, Query as SqlQuery, Select, SelectItem, SetExpr, Statement,
+    TableAlias, TableFactor, TableWithJoins, Value, Visit, Visitor, WildcardAdditionalOptions,
+    WindowType,
 };
 use sqlparser::dialect::DuckDbDialect;
 use sqlparser::parser::Parser;
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ops::ControlFlow;
 use thiserror::Error;
+use twox_hash::XxHash64;
+
+mod access;
+mod aggregation;
+mod bind;
+mod cache;
+mod columns;
+mod cost;
+mod ctes;
+mod decompose;
+mod distribute;
+mod equality;
+mod functions;
+mod hive;
+#[cfg(feature = "object-store")]
+mod listing;
+mod nesting;
+mod normalize;
+mod policy;
+mod projection;
+mod redact;
+mod scan;
+mod schema;
+mod split;
+mod time;
+mod warnings;
+
+pub use cache::PrefixCache;
+pub use cost::{CostEstimate, DEFAULT_FOOTER_SAMPLE};
+pub use decompose::{renamed_function, DecomposedQuery, UnsupportedFeature, PARTIAL_RESULTS_TABLE};
+pub use distribute::{Blocker, Distributability, Strategy};
+pub use policy::QueryPolicy;
+pub use projection::ALL_COLUMNS;
+pub use scan::{FileEntry, PrefixOrder, PrefixScanner, PrefixStats, ScanConfig, UrlStyle};
+pub use schema::Columns;
+pub use time::TimeBounds;
+pub use warnings::AnalysisWarning;
+
+/// A common table expression a query defines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CteInfo {
+    /// The name as written.
+    pub name: String,
+    /// Every relation the CTE's query reads, named as in
+    /// [`QueryAnalysis::tables`]: other CTEs, and itself when recursive.
+    pub referenced_tables: BTreeSet<String>,
+    /// Whether it is defined in a `WITH RECURSIVE`.
+    pub is_recursive: bool,
+    /// `Some(true)` for `AS MATERIALIZED`, `Some(false)` for
+    /// `AS NOT MATERIALIZED`. sqlparser only reads the hint in the PostgreSQL
+    /// dialect, so it is `None` for DuckDB queries until that changes.
+    pub materialized_hint: Option<bool>,
+}
+
+/// A PIVOT or UNPIVOT reshaping a relation in FROM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PivotInfo {
+    /// `table PIVOT (aggregates FOR pivot_column IN (values))`: a column per
+    /// value, holding the aggregates over the rows with that value.
+    Pivot {
+        /// The relation pivoted, named as in [`QueryAnalysis::tables`].
+        table: String,
+        pivot_column: String,
+        aggregates: Vec<String>,
+        /// The columns the values become: each value's alias, or the value
+        /// itself. Empty for `IN (ANY)` or a subquery, which are only
+        /// resolved against the data.
+        value_columns: Vec<String>,
+    },
+    /// `table UNPIVOT (value_column FOR name_column IN (columns))`: a row per
+    /// listed column, holding its name and value.
+    Unpivot {
+        /// The relation unpivoted, named as in [`QueryAnalysis::tables`].
+        table: String,
+        value_column: String,
+        name_column: String,
+        columns: Vec<String>,
+    },
+}
+
+impl PivotInfo {
+    /// The relation reshaped.
+    pub fn table(&self) -> &str {
+        match self {
+            Self::Pivot { table, .. } | Self::Unpivot { table, .. } => table,
+        }
+    }
+}
+
+/// A GROUP BY element grouping the rows by several sets of keys at once,
+/// which a plain GROUP BY can't express. Each element is a list of keys: one
+/// for `a`, several for a composite `(a, b)`. Keys are named like
+/// [`QueryAnalysis::columns`], other expressions as written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupingModifier {
+    /// `ROLLUP (a, b)`: grouped by `(a, b)`, `(a)` and `()`.
+    Rollup(Vec<Vec<String>>),
+    /// `CUBE (a, b)`: grouped by every subset of the elements.
+    Cube(Vec<Vec<String>>),
+    /// `GROUPING SETS ((a, b), (a), ())`: grouped by each set listed.
+    GroupingSets(Vec<Vec<String>>),
+}
+
+impl GroupingModifier {
+    /// The sets of keys the rows are grouped by, each flattened, in the
+    /// order DuckDB computes them: `ROLLUP (a, b)` gives `[a, b]`, `[a]` and
+    /// `[]`; `CUBE (a, b)` gives `[a, b]`, `[a]`, `[b]` and `[]`.
+    pub fn sets(&self) -> Vec<Vec<String>> {
+        match self {
+            Self::Rollup(elements) => (0..=elements.len())
+                .rev()
+                .map(|len| elements[..len].concat())
+                .collect(),
+            Self::Cube(elements) => {
+                let n = elements.len();
+                (0..1usize << n)
+                    .rev()
+                    .map(|mask| {
+                        (0..n)
+                            .filter(|i| mask & (1 << (n - 1 - i)) != 0)
+                            .flat_map(|i| elements[i].iter().cloned())
+                            .collect()
+                    })
+                    .collect()
+            }
+            Self::GroupingSets(sets) => sets.clone(),
+        }
+    }
+}
+
+/// A table function called in FROM, such as `read_parquet('s3://b/*.parquet')`
+/// or `range(10)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableFunctionCall {
+    /// The function's name, lowercased.
+    pub name: String,
+    /// Each argument as written, named ones as `name => value`.
+    pub args: Vec<String>,
+    /// The named arguments, e.g. `hive_partitioning` and `filename` for
+    /// `read_parquet`, by lowercased name, each with its value as written.
+    /// DuckDB's `name = value` form counts as well as `name => value`.
+    pub options: HashMap<String, String>,
+}
+
+impl TableFunctionCall {
+    fn new(name: String, args: &[FunctionArg]) -> Self {
+        let options = args
+            .iter()
+            .filter_map(|arg| match arg {
+                FunctionArg::Named { name, arg, .. } => {
+                    Some((normalized_ident(name), arg.to_string()))
+                }
+                FunctionArg::Unnamed(FunctionArgExpr::Expr(Expr::BinaryOp {
+                    left,
+                    op: BinaryOperator::Eq,
+                    right,
+                })) => match left.as_ref() {
+                    Expr::Identifier(name) => Some((normalized_ident(name), right.to_string())),
+                    _ => None,
+                },
+                FunctionArg::Unnamed(_) => None,
+            })
+            .collect();
+        Self {
+            name,
+            args: args.iter().map(ToString::to_string).collect(),
+            options,
+        }
+    }
+
+    /// Whether the `hive_partitioning` option is set to true, so DuckDB adds
+    /// a column for each `key=value` directory in the paths read.
+    pub fn hive_partitioning(&self) -> bool {
+        self.options
+            .get("hive_partitioning")
+            .is_some_and(|value| matches!(value.to_lowercase().as_str(), "true" | "1" | "'true'"))
+    }
+
+    /// Whether the function reads the files, or lists the paths, that its
+    /// first argument names: `glob`, `parquet_scan` or a `read_*` reader.
+    pub fn reads_paths(&self) -> bool {
+        reads_paths(&self.name)
+    }
+
+    /// Whether the function generates its rows from its arguments alone,
+    /// like `range` and `generate_series`.
+    pub fn is_generator(&self) -> bool {
+        is_generator(&self.name)
+    }
+
+    /// The paths a function that [`reads_paths`](Self::reads_paths) is given,
+    /// as one string or a list of them.
+    pub fn paths(&self) -> Vec<String> {
+        lazy_static! {
+            static ref PATH_RE: Regex = Regex::new(r"'((?:[^']|'')*)'").unwrap();
+        }
+
+        match self.args.first() {
+            Some(arg) if self.reads_paths() && (arg.starts_with('\'') || arg.starts_with('[')) => {
+                PATH_RE
+                    .captures_iter(arg)
+                    .map(|captures| captures[1].replace("''", "'"))
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// `ident` as DuckDB resolves it: lowercased unless quoted, when it is kept
+/// as written.
+pub(crate) fn normalized_ident(ident: &Ident) -> String {
+    match ident.quote_style {
+        Some(_) => ident.value.clone(),
+        None => ident.value.to_lowercase(),
+    }
+}
+
+/// A possibly qualified name with each part normalized as by
+/// [`normalized_ident`], joined by dots.
+pub(crate) fn normalized_name(idents: &[Ident]) -> String {
+    idents
+        .iter()
+        .map(normalized_ident)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// See [`TableFunctionCall::reads_paths`].
+fn reads_paths(function: &str) -> bool {
+    let function = function.to_lowercase();
+    function == "glob" || function == "parquet_scan" || function.starts_with("read_")
+}
+
+/// See [`TableFunctionCall::is_generator`].
+pub(crate) fn is_generator(function: &str) -> bool {
+    matches!(
+        function.to_lowercase().as_str(),
+        "range" | "generate_series"
+    )
+}
 
 #[derive(Debug, Default)]
 pub struct QueryAnalysis {
     tables: HashSet<String>,
+    aliases: HashMap<String, String>,
+    derived_tables: HashMap<String, SqlQuery>,
     columns: HashSet<String>,
-    conditions: Vec<String>,
+    ctes: Vec<CteInfo>,
+    pivots: Vec<PivotInfo>,
+    table_functions: Vec<TableFunctionCall>,
+    grouping_modifiers: Vec<GroupingModifier>,
+    grouping_sets: Vec<Vec<String>>,
+    excluded_columns: Vec<String>,
+    replaced_columns: HashMap<String, String>,
+    spellings: HashMap<String, String>,
+    warnings: Vec<AnalysisWarning>,
+    where_conditions: Vec<String>,
+    having_conditions: Vec<String>,
+    qualify: Option<String>,
     aggregations: Vec<String>,
     joins: Vec<String>,
     order_by: Vec<String>,
+    sort_requirement: SortRequirement,
     limit: Option<u64>,
     offset: Option<u64>,
+    case_expressions: usize,
+}
+
+impl QueryAnalysis {
+    /// The relations read, by real name: the table name, the path a quoted
+    /// source or reader function reads, or a derived table's synthetic name.
+    /// Like every identifier in the analysis, table names are lowercased
+    /// unless quoted; see [`spellings`](Self::spellings).
+    /// Other table functions, such as `glob` and `range`, read no relation
+    /// and are only listed in [`table_functions`](Self::table_functions).
+    pub fn tables(&self) -> &HashSet<String> {
+        &self.tables
+    }
+
+    /// Aliases, lowercased unless quoted, and the entry of
+    /// [`tables`](Self::tables) each stands for.
+    pub fn aliases(&self) -> &HashMap<String, String> {
+        &self.aliases
+    }
+
+    /// A derived table's subquery, by its synthetic name: `__derived_` followed
+    /// by its lowercased alias, or by its position among unaliased ones.
+    pub fn derived_table(&self, name: &str) -> Option<&SqlQuery> {
+        self.derived_tables.get(name)
+    }
+
+    /// The columns referenced, with qualifiers resolved through
+    /// [`aliases`](Self::aliases), so `Country` and `country` are one entry
+    /// while `"Country"` is another.
+    pub fn columns(&self) -> &HashSet<String> {
+        &self.columns
+    }
+
+    /// Every CTE defined, in the order they appear, including those in
+    /// subqueries and in other CTEs.
+    pub fn ctes(&self) -> &[CteInfo] {
+        &self.ctes
+    }
+
+    /// The PIVOTs and UNPIVOTs applied in FROM, innermost first where they
+    /// are stacked.
+    pub fn pivots(&self) -> &[PivotInfo] {
+        &self.pivots
+    }
+
+    /// Every table function called in FROM, in the order they appear,
+    /// readers such as `read_parquet` included.
+    pub fn table_functions(&self) -> &[TableFunctionCall] {
+        &self.table_functions
+    }
+
+    /// The [`options`](TableFunctionCall::options) each path is read with,
+    /// by path, for the table functions that
+    /// [`read paths`](TableFunctionCall::reads_paths).
+    pub fn source_options(&self) -> HashMap<String, &HashMap<String, String>> {
+        self.table_functions
+            .iter()
+            .flat_map(|call| call.paths().into_iter().map(|path| (path, &call.options)))
+            .collect()
+    }
+
+    /// The ROLLUPs, CUBEs and GROUPING SETS in any GROUP BY, in the order
+    /// they appear. Empty when every GROUP BY is a plain list of keys or ALL.
+    pub fn grouping_modifiers(&self) -> &[GroupingModifier] {
+        &self.grouping_modifiers
+    }
+
+    /// The sets of keys the outer GROUP BY groups by when it uses a
+    /// [`GroupingModifier`], each flattened and named like
+    /// [`grouping_modifiers`](Self::grouping_modifiers). Plain keys are added
+    /// to every set, so `GROUP BY a, ROLLUP (b, c)` gives `[a, b, c]`,
+    /// `[a, b]` and `[a]`; several modifiers combine as a cross product.
+    /// Empty for a plain GROUP BY, whose single set is its keys.
+    pub fn grouping_sets(&self) -> &[Vec<String>] {
+        &self.grouping_sets
+    }
+
+    /// The columns a wildcard leaves out with `* EXCLUDE (...)`, in order,
+    /// qualified like [`columns`](Self::columns) when the wildcard is.
+    pub fn excluded_columns(&self) -> &[String] {
+        &self.excluded_columns
+    }
+
+    /// The columns a wildcard overrides with `* REPLACE (expr AS column)`,
+    /// named like [`excluded_columns`](Self::excluded_columns), and the
+    /// expression each now holds.
+    pub fn replaced_columns(&self) -> &HashMap<String, String> {
+        &self.replaced_columns
+    }
+
+    /// How each identifier in the analysis was first spelled, by its
+    /// normalized form, for messages that quote the user's SQL back.
+    pub fn spellings(&self) -> &HashMap<String, String> {
+        &self.spellings
+    }
+
+    /// Normalizes `ident` as by [`normalized_ident`], recording its spelling.
+    fn normalize(&mut self, ident: &Ident) -> String {
+        let normalized = normalized_ident(ident);
+        self.spellings
+            .entry(normalized.clone())
+            .or_insert_with(|| ident.value.clone());
+        normalized
+    }
+
+    /// Normalizes a qualified name part by part, recording each spelling.
+    fn normalize_name(&mut self, idents: &[Ident]) -> String {
+        idents
+            .iter()
+            .map(|ident| self.normalize(ident))
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    /// Joins likely to produce a cartesian product by mistake.
+    pub fn warnings(&self) -> &[AnalysisWarning] {
+        &self.warnings
+    }
+
+    /// Every WHERE predicate, subqueries' included, in the order they appear.
+    /// These filter rows before aggregation.
+    pub fn where_conditions(&self) -> &[String] {
+        &self.where_conditions
+    }
+
+    /// Every HAVING predicate, subqueries' included, in the order they
+    /// appear. These filter groups after aggregation and must never be
+    /// pushed down to a scan.
+    pub fn having_conditions(&self) -> &[String] {
+        &self.having_conditions
+    }
+
+    #[deprecated(
+        note = "use `where_conditions()` or `having_conditions()`, which tell filters on rows \
+                from filters on groups"
+    )]
+    pub fn conditions(&self) -> Vec<String> {
+        self.where_conditions
+            .iter()
+            .chain(&self.having_conditions)
+            .cloned()
+            .collect()
+    }
+
+    /// The outer query's QUALIFY filter, which DuckDB applies after window
+    /// functions are computed.
+    pub fn qualify(&self) -> Option<&str> {
+        self.qualify.as_deref()
+    }
+
+    /// How the outer query's rows are ordered, with its ORDER BY keys resolved
+    /// through the SELECT list: positions and output aliases stand for the
+    /// expression they name, columns are given by their normalized name
+    /// without qualifier, and other expressions as SQL text.
+    pub fn sort_requirement(&self) -> &SortRequirement {
+        &self.sort_requirement
+    }
+
+    /// The query's LIMIT, when it is a literal number.
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    /// The query's OFFSET, when it is a literal number.
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    /// A rough complexity score for admission control.
+    ///
+    /// This is a heuristic, not a cost model: it knows nothing about data
+    /// volume. Starting from 1, the score is multiplied by 10 for each join (100
+    /// for a CROSS JOIN) and by the number of distinct tables, then 5 is added
+    /// per aggregation and 2 per CASE expression.
+    pub fn estimated_cost(&self) -> u64 {
+        let mut cost: u64 = 1;
+        for join in &self.joins {
+            let factor = if join == "CrossJoin" { 100 } else { 10 };
+            cost = cost.saturating_mul(factor);
+        }
+        cost = cost.saturating_mul(self.tables.len().max(1) as u64);
+        cost = cost.saturating_add(5 * self.aggregations.len() as u64);
+        cost.saturating_add(2 * self.case_expressions as u64)
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum QueryError {
     #[error("SQL parsing error: {0}")]
     SqlParseError(#[from] sqlparser::parser::ParserError),
+    #[error("SQL parse error at line {line}, column {column}: {message}\n{snippet}")]
+    ParseFailed {
+        /// Byte offset of the error in the full input.
+        offset: usize,
+        /// 1-based line number.
+        line: usize,
+        /// 1-based column, in characters.
+        column: usize,
+        /// The offending line followed by a caret under the error.
+        snippet: String,
+        /// sqlparser's message, without its location suffix.
+        message: String,
+    },
     #[error("DuckDB error: {0}")]
     DuckDbError(#[from] duckdb::Error),
     #[error("Invalid filesystem: {0}")]
     InvalidFilesystem(String),
+    #[error("Unsupported for distributed execution: {}", display_features(.0))]
+    Unsupported(Vec<UnsupportedFeature>),
+    #[error("No files match {0}")]
+    NoFilesMatched(String),
+    #[error("Scan of {bytes} bytes exceeds the limit of {max_bytes}")]
+    ScanTooLarge { bytes: u64, max_bytes: u64 },
+    #[error("Schema mismatch in {file} (matched by {glob}): {message}")]
+    SchemaMismatch {
+        glob: String,
+        file: String,
+        message: String,
+    },
+    #[error("Access denied to {}", .0.join(", "))]
+    AccessDenied(Vec<String>),
+    #[error("Policy violation ({rule}): {fragment}")]
+    PolicyViolation { rule: String, fragment: String },
+    #[error("Query nests {0} levels deep, more than allowed")]
+    TooDeep(usize),
     #[error("Other error: {0}")]
     Other(String),
 }
 
+fn display_features(features: &[UnsupportedFeature]) -> String {
+    features
+        .iter()
+        .map(|feature| feature.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The format of a file read by a query; see [`QueryWrapper::file_sources`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    Parquet,
+    Csv,
+    Json,
+    Unknown,
+}
+
+impl FileFormat {
+    fn from_reader(function: &str) -> Self {
+        match function.to_lowercase().as_str() {
+            "read_parquet" | "parquet_scan" => Self::Parquet,
+            "read_csv" | "read_csv_auto" => Self::Csv,
+            "read_json" | "read_json_auto" | "read_ndjson" | "read_ndjson_auto" => Self::Json,
+            _ => Self::Unknown,
+        }
+    }
+
+    fn from_path(path: &str) -> Self {
+        match path.rsplit('.').next().map(str::to_lowercase).as_deref() {
+            Some("parquet") => Self::Parquet,
+            Some("csv") => Self::Csv,
+            Some("json") => Self::Json,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// How a query's results combine across partitions; see
+/// [`QueryWrapper::query_kind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QueryKind {
+    /// Aggregates or groups, so partition results need a merge step.
+    Aggregate,
+    /// Reads no relation, as in `SELECT 42` or `SELECT now()`; it can run
+    /// anywhere.
+    Scalar,
+    /// Returns rows of its source, so partition results can be concatenated.
+    Rows,
+}
+
+/// The ordering the outer query's rows need once partitions are merged; see
+/// [`QueryAnalysis::sort_requirement`]. Keys pair a column with `true` when
+/// ascending.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SortRequirement {
+    /// No ORDER BY: partition results can be concatenated as they come.
+    #[default]
+    None,
+    /// ORDER BY with a literal LIMIT: only the first `k` rows are needed, the
+    /// LIMIT plus any OFFSET, so each partition can return its own top `k`
+    /// for the merge to pick from.
+    TopK { k: u64, keys: Vec<(String, bool)> },
+    /// ORDER BY without a LIMIT: every row is sorted again at merge time.
+    FullSort { keys: Vec<(String, bool)> },
+}
+
+/// The hash function behind [`QueryWrapper::hash_with`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashAlgo {
+    /// SHA-256, for keys shared between processes or persisted.
+    Sha256,
+    /// xxHash64, much cheaper, for keys kept in memory.
+    XxHash64,
+}
+
+impl HashAlgo {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::XxHash64 => "xxh64",
+        }
+    }
+}
+
+/// The scheme [`QueryWrapper::hash`] and [`QueryWrapper::hash_with`] prefix
+/// their values with, bumped whenever what is hashed changes so that keys
+/// from an older scheme miss instead of colliding.
+pub const HASH_VERSION: &str = "v2";
+
 pub struct QueryWrapper {
     sql: String,
+    /// The SQL as submitted, comments included.
+    raw: String,
+    /// The text of each comment in `raw`, in order, without delimiters.
+    comments: Vec<String>,
     hashed: String,
     ast: Statement,
+    /// Any statements after the first, kept so policy checks see the whole batch.
+    trailing: Vec<Statement>,
     list_of_prefixes: Option<Vec<String>>,
 }
 
 impl QueryWrapper {
     pub fn parse(query: &str) -> Result<Self, QueryError> {
-        let unified_query = Self::unify_query(query)?;
-        let ast = Parser::parse_sql(&DuckDbDialect {}, &unified_query)?;
+        // Parsed as submitted, so errors point into the user's text.
+        let mut statements = Parser::parse_sql(&DuckDbDialect {}, query)
+            .map_err(|err| Self::parse_failure(query, err))?
+            .into_iter();
 
-        if ast.is_empty() {
-            return Err(QueryError::Other("Empty query".to_string()));
-        }
+        let ast = match statements.next() {
+            Some(ast) => ast,
+            None => return Err(QueryError::Other("Empty query".to_string())),
+        };
 
+        let (unified_query, comments) = Self::unify_query(query);
         Ok(Self {
-            hashed: Self::create_hash_string(&unified_query),
+            hashed: Self::versioned_hash(&unified_query, HashAlgo::Sha256),
             sql: unified_query,
-            ast: ast[0].clone(),
+            raw: query.to_string(),
+            comments,
+            ast,
+            trailing: statements.collect(),
             list_of_prefixes: None,
         })
     }
 
+    /// Like [`parse`](Self::parse), failing with [`QueryError::TooDeep`] when
+    /// queries nest deeper than `max_depth`; see
+    /// [`max_nesting_depth`](Self::max_nesting_depth).
+    pub fn parse_with_max_depth(query: &str, max_depth: usize) -> Result<Self, QueryError> {
+        let wrapper = Self::parse(query)?;
+        let depth = wrapper.max_nesting_depth();
+        if depth > max_depth {
+            return Err(QueryError::TooDeep(depth));
+        }
+        Ok(wrapper)
+    }
+
+    /// Parses each semicolon-separated statement of `query` into its own
+    /// wrapper, in order. Semicolons inside string literals, quoted
+    /// identifiers and comments don't split, and fragments holding nothing
+    /// but whitespace or comments are skipped.
+    ///
+    /// Every wrapper hashes only its own statement, so a statement's hash is
+    /// the same whether it is sent alone or anywhere in a batch.
+    pub fn parse_batch(query: &str) -> Result<Vec<Self>, QueryError> {
+        let wrappers = split_statements(query)
+            .into_iter()
+            .map(|fragment| Self::parse(fragment.trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if wrappers.is_empty() {
+            return Err(QueryError::Other("Empty query".to_string()));
+        }
+        Ok(wrappers)
+    }
+
     pub fn analyze(&self) -> QueryAnalysis {
         let mut analysis = QueryAnalysis::default();
         self.analyze_ast(&self.ast, &mut analysis);
+        ctes::collect_ctes(&self.ast, &mut analysis.ctes);
+        analysis.sort_requirement = self.sort_requirement();
+        analysis.grouping_sets = self.grouping_sets(&mut analysis);
         analysis
     }
 
     fn analyze_ast(&self, statement: &Statement, analysis: &mut QueryAnalysis) {
-        match statement {
-            Statement::Query(query) => self.analyze_query(query.as_ref(), analysis),
-            _ => {} // Handle other statement types if needed
+        // Handle other statement types if needed
+        if let Statement::Query(query) = statement {
+            self.analyze_query(query.as_ref(), analysis);
         }
     }
 
@@ -79,32 +686,39 @@ impl QueryWrapper {
         }
 
         // Analyze ORDER BY
-        for order in &query.order_by {
-            analysis.order_by.push(order.to_string());
+        if let Some(order_by) = &query.order_by {
+            for order in &order_by.exprs {
+                analysis.order_by.push(order.to_string());
+            }
         }
 
         // Analyze LIMIT and OFFSET
-        if let Some(limit) = &query.limit {
-            if let Expr::Value(sqlparser::ast::Value::Number(n, _)) = limit {
-                if let Ok(limit_value) = n.parse::<u64>() {
-                    analysis.limit = Some(limit_value);
-                }
+        if let Some(Expr::Value(Value::Number(n, _))) = &query.limit {
+            if let Ok(limit_value) = n.parse::<u64>() {
+                analysis.limit = Some(limit_value);
             }
         }
-        if let Some(offset) = &query.offset {
-            if let Expr::Value(sqlparser::ast::Value::Number(n, _)) = &offset.value {
-                if let Ok(offset_value) = n.parse::<u64>() {
-                    analysis.offset = Some(offset_value);
-                }
+        if let Some(Offset {
+            value: Expr::Value(Value::Number(n, _)),
+            ..
+        }) = &query.offset
+        {
+            if let Ok(offset_value) = n.parse::<u64>() {
+                analysis.offset = Some(offset_value);
             }
         }
     }
 
     fn analyze_select(&self, select: &Select, analysis: &mut QueryAnalysis) {
         // Analyze FROM clause
-        for table_with_joins in &select.from {
-            self.analyze_from(table_with_joins, analysis);
-        }
+        let names: Vec<Vec<String>> = select
+            .from
+            .iter()
+            .map(|table_with_joins| self.analyze_from(table_with_joins, analysis))
+            .collect();
+        analysis
+            .warnings
+            .extend(warnings::join_warnings(select, &names));
 
         // Analyze SELECT items
         for item in &select.projection {
@@ -114,7 +728,7 @@ impl QueryWrapper {
         // Analyze WHERE clause
         if let Some(where_clause) = &select.selection {
             self.analyze_expr(where_clause, analysis);
-            analysis.conditions.push(where_clause.to_string());
+            analysis.where_conditions.push(where_clause.to_string());
         }
 
         // Analyze GROUP BY
@@ -129,6 +743,9 @@ impl QueryWrapper {
                 for expr in exprs {
                     self.analyze_expr(expr, analysis);
                     analysis.aggregations.push(expr.to_string());
+                    if let Some(modifier) = Self::grouping_modifier(expr, analysis) {
+                        analysis.grouping_modifiers.push(modifier);
+                    }
                 }
                 for modifier in modifiers {
                     analysis.aggregations.push(modifier.to_string());
@@ -139,16 +756,37 @@ impl QueryWrapper {
         // Analyze HAVING
         if let Some(having) = &select.having {
             self.analyze_expr(having, analysis);
-            analysis.conditions.push(having.to_string());
+            analysis.having_conditions.push(having.to_string());
+        }
+
+        // Analyze QUALIFY
+        if let Some(qualify) = &select.qualify {
+            self.analyze_expr(qualify, analysis);
+            analysis.qualify = Some(qualify.to_string());
         }
     }
 
-    fn analyze_from(&self, table_with_joins: &TableWithJoins, analysis: &mut QueryAnalysis) {
-        analysis
-            .tables
-            .insert(table_with_joins.relation.to_string());
+    /// Returns the names of the relations joined, in order.
+    fn analyze_from(
+        &self,
+        table_with_joins: &TableWithJoins,
+        analysis: &mut QueryAnalysis,
+    ) -> Vec<String> {
+        let mut names = vec![self.analyze_relation(&table_with_joins.relation, analysis)];
         for join in &table_with_joins.joins {
-            analysis.tables.insert(join.relation.to_string());
+            names.push(self.analyze_relation(&join.relation, analysis));
+            // An APPLY'd subquery is lateral without saying so.
+            if let (
+                JoinOperator::CrossApply | JoinOperator::OuterApply,
+                TableFactor::Derived {
+                    lateral: false,
+                    subquery,
+                    ..
+                },
+            ) = (&join.join_operator, &join.relation)
+            {
+                self.analyze_lateral(subquery, analysis);
+            }
             analysis.joins.push(format!("{:?}", join.join_operator));
 
             match &join.join_operator {
@@ -174,6 +812,260 @@ impl QueryWrapper {
                 }
             }
         }
+        names
+    }
+
+    /// Records `relation` under its real name and its alias, if any, as
+    /// standing for it, and returns the name.
+    fn analyze_relation(&self, relation: &TableFactor, analysis: &mut QueryAnalysis) -> String {
+        let (name, alias) = match relation {
+            // DuckDB's table functions, `unnest(list)` included, parse as
+            // tables with arguments.
+            TableFactor::Table {
+                name,
+                args: Some(args),
+                alias,
+                ..
+            } => {
+                let function = name.to_string().to_lowercase();
+                for arg in &args.args {
+                    self.analyze_function_arg(arg, analysis);
+                }
+                analysis
+                    .table_functions
+                    .push(TableFunctionCall::new(function.clone(), &args.args));
+                match schema::relation_path(relation) {
+                    Some(path) if reads_paths(&function) && function != "glob" => (path, alias),
+                    _ if function == "unnest" => {
+                        return Self::produced_relation(relation, "unnest", alias, analysis)
+                    }
+                    _ => return Self::produced_relation(relation, "function", alias, analysis),
+                }
+            }
+            TableFactor::Table { name, alias, .. } => (
+                schema::relation_path(relation).unwrap_or_else(|| analysis.normalize_name(&name.0)),
+                alias,
+            ),
+            TableFactor::Derived {
+                lateral,
+                subquery,
+                alias,
+            } => {
+                let name = match alias {
+                    Some(alias) => format!("__derived_{}", analysis.normalize(&alias.name)),
+                    None => format!("__derived_{}", analysis.derived_tables.len()),
+                };
+                analysis
+                    .derived_tables
+                    .insert(name.clone(), subquery.as_ref().clone());
+                if *lateral {
+                    self.analyze_lateral(subquery, analysis);
+                }
+                (name, alias)
+            }
+            TableFactor::UNNEST {
+                alias, array_exprs, ..
+            } => {
+                for expr in array_exprs {
+                    self.analyze_expr(expr, analysis);
+                }
+                return Self::produced_relation(relation, "unnest", alias, analysis);
+            }
+            TableFactor::Function {
+                name, args, alias, ..
+            } => {
+                for arg in args {
+                    self.analyze_function_arg(arg, analysis);
+                }
+                analysis.table_functions.push(TableFunctionCall::new(
+                    name.to_string().to_lowercase(),
+                    args,
+                ));
+                return Self::produced_relation(relation, "function", alias, analysis);
+            }
+            TableFactor::Pivot {
+                table,
+                aggregate_functions,
+                value_column,
+                value_source,
+                alias,
+                ..
+            } => {
+                let name = self.analyze_relation(table, analysis);
+                for aggregate in aggregate_functions {
+                    self.analyze_expr(&aggregate.expr, analysis);
+                }
+                let pivot_column = match value_column.as_slice() {
+                    [column] => Expr::Identifier(column.clone()),
+                    idents => Expr::CompoundIdentifier(idents.to_vec()),
+                };
+                self.analyze_expr(&pivot_column, analysis);
+                let value_columns = match value_source {
+                    PivotValueSource::List(values) => values
+                        .iter()
+                        .map(|value| match (&value.alias, &value.expr) {
+                            (Some(alias), _) => analysis.normalize(alias),
+                            (None, Expr::Value(Value::SingleQuotedString(value))) => value.clone(),
+                            (None, expr) => expr.to_string(),
+                        })
+                        .collect(),
+                    PivotValueSource::Any(_) | PivotValueSource::Subquery(_) => Vec::new(),
+                };
+                let pivot_column = analysis.normalize_name(value_column);
+                analysis.pivots.push(PivotInfo::Pivot {
+                    table: name.clone(),
+                    pivot_column,
+                    aggregates: aggregate_functions
+                        .iter()
+                        .map(|aggregate| aggregate.to_string())
+                        .collect(),
+                    value_columns,
+                });
+                (name, alias)
+            }
+            TableFactor::Unpivot {
+                table,
+                value,
+                name: name_column,
+                columns,
+                alias,
+            } => {
+                let name = self.analyze_relation(table, analysis);
+                for column in columns {
+                    self.analyze_expr(&Expr::Identifier(column.clone()), analysis);
+                }
+                let unpivot = PivotInfo::Unpivot {
+                    table: name.clone(),
+                    value_column: analysis.normalize(value),
+                    name_column: analysis.normalize(name_column),
+                    columns: columns
+                        .iter()
+                        .map(|column| analysis.normalize(column))
+                        .collect(),
+                };
+                analysis.pivots.push(unpivot);
+                (name, alias)
+            }
+            relation => (relation.to_string(), &None),
+        };
+        if let Some(alias) = alias {
+            let alias = analysis.normalize(&alias.name);
+            analysis.aliases.insert(alias, name.clone());
+        }
+        analysis.tables.insert(name.clone());
+        name
+    }
+
+    /// Records `alias` as standing for the rows a table function such as
+    /// UNNEST produces from the relations before it, which is no relation
+    /// read, and returns their name: `__` and `kind`, then `_` and the
+    /// normalized alias, or the SQL without an alias.
+    fn produced_relation(
+        relation: &TableFactor,
+        kind: &str,
+        alias: &Option<TableAlias>,
+        analysis: &mut QueryAnalysis,
+    ) -> String {
+        let Some(alias) = alias else {
+            return relation.to_string();
+        };
+        let alias = analysis.normalize(&alias.name);
+        let name = format!("__{}_{}", kind, alias);
+        analysis.aliases.insert(alias, name.clone());
+        name
+    }
+
+    /// Analyzes a lateral subquery, which may refer to the relations before
+    /// it, for the relations and columns it reads. Its clauses stay its own.
+    fn analyze_lateral(&self, subquery: &SqlQuery, analysis: &mut QueryAnalysis) {
+        let mut lateral = QueryAnalysis {
+            aliases: analysis.aliases.clone(),
+            ..QueryAnalysis::default()
+        };
+        self.analyze_query(subquery, &mut lateral);
+        analysis.tables.extend(lateral.tables);
+        analysis.columns.extend(lateral.columns);
+        analysis.table_functions.extend(lateral.table_functions);
+        for (normalized, spelling) in lateral.spellings {
+            analysis.spellings.entry(normalized).or_insert(spelling);
+        }
+    }
+
+    /// `expr` as a [`GroupingModifier`], or `None` for a plain GROUP BY key.
+    fn grouping_modifier(expr: &Expr, analysis: &mut QueryAnalysis) -> Option<GroupingModifier> {
+        let (sets, modifier): (_, fn(_) -> _) = match expr {
+            Expr::Rollup(sets) => (sets, GroupingModifier::Rollup),
+            Expr::Cube(sets) => (sets, GroupingModifier::Cube),
+            Expr::GroupingSets(sets) => (sets, GroupingModifier::GroupingSets),
+            _ => return None,
+        };
+        let sets = sets
+            .iter()
+            .map(|set| {
+                set.iter()
+                    .map(|expr| Self::grouping_key(expr, analysis))
+                    .collect()
+            })
+            .collect();
+        Some(modifier(sets))
+    }
+
+    fn grouping_key(expr: &Expr, analysis: &mut QueryAnalysis) -> String {
+        match expr {
+            Expr::Identifier(column) => analysis.normalize(column),
+            Expr::CompoundIdentifier(idents) => match idents.split_last() {
+                Some((column, qualifier)) => Self::qualified_column(qualifier, column, analysis),
+                None => expr.to_string(),
+            },
+            _ => expr.to_string(),
+        }
+    }
+
+    /// See [`QueryAnalysis::grouping_sets`].
+    fn grouping_sets(&self, analysis: &mut QueryAnalysis) -> Vec<Vec<String>> {
+        let Some(GroupByExpr::Expressions(exprs, _)) = self.group_by() else {
+            return Vec::new();
+        };
+        let mut sets: Vec<Vec<String>> = vec![Vec::new()];
+        let mut modified = false;
+        for expr in exprs {
+            let expansion = match Self::grouping_modifier(expr, analysis) {
+                Some(modifier) => {
+                    modified = true;
+                    modifier.sets()
+                }
+                None => vec![vec![Self::grouping_key(expr, analysis)]],
+            };
+            sets = sets
+                .iter()
+                .flat_map(|set| {
+                    expansion
+                        .iter()
+                        .map(move |keys| set.iter().chain(keys).cloned().collect())
+                })
+                .collect();
+        }
+        if modified {
+            sets
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// `qualifier.column`, with `qualifier` replaced by the table it aliases.
+    fn qualified_column(
+        qualifier: &[Ident],
+        column: &Ident,
+        analysis: &mut QueryAnalysis,
+    ) -> String {
+        let qualifier = match qualifier {
+            [alias] => {
+                let alias = analysis.normalize(alias);
+                analysis.aliases.get(&alias).cloned().unwrap_or(alias)
+            }
+            idents => analysis.normalize_name(idents),
+        };
+        format!("{}.{}", qualifier, analysis.normalize(column))
     }
 
     fn analyze_join_constraint(&self, constraint: &JoinConstraint, analysis: &mut QueryAnalysis) {
@@ -183,7 +1075,8 @@ impl QueryWrapper {
             }
             JoinConstraint::Using(idents) => {
                 for ident in idents {
-                    analysis.columns.insert(ident.value.clone());
+                    let column = analysis.normalize(ident);
+                    analysis.columns.insert(column);
                 }
             }
             JoinConstraint::Natural => {
@@ -200,22 +1093,82 @@ impl QueryWrapper {
             SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
                 self.analyze_expr(expr, analysis);
             }
-            SelectItem::QualifiedWildcard(name, _) => {
-                analysis.columns.insert(name.to_string());
+            SelectItem::QualifiedWildcard(name, options) => {
+                let column = Self::qualified_column(&name.0, &Ident::new("*"), analysis);
+                analysis.columns.insert(column);
+                self.analyze_wildcard_options(&name.0, options, analysis);
             }
-            SelectItem::Wildcard(_) => {
+            SelectItem::Wildcard(options) => {
                 analysis.columns.insert("*".to_string());
+                self.analyze_wildcard_options(&[], options, analysis);
             }
         }
     }
 
+    /// Records the columns a wildcard excludes or replaces, qualified with
+    /// `qualifier` when it has one.
+    fn analyze_wildcard_options(
+        &self,
+        qualifier: &[Ident],
+        options: &WildcardAdditionalOptions,
+        analysis: &mut QueryAnalysis,
+    ) {
+        let column = |column: &Ident, analysis: &mut QueryAnalysis| match qualifier {
+            [] => analysis.normalize(column),
+            qualifier => Self::qualified_column(qualifier, column, analysis),
+        };
+
+        let excluded = match &options.opt_exclude {
+            Some(ExcludeSelectItem::Single(ident)) => std::slice::from_ref(ident),
+            Some(ExcludeSelectItem::Multiple(idents)) => idents.as_slice(),
+            None => &[],
+        };
+        // ClickHouse's `* EXCEPT (...)` spelling.
+        let excepted = options.opt_except.iter().flat_map(|except| {
+            std::iter::once(&except.first_element).chain(&except.additional_elements)
+        });
+        for ident in excluded.iter().chain(excepted) {
+            let excluded = column(ident, analysis);
+            analysis.excluded_columns.push(excluded);
+        }
+
+        for element in options
+            .opt_replace
+            .iter()
+            .flat_map(|replace| &replace.items)
+        {
+            self.analyze_expr(&element.expr, analysis);
+            let replaced = column(&element.column_name, analysis);
+            analysis
+                .replaced_columns
+                .insert(replaced, element.expr.to_string());
+        }
+    }
+
     fn analyze_expr(&self, expr: &Expr, analysis: &mut QueryAnalysis) {
         match expr {
             Expr::Identifier(col) => {
-                analysis.columns.insert(col.value.clone());
+                let column = analysis.normalize(col);
+                analysis.columns.insert(column);
             }
-            Expr::Function(Function { name, args, .. }) => {
+            Expr::CompoundIdentifier(idents) => {
+                if let Some((column, qualifier)) = idents.split_last() {
+                    let column = Self::qualified_column(qualifier, column, analysis);
+                    analysis.columns.insert(column);
+                }
+            }
+            Expr::Function(Function {
+                name, args, over, ..
+            }) => {
                 analysis.aggregations.push(name.to_string());
+                if let Some(WindowType::WindowSpec(spec)) = over {
+                    for expr in &spec.partition_by {
+                        self.analyze_expr(expr, analysis);
+                    }
+                    for order in &spec.order_by {
+                        self.analyze_expr(&order.expr, analysis);
+                    }
+                }
                 match args {
                     FunctionArguments::None => {}
                     FunctionArguments::Subquery(query) => {
@@ -223,14 +1176,7 @@ impl QueryWrapper {
                     }
                     FunctionArguments::List(arg_list) => {
                         for arg in &arg_list.args {
-                            match arg {
-                                FunctionArg::Unnamed(arg_expr) => {
-                                    self.analyze_function_arg_expr(arg_expr, analysis);
-                                }
-                                FunctionArg::Named { arg, .. } => {
-                                    self.analyze_function_arg_expr(arg, analysis);
-                                }
-                            }
+                            self.analyze_function_arg(arg, analysis);
                         }
                     }
                 }
@@ -239,16 +1185,44 @@ impl QueryWrapper {
                 self.analyze_expr(left, analysis);
                 self.analyze_expr(right, analysis);
             }
+            Expr::Case {
+                operand,
+                conditions,
+                results,
+                else_result,
+            } => {
+                analysis.case_expressions += 1;
+                for expr in operand.iter().chain(else_result.iter()) {
+                    self.analyze_expr(expr, analysis);
+                }
+                for expr in conditions.iter().chain(results.iter()) {
+                    self.analyze_expr(expr, analysis);
+                }
+            }
+            Expr::Rollup(sets) | Expr::Cube(sets) | Expr::GroupingSets(sets) => {
+                for expr in sets.iter().flatten() {
+                    self.analyze_expr(expr, analysis);
+                }
+            }
             // Add more cases as needed for other expression types
             _ => {}
         }
     }
 
+    fn analyze_function_arg(&self, arg: &FunctionArg, analysis: &mut QueryAnalysis) {
+        match arg {
+            FunctionArg::Unnamed(arg_expr) | FunctionArg::Named { arg: arg_expr, .. } => {
+                self.analyze_function_arg_expr(arg_expr, analysis);
+            }
+        }
+    }
+
     fn analyze_function_arg_expr(&self, arg_expr: &FunctionArgExpr, analysis: &mut QueryAnalysis) {
         match arg_expr {
             FunctionArgExpr::Expr(expr) => self.analyze_expr(expr, analysis),
             FunctionArgExpr::QualifiedWildcard(object_name) => {
-                analysis.columns.insert(object_name.to_string() + ".*");
+                let column = Self::qualified_column(&object_name.0, &Ident::new("*"), analysis);
+                analysis.columns.insert(column);
             }
             FunctionArgExpr::Wildcard => {
                 analysis.columns.insert("*".to_string());
@@ -260,106 +1234,713 @@ impl QueryWrapper {
         self.sql = self.sql.replace(old, new);
     }
 
-    pub fn list_of_prefixes(&mut self) -> Result<&Vec<String>, QueryError> {
-        if self.list_of_prefixes.is_none() {
-            let prefixes = self.scan_source_for_prefixes()?;
-            self.list_of_prefixes = Some(prefixes);
-        }
-        Ok(self.list_of_prefixes.as_ref().unwrap())
+    /// Replaces the outer query's LIMIT, or removes it when `limit` is `None`.
+    pub fn set_limit(&mut self, limit: Option<u64>) -> Result<(), QueryError> {
+        self.query_mut()?.limit = limit.map(Self::number);
+        self.rerender();
+        Ok(())
     }
 
-    pub fn tables(&self) -> Vec<&TableFactor> {
-        let mut tables = Vec::new();
-        if let Statement::Query(query) = &self.ast {
-            if let SetExpr::Select(select) = query.body.as_ref() {
-                for TableWithJoins { relation, joins } in &select.from {
-                    tables.push(relation);
-                    for join in joins {
-                        tables.push(&join.relation);
-                    }
-                }
-            }
-        }
-        tables
+    /// Replaces the outer query's OFFSET, or removes it when `offset` is `None`.
+    pub fn set_offset(&mut self, offset: Option<u64>) -> Result<(), QueryError> {
+        self.query_mut()?.offset = offset.map(|value| Offset {
+            value: Self::number(value),
+            rows: OffsetRows::None,
+        });
+        self.rerender();
+        Ok(())
     }
 
-    pub fn bucket(&self) -> Result<String, QueryError> {
-        lazy_static! {
-            static ref BUCKET_RE: Regex = Regex::new(r"s3://([A-Za-z0-9_-]+)").unwrap();
-        }
-
-        for table in self.tables() {
-            if let Some(captures) = BUCKET_RE.captures(&table.to_string()) {
-                return Ok(format!("s3://{}", &captures[1]));
-            }
-        }
+    /// Removes the outer query's ORDER BY, e.g. for per-partition fragments that
+    /// are re-sorted at merge time.
+    pub fn strip_order_by(&mut self) -> Result<(), QueryError> {
+        self.query_mut()?.order_by = None;
+        self.rerender();
+        Ok(())
+    }
 
-        Err(QueryError::InvalidFilesystem(
-            "Not able to locate any bucket name in query".to_string(),
-        ))
+    /// Removes the outer query's LIMIT.
+    pub fn strip_limit(&mut self) -> Result<(), QueryError> {
+        self.set_limit(None)
     }
 
-    pub fn source(&self) -> Result<String, QueryError> {
-        for table in self.tables() {
-            if let TableFactor::Table { name, .. } = table {
-                // Assuming the source is always the first (and only) part of the table name
-                if name.0.len() == 1 {
-                    return Ok(name.0[0].value.clone());
-                }
-            }
+    /// The outermost query node. For set operations this is the query wrapping
+    /// the whole UNION/INTERSECT/EXCEPT, so clauses apply to the combined result.
+    fn query_mut(&mut self) -> Result<&mut SqlQuery, QueryError> {
+        match &mut self.ast {
+            Statement::Query(query) => Ok(query.as_mut()),
+            _ => Err(QueryError::Other(
+                "Statement is not a query and has no LIMIT/OFFSET/ORDER BY".to_string(),
+            )),
         }
-        Err(QueryError::Other("No source found in query".to_string()))
     }
 
-    pub fn scan_source_for_prefixes(&self) -> Result<Vec<String>, QueryError> {
-        let conn = Connection::open_in_memory()?;
-        conn.execute_batch("INSTALL httpfs; LOAD httpfs;")?;
+    /// Re-renders `sql` (and its hash) from the AST after a mutation.
+    fn rerender(&mut self) {
+        self.sql = self.strip_comments();
+        self.hashed = Self::versioned_hash(&self.sql, HashAlgo::Sha256);
+    }
 
-        let source = self.source()?;
-        let glob_query = format!(
-            "SELECT DISTINCT CONCAT(REGEXP_REPLACE(file, '/[^/]+$', ''), '/*') AS prefix FROM GLOB('{}')",
-            source
-        );
+    /// The SQL rendered back from the parsed statements, which leaves out
+    /// any comments along with the original spacing. Comments don't change
+    /// what a query does, so this is the text to log or hash.
+    pub fn strip_comments(&self) -> String {
+        std::iter::once(&self.ast)
+            .chain(&self.trailing)
+            .map(|statement| statement.to_string())
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
 
-        let mut stmt = conn.prepare(&glob_query)?;
-        let prefixes: Vec<String> = stmt
-            .query_map([], |row| row.get(0))?
-            .collect::<DuckResult<Vec<String>>>()?;
+    fn number(value: u64) -> Expr {
+        Expr::Value(Value::Number(value.to_string(), false))
+    }
 
-        Ok(prefixes)
+    pub async fn list_of_prefixes(&mut self) -> Result<&Vec<String>, QueryError> {
+        self.list_of_prefixes_with(&PrefixScanner::shared()).await
     }
 
-    pub fn parquet_files(&self) -> Vec<String> {
-        lazy_static! {
-            static ref PARQUET_RE: Regex = Regex::new(r"'([^']+\.parquet)'").unwrap();
+    /// Like [`list_of_prefixes`](Self::list_of_prefixes), scanning on `scanner`.
+    pub async fn list_of_prefixes_with(
+        &mut self,
+        scanner: &PrefixScanner,
+    ) -> Result<&Vec<String>, QueryError> {
+        if self.list_of_prefixes.is_none() {
+            let prefixes = scanner.scan(self, &ScanConfig::from_env()).await?;
+            self.list_of_prefixes = Some(prefixes);
         }
+        Ok(self.list_of_prefixes.as_ref().unwrap())
+    }
 
-        let mut files = Vec::new();
-        for table in self.tables() {
-            let table_str = table.to_string();
-            for cap in PARQUET_RE.captures_iter(&table_str) {
-                files.push(cap[1].to_string());
-            }
+    /// The SQL DuckDB will run, reflecting any changes made since parsing.
+    pub fn sql(&self) -> &str {
+        &self.sql
+    }
+
+    /// The key a query is cached by: the SHA-256 of [`sql`](Self::sql),
+    /// which changes along with it, as `v2:sha256:<hex>`. Being taken after
+    /// comments are stripped, it is the same for queries differing only in
+    /// comments.
+    pub fn hash(&self) -> &str {
+        &self.hashed
+    }
+
+    /// Like [`hash`](Self::hash) with `algo`, as `v2:<algo>:<hex>` where
+    /// `algo` is `sha256` or `xxh64`.
+    pub fn hash_with(&self, algo: HashAlgo) -> String {
+        match algo {
+            HashAlgo::Sha256 => self.hashed.clone(),
+            HashAlgo::XxHash64 => Self::versioned_hash(&self.sql, algo),
         }
-        files
     }
 
-    fn unify_query(query: &str) -> Result<String, QueryError> {
-        // For now, we'll just return the original query
-        // In a real implementation, you'd want to use a SQL formatter here
-        Ok(query.to_string())
+    /// The key from before hashes were versioned: the bare hex SHA-256 of
+    /// [`raw_sql`](Self::raw_sql), comments included, for finding entries
+    /// cached under it while migrating to [`hash`](Self::hash).
+    pub fn hash_v1(&self) -> String {
+        Self::create_hash_string(&self.raw)
     }
 
-    fn create_hash_string(s: &str) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(s.as_bytes());
-        format!("{:x}", hasher.finalize())
+    /// The parsed statement, the first of a batch, for callers walking the
+    /// AST themselves.
+    pub fn ast(&self) -> &Statement {
+        &self.ast
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// The outer query's SELECT, unless it is a set operation or not a query.
+    fn outer_select(&self) -> Option<&Select> {
+        match &self.ast {
+            Statement::Query(query) => match query.body.as_ref() {
+                SetExpr::Select(select) => Some(select),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The outer query's WHERE predicate, i.e. the filter every partition scan must
+    /// apply. HAVING is deliberately excluded since it runs after aggregation.
+    pub fn where_clause(&self) -> Option<&Expr> {
+        self.outer_select()?.selection.as_ref()
+    }
+
+    /// The outer query's HAVING predicate, applied to groups after aggregation.
+    pub fn having(&self) -> Option<&Expr> {
+        self.outer_select()?.having.as_ref()
+    }
+
+    /// The outer SELECT list; empty for set operations and non-queries.
+    pub fn projection(&self) -> &[SelectItem] {
+        self.outer_select()
+            .map_or(&[], |select| select.projection.as_slice())
+    }
+
+    /// The outer query's GROUP BY, or `None` for set operations and non-queries.
+    pub fn group_by(&self) -> Option<&GroupByExpr> {
+        Some(&self.outer_select()?.group_by)
+    }
+
+    /// The outer query's ORDER BY terms.
+    pub fn order_by(&self) -> &[OrderByExpr] {
+        match &self.ast {
+            Statement::Query(query) => query
+                .order_by
+                .as_ref()
+                .map_or(&[], |order_by| order_by.exprs.as_slice()),
+            _ => &[],
+        }
+    }
+
+    /// Aggregate calls that are whole items of the outer SELECT list, with the
+    /// column each produces: its alias, or the call's SQL text. Aggregates
+    /// nested in other expressions and window functions are left out.
+    pub fn aggregate_columns(&self) -> Vec<(&Function, String)> {
+        self.projection()
+            .iter()
+            .filter_map(|item| match item {
+                SelectItem::UnnamedExpr(Expr::Function(func))
+                    if decompose::is_aggregate_call(func) =>
+                {
+                    Some((func, func.to_string()))
+                }
+                SelectItem::ExprWithAlias {
+                    expr: Expr::Function(func),
+                    alias,
+                } if decompose::is_aggregate_call(func) => Some((func, alias.value.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Whether the query aggregates, reads nothing or returns rows. A query
+    /// without a FROM clause is [`QueryKind::Scalar`] even when it calls an
+    /// aggregate, since there is nothing to partition.
+    ///
+    /// Only the outer SELECT is looked at: its GROUP BY, its HAVING and the
+    /// aggregates in its SELECT list. A query that filters on an aggregating
+    /// subquery or reads an aggregating CTE still returns rows. Set operations
+    /// are [`QueryKind::Rows`]; see
+    /// [`split_set_operation`](Self::split_set_operation) to classify each
+    /// branch.
+    pub fn query_kind(&self) -> QueryKind {
+        if !self.has_from() {
+            QueryKind::Scalar
+        } else if self
+            .outer_select()
+            .is_some_and(distribute::is_aggregate_select)
+        {
+            QueryKind::Aggregate
+        } else {
+            QueryKind::Rows
+        }
+    }
+
+    /// The aliases given to the outer FROM clause's tables and paths,
+    /// lowercased, each mapped to the table name or path it stands for.
+    pub fn table_aliases(&self) -> HashMap<String, String> {
+        self.tables()
+            .into_iter()
+            .filter_map(|table| match table {
+                TableFactor::Table {
+                    name,
+                    alias: Some(alias),
+                    ..
+                } => {
+                    let target = schema::relation_path(table).unwrap_or_else(|| name.to_string());
+                    Some((alias.name.value.to_lowercase(), target))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The schemas qualifying relation names anywhere in the batch, as
+    /// written: `sales` for `sales.orders` and `warehouse.sales.orders`.
+    pub fn referenced_schemas(&self) -> HashSet<String> {
+        self.name_qualifiers(2)
+    }
+
+    /// The catalogs qualifying three-part relation names anywhere in the
+    /// batch, as written: `warehouse` for `warehouse.sales.orders`.
+    pub fn referenced_catalogs(&self) -> HashSet<String> {
+        self.name_qualifiers(3)
+    }
+
+    /// The part `from_end` places from the end of every relation name with
+    /// that many parts.
+    fn name_qualifiers(&self, from_end: usize) -> HashSet<String> {
+        let mut qualifiers = HashSet::new();
+        for statement in std::iter::once(&self.ast).chain(&self.trailing) {
+            let _ = sqlparser::ast::visit_relations(statement, |name| {
+                if let Some(index) = name.0.len().checked_sub(from_end) {
+                    qualifiers.insert(name.0[index].value.clone());
+                }
+                std::ops::ControlFlow::<()>::Continue(())
+            });
+        }
+        qualifiers
+    }
+
+    /// The relations in the outer FROM clause, joined ones included. A PIVOT
+    /// or UNPIVOT is represented by the relation it reshapes.
+    pub fn tables(&self) -> Vec<&TableFactor> {
+        let mut tables = Vec::new();
+        if let Some(select) = self.outer_select() {
+            for TableWithJoins { relation, joins } in &select.from {
+                tables.push(unpivoted(relation));
+                for join in joins {
+                    tables.push(unpivoted(&join.relation));
+                }
+            }
+        }
+        tables
+    }
+
+    /// Whether any SELECT in the query, subqueries included, has a FROM
+    /// clause. Without one, as in `SELECT 42` or `SELECT now()`, the query
+    /// reads nothing: [`tables`](Self::tables) is empty,
+    /// [`source`](Self::source) is an empty string and prefix scans find no
+    /// prefixes.
+    pub fn has_from(&self) -> bool {
+        struct FindRelation;
+
+        impl Visitor for FindRelation {
+            type Break = ();
+
+            fn pre_visit_table_factor(&mut self, _: &TableFactor) -> ControlFlow<()> {
+                ControlFlow::Break(())
+            }
+        }
+
+        std::iter::once(&self.ast)
+            .chain(&self.trailing)
+            .any(|statement| statement.visit(&mut FindRelation).is_break())
+    }
+
+    /// Whether any SELECT, subqueries included, joins a relation that reads
+    /// the ones before it: a `LATERAL` subquery or table function, or a
+    /// subquery under CROSS or OUTER APPLY. Such a join runs once per left
+    /// row.
+    pub fn contains_lateral_join(&self) -> bool {
+        struct FindLateral;
+
+        impl Visitor for FindLateral {
+            type Break = ();
+
+            fn pre_visit_table_factor(&mut self, relation: &TableFactor) -> ControlFlow<()> {
+                match relation {
+                    TableFactor::Derived { lateral: true, .. }
+                    | TableFactor::Function { lateral: true, .. } => ControlFlow::Break(()),
+                    _ => ControlFlow::Continue(()),
+                }
+            }
+
+            fn pre_visit_query(&mut self, query: &SqlQuery) -> ControlFlow<()> {
+                let SetExpr::Select(select) = query.body.as_ref() else {
+                    return ControlFlow::Continue(());
+                };
+                let apply = select.from.iter().flat_map(|from| &from.joins).any(|join| {
+                    matches!(
+                        join.join_operator,
+                        JoinOperator::CrossApply | JoinOperator::OuterApply
+                    )
+                });
+                if apply {
+                    return ControlFlow::Break(());
+                }
+                ControlFlow::Continue(())
+            }
+        }
+
+        std::iter::once(&self.ast)
+            .chain(&self.trailing)
+            .any(|statement| statement.visit(&mut FindLateral).is_break())
+    }
+
+    pub fn bucket(&self) -> Result<String, QueryError> {
+        lazy_static! {
+            static ref BUCKET_RE: Regex = Regex::new(r"s3://([A-Za-z0-9_-]+)").unwrap();
+        }
+
+        for table in self
+            .tables()
+            .into_iter()
+            .filter(|table| reads_source(table))
+        {
+            if let Some(captures) = BUCKET_RE.captures(&table.to_string()) {
+                return Ok(format!("s3://{}", &captures[1]));
+            }
+        }
+
+        Err(QueryError::InvalidFilesystem(
+            "Not able to locate any bucket name in query".to_string(),
+        ))
+    }
+
+    /// The first relation's name or path; for reader functions such as
+    /// `read_parquet('s3://...')` and `glob`, the path they read. Other table
+    /// functions, such as `range`, are skipped. Qualified names such as
+    /// `sales.orders` come back dotted, without quotes. A query with no FROM
+    /// clause has an empty source; see [`has_from`](Self::has_from).
+    pub fn source(&self) -> Result<String, QueryError> {
+        if !self.has_from() {
+            return Ok(String::new());
+        }
+        for table in self.tables() {
+            match table {
+                TableFactor::Table {
+                    name,
+                    args: Some(args),
+                    ..
+                } => {
+                    if !reads_paths(&name.to_string()) {
+                        continue;
+                    }
+                    let path = args.args.iter().find_map(|arg| match arg {
+                        FunctionArg::Unnamed(FunctionArgExpr::Expr(Expr::Value(
+                            Value::SingleQuotedString(path),
+                        ))) => Some(path.clone()),
+                        _ => None,
+                    });
+                    if let Some(path) = path {
+                        return Ok(path);
+                    }
+                }
+                TableFactor::Table { name, .. } => {
+                    return Ok(name
+                        .0
+                        .iter()
+                        .map(|ident| ident.value.as_str())
+                        .collect::<Vec<_>>()
+                        .join("."));
+                }
+                _ => {}
+            }
+        }
+        Err(QueryError::Other("No source found in query".to_string()))
+    }
+
+    /// Files the query reads, with the format DuckDB will read them as.
+    ///
+    /// Paths passed to a `read_*` table function take the function's format
+    /// (unrecognised readers such as `read_text` give [`FileFormat::Unknown`]);
+    /// bare quoted paths are classified by extension.
+    pub fn file_sources(&self) -> Vec<(String, FileFormat)> {
+        lazy_static! {
+            static ref READER_RE: Regex =
+                Regex::new(r"(?i)\b(read_\w+|parquet_scan)\s*\(\s*(\[[^\]]*\]|'(?:[^']|'')*')")
+                    .unwrap();
+            static ref PATH_RE: Regex = Regex::new(r"'((?:[^']|'')*)'").unwrap();
+            static ref FILE_RE: Regex =
+                Regex::new(r"(?i)'((?:[^']|'')+\.(?:parquet|csv|json))'").unwrap();
+        }
+
+        let mut sources = Vec::new();
+        for table in self.tables() {
+            let table_str = table.to_string();
+            let mut reader_spans = Vec::new();
+            for cap in READER_RE.captures_iter(&table_str) {
+                let format = FileFormat::from_reader(&cap[1]);
+                for path in PATH_RE.captures_iter(&cap[2]) {
+                    sources.push((path[1].replace("''", "'"), format));
+                }
+                reader_spans.push(cap.get(0).unwrap().range());
+            }
+            for cap in FILE_RE.captures_iter(&table_str) {
+                let start = cap.get(0).unwrap().start();
+                if reader_spans.iter().any(|span| span.contains(&start)) {
+                    continue;
+                }
+                let path = cap[1].replace("''", "'");
+                let format = FileFormat::from_path(&path);
+                sources.push((path, format));
+            }
+        }
+        sources
+    }
+
+    #[deprecated(note = "use `file_sources()`, which also reports CSV and JSON sources")]
+    pub fn parquet_files(&self) -> Vec<String> {
+        self.file_sources()
+            .into_iter()
+            .filter(|(_, format)| *format == FileFormat::Parquet)
+            .map(|(path, _)| path)
+            .collect()
+    }
+
+    /// Locates a parse error in `sql`, using the line and column sqlparser takes
+    /// from the failing token. Errors without a location, such as an
+    /// unexpected end of input, point just past the last character.
+    fn parse_failure(sql: &str, err: sqlparser::parser::ParserError) -> QueryError {
+        lazy_static! {
+            static ref LOCATION_RE: Regex = Regex::new(r" at Line: (\d+), Column: (\d+)$").unwrap();
+        }
+
+        let text = match err {
+            sqlparser::parser::ParserError::TokenizerError(text)
+            | sqlparser::parser::ParserError::ParserError(text) => text,
+            other => return QueryError::SqlParseError(other),
+        };
+        let lines: Vec<&str> = sql.split('\n').collect();
+        let (message, line, column) = match LOCATION_RE.captures(&text) {
+            Some(captures) => (
+                text[..captures.get(0).unwrap().start()].to_string(),
+                captures[1].parse().unwrap_or(1),
+                captures[2].parse().unwrap_or(1),
+            ),
+            None => {
+                let last = lines.last().copied().unwrap_or_default();
+                (text.clone(), lines.len(), last.chars().count() + 1)
+            }
+        };
+
+        let line_text = lines
+            .get(line.saturating_sub(1))
+            .copied()
+            .unwrap_or_default();
+        let line_start: usize = lines
+            .iter()
+            .take(line.saturating_sub(1))
+            .map(|line| line.len() + 1)
+            .sum();
+        let column_offset = line_text
+            .char_indices()
+            .nth(column.saturating_sub(1))
+            .map(|(index, _)| index)
+            .unwrap_or(line_text.len());
+        let caret_indent: String = line_text
+            .chars()
+            .take(column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        QueryError::ParseFailed {
+            offset: line_start + column_offset,
+            line,
+            column,
+            snippet: format!("{}\n{}^", line_text, caret_indent),
+            message,
+        }
+    }
+
+    /// Splits the comments out of `query`, returning the SQL without them
+    /// and their text. The whitespace around a comment collapses into a
+    /// single space, so queries that differ only in comments, such as the
+    /// `/* trace_id=... */` a BI tool adds, hash the same.
+    fn unify_query(query: &str) -> (String, Vec<String>) {
+        let spans = comment_spans(query);
+        if spans.is_empty() {
+            return (query.to_string(), Vec::new());
+        }
+
+        let mut sql = String::new();
+        let mut push = |piece: &str| {
+            if !piece.is_empty() {
+                if !sql.is_empty() {
+                    sql.push(' ');
+                }
+                sql.push_str(piece);
+            }
+        };
+        let mut comments = Vec::new();
+        let mut rest = 0;
+        for span in spans {
+            let piece = &query[rest..span.start];
+            push(if rest > 0 {
+                piece.trim()
+            } else {
+                piece.trim_end()
+            });
+            let comment = &query[span.clone()];
+            let text = match comment.strip_prefix("/*") {
+                Some(block) => block.strip_suffix("*/").unwrap_or(block),
+                None => &comment[2..],
+            };
+            comments.push(text.trim().to_string());
+            rest = span.end;
+        }
+        push(query[rest..].trim_start());
+        (sql, comments)
+    }
+
+    /// The SQL as submitted, comments included; [`sql`](Self::sql) has them
+    /// stripped.
+    pub fn raw_sql(&self) -> &str {
+        &self.raw
+    }
+
+    /// The text of every comment in the submitted SQL, in order, without
+    /// `--` or `/* */` and trimmed.
+    pub fn comments(&self) -> &[String] {
+        &self.comments
+    }
+
+    /// The `key=value` pairs in a comment the SQL starts with, such as
+    /// `/* trace_id=abc, priority=low */`, for callers to use as execution
+    /// hints. Pairs are separated by whitespace or commas and values may be
+    /// quoted; anything else in the comment is ignored.
+    pub fn comment_hints(&self) -> HashMap<String, String> {
+        let leading = self.raw.trim_start();
+        if !leading.starts_with("--") && !leading.starts_with("/*") {
+            return HashMap::new();
+        }
+        let Some(comment) = self.comments.first() else {
+            return HashMap::new();
+        };
+        comment
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter_map(|pair| pair.split_once('='))
+            .filter(|(key, _)| !key.is_empty())
+            .map(|(key, value)| {
+                let value = value.trim_matches(|c| c == '\'' || c == '"');
+                (key.to_string(), value.to_string())
+            })
+            .collect()
+    }
+
+    /// The hex-encoded SHA-256 of `s`, unversioned; see
+    /// [`hash_v1`](Self::hash_v1).
+    pub fn create_hash_string(s: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(s.as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn versioned_hash(sql: &str, algo: HashAlgo) -> String {
+        let hex = match algo {
+            HashAlgo::Sha256 => Self::create_hash_string(sql),
+            HashAlgo::XxHash64 => format!("{:016x}", XxHash64::oneshot(0, sql.as_bytes())),
+        };
+        format!("{}:{}:{}", HASH_VERSION, algo.name(), hex)
+    }
+}
+
+/// The relation under any PIVOTs and UNPIVOTs applied to `relation`.
+fn unpivoted(relation: &TableFactor) -> &TableFactor {
+    match relation {
+        TableFactor::Pivot { table, .. } | TableFactor::Unpivot { table, .. } => unpivoted(table),
+        relation => relation,
+    }
+}
+
+/// Whether `relation` can name a source: any relation but a table function
+/// that reads no paths.
+fn reads_source(relation: &TableFactor) -> bool {
+    match relation {
+        TableFactor::Table {
+            name,
+            args: Some(_),
+            ..
+        } => reads_paths(&name.to_string()),
+        TableFactor::Function { .. } | TableFactor::UNNEST { .. } => false,
+        _ => true,
+    }
+}
+
+/// Splits `sql` on the semicolons that end statements, dropping fragments
+/// without any SQL in them.
+fn split_statements(sql: &str) -> Vec<&str> {
+    let mut fragments = Vec::new();
+    let mut start = 0;
+    let mut has_sql = false;
+    let mut chars = sql.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            // Doubled quotes inside a literal read as closing and reopening
+            // it, which leaves the state where it should be.
+            '\'' | '"' => {
+                has_sql = true;
+                for (_, next) in chars.by_ref() {
+                    if next == c {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.peek().map(|(_, next)| *next) == Some('-') => {
+                for (_, next) in chars.by_ref() {
+                    if next == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek().map(|(_, next)| *next) == Some('*') => {
+                chars.next();
+                let mut previous = None;
+                for (_, next) in chars.by_ref() {
+                    if previous == Some('*') && next == '/' {
+                        break;
+                    }
+                    previous = Some(next);
+                }
+            }
+            ';' => {
+                if has_sql {
+                    fragments.push(&sql[start..index]);
+                }
+                start = index + 1;
+                has_sql = false;
+            }
+            c if c.is_whitespace() => {}
+            _ => has_sql = true,
+        }
+    }
+    if has_sql {
+        fragments.push(&sql[start..]);
+    }
+    fragments
+}
+
+/// The byte ranges of the comments in `sql`, delimiters included: `--` up to
+/// the end of the line, or `/*` through `*/`.
+fn comment_spans(sql: &str) -> Vec<std::ops::Range<usize>> {
+    let mut spans = Vec::new();
+    let mut chars = sql.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                for (_, next) in chars.by_ref() {
+                    if next == c {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.peek().map(|(_, next)| *next) == Some('-') => {
+                let mut end = sql.len();
+                while let Some(&(next_index, next)) = chars.peek() {
+                    if next == '\n' {
+                        end = next_index;
+                        break;
+                    }
+                    chars.next();
+                }
+                spans.push(index..end);
+            }
+            '/' if chars.peek().map(|(_, next)| *next) == Some('*') => {
+                chars.next();
+                let mut end = sql.len();
+                let mut previous = None;
+                for (next_index, next) in chars.by_ref() {
+                    if previous == Some('*') && next == '/' {
+                        end = next_index + 1;
+                        break;
+                    }
+                    previous = Some(next);
+                }
+                spans.push(index..end);
+            }
+            _ => {}
+        }
+    }
+    spans
+}
+
+#[cfg(all(test, feature = "integration"))]
+mod integration_tests;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
 
     #[test]
     fn test_basic_select() {
@@ -459,6 +2040,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(deprecated)]
     fn test_select_from_parquet() {
         let query = "SELECT * FROM 's3://my-bucket/data/*.parquet'";
         let parsed = QueryWrapper::parse(query).unwrap();
@@ -471,6 +2053,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(deprecated)]
     fn test_select_from_multiple_parquet() {
         let query = "SELECT * FROM 's3://bucket1/data1.parquet', 's3://bucket2/data2.parquet'";
         let parsed = QueryWrapper::parse(query).unwrap();
@@ -483,6 +2066,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(deprecated)]
     fn test_select_parquet_with_join() {
         let query = "SELECT o.id, c.name FROM 's3://bucket1/orders.parquet' o JOIN 's3://bucket2/customers.parquet' c ON o.customer_id = c.id";
         let parsed = QueryWrapper::parse(query).unwrap();
@@ -497,6 +2081,881 @@ mod tests {
         assert_eq!(parsed.bucket().unwrap(), "s3://bucket1");
     }
 
+    #[test]
+    fn test_file_sources() {
+        let query = "SELECT * FROM read_csv('s3://bucket/a.csv') a \
+                     JOIN read_json_auto(['s3://bucket/b.json', 's3://bucket/c.json']) b ON a.id = b.id \
+                     JOIN 's3://bucket/d.parquet' d ON a.id = d.id \
+                     JOIN READ_PARQUET('s3://bucket/e/*') e ON a.id = e.id \
+                     JOIN read_text('s3://bucket/notes.txt') n ON a.id = n.id";
+        let parsed = QueryWrapper::parse(query).unwrap();
+        assert_eq!(
+            parsed.file_sources(),
+            vec![
+                ("s3://bucket/a.csv".to_string(), FileFormat::Csv),
+                ("s3://bucket/b.json".to_string(), FileFormat::Json),
+                ("s3://bucket/c.json".to_string(), FileFormat::Json),
+                ("s3://bucket/d.parquet".to_string(), FileFormat::Parquet),
+                ("s3://bucket/e/*".to_string(), FileFormat::Parquet),
+                ("s3://bucket/notes.txt".to_string(), FileFormat::Unknown),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_file_sources_bare_paths() {
+        let query = "SELECT * FROM 's3://bucket/events.json', 's3://bucket/users.CSV'";
+        let parsed = QueryWrapper::parse(query).unwrap();
+        assert_eq!(
+            parsed.file_sources(),
+            vec![
+                ("s3://bucket/events.json".to_string(), FileFormat::Json),
+                ("s3://bucket/users.CSV".to_string(), FileFormat::Csv),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_batch() {
+        let batch = QueryWrapper::parse_batch(
+            "SELECT 'a;b' AS \"x;y\" FROM t1; -- done; really\n\
+             ;; SELECT * FROM t2 /* first; */ WHERE s = 'it''s; fine';\n",
+        )
+        .unwrap();
+        let sql: Vec<&str> = batch.iter().map(|wrapper| wrapper.sql.as_str()).collect();
+        assert_eq!(
+            sql,
+            [
+                "SELECT 'a;b' AS \"x;y\" FROM t1",
+                "SELECT * FROM t2 WHERE s = 'it''s; fine'",
+            ]
+        );
+        assert_eq!(batch[1].comments(), ["first;"]);
+
+        // Hashes don't depend on where a statement sits in the batch.
+        let alone =
+            QueryWrapper::parse("SELECT * FROM t2 /* first; */ WHERE s = 'it''s; fine'").unwrap();
+        assert_eq!(batch[1].hashed, alone.hashed);
+        assert_ne!(batch[0].hashed, batch[1].hashed);
+
+        assert!(QueryWrapper::parse_batch(" ; -- nothing here\n").is_err());
+        assert!(QueryWrapper::parse_batch("SELECT 1; SELEC 2").is_err());
+    }
+
+    #[test]
+    fn test_accessors() {
+        let mut wrapper = QueryWrapper::parse("SELECT * FROM t; SELECT 2").unwrap();
+        assert_eq!(wrapper.sql(), "SELECT * FROM t; SELECT 2");
+        assert_eq!(
+            wrapper.hash(),
+            format!(
+                "v2:sha256:{}",
+                QueryWrapper::create_hash_string("SELECT * FROM t; SELECT 2")
+            )
+        );
+        assert!(matches!(wrapper.ast(), Statement::Query(_)));
+        assert_eq!(wrapper.ast().to_string(), "SELECT * FROM t");
+
+        wrapper.set_limit(Some(5)).unwrap();
+        assert_eq!(wrapper.sql(), "SELECT * FROM t LIMIT 5; SELECT 2");
+        assert_eq!(
+            wrapper.hash(),
+            format!(
+                "v2:sha256:{}",
+                QueryWrapper::create_hash_string(wrapper.sql())
+            )
+        );
+    }
+
+    #[test]
+    fn test_hash_versions() {
+        let plain = QueryWrapper::parse("SELECT * FROM t").unwrap();
+        let commented = QueryWrapper::parse("SELECT * FROM t -- nightly").unwrap();
+        assert_eq!(plain.hash(), plain.hash_with(HashAlgo::Sha256));
+
+        let xxh64 = plain.hash_with(HashAlgo::XxHash64);
+        assert!(xxh64.starts_with("v2:xxh64:"), "{}", xxh64);
+        assert_eq!(xxh64.len(), "v2:xxh64:".len() + 16);
+        assert_eq!(xxh64, commented.hash_with(HashAlgo::XxHash64));
+        assert_ne!(
+            xxh64,
+            QueryWrapper::parse("SELECT * FROM u")
+                .unwrap()
+                .hash_with(HashAlgo::XxHash64)
+        );
+
+        // v1 keys were the bare SHA-256 of the submitted text.
+        assert_eq!(
+            plain.hash_v1(),
+            "bdd379b02821e0bc9d5bab29094f054ce781e1cd351e51bd8f6329c0799638d4"
+        );
+        assert_ne!(commented.hash_v1(), plain.hash_v1());
+        assert_eq!(plain.hash(), format!("v2:sha256:{}", plain.hash_v1()));
+    }
+
+    #[test]
+    fn test_strip_comments() {
+        let commented = QueryWrapper::parse(
+            "-- daily totals\nSELECT day, /* gross */ SUM(amount)\n  FROM sales -- all of them\n\
+             WHERE note <> '-- not a comment' GROUP BY day",
+        )
+        .unwrap();
+        let plain = QueryWrapper::parse(
+            "SELECT day, SUM(amount) FROM sales WHERE note <> '-- not a comment' GROUP BY day",
+        )
+        .unwrap();
+        assert!(commented.raw_sql().contains("-- daily totals"));
+        assert_eq!(
+            commented.strip_comments(),
+            "SELECT day, SUM(amount) FROM sales WHERE note <> '-- not a comment' GROUP BY day"
+        );
+        assert_eq!(commented.strip_comments(), plain.strip_comments());
+        assert_eq!(
+            QueryWrapper::create_hash_string(&commented.strip_comments()),
+            QueryWrapper::create_hash_string(&plain.strip_comments())
+        );
+    }
+
+    #[test]
+    fn test_comments_are_kept_out_of_the_hash() {
+        let plain =
+            QueryWrapper::parse("SELECT region, SUM(amount) FROM sales GROUP BY 1").unwrap();
+        for commented in [
+            "/* trace_id=abc123, job='daily report' */ SELECT region, SUM(amount) FROM sales GROUP BY 1",
+            "SELECT region, /* inline */ SUM(amount) FROM sales GROUP BY 1 -- trailing",
+            "-- trace_id=def456\nSELECT region, SUM(amount) FROM sales GROUP BY 1",
+        ] {
+            let wrapper = QueryWrapper::parse(commented).unwrap();
+            assert_eq!(wrapper.sql(), plain.sql(), "{}", commented);
+            assert_eq!(wrapper.hash(), plain.hash(), "{}", commented);
+            assert_eq!(wrapper.raw_sql(), commented);
+        }
+
+        let wrapper = QueryWrapper::parse(
+            "/* trace_id=abc123, job='daily report' priority=low */\n\
+             SELECT '/* not a comment */' AS s -- nor -- this\n",
+        )
+        .unwrap();
+        assert_eq!(
+            wrapper.comments(),
+            [
+                "trace_id=abc123, job='daily report' priority=low",
+                "nor -- this"
+            ]
+        );
+        assert_eq!(wrapper.sql(), "SELECT '/* not a comment */' AS s");
+        let hints = wrapper.comment_hints();
+        assert_eq!(hints["trace_id"], "abc123");
+        assert_eq!(hints["priority"], "low");
+        // Values can't contain the separators, even quoted.
+        assert_eq!(hints["job"], "daily");
+
+        // Only a comment the query starts with holds hints.
+        let wrapper = QueryWrapper::parse("SELECT 1 /* trace_id=abc123 */").unwrap();
+        assert!(wrapper.comment_hints().is_empty());
+        assert!(plain.comments().is_empty());
+        assert_eq!(plain.raw_sql(), plain.sql());
+    }
+
+    #[test]
+    fn test_parse_error_location() {
+        let query = "SELECT id,\n       amount\nFROM sales WHERE amount = )\nLIMIT 1";
+        match QueryWrapper::parse(query) {
+            Err(QueryError::ParseFailed {
+                offset,
+                line,
+                column,
+                snippet,
+                message,
+            }) => {
+                assert_eq!((line, column), (3, 27));
+                assert_eq!(&query[offset..offset + 1], ")");
+                assert_eq!(
+                    snippet,
+                    "FROM sales WHERE amount = )\n                          ^"
+                );
+                assert!(
+                    message.starts_with("Expected: an expression"),
+                    "{}",
+                    message
+                );
+            }
+            other => panic!("expected ParseFailed, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_parse_error_location_in_later_statement() {
+        let query = "SELECT 1;\nSELECT 2;\nSELECT FROM WHERE;";
+        match QueryWrapper::parse(query) {
+            Err(QueryError::ParseFailed { line, offset, .. }) => {
+                assert_eq!(line, 3);
+                assert!(offset >= "SELECT 1;\nSELECT 2;\n".len());
+            }
+            other => panic!("expected ParseFailed, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_parse_error_at_end_of_input() {
+        match QueryWrapper::parse("SELECT * FROM") {
+            Err(QueryError::ParseFailed { line, column, .. }) => {
+                assert_eq!((line, column), (1, 14));
+            }
+            other => panic!("expected ParseFailed, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_where_clause() {
+        let query = "SELECT region, COUNT(*) FROM 's3://my-bucket/data/*.parquet' WHERE amount > 10 AND region = 'east' GROUP BY region HAVING COUNT(*) > 1";
+        let parsed = QueryWrapper::parse(query).unwrap();
+        assert_eq!(
+            parsed.where_clause().unwrap().to_string(),
+            "amount > 10 AND region = 'east'"
+        );
+
+        let parsed = QueryWrapper::parse("SELECT * FROM mytable").unwrap();
+        assert!(parsed.where_clause().is_none());
+    }
+
+    #[test]
+    fn test_clause_accessors() {
+        let query = "SELECT region, SUM(amount) AS total, COUNT(*), ROW_NUMBER() OVER () \
+                     FROM read_parquet('s3://my-bucket/data/*.parquet') \
+                     GROUP BY region ORDER BY total DESC";
+        let parsed = QueryWrapper::parse(query).unwrap();
+        assert_eq!(parsed.source().unwrap(), "s3://my-bucket/data/*.parquet");
+        assert_eq!(parsed.projection().len(), 4);
+        assert_eq!(parsed.group_by().unwrap().to_string(), "GROUP BY region");
+        assert_eq!(parsed.order_by()[0].to_string(), "total DESC");
+        let aggregates: Vec<_> = parsed
+            .aggregate_columns()
+            .into_iter()
+            .map(|(func, name)| (func.to_string(), name))
+            .collect();
+        assert_eq!(
+            aggregates,
+            vec![
+                ("SUM(amount)".to_string(), "total".to_string()),
+                ("COUNT(*)".to_string(), "COUNT(*)".to_string()),
+            ]
+        );
+
+        let parsed = QueryWrapper::parse("SELECT 1 UNION SELECT 2").unwrap();
+        assert!(parsed.projection().is_empty());
+        assert!(parsed.group_by().is_none());
+    }
+
+    #[test]
+    fn test_table_aliases_and_functions() {
+        let wrapper = QueryWrapper::parse(
+            "SELECT UPPER(s.name), COUNT(*) FROM 's3://b/sales.parquet' AS S \
+             JOIN read_csv('s3://b/users.csv') u ON s.id = u.id JOIN regions ON true \
+             WHERE s.id IN (SELECT MAX(id) FROM t) GROUP BY 1",
+        )
+        .unwrap();
+        assert_eq!(
+            wrapper.table_aliases(),
+            HashMap::from([
+                ("s".to_string(), "s3://b/sales.parquet".to_string()),
+                ("u".to_string(), "s3://b/users.csv".to_string()),
+            ])
+        );
+        assert_eq!(
+            wrapper
+                .referenced_functions()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            ["count", "max", "read_csv", "upper"]
+        );
+    }
+
+    #[test]
+    fn test_referenced_schemas_and_catalogs() {
+        let wrapper = QueryWrapper::parse(
+            "WITH recent AS (SELECT * FROM warehouse.sales.orders) \
+             SELECT * FROM recent JOIN \"Sales\".customers c ON true \
+             JOIN read_parquet('s3://b/x.parquet') p ON true \
+             WHERE c.id IN (SELECT id FROM lake.main.vip)",
+        )
+        .unwrap();
+        let set = |items: &[&str]| items.iter().map(|item| item.to_string()).collect();
+        assert_eq!(
+            wrapper.referenced_schemas(),
+            set(&["sales", "Sales", "main"])
+        );
+        assert_eq!(wrapper.referenced_catalogs(), set(&["warehouse", "lake"]));
+        assert_eq!(wrapper.source().unwrap(), "recent");
+
+        let wrapper = QueryWrapper::parse("SELECT * FROM warehouse.sales.orders").unwrap();
+        assert_eq!(wrapper.source().unwrap(), "warehouse.sales.orders");
+        let wrapper = QueryWrapper::parse("SELECT * FROM orders").unwrap();
+        assert!(wrapper.referenced_schemas().is_empty());
+        assert!(wrapper.referenced_catalogs().is_empty());
+    }
+
+    #[test]
+    fn test_analyze_resolves_aliases() {
+        let wrapper = QueryWrapper::parse(
+            "SELECT o.id, c.*, COUNT(r.*), total FROM orders AS o \
+             JOIN 's3://b/customers.parquet' c ON o.customer_id = c.id \
+             JOIN (SELECT order_id, SUM(amount) AS total FROM refunds GROUP BY 1) R \
+             ON r.order_id = O.id WHERE c.region = 'eu'",
+        )
+        .unwrap();
+        let analysis = wrapper.analyze();
+        let set = |items: &[&str]| items.iter().map(|item| item.to_string()).collect();
+        assert_eq!(
+            *analysis.tables(),
+            set(&["orders", "s3://b/customers.parquet", "__derived_r"])
+        );
+        assert_eq!(
+            *analysis.aliases(),
+            HashMap::from([
+                ("o".to_string(), "orders".to_string()),
+                ("c".to_string(), "s3://b/customers.parquet".to_string()),
+                ("r".to_string(), "__derived_r".to_string()),
+            ])
+        );
+        assert_eq!(
+            *analysis.columns(),
+            set(&[
+                "orders.id",
+                "s3://b/customers.parquet.*",
+                "__derived_r.*",
+                "total",
+                "orders.customer_id",
+                "s3://b/customers.parquet.id",
+                "__derived_r.order_id",
+                "s3://b/customers.parquet.region",
+            ])
+        );
+        assert_eq!(
+            analysis.derived_table("__derived_r").unwrap().to_string(),
+            "SELECT order_id, SUM(amount) AS total FROM refunds GROUP BY 1"
+        );
+        assert!(analysis.derived_table("r").is_none());
+
+        // A self-join counts as one distinct table.
+        let analysis = QueryWrapper::parse("SELECT a.id FROM t a JOIN t b ON a.id = b.id")
+            .unwrap()
+            .analyze();
+        assert_eq!(*analysis.tables(), set(&["t"]));
+        assert_eq!(*analysis.columns(), set(&["t.id"]));
+    }
+
+    #[test]
+    fn test_analyze_normalizes_identifiers() {
+        let analysis = QueryWrapper::parse(
+            "SELECT Country, \"Country\", C.REGION FROM Customers AS C \
+             JOIN \"Orders\" \"O\" ON \"O\".customer_id = c.Id \
+             WHERE country = 'US' GROUP BY COUNTRY, \"Country\", c.region",
+        )
+        .unwrap()
+        .analyze();
+        let set = |items: &[&str]| items.iter().map(|item| item.to_string()).collect();
+        assert_eq!(*analysis.tables(), set(&["customers", "Orders"]));
+        assert_eq!(
+            *analysis.aliases(),
+            HashMap::from([
+                ("c".to_string(), "customers".to_string()),
+                ("O".to_string(), "Orders".to_string()),
+            ])
+        );
+        assert_eq!(
+            *analysis.columns(),
+            set(&[
+                "country",
+                "Country",
+                "customers.region",
+                "Orders.customer_id",
+                "customers.id",
+            ])
+        );
+        assert_eq!(analysis.spellings()["country"], "Country");
+        assert_eq!(analysis.spellings()["customers"], "Customers");
+        assert_eq!(analysis.spellings()["region"], "REGION");
+        assert_eq!(analysis.spellings()["Orders"], "Orders");
+    }
+
+    #[test]
+    fn test_analyze_qualify() {
+        let analysis = QueryWrapper::parse(
+            "SELECT * FROM 's3://b/events/*.parquet' e WHERE kind = 'click' \
+             QUALIFY row_number() OVER (PARTITION BY e.user_id ORDER BY ts DESC) = 1",
+        )
+        .unwrap()
+        .analyze();
+        assert_eq!(
+            analysis.qualify(),
+            Some("row_number() OVER (PARTITION BY e.user_id ORDER BY ts DESC) = 1")
+        );
+        assert_eq!(analysis.where_conditions(), ["kind = 'click'"]);
+        for column in ["s3://b/events/*.parquet.user_id", "ts", "kind"] {
+            assert!(analysis.columns().contains(column), "{}", column);
+        }
+        assert!(analysis.aggregations.contains(&"row_number".to_string()));
+
+        let analysis = QueryWrapper::parse("SELECT * FROM t").unwrap().analyze();
+        assert!(analysis.qualify().is_none());
+    }
+
+    #[test]
+    fn test_analyze_pivot() {
+        let sql = "SELECT * FROM 's3://b/sales/*.parquet' s \
+                   PIVOT (SUM(s.amount) AS total FOR month IN ('jan', 'feb' AS february)) p";
+        let parsed = QueryWrapper::parse(sql).unwrap();
+        let analysis = parsed.analyze();
+        assert_eq!(
+            analysis.pivots(),
+            [PivotInfo::Pivot {
+                table: "s3://b/sales/*.parquet".to_string(),
+                pivot_column: "month".to_string(),
+                aggregates: vec!["SUM(s.amount) AS total".to_string()],
+                value_columns: vec!["jan".to_string(), "february".to_string()],
+            }]
+        );
+        assert_eq!(
+            analysis.tables(),
+            &HashSet::from(["s3://b/sales/*.parquet".to_string()])
+        );
+        assert_eq!(analysis.aliases()["p"], "s3://b/sales/*.parquet");
+        for column in ["s3://b/sales/*.parquet.amount", "month"] {
+            assert!(analysis.columns().contains(column), "{}", column);
+        }
+        assert_eq!(
+            parsed.tables()[0].to_string(),
+            "'s3://b/sales/*.parquet' AS s"
+        );
+        assert_eq!(parsed.source().unwrap(), "s3://b/sales/*.parquet");
+    }
+
+    #[test]
+    fn test_analyze_unpivot() {
+        let sql = "SELECT * FROM read_parquet('s3://b/monthly/*.parquet') \
+                   UNPIVOT (amount FOR month IN (jan, feb, mar))";
+        let parsed = QueryWrapper::parse(sql).unwrap();
+        let analysis = parsed.analyze();
+        assert_eq!(
+            analysis.pivots(),
+            [PivotInfo::Unpivot {
+                table: "s3://b/monthly/*.parquet".to_string(),
+                value_column: "amount".to_string(),
+                name_column: "month".to_string(),
+                columns: vec!["jan".to_string(), "feb".to_string(), "mar".to_string()],
+            }]
+        );
+        assert_eq!(analysis.pivots()[0].table(), "s3://b/monthly/*.parquet");
+        for column in ["jan", "feb", "mar"] {
+            assert!(analysis.columns().contains(column), "{}", column);
+        }
+        assert_eq!(parsed.source().unwrap(), "s3://b/monthly/*.parquet");
+        assert_eq!(parsed.bucket().unwrap(), "s3://b");
+    }
+
+    #[test]
+    fn test_analyze_unnest() {
+        let analysis = QueryWrapper::parse(
+            "SELECT e.id, t.tag FROM 's3://b/events/*.parquet' e, UNNEST(e.tags) AS t(tag) \
+             WHERE t.tag <> 'spam'",
+        )
+        .unwrap()
+        .analyze();
+        assert_eq!(
+            analysis.tables(),
+            &HashSet::from(["s3://b/events/*.parquet".to_string()])
+        );
+        assert_eq!(analysis.aliases()["t"], "__unnest_t");
+        for column in [
+            "s3://b/events/*.parquet.tags",
+            "s3://b/events/*.parquet.id",
+            "__unnest_t.tag",
+        ] {
+            assert!(analysis.columns().contains(column), "{}", column);
+        }
+        assert!(analysis.warnings().is_empty());
+
+        let analysis = QueryWrapper::parse("SELECT u.x FROM unnest([1, 2, 3]) AS u(x)")
+            .unwrap()
+            .analyze();
+        assert!(analysis.tables().is_empty());
+        assert_eq!(analysis.aliases()["u"], "__unnest_u");
+        assert_eq!(
+            analysis.columns(),
+            &HashSet::from(["__unnest_u.x".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_analyze_table_functions() {
+        let sql = "SELECT g.file, r.id FROM glob('s3://b/data/*') g \
+                   JOIN read_json_auto(['s3://b/a.json', 's3://b/it''s.json'], format => 'array') r \
+                   ON r.file = g.file CROSS JOIN range(10) n";
+        let parsed = QueryWrapper::parse(sql).unwrap();
+        let analysis = parsed.analyze();
+        assert_eq!(
+            analysis.tables(),
+            &HashSet::from(["s3://b/a.json".to_string()])
+        );
+        assert_eq!(analysis.aliases()["g"], "__function_g");
+        assert_eq!(analysis.aliases()["r"], "s3://b/a.json");
+        assert_eq!(
+            analysis.table_functions(),
+            [
+                TableFunctionCall {
+                    name: "glob".to_string(),
+                    args: vec!["'s3://b/data/*'".to_string()],
+                    options: HashMap::new(),
+                },
+                TableFunctionCall {
+                    name: "read_json_auto".to_string(),
+                    args: vec![
+                        "['s3://b/a.json', 's3://b/it''s.json']".to_string(),
+                        "format => 'array'".to_string(),
+                    ],
+                    options: HashMap::from([("format".to_string(), "'array'".to_string())]),
+                },
+                TableFunctionCall {
+                    name: "range".to_string(),
+                    args: vec!["10".to_string()],
+                    options: HashMap::new(),
+                },
+            ]
+        );
+        let [glob, reader, range] = analysis.table_functions() else {
+            panic!("expected three table functions");
+        };
+        assert_eq!(glob.paths(), ["s3://b/data/*"]);
+        assert_eq!(reader.paths(), ["s3://b/a.json", "s3://b/it's.json"]);
+        assert!(range.is_generator() && !range.reads_paths());
+        assert!(range.paths().is_empty());
+        assert_eq!(parsed.source().unwrap(), "s3://b/data/*");
+        assert_eq!(parsed.bucket().unwrap(), "s3://b");
+
+        let parsed = QueryWrapper::parse(
+            "SELECT * FROM generate_series(1, 3) s CROSS JOIN parquet_scan('s3://c/t.parquet')",
+        )
+        .unwrap();
+        assert_eq!(parsed.source().unwrap(), "s3://c/t.parquet");
+        assert_eq!(parsed.bucket().unwrap(), "s3://c");
+        assert_eq!(
+            parsed.file_sources(),
+            [("s3://c/t.parquet".to_string(), FileFormat::Parquet)]
+        );
+        assert!(QueryWrapper::parse("SELECT * FROM range(10)")
+            .unwrap()
+            .source()
+            .is_err());
+    }
+
+    #[test]
+    fn test_contains_lateral_join() {
+        let lateral = |sql: &str| QueryWrapper::parse(sql).unwrap().contains_lateral_join();
+        assert!(lateral(
+            "SELECT * FROM users u JOIN LATERAL (SELECT * FROM orders o \
+             WHERE o.user_id = u.id LIMIT 1) l ON TRUE"
+        ));
+        assert!(lateral(
+            "SELECT * FROM users u CROSS JOIN LATERAL (SELECT u.id + 1 AS next) n"
+        ));
+        assert!(lateral(
+            "SELECT * FROM (SELECT * FROM users u \
+             CROSS APPLY (SELECT * FROM orders o WHERE o.user_id = u.id) l)"
+        ));
+        assert!(!lateral(
+            "SELECT * FROM users u JOIN (SELECT * FROM orders) o ON o.user_id = u.id"
+        ));
+        assert!(!lateral("SELECT 1"));
+    }
+
+    #[test]
+    fn test_where_and_having_conditions() {
+        let parsed = QueryWrapper::parse(
+            "SELECT region, SUM(amount) FROM sales WHERE amount > 0 \
+             GROUP BY region HAVING SUM(amount) > 100",
+        )
+        .unwrap();
+        let analysis = parsed.analyze();
+        assert_eq!(analysis.where_conditions(), ["amount > 0"]);
+        assert_eq!(analysis.having_conditions(), ["SUM(amount) > 100"]);
+        #[allow(deprecated)]
+        let conditions = analysis.conditions();
+        assert_eq!(conditions, ["amount > 0", "SUM(amount) > 100"]);
+        // Only WHERE reaches the scans.
+        assert_eq!(parsed.where_clause().unwrap().to_string(), "amount > 0");
+    }
+
+    #[test]
+    fn test_table_function_options() {
+        let sql = "SELECT * FROM read_parquet('s3://b/sales/*/*.parquet', hive_partitioning=true, \
+                   FILENAME = true) s JOIN read_csv('s3://b/regions.csv', delim => '|', \
+                   header => false) r ON s.region = r.code";
+        let analysis = QueryWrapper::parse(sql).unwrap().analyze();
+        let [parquet, csv] = analysis.table_functions() else {
+            panic!("expected two table functions");
+        };
+        assert_eq!(
+            parquet.options,
+            HashMap::from([
+                ("hive_partitioning".to_string(), "true".to_string()),
+                ("filename".to_string(), "true".to_string()),
+            ])
+        );
+        assert!(parquet.hive_partitioning());
+        assert_eq!(csv.options["delim"], "'|'");
+        assert!(!csv.hive_partitioning());
+
+        let options = analysis.source_options();
+        assert_eq!(options.len(), 2);
+        assert_eq!(options["s3://b/sales/*/*.parquet"], &parquet.options);
+        assert_eq!(options["s3://b/regions.csv"]["header"], "false");
+
+        let analysis = QueryWrapper::parse("SELECT * FROM read_parquet('s3://b/x.parquet')")
+            .unwrap()
+            .analyze();
+        assert!(analysis.source_options()["s3://b/x.parquet"].is_empty());
+    }
+
+    #[test]
+    fn test_analyze_grouping_modifiers() {
+        let wrapper = QueryWrapper::parse(
+            "SELECT s.Region, country, city, SUM(amount) FROM sales s \
+             GROUP BY ROLLUP (s.Region, (country, city))",
+        )
+        .unwrap();
+        let analysis = wrapper.analyze();
+        assert_eq!(
+            analysis.grouping_modifiers(),
+            [GroupingModifier::Rollup(vec![
+                vec!["sales.region".to_string()],
+                vec!["country".to_string(), "city".to_string()],
+            ])]
+        );
+        assert!(analysis.columns().contains("city"));
+
+        let wrapper = QueryWrapper::parse(
+            "SELECT a, b, c, COUNT(*) FROM t GROUP BY a, CUBE (b, c), GROUPING SETS ((a), ())",
+        )
+        .unwrap();
+        assert_eq!(
+            wrapper.analyze().grouping_modifiers(),
+            [
+                GroupingModifier::Cube(vec![vec!["b".to_string()], vec!["c".to_string()]]),
+                GroupingModifier::GroupingSets(vec![vec!["a".to_string()], vec![]]),
+            ]
+        );
+
+        let wrapper = QueryWrapper::parse("SELECT a, COUNT(*) FROM t GROUP BY a").unwrap();
+        assert!(wrapper.analyze().grouping_modifiers().is_empty());
+    }
+
+    #[test]
+    fn test_analyze_grouping_sets() {
+        let sets = |sql: &str| {
+            let sets = QueryWrapper::parse(sql)
+                .unwrap()
+                .analyze()
+                .grouping_sets()
+                .to_vec();
+            sets.iter().map(|set| set.join(",")).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            sets("SELECT region, country, SUM(x) FROM t GROUP BY ROLLUP (region, country)"),
+            ["region,country", "region", ""]
+        );
+        assert_eq!(
+            sets("SELECT a, b, SUM(x) FROM t GROUP BY CUBE (a, b)"),
+            ["a,b", "a", "b", ""]
+        );
+        assert_eq!(
+            sets("SELECT a, b, SUM(x) FROM t GROUP BY GROUPING SETS ((a, b), (b), ())"),
+            ["a,b", "b", ""]
+        );
+        assert_eq!(
+            sets("SELECT a, b, c, SUM(x) FROM t s GROUP BY s.a, ROLLUP (b, c)"),
+            ["t.a,b,c", "t.a,b", "t.a"]
+        );
+        assert_eq!(
+            sets("SELECT a, b, SUM(x) FROM t GROUP BY ROLLUP (a), CUBE (b)"),
+            ["a,b", "a", "b", ""]
+        );
+        assert!(sets("SELECT a, SUM(x) FROM t GROUP BY a").is_empty());
+        // Only the outer GROUP BY is expanded.
+        assert!(sets("SELECT * FROM (SELECT a, SUM(x) FROM t GROUP BY ROLLUP (a))").is_empty());
+    }
+
+    #[test]
+    fn test_analyze_wildcard_exclude_and_replace() {
+        let wrapper = QueryWrapper::parse("SELECT * EXCLUDE (ssn) FROM users").unwrap();
+        let analysis = wrapper.analyze();
+        assert_eq!(analysis.excluded_columns(), ["ssn"]);
+        assert!(analysis.replaced_columns().is_empty());
+
+        let wrapper = QueryWrapper::parse(
+            "SELECT u.* EXCLUDE (SSN, dob) REPLACE (lower(u.email) AS email), o.* \
+             FROM users u JOIN orders o ON o.user_id = u.id",
+        )
+        .unwrap();
+        let analysis = wrapper.analyze();
+        assert_eq!(analysis.excluded_columns(), ["users.ssn", "users.dob"]);
+        assert_eq!(
+            analysis.replaced_columns(),
+            &HashMap::from([("users.email".to_string(), "lower(u.email)".to_string())])
+        );
+        assert!(analysis.columns().contains("users.email"));
+        assert_eq!(analysis.spellings()["ssn"], "SSN");
+    }
+
+    #[test]
+    fn test_analyze_lateral_subqueries() {
+        for join in ["CROSS JOIN LATERAL", "CROSS APPLY"] {
+            let analysis = QueryWrapper::parse(&format!(
+                "SELECT u.id, l.amount FROM 's3://b/users/*.parquet' u {} \
+                 (SELECT o.amount FROM 's3://b/orders/*.parquet' o WHERE o.user_id = u.id \
+                 ORDER BY o.ts DESC LIMIT 1) l",
+                join
+            ))
+            .unwrap()
+            .analyze();
+            for table in [
+                "s3://b/users/*.parquet",
+                "s3://b/orders/*.parquet",
+                "__derived_l",
+            ] {
+                assert!(analysis.tables().contains(table), "{}: {}", join, table);
+            }
+            for column in [
+                "s3://b/users/*.parquet.id",
+                "s3://b/orders/*.parquet.user_id",
+                "__derived_l.amount",
+            ] {
+                assert!(analysis.columns().contains(column), "{}: {}", join, column);
+            }
+            assert!(analysis.where_conditions().is_empty());
+            assert_eq!(analysis.limit(), None);
+        }
+    }
+
+    #[test]
+    fn test_analyze_ctes() {
+        let analysis = QueryWrapper::parse(
+            "WITH daily AS (SELECT day, SUM(amount) AS total FROM 's3://b/sales/*.parquet' GROUP BY 1), \
+             ranked AS (SELECT d.*, RANK() OVER (ORDER BY total) AS r FROM daily d JOIN days USING (day)) \
+             SELECT * FROM ranked JOIN (WITH top AS (SELECT * FROM ranked WHERE r = 1) SELECT * FROM top) t \
+             ON true",
+        )
+        .unwrap()
+        .analyze();
+        let cte = |name: &str, tables: &[&str]| CteInfo {
+            name: name.to_string(),
+            referenced_tables: tables.iter().map(|table| table.to_string()).collect(),
+            is_recursive: false,
+            materialized_hint: None,
+        };
+        assert_eq!(
+            analysis.ctes(),
+            [
+                cte("daily", &["s3://b/sales/*.parquet"]),
+                cte("ranked", &["daily", "days"]),
+                cte("top", &["ranked"]),
+            ]
+        );
+
+        // Each CTE only reads ones defined before it.
+        let names: Vec<&str> = analysis
+            .ctes()
+            .iter()
+            .map(|cte| cte.name.as_str())
+            .collect();
+        for (i, cte) in analysis.ctes().iter().enumerate() {
+            for table in &cte.referenced_tables {
+                assert!(!names[i..].contains(&table.as_str()), "{}", table);
+            }
+        }
+
+        let analysis = QueryWrapper::parse(
+            "WITH RECURSIVE counter(n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM counter WHERE n < 5) \
+             SELECT * FROM counter",
+        )
+        .unwrap()
+        .analyze();
+        assert_eq!(
+            analysis.ctes(),
+            [CteInfo {
+                is_recursive: true,
+                ..cte("counter", &["counter"])
+            }]
+        );
+    }
+
+    #[test]
+    fn test_query_kind() {
+        let kind = |sql: &str| QueryWrapper::parse(sql).unwrap().query_kind();
+        assert_eq!(
+            kind("SELECT region, SUM(amount) FROM t GROUP BY region"),
+            QueryKind::Aggregate
+        );
+        assert_eq!(kind("SELECT a FROM t GROUP BY a"), QueryKind::Aggregate);
+        assert_eq!(kind("SELECT COUNT(*) FROM t"), QueryKind::Aggregate);
+        assert_eq!(
+            kind("SELECT a, b FROM t WHERE a > 1 LIMIT 5"),
+            QueryKind::Rows
+        );
+        assert_eq!(kind("SELECT ROW_NUMBER() OVER () FROM t"), QueryKind::Rows);
+        assert_eq!(
+            kind("SELECT id FROM t WHERE amount > (SELECT AVG(amount) FROM t)"),
+            QueryKind::Rows
+        );
+        assert_eq!(
+            kind("SELECT id, (SELECT MAX(amount) FROM t) FROM t"),
+            QueryKind::Rows
+        );
+        assert_eq!(
+            kind(
+                "WITH totals AS (SELECT region, SUM(amount) AS total FROM t GROUP BY region) \
+                 SELECT region FROM totals WHERE total > 10"
+            ),
+            QueryKind::Rows
+        );
+        assert_eq!(
+            kind("SELECT a FROM t UNION ALL SELECT COUNT(*) FROM u"),
+            QueryKind::Rows
+        );
+        assert_eq!(kind("SELECT 42"), QueryKind::Scalar);
+        assert_eq!(kind("SELECT now(), 1 + 1"), QueryKind::Scalar);
+        assert_eq!(
+            kind("SELECT MAX(x) FROM (VALUES (1)) v(x)"),
+            QueryKind::Aggregate
+        );
+    }
+
+    #[tokio::test]
+    async fn test_queries_without_from() {
+        for sql in [
+            "SELECT 42",
+            "SELECT now()",
+            "SELECT 1 + 1 AS two UNION ALL SELECT 3",
+        ] {
+            let mut wrapper = QueryWrapper::parse(sql).unwrap();
+            assert!(!wrapper.has_from(), "{}", sql);
+            assert!(wrapper.tables().is_empty());
+            assert!(wrapper.analyze().tables().is_empty());
+            assert_eq!(wrapper.source().unwrap(), "");
+            assert!(wrapper.list_of_prefixes().await.unwrap().is_empty());
+            assert!(wrapper.list_files(None).await.unwrap().is_empty());
+            assert_eq!(wrapper.estimate_cost().await.unwrap().files, 0);
+        }
+
+        for sql in [
+            "SELECT * FROM t",
+            "SELECT (SELECT max(id) FROM t)",
+            "SELECT * FROM range(3)",
+        ] {
+            assert!(QueryWrapper::parse(sql).unwrap().has_from(), "{}", sql);
+        }
+    }
+
     #[test]
     fn test_source_extraction() -> Result<(), QueryError> {
         let query = "SELECT * FROM 's3://my-bucket/data/*.parquet'";
@@ -525,4 +2984,94 @@ mod tests {
         assert_eq!(parsed.sql, query);
         assert_eq!(parsed.tables().len(), 2);
     }
+
+    #[test]
+    fn test_estimated_cost_simple_select() {
+        let parsed = QueryWrapper::parse("SELECT * FROM mytable").unwrap();
+        assert_eq!(parsed.analyze().estimated_cost(), 1);
+    }
+
+    #[test]
+    fn test_estimated_cost_joins() {
+        let query = "SELECT orders.id, customers.name FROM orders JOIN customers ON orders.customer_id = customers.id";
+        let parsed = QueryWrapper::parse(query).unwrap();
+        assert_eq!(parsed.analyze().estimated_cost(), 10 * 2);
+
+        let query = "SELECT * FROM orders CROSS JOIN customers";
+        let parsed = QueryWrapper::parse(query).unwrap();
+        assert_eq!(parsed.analyze().estimated_cost(), 100 * 2);
+    }
+
+    #[test]
+    fn test_estimated_cost_aggregations_and_case() {
+        let query = "SELECT COUNT(*), SUM(amount), MAX(amount) FROM orders";
+        let parsed = QueryWrapper::parse(query).unwrap();
+        assert_eq!(parsed.analyze().estimated_cost(), 1 + 3 * 5);
+
+        let query = "SELECT id, CASE WHEN age < 18 THEN 'Minor' ELSE 'Adult' END AS age_category FROM users";
+        let parsed = QueryWrapper::parse(query).unwrap();
+        assert_eq!(parsed.analyze().estimated_cost(), 1 + 2);
+    }
+
+    #[test]
+    fn test_set_limit_and_offset() -> Result<(), QueryError> {
+        let mut parsed = QueryWrapper::parse("SELECT * FROM products LIMIT 10")?;
+        parsed.set_limit(Some(100))?;
+        parsed.set_offset(Some(20))?;
+        assert_eq!(parsed.sql, "SELECT * FROM products LIMIT 100 OFFSET 20");
+
+        let analysis = parsed.analyze();
+        assert_eq!(analysis.limit, Some(100));
+        assert_eq!(analysis.offset, Some(20));
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_order_by_and_limit() -> Result<(), QueryError> {
+        let query = "SELECT * FROM products ORDER BY price DESC LIMIT 10";
+        let mut parsed = QueryWrapper::parse(query)?;
+        let original_hash = parsed.hashed.clone();
+        parsed.strip_order_by()?;
+        parsed.strip_limit()?;
+        assert_eq!(parsed.sql, "SELECT * FROM products");
+        assert_ne!(parsed.hashed, original_hash);
+
+        let analysis = parsed.analyze();
+        assert!(analysis.order_by.is_empty());
+        assert_eq!(analysis.limit, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mutation_on_set_operation() -> Result<(), QueryError> {
+        let query = "SELECT id FROM table1 UNION SELECT id FROM table2 ORDER BY id LIMIT 5";
+        let mut parsed = QueryWrapper::parse(query)?;
+        parsed.strip_order_by()?;
+        parsed.set_limit(Some(50))?;
+        assert_eq!(
+            parsed.sql,
+            "SELECT id FROM table1 UNION SELECT id FROM table2 LIMIT 50"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_mutation_rejects_non_query() {
+        let mut parsed = QueryWrapper::parse("CREATE TABLE t (id INTEGER)").unwrap();
+        assert!(parsed.set_limit(Some(1)).is_err());
+        assert!(parsed.strip_order_by().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_prefix_scan_timeout() {
+        let query = "SELECT * FROM 's3://my-bucket/data/*.parquet'";
+        let parsed = QueryWrapper::parse(query).unwrap();
+        // A scanner of its own, so the timeout can't interfere with other
+        // tests using the shared one.
+        let err = PrefixScanner::new()
+            .scan(&parsed, &ScanConfig::from_env().timeout(Duration::ZERO))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Other error: prefix scan timed out");
+    }
 }
@@ -0,0 +1,243 @@
+use crate::access::path_source;
+use crate::{PrefixStats, QueryError, QueryWrapper, ScanConfig, UrlStyle};
+use futures::TryStreamExt;
+use object_store::aws::AmazonS3Builder;
+use object_store::local::LocalFileSystem;
+use object_store::path::Path as ObjectPath;
+use object_store::ObjectStore;
+use regex::Regex;
+
+impl QueryWrapper {
+    /// Lists the query's source with `object_store` and returns the same
+    /// prefixes as [`scan_source_for_prefixes`](Self::scan_source_for_prefixes),
+    /// without starting DuckDB.
+    ///
+    /// `s3://` sources are listed through `config`; paths without a scheme are
+    /// read from the local filesystem. Globs follow DuckDB: `*` and `?` stay
+    /// within one path segment and `**` spans any number of directories,
+    /// including none. Only the part of the source before its first wildcard is
+    /// listed, page by page, so buckets of any size are covered. Prefixes come
+    /// back in the order `config` asks for.
+    pub async fn scan_source_for_prefixes_async(
+        &self,
+        config: &ScanConfig,
+    ) -> Result<Vec<String>, QueryError> {
+        let stats = self.scan_source_for_prefix_stats_async(config).await?;
+        Ok(stats.into_iter().map(|stats| stats.prefix).collect())
+    }
+
+    /// Like [`scan_source_for_prefix_stats`](Self::scan_source_for_prefix_stats),
+    /// listing with `object_store` as
+    /// [`scan_source_for_prefixes_async`](Self::scan_source_for_prefixes_async)
+    /// does.
+    pub async fn scan_source_for_prefix_stats_async(
+        &self,
+        config: &ScanConfig,
+    ) -> Result<Vec<PrefixStats>, QueryError> {
+        if !self.has_from() {
+            return Ok(Vec::new());
+        }
+        let source = self.source()?;
+        match tokio::time::timeout(config.timeout, list_prefixes(&source, config)).await {
+            Ok(result) => result,
+            Err(_) => Err(QueryError::Other("prefix scan timed out".to_string())),
+        }
+    }
+}
+
+async fn list_prefixes(source: &str, config: &ScanConfig) -> Result<Vec<PrefixStats>, QueryError> {
+    let (store, root, pattern) = open_store(source, config)?;
+    let matcher = glob_regex(pattern);
+
+    // List from the last directory before the first wildcard.
+    let literal = &pattern[..pattern.find(['*', '?', '[']).unwrap_or(pattern.len())];
+    let listed = literal
+        .rfind('/')
+        .map(|slash| ObjectPath::from(&literal[..slash]));
+
+    let mut directories = Vec::new();
+    let mut objects = store.list(listed.as_ref());
+    while let Some(object) = objects
+        .try_next()
+        .await
+        .map_err(|err| listing_error(source, err))?
+    {
+        let location = object.location.as_ref();
+        if !matcher.is_match(location) {
+            continue;
+        }
+        // Mirrors the DuckDB scan's `REGEXP_REPLACE(filename, '/[^/]+$', '')`.
+        let file = format!("{}{}", root, location);
+        let parent = file.rsplit_once('/').map_or(file.as_str(), |(dir, _)| dir);
+        directories.push((parent.to_string(), 1, object.size as u64));
+    }
+    Ok(config.summarize(source, directories))
+}
+
+/// The store holding `source`, the text to put back in front of its object
+/// paths, and the glob relative to the store.
+pub(crate) fn open_store<'a>(
+    source: &'a str,
+    config: &ScanConfig,
+) -> Result<(Box<dyn ObjectStore>, String, &'a str), QueryError> {
+    if let Some(rest) = source.strip_prefix("s3://") {
+        let (bucket, pattern) = rest.split_once('/').unwrap_or((rest, ""));
+        let mut builder = AmazonS3Builder::from_env().with_bucket_name(bucket);
+        if let Some(region) = &config.region {
+            builder = builder.with_region(region);
+        }
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder
+                .with_endpoint(endpoint)
+                .with_allow_http(endpoint.starts_with("http://"));
+        }
+        if let Some(access_key_id) = &config.access_key_id {
+            builder = builder.with_access_key_id(access_key_id);
+        }
+        if let Some(secret_access_key) = &config.secret_access_key {
+            builder = builder.with_secret_access_key(secret_access_key);
+        }
+        if let Some(session_token) = &config.session_token {
+            builder = builder.with_token(session_token);
+        }
+        if let Some(url_style) = config.url_style {
+            builder = builder.with_virtual_hosted_style_request(url_style == UrlStyle::Vhost);
+        }
+        // The builder's errors don't carry credentials, only which setting is wrong.
+        let store = builder
+            .build()
+            .map_err(|err| QueryError::Other(format!("invalid S3 configuration: {}", err)))?;
+        return Ok((Box::new(store), format!("s3://{}/", bucket), pattern));
+    }
+    if source.contains("://") {
+        return Err(QueryError::InvalidFilesystem(format!(
+            "Cannot list {} without DuckDB",
+            path_source(source)
+        )));
+    }
+
+    match source.strip_prefix('/') {
+        Some(pattern) => Ok((Box::new(LocalFileSystem::new()), "/".to_string(), pattern)),
+        None => {
+            let cwd = std::env::current_dir().map_err(|err| QueryError::Other(err.to_string()))?;
+            let store = LocalFileSystem::new_with_prefix(cwd)
+                .map_err(|err| QueryError::Other(err.to_string()))?;
+            Ok((Box::new(store), String::new(), source))
+        }
+    }
+}
+
+fn listing_error(source: &str, err: object_store::Error) -> QueryError {
+    match err {
+        object_store::Error::PermissionDenied { .. }
+        | object_store::Error::Unauthenticated { .. } => {
+            QueryError::AccessDenied(vec![path_source(source)])
+        }
+        err => QueryError::Other(format!("prefix scan failed: {}", err)),
+    }
+}
+
+/// Translates a DuckDB glob into an anchored regex over object paths.
+fn glob_regex(pattern: &str) -> Regex {
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                let class: String = chars.by_ref().take_while(|c| *c != ']').collect();
+                let (negated, class) = match class.strip_prefix('!') {
+                    Some(class) => (true, class),
+                    None => (false, class.as_str()),
+                };
+                regex.push('[');
+                if negated {
+                    regex.push('^');
+                }
+                regex.push_str(&class.replace('\\', r"\\").replace('[', r"\["));
+                regex.push(']');
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).unwrap_or_else(|_| Regex::new("^$").expect("empty regex is valid"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn test_glob_regex() {
+        let matches = |pattern: &str, path: &str| glob_regex(pattern).is_match(path);
+        assert!(matches("data/*.parquet", "data/a.parquet"));
+        assert!(!matches("data/*.parquet", "data/2024/a.parquet"));
+        assert!(matches("data/**/*.parquet", "data/a.parquet"));
+        assert!(matches("data/**/*.parquet", "data/2024/01/a.parquet"));
+        assert!(matches("data/**", "data/2024/a.csv"));
+        assert!(matches("data/part-?.csv", "data/part-1.csv"));
+        assert!(!matches("data/part-?.csv", "data/part-10.csv"));
+        assert!(matches("data/[ab].csv", "data/b.csv"));
+        assert!(!matches("data/[!ab].csv", "data/b.csv"));
+        assert!(!matches("data/a.csv", "data/aXcsv"));
+    }
+
+    #[tokio::test]
+    async fn test_matches_duckdb_scan_on_local_files() {
+        let root = std::env::temp_dir().join(format!("pond-listing-{}", std::process::id()));
+        for file in [
+            "data/a.parquet",
+            "data/b.csv",
+            "data/2024/01/c.parquet",
+            "data/2024/02/d.parquet",
+            "data/2024/02/e.parquet",
+            "data/2024/03/empty.parquet",
+            "other/f.parquet",
+        ] {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let contents: &[u8] = if file.ends_with("empty.parquet") {
+                b""
+            } else {
+                b"PAR1"
+            };
+            fs::write(path, contents).unwrap();
+        }
+
+        let config = ScanConfig::from_env().timeout(Duration::from_secs(30));
+        let root_text = root.to_str().unwrap();
+        for pattern in [
+            "data/*.parquet",
+            "data/*",
+            "data/**/*.parquet",
+            "data/2024/*/*.parquet",
+            "data/2024/0?/d.parquet",
+            "*/*.parquet",
+        ] {
+            let query = format!("SELECT * FROM '{}/{}'", root_text, pattern);
+            let wrapper = QueryWrapper::parse(&query).unwrap();
+            let expected = wrapper.scan_source_for_prefix_stats(&config).await.unwrap();
+            let listed = wrapper
+                .scan_source_for_prefix_stats_async(&config)
+                .await
+                .unwrap();
+            assert_eq!(listed, expected, "{}", pattern);
+            assert!(!listed.is_empty(), "{}", pattern);
+        }
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
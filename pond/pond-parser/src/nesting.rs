@@ -0,0 +1,87 @@
+use crate::QueryWrapper;
+use sqlparser::ast::{Query, SetExpr, Visit, Visitor};
+use std::ops::ControlFlow;
+
+impl QueryWrapper {
+    /// How deeply queries nest across the batch: 0 for a flat SELECT, 1 for a
+    /// subquery in FROM, a CTE or a UNION, and one more for each further level.
+    pub fn max_nesting_depth(&self) -> usize {
+        std::iter::once(&self.ast)
+            .chain(&self.trailing)
+            .map(|statement| {
+                let mut finder = DepthFinder::default();
+                let _ = statement.visit(&mut finder);
+                finder.max
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Tracks how deeply queries nest: 0 for a flat SELECT, plus one per
+/// subquery or CTE a query sits in and per set operation above it.
+#[derive(Default)]
+struct DepthFinder {
+    /// The depth inside each query being visited, outermost first.
+    stack: Vec<usize>,
+    max: usize,
+}
+
+impl Visitor for DepthFinder {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<()> {
+        let level = self.stack.last().map_or(0, |parent| parent + 1);
+        let inner = level + set_operation_depth(&query.body);
+        self.max = self.max.max(inner);
+        self.stack.push(inner);
+        ControlFlow::Continue(())
+    }
+
+    fn post_visit_query(&mut self, _query: &Query) -> ControlFlow<()> {
+        self.stack.pop();
+        ControlFlow::Continue(())
+    }
+}
+
+/// How many set operations nest in `body`, not counting parenthesized
+/// queries, which are visited separately.
+fn set_operation_depth(body: &SetExpr) -> usize {
+    match body {
+        SetExpr::SetOperation { left, right, .. } => {
+            1 + set_operation_depth(left).max(set_operation_depth(right))
+        }
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::QueryError;
+
+    #[test]
+    fn test_max_nesting_depth() {
+        let depth = |sql: &str| QueryWrapper::parse(sql).unwrap().max_nesting_depth();
+        assert_eq!(depth("SELECT a FROM t WHERE b > 1"), 0);
+        assert_eq!(depth("SELECT * FROM (SELECT a FROM t)"), 1);
+        assert_eq!(depth("WITH c AS (SELECT a FROM t) SELECT * FROM c"), 1);
+        assert_eq!(depth("SELECT a FROM t UNION SELECT b FROM u"), 1);
+        assert_eq!(
+            depth("SELECT * FROM (SELECT * FROM (SELECT a FROM t)) WHERE a IN (SELECT b FROM u)"),
+            2
+        );
+        assert_eq!(
+            depth("SELECT a FROM t UNION SELECT b FROM u WHERE b IN (SELECT c FROM v)"),
+            2
+        );
+        assert_eq!(depth("SELECT 1; SELECT * FROM (SELECT 1)"), 1);
+
+        let sql = "SELECT * FROM (SELECT * FROM (SELECT * FROM (SELECT 1)))";
+        assert!(QueryWrapper::parse_with_max_depth(sql, 3).is_ok());
+        assert!(matches!(
+            QueryWrapper::parse_with_max_depth(sql, 2),
+            Err(QueryError::TooDeep(3))
+        ));
+    }
+}
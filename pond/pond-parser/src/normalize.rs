@@ -0,0 +1,177 @@
+use crate::{QueryError, QueryWrapper};
+use sqlparser::ast::{
+    Expr, FunctionArg, Ident, ObjectName, TableFactor, Value, VisitMut, VisitorMut,
+};
+use sqlparser::dialect::DuckDbDialect;
+use sqlparser::parser::Parser;
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+
+impl QueryWrapper {
+    /// Rewrites the relations the query reads according to `mapping`, e.g. to
+    /// point a query written against staging buckets at production ones.
+    ///
+    /// Keys are matched exactly against bare table names (as dotted names,
+    /// without quotes), quoted paths such as `FROM 's3://bucket/x.parquet'` and
+    /// string arguments of table functions such as `read_parquet`. Nothing
+    /// outside a FROM clause is touched, so column names and string literals in
+    /// WHERE clauses that happen to contain a key stay as they are.
+    ///
+    /// A bare table name may map to another name (`analytics.events`) or to a
+    /// path, which is then quoted. Fails if a replacement for a table name is
+    /// neither.
+    pub fn normalize_table_names(
+        &mut self,
+        mapping: &HashMap<String, String>,
+    ) -> Result<(), QueryError> {
+        let mut normalizer = Normalizer { mapping };
+        for statement in std::iter::once(&mut self.ast).chain(&mut self.trailing) {
+            if let ControlFlow::Break(err) = statement.visit(&mut normalizer) {
+                return Err(err);
+            }
+        }
+        self.list_of_prefixes = None;
+        self.rerender();
+        Ok(())
+    }
+}
+
+struct Normalizer<'a> {
+    mapping: &'a HashMap<String, String>,
+}
+
+impl Normalizer<'_> {
+    fn rewrite_arguments(&self, args: &mut Vec<FunctionArg>) {
+        let _ = sqlparser::ast::visit_expressions_mut(args, |expr| {
+            if let Expr::Value(Value::SingleQuotedString(path)) = expr {
+                if let Some(new) = self.mapping.get(path.as_str()) {
+                    *path = new.clone();
+                }
+            }
+            ControlFlow::<()>::Continue(())
+        });
+    }
+
+    fn rewrite_name(&self, name: &mut ObjectName) -> Result<(), QueryError> {
+        // `FROM 's3://bucket/file.parquet'`
+        if let [ident] = name.0.as_mut_slice() {
+            if ident.quote_style == Some('\'') {
+                if let Some(new) = self.mapping.get(&ident.value) {
+                    ident.value = new.clone();
+                }
+                return Ok(());
+            }
+        }
+
+        let key = name
+            .0
+            .iter()
+            .map(|ident| ident.value.as_str())
+            .collect::<Vec<_>>()
+            .join(".");
+        if let Some(new) = self.mapping.get(&key) {
+            *name = table_name(new)?;
+        }
+        Ok(())
+    }
+}
+
+impl VisitorMut for Normalizer<'_> {
+    type Break = QueryError;
+
+    fn pre_visit_table_factor(
+        &mut self,
+        table_factor: &mut TableFactor,
+    ) -> ControlFlow<QueryError> {
+        match table_factor {
+            TableFactor::Table {
+                args: Some(args), ..
+            } => self.rewrite_arguments(&mut args.args),
+            TableFactor::Table { name, .. } => {
+                if let Err(err) = self.rewrite_name(name) {
+                    return ControlFlow::Break(err);
+                }
+            }
+            TableFactor::Function { args, .. } => self.rewrite_arguments(args),
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// Paths (anything with a scheme or a `/`) become a quoted relation; anything
+/// else must parse as a possibly qualified table name.
+fn table_name(replacement: &str) -> Result<ObjectName, QueryError> {
+    if replacement.contains("://") || replacement.contains('/') {
+        return Ok(ObjectName(vec![Ident::with_quote('\'', replacement)]));
+    }
+    let mut parser = Parser::new(&DuckDbDialect {}).try_with_sql(replacement)?;
+    let name = parser.parse_object_name(false)?;
+    if name.to_string() != replacement {
+        return Err(QueryError::Other(format!(
+            "Invalid table name replacement: {}",
+            replacement
+        )));
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(sql: &str, pairs: &[(&str, &str)]) -> Result<String, QueryError> {
+        let mapping = pairs
+            .iter()
+            .map(|(old, new)| (old.to_string(), new.to_string()))
+            .collect();
+        let mut wrapper = QueryWrapper::parse(sql)?;
+        wrapper.normalize_table_names(&mapping)?;
+        Ok(wrapper.sql)
+    }
+
+    #[test]
+    fn test_normalize_paths_and_reader_arguments() {
+        let sql = "SELECT s.id FROM 's3://staging/sales.parquet' s \
+                   JOIN read_csv('s3://staging/users.csv', delim = ',') u ON s.uid = u.id";
+        assert_eq!(
+            normalized(
+                sql,
+                &[
+                    ("s3://staging/sales.parquet", "s3://prod/sales.parquet"),
+                    ("s3://staging/users.csv", "s3://prod/users.csv"),
+                ]
+            )
+            .unwrap(),
+            "SELECT s.id FROM 's3://prod/sales.parquet' AS s \
+             JOIN read_csv('s3://prod/users.csv', delim = ',') AS u ON s.uid = u.id"
+        );
+    }
+
+    #[test]
+    fn test_normalize_leaves_columns_and_literals_alone() {
+        let sql = "SELECT sales, 'sales' FROM sales WHERE note = 'sales' \
+                   AND id IN (SELECT id FROM sales)";
+        assert_eq!(
+            normalized(sql, &[("sales", "prod.sales")]).unwrap(),
+            "SELECT sales, 'sales' FROM prod.sales WHERE note = 'sales' \
+             AND id IN (SELECT id FROM prod.sales)"
+        );
+    }
+
+    #[test]
+    fn test_normalize_table_to_path() {
+        assert_eq!(
+            normalized(
+                "SELECT * FROM analytics.events",
+                &[("analytics.events", "s3://prod/events/*.parquet")]
+            )
+            .unwrap(),
+            "SELECT * FROM 's3://prod/events/*.parquet'"
+        );
+        assert!(matches!(
+            normalized("SELECT * FROM events", &[("events", "not a name")]),
+            Err(QueryError::Other(_))
+        ));
+    }
+}
@@ -0,0 +1,354 @@
+use crate::{QueryError, QueryWrapper};
+use sqlparser::ast::{Query as SqlQuery, SetExpr, Statement, Visit, Visitor};
+use std::ops::ControlFlow;
+
+/// Which kinds of statements a caller is willing to execute.
+///
+/// Plain queries are always allowed; everything else is off unless switched on.
+/// Start from [`QueryPolicy::read_only`] (also the default) and enable what you
+/// need:
+///
+/// ```
+/// use pond_parser::QueryPolicy;
+///
+/// let policy = QueryPolicy::read_only().allow_pragma(true).max_statement_count(3);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryPolicy {
+    allow_ddl: bool,
+    allow_dml: bool,
+    allow_copy_to: bool,
+    allow_install_load: bool,
+    allow_attach: bool,
+    allow_pragma: bool,
+    allow_other: bool,
+    max_statement_count: Option<usize>,
+    strict: bool,
+}
+
+impl Default for QueryPolicy {
+    fn default() -> Self {
+        Self::read_only()
+    }
+}
+
+impl QueryPolicy {
+    /// A single query and nothing else.
+    pub fn read_only() -> Self {
+        Self {
+            allow_ddl: false,
+            allow_dml: false,
+            allow_copy_to: false,
+            allow_install_load: false,
+            allow_attach: false,
+            allow_pragma: false,
+            allow_other: false,
+            max_statement_count: Some(1),
+            strict: false,
+        }
+    }
+
+    /// CREATE, ALTER, DROP, TRUNCATE, GRANT and friends, plus `SELECT ... INTO`.
+    pub fn allow_ddl(mut self, allow: bool) -> Self {
+        self.allow_ddl = allow;
+        self
+    }
+
+    /// INSERT, UPDATE, DELETE, MERGE and `COPY ... FROM`.
+    pub fn allow_dml(mut self, allow: bool) -> Self {
+        self.allow_dml = allow;
+        self
+    }
+
+    /// `COPY ... TO` and other statements that write files.
+    pub fn allow_copy_to(mut self, allow: bool) -> Self {
+        self.allow_copy_to = allow;
+        self
+    }
+
+    /// INSTALL and LOAD of DuckDB extensions.
+    pub fn allow_install_load(mut self, allow: bool) -> Self {
+        self.allow_install_load = allow;
+        self
+    }
+
+    /// ATTACH and DETACH of other databases.
+    pub fn allow_attach(mut self, allow: bool) -> Self {
+        self.allow_attach = allow;
+        self
+    }
+
+    /// PRAGMA, SET and CALL, which change DuckDB settings.
+    pub fn allow_pragma(mut self, allow: bool) -> Self {
+        self.allow_pragma = allow;
+        self
+    }
+
+    /// Statements outside every other category, such as SHOW or transaction control.
+    pub fn allow_other(mut self, allow: bool) -> Self {
+        self.allow_other = allow;
+        self
+    }
+
+    /// Caps the number of `;`-separated statements; `None` removes the cap.
+    pub fn max_statement_count(mut self, max: impl Into<Option<usize>>) -> Self {
+        self.max_statement_count = max.into();
+        self
+    }
+
+    /// Rejects queries with an [`AnalysisWarning`](crate::AnalysisWarning), such as a CROSS JOIN, as
+    /// violating the `cartesian_product` rule.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// The rule `statement` falls under, or `None` when the policy allows it.
+    fn denied_rule(&self, statement: &Statement) -> Option<&'static str> {
+        let (rule, allowed) = match statement {
+            Statement::Query(_) | Statement::Explain { .. } | Statement::ExplainTable { .. } => {
+                return None
+            }
+            Statement::Insert(_)
+            | Statement::Update { .. }
+            | Statement::Delete(_)
+            | Statement::Merge { .. }
+            | Statement::Copy { to: false, .. }
+            | Statement::CopyIntoSnowflake { .. } => ("dml", self.allow_dml),
+            Statement::Copy { to: true, .. }
+            | Statement::Directory { .. }
+            | Statement::Unload { .. } => ("copy_to", self.allow_copy_to),
+            Statement::Install { .. } | Statement::Load { .. } => {
+                ("install_load", self.allow_install_load)
+            }
+            Statement::AttachDatabase { .. }
+            | Statement::AttachDuckDBDatabase { .. }
+            | Statement::DetachDuckDBDatabase { .. } => ("attach", self.allow_attach),
+            Statement::Pragma { .. }
+            | Statement::Call(_)
+            | Statement::SetVariable { .. }
+            | Statement::SetTimeZone { .. }
+            | Statement::SetNames { .. }
+            | Statement::SetNamesDefault {}
+            | Statement::SetRole { .. } => ("pragma", self.allow_pragma),
+            Statement::CreateView { .. }
+            | Statement::CreateTable(_)
+            | Statement::CreateVirtualTable { .. }
+            | Statement::CreateIndex(_)
+            | Statement::CreateRole { .. }
+            | Statement::CreateSecret { .. }
+            | Statement::CreateSchema { .. }
+            | Statement::CreateDatabase { .. }
+            | Statement::CreateFunction { .. }
+            | Statement::CreateTrigger { .. }
+            | Statement::CreateProcedure { .. }
+            | Statement::CreateMacro { .. }
+            | Statement::CreateStage { .. }
+            | Statement::CreateSequence { .. }
+            | Statement::CreateType { .. }
+            | Statement::CreateExtension { .. }
+            | Statement::AlterTable { .. }
+            | Statement::AlterIndex { .. }
+            | Statement::AlterView { .. }
+            | Statement::AlterRole { .. }
+            | Statement::Drop { .. }
+            | Statement::DropFunction { .. }
+            | Statement::DropProcedure { .. }
+            | Statement::DropSecret { .. }
+            | Statement::DropTrigger { .. }
+            | Statement::Truncate { .. }
+            | Statement::Comment { .. }
+            | Statement::Grant { .. }
+            | Statement::Revoke { .. } => ("ddl", self.allow_ddl),
+            _ => ("other", self.allow_other),
+        };
+        (!allowed).then_some(rule)
+    }
+}
+
+impl QueryWrapper {
+    /// Checks every statement against `policy`, returning the first
+    /// [`QueryError::PolicyViolation`].
+    ///
+    /// The whole AST is walked, so statements nested inside others (the target
+    /// of a `PREPARE` or `EXPLAIN`) and `SELECT ... INTO` buried in a CTE or
+    /// subquery are held to the same rules as top-level ones.
+    pub fn validate(&self, policy: &QueryPolicy) -> Result<(), QueryError> {
+        let statements: Vec<&Statement> =
+            std::iter::once(&self.ast).chain(&self.trailing).collect();
+
+        if let Some(max) = policy.max_statement_count {
+            if let Some(statement) = statements.get(max) {
+                return Err(violation("max_statement_count", statement));
+            }
+        }
+
+        let mut checker = PolicyChecker { policy };
+        for statement in statements {
+            if let ControlFlow::Break(err) = statement.visit(&mut checker) {
+                return Err(err);
+            }
+        }
+
+        if policy.strict {
+            if let Some(warning) = self.analyze().warnings().first() {
+                return Err(violation("cartesian_product", warning));
+            }
+        }
+        Ok(())
+    }
+}
+
+struct PolicyChecker<'a> {
+    policy: &'a QueryPolicy,
+}
+
+impl Visitor for PolicyChecker<'_> {
+    type Break = QueryError;
+
+    fn pre_visit_statement(&mut self, statement: &Statement) -> ControlFlow<QueryError> {
+        match self.policy.denied_rule(statement) {
+            Some(rule) => ControlFlow::Break(violation(rule, statement)),
+            None => ControlFlow::Continue(()),
+        }
+    }
+
+    fn pre_visit_query(&mut self, query: &SqlQuery) -> ControlFlow<QueryError> {
+        // `SELECT ... INTO t` creates a table.
+        if let SetExpr::Select(select) = query.body.as_ref() {
+            if select.into.is_some() && !self.policy.allow_ddl {
+                return ControlFlow::Break(violation("ddl", select));
+            }
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+fn violation(rule: &str, fragment: impl ToString) -> QueryError {
+    QueryError::PolicyViolation {
+        rule: rule.to_string(),
+        fragment: fragment.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(sql: &str, policy: &QueryPolicy) -> Option<String> {
+        match QueryWrapper::parse(sql).unwrap().validate(policy) {
+            Ok(()) => None,
+            Err(QueryError::PolicyViolation { rule, .. }) => Some(rule),
+            Err(err) => panic!("unexpected error for {}: {}", sql, err),
+        }
+    }
+
+    #[test]
+    fn test_read_only_allows_select() {
+        let policy = QueryPolicy::read_only();
+        assert_eq!(rule("SELECT * FROM 's3://bucket/a.parquet'", &policy), None);
+        assert_eq!(
+            rule(
+                "WITH t AS (SELECT 1 AS x) SELECT x FROM t WHERE x IN (SELECT 1)",
+                &policy
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_read_only_denies_each_category() {
+        let policy = QueryPolicy::read_only();
+        let cases = [
+            ("CREATE TABLE t (x INT)", "ddl"),
+            ("DROP TABLE t", "ddl"),
+            ("SELECT 1 AS x INTO t", "ddl"),
+            ("INSERT INTO t VALUES (1)", "dml"),
+            ("DELETE FROM t", "dml"),
+            ("UPDATE t SET x = 2", "dml"),
+            ("COPY t FROM 'data.csv'", "dml"),
+            ("COPY t TO 's3://bucket/out.csv'", "copy_to"),
+            ("INSTALL httpfs", "install_load"),
+            ("LOAD httpfs", "install_load"),
+            ("ATTACH 'other.db' AS other", "attach"),
+            ("PRAGMA enable_profiling", "pragma"),
+            ("SET threads = 1", "pragma"),
+            ("SHOW TABLES", "other"),
+            ("SELECT 1; SELECT 2", "max_statement_count"),
+        ];
+        for (sql, expected) in cases {
+            assert_eq!(rule(sql, &policy).as_deref(), Some(expected), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_nested_statements_are_checked() {
+        let policy = QueryPolicy::read_only().allow_other(true);
+        assert_eq!(
+            rule("EXPLAIN DELETE FROM t", &policy).as_deref(),
+            Some("dml")
+        );
+        assert_eq!(
+            rule("PREPARE wipe AS DELETE FROM t", &policy).as_deref(),
+            Some("dml")
+        );
+        assert_eq!(rule("PREPARE q AS SELECT 1", &policy), None);
+    }
+
+    #[test]
+    fn test_toggles_and_statement_count() {
+        let policy = QueryPolicy::read_only()
+            .allow_install_load(true)
+            .allow_pragma(true)
+            .max_statement_count(3);
+        assert_eq!(rule("INSTALL httpfs; LOAD httpfs; SELECT 1", &policy), None);
+        assert_eq!(
+            rule(
+                "SET threads = 1; PRAGMA version; SELECT 1; SELECT 2",
+                &policy
+            )
+            .as_deref(),
+            Some("max_statement_count")
+        );
+        assert_eq!(
+            rule("LOAD httpfs; DROP TABLE t", &policy).as_deref(),
+            Some("ddl")
+        );
+
+        let unlimited = policy.max_statement_count(None);
+        assert_eq!(
+            rule("SELECT 1; SELECT 2; SELECT 3; SELECT 4", &unlimited),
+            None
+        );
+    }
+
+    #[test]
+    fn test_violation_reports_fragment() {
+        let err = QueryWrapper::parse("SELECT 1; DROP TABLE sales")
+            .unwrap()
+            .validate(&QueryPolicy::read_only().max_statement_count(None))
+            .unwrap_err();
+        match err {
+            QueryError::PolicyViolation { rule, fragment } => {
+                assert_eq!(rule, "ddl");
+                assert_eq!(fragment, "DROP TABLE sales");
+            }
+            other => panic!("unexpected error: {}", other),
+        }
+    }
+
+    #[test]
+    fn test_strict_rejects_cartesian_products() {
+        let sql = "SELECT * FROM orders CROSS JOIN customers";
+        assert_eq!(rule(sql, &QueryPolicy::read_only()), None);
+        let strict = QueryPolicy::read_only().strict(true);
+        assert_eq!(rule(sql, &strict), Some("cartesian_product".to_string()));
+        assert_eq!(
+            rule(
+                "SELECT * FROM orders o, customers c WHERE o.customer_id = c.id",
+                &strict
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,418 @@
+use crate::columns::relation_name;
+use crate::schema::relation_path;
+use crate::{normalized_ident, normalized_name, QueryError, QueryWrapper};
+use sqlparser::ast::{
+    Expr, Ident, JoinConstraint, JoinOperator, Query as SqlQuery, Select, SelectItem, SetExpr,
+    Statement, TableFactor, Visit, Visitor,
+};
+use std::collections::{HashMap, HashSet};
+use std::ops::ControlFlow;
+
+/// Stands for every column of a relation in
+/// [`QueryWrapper::required_columns`], for `SELECT *` and `t.*`.
+pub const ALL_COLUMNS: &str = "*";
+
+impl QueryWrapper {
+    /// The columns the outer SELECT reads from each relation in its FROM
+    /// clause: those in the projection, WHERE, GROUP BY, HAVING, QUALIFY,
+    /// ORDER BY and join conditions.
+    ///
+    /// Relations are keyed by the path they read, else their table name, else
+    /// their alias. Names and columns are lowercased unless quoted, as in
+    /// [`QueryWrapper::analyze`]. Qualified columns are resolved through aliases, and
+    /// references to projection aliases are dropped since the aliased
+    /// expression's columns are already counted. Without a schema the owner
+    /// of an unqualified column is unknown when several relations are joined,
+    /// so it is listed under each of them. Wildcards list [`ALL_COLUMNS`].
+    /// Columns used only inside subqueries aren't included.
+    pub fn required_columns(&self) -> HashMap<String, HashSet<String>> {
+        let mut required: HashMap<String, HashSet<String>> = HashMap::new();
+        let Some(select) = self.outer_select() else {
+            return required;
+        };
+        for relation in Requirements::of(select, self.order_by_exprs()).relations {
+            if let Some(key) = relation.key {
+                required
+                    .entry(key)
+                    .or_default()
+                    .extend(relation.columns.iter().map(normalized_ident));
+            }
+        }
+        required
+    }
+
+    /// Narrows `SELECT *` subqueries in the outer FROM clause, such as
+    /// `FROM (SELECT * FROM 's3://...') t`, to the columns the query needs
+    /// from them (see [`required_columns`](Self::required_columns)), so a
+    /// worker reads only those.
+    ///
+    /// Subqueries with a join, CTE, GROUP BY, HAVING or DISTINCT of their own
+    /// are left alone, as are those the query needs whole, and everything is
+    /// when an unqualified column could come from more than one relation.
+    pub fn with_projection_pushdown(&mut self) -> Result<(), QueryError> {
+        let Some(select) = self.outer_select() else {
+            return Ok(());
+        };
+        let requirements = Requirements::of(select, self.order_by_exprs());
+        if requirements.ambiguous {
+            return Ok(());
+        }
+
+        let Statement::Query(query) = &mut self.ast else {
+            return Ok(());
+        };
+        let SetExpr::Select(select) = query.body.as_mut() else {
+            return Ok(());
+        };
+        let relations = select
+            .from
+            .iter_mut()
+            .flat_map(|from| {
+                std::iter::once(&mut from.relation)
+                    .chain(from.joins.iter_mut().map(|join| &mut join.relation))
+            })
+            .zip(requirements.relations);
+        let mut changed = false;
+        for (relation, required) in relations {
+            if required.columns.is_empty()
+                || required
+                    .columns
+                    .iter()
+                    .any(|ident| ident.value == ALL_COLUMNS)
+            {
+                continue;
+            }
+            if let TableFactor::Derived { subquery, .. } = relation {
+                if let Some(inner) = star_select(subquery) {
+                    inner.projection = required
+                        .columns
+                        .into_iter()
+                        .map(|ident| SelectItem::UnnamedExpr(Expr::Identifier(ident)))
+                        .collect();
+                    changed = true;
+                }
+            }
+        }
+        if changed {
+            self.rerender();
+        }
+        Ok(())
+    }
+
+    fn order_by_exprs(&self) -> Vec<&Expr> {
+        match &self.ast {
+            Statement::Query(query) => query
+                .order_by
+                .iter()
+                .flat_map(|order_by| &order_by.exprs)
+                .map(|order| &order.expr)
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// The SELECT of a subquery that is a plain `SELECT * FROM <one relation>`,
+/// whose projection can be narrowed without changing its rows.
+fn star_select(subquery: &mut SqlQuery) -> Option<&mut Select> {
+    if subquery.with.is_some() {
+        return None;
+    }
+    let SetExpr::Select(select) = subquery.body.as_mut() else {
+        return None;
+    };
+    // EXCLUDE only drops columns nothing outside can reference, but REPLACE
+    // changes what a column holds, so it must stay.
+    let plain = matches!(
+        select.projection.as_slice(),
+        [SelectItem::Wildcard(options)] if options.opt_replace.is_none()
+    ) && select.distinct.is_none()
+        && select.having.is_none()
+        && matches!(
+            &select.group_by,
+            sqlparser::ast::GroupByExpr::Expressions(exprs, modifiers)
+                if exprs.is_empty() && modifiers.is_empty()
+        )
+        && matches!(select.from.as_slice(), [from] if from.joins.is_empty());
+    plain.then_some(select.as_mut())
+}
+
+/// The columns needed from each relation of a SELECT, in FROM order.
+struct Requirements {
+    relations: Vec<Required>,
+    /// Whether an unqualified column was attributed to several relations.
+    ambiguous: bool,
+}
+
+struct Required {
+    key: Option<String>,
+    /// Lowercased alias or table name, to resolve qualified columns.
+    name: Option<String>,
+    /// In order of first use, without duplicates. DuckDB matches even quoted
+    /// names case-insensitively, so `"Region"` and `region` are one column.
+    columns: Vec<Ident>,
+}
+
+impl Required {
+    fn add(&mut self, ident: &Ident) {
+        if !self
+            .columns
+            .iter()
+            .any(|seen| seen.value.eq_ignore_ascii_case(&ident.value))
+        {
+            self.columns.push(ident.clone());
+        }
+    }
+}
+
+impl Requirements {
+    fn of(select: &Select, order_by: Vec<&Expr>) -> Self {
+        let mut relations = Vec::new();
+        for from in &select.from {
+            for relation in
+                std::iter::once(&from.relation).chain(from.joins.iter().map(|join| &join.relation))
+            {
+                let key = relation_path(relation).or_else(|| match relation {
+                    TableFactor::Table { name, .. } => Some(normalized_name(&name.0)),
+                    _ => relation_name(relation),
+                });
+                relations.push(Required {
+                    key,
+                    name: relation_name(relation),
+                    columns: Vec::new(),
+                });
+            }
+        }
+        let mut requirements = Self {
+            relations,
+            ambiguous: false,
+        };
+
+        let aliases: HashSet<String> = select
+            .projection
+            .iter()
+            .filter_map(|item| match item {
+                SelectItem::ExprWithAlias { alias, .. } => Some(alias.value.to_lowercase()),
+                _ => None,
+            })
+            .collect();
+        let mut exprs: Vec<&Expr> = Vec::new();
+        for item in &select.projection {
+            match item {
+                SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                    exprs.push(expr)
+                }
+                SelectItem::Wildcard(_) => {
+                    let all = Ident::new(ALL_COLUMNS);
+                    for relation in &mut requirements.relations {
+                        relation.add(&all);
+                    }
+                }
+                SelectItem::QualifiedWildcard(name, _) => {
+                    let qualifier = name.0.last().map(normalized_ident);
+                    if let Some(relation) = requirements
+                        .relations
+                        .iter_mut()
+                        .find(|relation| relation.name == qualifier)
+                    {
+                        relation.add(&Ident::new(ALL_COLUMNS));
+                    }
+                }
+            }
+        }
+        let in_projection = exprs.len();
+        exprs.extend(&select.selection);
+        if let sqlparser::ast::GroupByExpr::Expressions(group_by, _) = &select.group_by {
+            exprs.extend(group_by);
+        }
+        exprs.extend(&select.having);
+        exprs.extend(&select.qualify);
+        for from in &select.from {
+            for join in &from.joins {
+                match join_constraint(&join.join_operator) {
+                    Some(JoinConstraint::On(expr)) => exprs.push(expr),
+                    Some(JoinConstraint::Using(columns)) => {
+                        for column in columns {
+                            for relation in &mut requirements.relations {
+                                relation.add(column);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        exprs.extend(order_by);
+
+        for (index, expr) in exprs.into_iter().enumerate() {
+            let mut collector = ColumnCollector::default();
+            let _ = expr.visit(&mut collector);
+            for column in collector.columns {
+                // Aliases can't be referenced from inside the projection itself.
+                let is_alias = index >= in_projection
+                    && column.len() == 1
+                    && aliases.contains(&column[0].value.to_lowercase());
+                if !is_alias {
+                    requirements.resolve(&column);
+                }
+            }
+        }
+        requirements
+    }
+
+    /// Attributes a column reference, `[column]` or `[.., relation, column]`,
+    /// to the relation it belongs to.
+    fn resolve(&mut self, column: &[Ident]) {
+        if let [.., qualifier, ident] = column {
+            let qualifier = normalized_ident(qualifier);
+            if let Some(relation) = self
+                .relations
+                .iter_mut()
+                .find(|relation| relation.name.as_deref() == Some(qualifier.as_str()))
+            {
+                relation.add(ident);
+                return;
+            }
+        }
+        // Unqualified, or a struct field of the column the path starts with.
+        let Some(ident) = column.first() else {
+            return;
+        };
+        if self.relations.len() > 1 {
+            self.ambiguous = true;
+        }
+        for relation in &mut self.relations {
+            relation.add(ident);
+        }
+    }
+}
+
+fn join_constraint(operator: &JoinOperator) -> Option<&JoinConstraint> {
+    match operator {
+        JoinOperator::Inner(constraint)
+        | JoinOperator::LeftOuter(constraint)
+        | JoinOperator::RightOuter(constraint)
+        | JoinOperator::FullOuter(constraint)
+        | JoinOperator::LeftSemi(constraint)
+        | JoinOperator::RightSemi(constraint)
+        | JoinOperator::LeftAnti(constraint)
+        | JoinOperator::RightAnti(constraint) => Some(constraint),
+        _ => None,
+    }
+}
+
+/// Column references in an expression, skipping those inside subqueries,
+/// which resolve against their own FROM clause.
+#[derive(Default)]
+struct ColumnCollector {
+    depth: usize,
+    columns: Vec<Vec<Ident>>,
+}
+
+impl Visitor for ColumnCollector {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, _query: &SqlQuery) -> ControlFlow<()> {
+        self.depth += 1;
+        ControlFlow::Continue(())
+    }
+
+    fn post_visit_query(&mut self, _query: &SqlQuery) -> ControlFlow<()> {
+        self.depth -= 1;
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<()> {
+        if self.depth == 0 {
+            match expr {
+                Expr::Identifier(ident) => self.columns.push(vec![ident.clone()]),
+                Expr::CompoundIdentifier(idents) => self.columns.push(idents.clone()),
+                _ => {}
+            }
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn required(sql: &str) -> Vec<(String, Vec<String>)> {
+        let mut required: Vec<_> = QueryWrapper::parse(sql)
+            .unwrap()
+            .required_columns()
+            .into_iter()
+            .map(|(table, columns)| {
+                let mut columns: Vec<_> = columns.into_iter().collect();
+                columns.sort();
+                (table, columns)
+            })
+            .collect();
+        required.sort();
+        required
+    }
+
+    fn pushed_down(sql: &str) -> String {
+        let mut wrapper = QueryWrapper::parse(sql).unwrap();
+        wrapper.with_projection_pushdown().unwrap();
+        wrapper.sql
+    }
+
+    #[test]
+    fn test_required_columns() {
+        let strings = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            required(
+                "SELECT s.region, SUM(s.amount) AS total FROM 's3://b/sales/*.parquet' s \
+                 JOIN users u ON s.user_id = u.id WHERE u.active \
+                 GROUP BY s.region HAVING COUNT(*) > 1 ORDER BY total DESC"
+            ),
+            [
+                (
+                    "s3://b/sales/*.parquet".to_string(),
+                    strings(&["amount", "region", "user_id"])
+                ),
+                ("users".to_string(), strings(&["active", "id"])),
+            ]
+        );
+        assert_eq!(
+            required("SELECT * FROM t WHERE a IN (SELECT b FROM u)"),
+            [("t".to_string(), strings(&["*", "a"]))]
+        );
+        assert_eq!(
+            required("SELECT x.a FROM t x JOIN u USING (id) WHERE b = 1"),
+            [
+                ("t".to_string(), strings(&["a", "b", "id"])),
+                ("u".to_string(), strings(&["b", "id"])),
+            ]
+        );
+        assert_eq!(
+            required("SELECT S.Region FROM Sales s WHERE s.REGION = 'eu' AND \"Day\" > 1"),
+            [("sales".to_string(), strings(&["Day", "region"]))]
+        );
+    }
+
+    #[test]
+    fn test_projection_pushdown() {
+        let before = "SELECT t.region, SUM(t.amount) AS total \
+                      FROM (SELECT * FROM 's3://b/sales/*.parquet') AS t \
+                      WHERE t.day > '2024-01-01' GROUP BY t.region ORDER BY total";
+        assert_eq!(
+            pushed_down(before),
+            "SELECT t.region, SUM(t.amount) AS total \
+             FROM (SELECT region, amount, day FROM 's3://b/sales/*.parquet') AS t \
+             WHERE t.day > '2024-01-01' GROUP BY t.region ORDER BY total"
+        );
+
+        // The owner of an unqualified column is unknown, a wildcard needs every
+        // column, and a DISTINCT subquery's rows depend on all of them.
+        for unchanged in [
+            "SELECT a FROM (SELECT * FROM t) AS x JOIN (SELECT * FROM u) AS y ON x.id = y.id",
+            "SELECT * FROM (SELECT * FROM t) AS x",
+            "SELECT x.a FROM (SELECT DISTINCT * FROM t) AS x",
+        ] {
+            assert_eq!(pushed_down(unchanged), unchanged);
+        }
+    }
+}
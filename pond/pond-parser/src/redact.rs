@@ -0,0 +1,167 @@
+use crate::QueryWrapper;
+use sqlparser::ast::{Expr, Ident, Value, VisitMut, VisitorMut};
+use std::ops::ControlFlow;
+
+impl QueryWrapper {
+    /// The SQL with every string and numeric literal replaced by `?`, for
+    /// logging queries whose filters hold personal data. IN lists collapse
+    /// to `IN (?… /* n */)`, noting how many values there were.
+    ///
+    /// Literals compared against one of `safe_columns` (matched
+    /// case-insensitively by name, any qualifier aside) with `=`, `<`, IN,
+    /// BETWEEN and the like are kept, e.g. for partition dates.
+    pub fn redacted_sql(&self, safe_columns: &[&str]) -> String {
+        let mut redactor = Redactor {
+            safe_columns,
+            safe: Vec::new(),
+        };
+        std::iter::once(&self.ast)
+            .chain(&self.trailing)
+            .map(|statement| {
+                let mut statement = statement.clone();
+                let _ = statement.visit(&mut redactor);
+                statement.to_string()
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+// Hand-written so the SQL's literals can't reach the logs through `{:?}`.
+impl std::fmt::Debug for QueryWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueryWrapper")
+            .field("sql", &self.redacted_sql(&[]))
+            .field("hash", &self.hash())
+            .finish_non_exhaustive()
+    }
+}
+
+struct Redactor<'a> {
+    safe_columns: &'a [&'a str],
+    /// For each expression being visited, outermost first, whether literals
+    /// directly under it are kept.
+    safe: Vec<bool>,
+}
+
+impl Redactor<'_> {
+    fn is_safe_column(&self, expr: &Expr) -> bool {
+        let column = match expr {
+            Expr::Identifier(ident) => ident,
+            Expr::CompoundIdentifier(idents) => match idents.last() {
+                Some(ident) => ident,
+                None => return false,
+            },
+            Expr::Nested(expr) => return self.is_safe_column(expr),
+            _ => return false,
+        };
+        self.safe_columns
+            .iter()
+            .any(|safe| safe.eq_ignore_ascii_case(&column.value))
+    }
+
+    /// Whether `expr` compares one of the safe columns with other values.
+    fn compares_safe_column(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::BinaryOp { left, right, .. } => {
+                self.is_safe_column(left) || self.is_safe_column(right)
+            }
+            Expr::InList { expr, .. } | Expr::Between { expr, .. } => self.is_safe_column(expr),
+            _ => false,
+        }
+    }
+}
+
+impl VisitorMut for Redactor<'_> {
+    type Break = ();
+
+    fn pre_visit_expr(&mut self, expr: &mut Expr) -> ControlFlow<()> {
+        let parent_safe = self.safe.last().copied().unwrap_or(false);
+        // `day = DATE '2024-01-01'` and `day = CAST('2024-01-01' AS DATE)`
+        // keep the literal as much as `day = '2024-01-01'` does.
+        let transparent = matches!(expr, Expr::Cast { .. } | Expr::Nested(_));
+        self.safe
+            .push(self.compares_safe_column(expr) || (parent_safe && transparent));
+        if parent_safe {
+            return ControlFlow::Continue(());
+        }
+
+        match expr {
+            Expr::Value(value) if is_redacted(value) => {
+                *value = Value::Placeholder("?".to_string());
+            }
+            Expr::TypedString { value, .. } => *value = "?".to_string(),
+            Expr::InList { list, .. } if !self.safe.last().copied().unwrap_or(false) => {
+                let count = list.len();
+                *list = vec![Expr::Identifier(Ident::new(format!("?… /* {} */", count)))];
+            }
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn post_visit_expr(&mut self, _expr: &mut Expr) -> ControlFlow<()> {
+        self.safe.pop();
+        ControlFlow::Continue(())
+    }
+}
+
+/// Whether `value` is a string or number, rather than a boolean, NULL or
+/// placeholder.
+fn is_redacted(value: &Value) -> bool {
+    !matches!(
+        value,
+        Value::Boolean(_) | Value::Null | Value::Placeholder(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacted_sql() {
+        let wrapper = QueryWrapper::parse(
+            "SELECT id FROM users u WHERE u.email = 'jane@example.com' AND age > 41 \
+             AND user_id IN (7, 8, 9) AND day BETWEEN '2024-01-01' AND '2024-01-31' \
+             AND deleted = false AND note IS NULL",
+        )
+        .unwrap();
+
+        let redacted = wrapper.redacted_sql(&[]);
+        assert_eq!(
+            redacted,
+            "SELECT id FROM users AS u WHERE u.email = ? AND age > ? \
+             AND user_id IN (?… /* 3 */) AND day BETWEEN ? AND ? \
+             AND deleted = false AND note IS NULL"
+        );
+
+        let redacted = wrapper.redacted_sql(&["DAY", "user_id"]);
+        assert!(!redacted.contains("jane@example.com"), "{}", redacted);
+        assert!(redacted.contains("day BETWEEN '2024-01-01' AND '2024-01-31'"));
+        assert!(redacted.contains("user_id IN (7, 8, 9)"));
+        assert!(redacted.contains("age > ?"));
+    }
+
+    #[test]
+    fn test_debug_never_shows_literals() {
+        let wrapper = QueryWrapper::parse(
+            "SELECT * FROM users WHERE lower(email) = lower('Jane@Example.com') \
+             AND day = DATE '2024-01-01' -- jane@example.com",
+        )
+        .unwrap();
+        let debug = format!("{:?}", wrapper);
+        assert!(
+            !debug.to_lowercase().contains("jane@example.com"),
+            "{}",
+            debug
+        );
+        assert!(!debug.contains("2024-01-01"), "{}", debug);
+        assert!(debug.contains("lower(email) = lower(?)"), "{}", debug);
+
+        // A safe column keeps its literals through the DATE prefix.
+        assert!(wrapper
+            .redacted_sql(&["day"])
+            .contains("day = DATE '2024-01-01'"));
+    }
+}
@@ -0,0 +1,1186 @@
+use crate::access::path_source;
+use crate::{QueryError, QueryWrapper};
+use duckdb::{Connection, Result as DuckResult};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+/// How long a prefix scan may run before giving up, unless overridden by
+/// `POND_PREFIX_SCAN_TIMEOUT_SECS`.
+const DEFAULT_PREFIX_SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How S3 object URLs are addressed: `bucket.host/key` or `host/bucket/key`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrlStyle {
+    Vhost,
+    /// Needed by most S3-compatible servers such as MinIO.
+    Path,
+}
+
+impl UrlStyle {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Vhost => "vhost",
+            Self::Path => "path",
+        }
+    }
+}
+
+/// The files found under one prefix of a scanned source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixStats {
+    /// The directory with `/*` appended, as in plain prefix lists.
+    pub prefix: String,
+    pub file_count: u64,
+    pub total_bytes: u64,
+}
+
+/// One object matched by a query's sources; see [`QueryWrapper::list_files`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    pub path: String,
+    pub size_bytes: u64,
+    pub last_modified: SystemTime,
+}
+
+/// The order a prefix scan returns its prefixes in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PrefixOrder {
+    /// Alphabetical.
+    #[default]
+    Prefix,
+    /// Most bytes first, for bin-packing partitions. Ties are alphabetical.
+    LargestFirst,
+}
+
+/// Where and how a prefix scan reaches object storage, and the order its
+/// results come back in.
+///
+/// [`ScanConfig::from_env`] (also the default) reads the usual AWS variables;
+/// override what you need:
+///
+/// ```
+/// use pond_parser::{ScanConfig, UrlStyle};
+///
+/// let config = ScanConfig::from_env()
+///     .endpoint("http://localhost:9000")
+///     .url_style(UrlStyle::Path);
+/// ```
+#[derive(Clone, PartialEq, Eq)]
+pub struct ScanConfig {
+    pub(crate) region: Option<String>,
+    pub(crate) endpoint: Option<String>,
+    pub(crate) access_key_id: Option<String>,
+    pub(crate) secret_access_key: Option<String>,
+    pub(crate) session_token: Option<String>,
+    pub(crate) url_style: Option<UrlStyle>,
+    pub(crate) timeout: Duration,
+    pub(crate) order: PrefixOrder,
+    pub(crate) depth: Option<usize>,
+    pub(crate) extensions: Vec<String>,
+}
+
+// Hand-written so credentials can't reach the logs through `{:?}`.
+impl std::fmt::Debug for ScanConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let redacted = |value: &Option<String>| value.as_ref().map(|_| "<redacted>");
+        f.debug_struct("ScanConfig")
+            .field("region", &self.region)
+            .field("endpoint", &self.endpoint)
+            .field("access_key_id", &redacted(&self.access_key_id))
+            .field("secret_access_key", &redacted(&self.secret_access_key))
+            .field("session_token", &redacted(&self.session_token))
+            .field("url_style", &self.url_style)
+            .field("timeout", &self.timeout)
+            .field("order", &self.order)
+            .field("depth", &self.depth)
+            .field("extensions", &self.extensions)
+            .finish()
+    }
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        Self::from_env()
+    }
+}
+
+impl ScanConfig {
+    /// Reads `AWS_REGION` (or `AWS_DEFAULT_REGION`), `AWS_ENDPOINT_URL_S3` (or
+    /// `AWS_ENDPOINT_URL`), `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`,
+    /// `AWS_SESSION_TOKEN`, `POND_S3_URL_STYLE` (`vhost` or `path`),
+    /// `POND_PREFIX_SCAN_TIMEOUT_SECS` and `POND_DUCKDB_EXTENSIONS` (e.g.
+    /// `httpfs,json`, default `httpfs`).
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        Self {
+            region: var("AWS_REGION").or_else(|| var("AWS_DEFAULT_REGION")),
+            endpoint: var("AWS_ENDPOINT_URL_S3").or_else(|| var("AWS_ENDPOINT_URL")),
+            access_key_id: var("AWS_ACCESS_KEY_ID"),
+            secret_access_key: var("AWS_SECRET_ACCESS_KEY"),
+            session_token: var("AWS_SESSION_TOKEN"),
+            url_style: match var("POND_S3_URL_STYLE").as_deref() {
+                Some("path") => Some(UrlStyle::Path),
+                Some("vhost") => Some(UrlStyle::Vhost),
+                _ => None,
+            },
+            timeout: var("POND_PREFIX_SCAN_TIMEOUT_SECS")
+                .and_then(|secs| secs.parse::<u64>().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_PREFIX_SCAN_TIMEOUT),
+            order: PrefixOrder::default(),
+            depth: None,
+            extensions: var("POND_DUCKDB_EXTENSIONS").map_or_else(
+                || vec!["httpfs".to_string()],
+                |names| parse_extensions(&names),
+            ),
+        }
+    }
+
+    pub fn region(mut self, region: impl Into<String>) -> Self {
+        self.region = Some(region.into());
+        self
+    }
+
+    /// An S3-compatible endpoint such as `http://localhost:9000`. Without a
+    /// scheme, HTTPS is assumed.
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.endpoint = Some(endpoint.into());
+        self
+    }
+
+    pub fn access_key_id(mut self, access_key_id: impl Into<String>) -> Self {
+        self.access_key_id = Some(access_key_id.into());
+        self
+    }
+
+    pub fn secret_access_key(mut self, secret_access_key: impl Into<String>) -> Self {
+        self.secret_access_key = Some(secret_access_key.into());
+        self
+    }
+
+    /// For temporary credentials, e.g. from an assumed role.
+    pub fn session_token(mut self, session_token: impl Into<String>) -> Self {
+        self.session_token = Some(session_token.into());
+        self
+    }
+
+    pub fn url_style(mut self, url_style: UrlStyle) -> Self {
+        self.url_style = Some(url_style);
+        self
+    }
+
+    /// How long the scan may run before giving up.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn order(mut self, order: PrefixOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Groups files by the first `depth` directories below the glob root (the
+    /// part of the source before its first wildcard), rather than by their
+    /// parent directory. Each group becomes one `dir/**` prefix that also
+    /// covers its subdirectories, with counts and sizes summed; files in
+    /// shallower directories keep their `dir/*` prefix. A depth of 0 gives
+    /// one prefix for the whole root.
+    ///
+    /// For `s3://b/events/date=*/hour=*/*.parquet`, a depth of 1 yields one
+    /// `s3://b/events/date=.../**` prefix per day instead of one per hour.
+    pub fn group_by_segments(mut self, depth: usize) -> Self {
+        self.depth = Some(depth);
+        self
+    }
+
+    /// DuckDB extensions to install and load before reaching object storage,
+    /// replacing those from `POND_DUCKDB_EXTENSIONS`. Names DuckDB doesn't
+    /// know fail the scan.
+    pub fn extensions<I>(mut self, extensions: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.extensions = extensions.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// What a scan of remote sources sets up on its connection.
+    fn remote_setup(&self) -> RemoteSetup {
+        RemoteSetup {
+            secret: self.secret_statement(),
+            extensions: self.extensions.clone(),
+        }
+    }
+
+    /// Folds per-directory file counts and sizes for `source` into prefix
+    /// stats, grouping and sorting as configured and dropping prefixes
+    /// without any bytes.
+    pub(crate) fn summarize(
+        &self,
+        source: &str,
+        directories: impl IntoIterator<Item = (String, u64, u64)>,
+    ) -> Vec<PrefixStats> {
+        let root = glob_root(source);
+        let mut merged = BTreeMap::<String, (u64, u64)>::new();
+        for (directory, files, bytes) in directories {
+            let prefix = match (self.depth, root) {
+                (Some(depth), Some(root)) => grouped_prefix(&directory, root, depth),
+                _ => format!("{}/*", directory),
+            };
+            let (file_count, total_bytes) = merged.entry(prefix).or_default();
+            *file_count += files;
+            *total_bytes += bytes;
+        }
+        let mut stats: Vec<_> = merged
+            .into_iter()
+            .filter(|(_, (_, total_bytes))| *total_bytes > 0)
+            .map(|(prefix, (file_count, total_bytes))| PrefixStats {
+                prefix,
+                file_count,
+                total_bytes,
+            })
+            .collect();
+        if self.order == PrefixOrder::LargestFirst {
+            // Stable, so equal sizes stay alphabetical.
+            stats.sort_by_key(|stats| Reverse(stats.total_bytes));
+        }
+        stats
+    }
+
+    /// The `CREATE SECRET` statement carrying this configuration, or `None`
+    /// when nothing is set and DuckDB's defaults apply.
+    fn secret_statement(&self) -> Option<String> {
+        let mut options = Vec::new();
+        let mut option = |name: &str, value: &str| {
+            options.push(format!("{} '{}'", name, value.replace('\'', "''")));
+        };
+        if let Some(key_id) = &self.access_key_id {
+            option("KEY_ID", key_id);
+        }
+        if let Some(secret) = &self.secret_access_key {
+            option("SECRET", secret);
+        }
+        if let Some(token) = &self.session_token {
+            option("SESSION_TOKEN", token);
+        }
+        if let Some(region) = &self.region {
+            option("REGION", region);
+        }
+        let mut use_ssl = None;
+        if let Some(endpoint) = &self.endpoint {
+            // DuckDB wants the bare host and a separate switch for plain HTTP.
+            let (host, ssl) = match endpoint.split_once("://") {
+                Some((scheme, host)) => (host, !scheme.eq_ignore_ascii_case("http")),
+                None => (endpoint.as_str(), true),
+            };
+            option("ENDPOINT", host.trim_end_matches('/'));
+            use_ssl = Some(ssl);
+        }
+        if let Some(url_style) = self.url_style {
+            option("URL_STYLE", url_style.as_str());
+        }
+        if let Some(use_ssl) = use_ssl {
+            options.push(format!("USE_SSL {}", use_ssl));
+        }
+
+        if options.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "CREATE OR REPLACE SECRET pond_scan (TYPE S3, {})",
+            options.join(", ")
+        ))
+    }
+}
+
+impl QueryWrapper {
+    /// Globs the query's source for its distinct parent prefixes, using
+    /// [`ScanConfig::from_env`].
+    pub async fn scan_source_for_prefixes(&self) -> Result<Vec<String>, QueryError> {
+        self.scan_source_for_prefixes_with_config(&ScanConfig::from_env())
+            .await
+    }
+
+    /// Like [`scan_source_for_prefixes`](Self::scan_source_for_prefixes) with an
+    /// explicit timeout.
+    pub async fn scan_source_for_prefixes_with_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<Vec<String>, QueryError> {
+        self.scan_source_for_prefixes_with_config(&ScanConfig::from_env().timeout(timeout))
+            .await
+    }
+
+    /// Globs the query's source for its distinct parent prefixes, reaching
+    /// object storage as `config` describes. Runs on the shared
+    /// [`PrefixScanner`].
+    pub async fn scan_source_for_prefixes_with_config(
+        &self,
+        config: &ScanConfig,
+    ) -> Result<Vec<String>, QueryError> {
+        PrefixScanner::shared().scan(self, config).await
+    }
+
+    /// Lists every object matching the query's file sources (or its single
+    /// source), without duplicates and sorted by path, using
+    /// [`ScanConfig::from_env`] and the shared [`PrefixScanner`]. `limit` caps
+    /// how many are returned.
+    ///
+    /// A source matching nothing fails with [`QueryError::NoFilesMatched`].
+    pub async fn list_files(&self, limit: Option<usize>) -> Result<Vec<FileEntry>, QueryError> {
+        PrefixScanner::shared()
+            .list_files(self, &ScanConfig::from_env(), limit)
+            .await
+    }
+
+    /// Like
+    /// [`scan_source_for_prefixes_with_config`](Self::scan_source_for_prefixes_with_config),
+    /// with the number and total size of the files under each prefix.
+    pub async fn scan_source_for_prefix_stats(
+        &self,
+        config: &ScanConfig,
+    ) -> Result<Vec<PrefixStats>, QueryError> {
+        PrefixScanner::shared().scan_stats(self, config).await
+    }
+}
+
+/// Runs prefix scans on one DuckDB connection, opened on first use and kept
+/// for later scans so httpfs is installed and loaded once per process rather
+/// than once per scan.
+///
+/// Clones share the connection. Scans through one scanner run one at a time;
+/// [`PrefixScanner::shared`] is the scanner `QueryWrapper`'s own scan methods
+/// use.
+///
+/// In a debug build, `bench_connection_reuse` measured about 32ms per local scan
+/// on fresh connections and 3ms on a reused one. `s3://` sources also skip
+/// re-running `INSTALL httpfs; LOAD httpfs;`, which can go to the network.
+#[derive(Clone, Default)]
+pub struct PrefixScanner {
+    state: Arc<Mutex<Option<ScanConnection>>>,
+}
+
+impl std::fmt::Debug for PrefixScanner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PrefixScanner").finish_non_exhaustive()
+    }
+}
+
+impl PrefixScanner {
+    /// A scanner with its own connection, opened on the first scan.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A scanner using `conn` as is, e.g. one with extensions or settings
+    /// prepared by a test. [`ScanConfig::extensions`] are still loaded before
+    /// the first remote scan.
+    pub fn with_connection(conn: Connection) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(Some(ScanConnection::new(conn)))),
+        }
+    }
+
+    /// The process-wide scanner.
+    pub fn shared() -> Self {
+        static SHARED: OnceLock<PrefixScanner> = OnceLock::new();
+        SHARED.get_or_init(Self::new).clone()
+    }
+
+    /// Globs `wrapper`'s source for its distinct parent prefixes, reaching
+    /// object storage as `config` describes. See
+    /// [`scan_stats`](Self::scan_stats).
+    pub async fn scan(
+        &self,
+        wrapper: &QueryWrapper,
+        config: &ScanConfig,
+    ) -> Result<Vec<String>, QueryError> {
+        let stats = self.scan_stats(wrapper, config).await?;
+        Ok(stats.into_iter().map(|stats| stats.prefix).collect())
+    }
+
+    /// Globs `wrapper`'s source and sums the files under each parent prefix,
+    /// reaching object storage as `config` describes. Sizes come from the
+    /// listing; no file is read. Prefixes holding only empty files are left
+    /// out.
+    ///
+    /// Authorization failures (HTTP 403, rejected keys) are reported as
+    /// [`QueryError::AccessDenied`] naming the bucket. DuckDB has no cooperative
+    /// cancellation here, so a timed-out scan is abandoned on its blocking
+    /// thread rather than stopped, and holds the connection until it finishes.
+    pub async fn scan_stats(
+        &self,
+        wrapper: &QueryWrapper,
+        config: &ScanConfig,
+    ) -> Result<Vec<PrefixStats>, QueryError> {
+        if !wrapper.has_from() {
+            return Ok(Vec::new());
+        }
+        let source = wrapper.source()?;
+        let remote = config.remote_setup();
+        let summary = config.clone();
+        self.run("prefix scan", config, move |conn| {
+            let directories = conn.glob_directories(&source, &remote)?;
+            Ok(summary.summarize(&source, directories))
+        })
+        .await
+    }
+
+    /// Lists the objects matching `wrapper`'s file sources, or its single
+    /// source when it has none, without duplicates and sorted by path.
+    /// `limit` caps how many are listed per source and returned overall.
+    ///
+    /// A source matching nothing fails with [`QueryError::NoFilesMatched`]; a
+    /// query with no FROM clause lists nothing.
+    pub async fn list_files(
+        &self,
+        wrapper: &QueryWrapper,
+        config: &ScanConfig,
+        limit: Option<usize>,
+    ) -> Result<Vec<FileEntry>, QueryError> {
+        if !wrapper.has_from() {
+            return Ok(Vec::new());
+        }
+        let mut sources = Vec::new();
+        for (source, _) in wrapper.file_sources() {
+            if !sources.contains(&source) {
+                sources.push(source);
+            }
+        }
+        if sources.is_empty() {
+            sources.push(wrapper.source()?);
+        }
+        let remote = config.remote_setup();
+        self.run("file listing", config, move |conn| {
+            let mut files = BTreeMap::new();
+            for source in &sources {
+                for file in conn.list_files(source, &remote, limit)? {
+                    files.entry(file.path.clone()).or_insert(file);
+                }
+            }
+            let mut files: Vec<_> = files.into_values().collect();
+            files.truncate(limit.unwrap_or(usize::MAX));
+            Ok(files)
+        })
+        .await
+    }
+
+    /// The objects matching the single glob `source`, sorted by path.
+    pub(crate) async fn list_source_files(
+        &self,
+        source: String,
+        config: &ScanConfig,
+    ) -> Result<Vec<FileEntry>, QueryError> {
+        let remote = config.remote_setup();
+        self.run("file listing", config, move |conn| {
+            conn.list_files(&source, &remote, None)
+        })
+        .await
+    }
+
+    /// Runs `scan` on this scanner's connection, on a blocking thread and
+    /// within `config`'s timeout, opening the connection first if needed.
+    async fn run<T, F>(&self, what: &str, config: &ScanConfig, scan: F) -> Result<T, QueryError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut ScanConnection) -> Result<T, QueryError> + Send + 'static,
+    {
+        let state = Arc::clone(&self.state);
+        let task = tokio::task::spawn_blocking(move || {
+            // A scan that panicked may have left the connection half
+            // configured; start over with a fresh one.
+            let mut state = state.lock().unwrap_or_else(|poisoned| {
+                let mut state = poisoned.into_inner();
+                *state = None;
+                state
+            });
+            if state.is_none() {
+                *state = Some(ScanConnection::new(Connection::open_in_memory()?));
+            }
+            scan(state.as_mut().expect("connection was just opened"))
+        });
+
+        match tokio::time::timeout(config.timeout, task).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(err)) => Err(QueryError::Other(format!("{} failed: {}", what, err))),
+            Err(_) => Err(QueryError::Other(format!("{} timed out", what))),
+        }
+    }
+}
+
+/// Extensions and credentials for scanning remote sources.
+struct RemoteSetup {
+    /// The `CREATE SECRET` statement to apply, if any.
+    secret: Option<String>,
+    extensions: Vec<String>,
+}
+
+/// A scan connection and what has been set up on it so far.
+struct ScanConnection {
+    conn: Connection,
+    loaded: HashSet<String>,
+    /// The `CREATE SECRET` statement last applied, if any.
+    secret: Option<String>,
+}
+
+impl ScanConnection {
+    fn new(conn: Connection) -> Self {
+        Self {
+            conn,
+            loaded: HashSet::new(),
+            secret: None,
+        }
+    }
+
+    /// Readies the connection to read `source`: the extension its scheme needs
+    /// and then `remote`'s setup. Local paths need neither.
+    fn prepare_source(&mut self, source: &str, remote: &RemoteSetup) -> Result<(), QueryError> {
+        let Some(extension) = scheme_extension(source)? else {
+            return Ok(());
+        };
+        if !self.loaded.contains(extension) {
+            detect_and_install_extension(&self.conn, source)?;
+            self.loaded.insert(extension.to_string());
+        }
+        self.prepare_remote(remote)
+    }
+
+    /// Loads `remote`'s extensions and brings the S3 secret in line with its
+    /// secret, skipping whatever is already in place.
+    fn prepare_remote(&mut self, remote: &RemoteSetup) -> Result<(), QueryError> {
+        for extension in &remote.extensions {
+            if !self.loaded.contains(extension) {
+                load_extension(&self.conn, extension)?;
+                self.loaded.insert(extension.clone());
+            }
+        }
+        let secret = remote.secret.as_deref();
+        if self.secret.as_deref() == secret {
+            return Ok(());
+        }
+        let applied = match secret {
+            Some(secret) => self.conn.execute_batch(secret),
+            None => self.conn.execute_batch("DROP SECRET IF EXISTS pond_scan"),
+        };
+        if applied.is_err() {
+            // Don't leave a previous scan's credentials in place.
+            let _ = self.conn.execute_batch("DROP SECRET IF EXISTS pond_scan");
+            self.secret = None;
+            // DuckDB's message could echo the statement, credentials included.
+            return Err(QueryError::Other(
+                "failed to configure S3 access".to_string(),
+            ));
+        }
+        self.secret = secret.map(str::to_string);
+        Ok(())
+    }
+
+    /// The parent directories of the files matching `source`, each with its
+    /// file count and total size.
+    fn glob_directories(
+        &mut self,
+        source: &str,
+        remote: &RemoteSetup,
+    ) -> Result<Vec<(String, u64, u64)>, QueryError> {
+        self.prepare_source(source, remote)?;
+
+        // read_blob only reads `content` when it's selected, so this lists
+        // names and sizes without fetching any data.
+        let glob_query = "SELECT REGEXP_REPLACE(filename, '/[^/]+$', '') AS directory, \
+                          CAST(COUNT(*) AS UBIGINT), CAST(SUM(size) AS UBIGINT) \
+                          FROM read_blob(?) GROUP BY directory";
+
+        let result = self.conn.prepare(glob_query).and_then(|mut stmt| {
+            stmt.query_map([source], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                .collect::<DuckResult<Vec<_>>>()
+        });
+        result.or_else(|err| match listing_error(source, err) {
+            QueryError::NoFilesMatched(_) => Ok(Vec::new()),
+            err => Err(err),
+        })
+    }
+
+    /// The files matching `source`, sorted by path, at most `limit` of them.
+    fn list_files(
+        &mut self,
+        source: &str,
+        remote: &RemoteSetup,
+        limit: Option<usize>,
+    ) -> Result<Vec<FileEntry>, QueryError> {
+        self.prepare_source(source, remote)?;
+
+        let mut list_query = "SELECT filename, CAST(size AS UBIGINT), epoch_ms(last_modified) \
+                              FROM read_blob(?) ORDER BY filename"
+            .to_string();
+        if let Some(limit) = limit {
+            list_query.push_str(&format!(" LIMIT {}", limit));
+        }
+
+        let result = self.conn.prepare(&list_query).and_then(|mut stmt| {
+            stmt.query_map([source], |row| {
+                let modified_ms: i64 = row.get(2)?;
+                Ok(FileEntry {
+                    path: row.get(0)?,
+                    size_bytes: row.get(1)?,
+                    last_modified: SystemTime::UNIX_EPOCH
+                        + Duration::from_millis(modified_ms.max(0) as u64),
+                })
+            })?
+            .collect::<DuckResult<Vec<_>>>()
+        });
+        match result {
+            Ok(files) if files.is_empty() => Err(QueryError::NoFilesMatched(source.to_string())),
+            Ok(files) => Ok(files),
+            Err(err) => Err(listing_error(source, err)),
+        }
+    }
+}
+
+/// Splits a comma-separated list of extension names, as in
+/// `POND_DUCKDB_EXTENSIONS`.
+fn parse_extensions(names: &str) -> Vec<String> {
+    names
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// Installs and loads `name` after checking it is an extension DuckDB knows,
+/// so a typo fails here rather than as a confusing error mid-scan.
+fn load_extension(conn: &Connection, name: &str) -> Result<(), QueryError> {
+    let known = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && conn.query_row(
+            "SELECT COUNT(*) > 0 FROM duckdb_extensions() WHERE extension_name = ?",
+            [name],
+            |row| row.get::<_, bool>(0),
+        )?;
+    if !known {
+        return Err(QueryError::Other(format!(
+            "Unknown DuckDB extension: {}",
+            name
+        )));
+    }
+    conn.execute_batch(&format!("INSTALL {0}; LOAD {0};", name))?;
+    Ok(())
+}
+
+/// The extension DuckDB reads `source` with, by its URI scheme: httpfs for
+/// S3, GCS, R2 and HTTP(S), azure for Azure Blob Storage and ADLS, and none
+/// for local paths. Fails with [`QueryError::InvalidFilesystem`] for any
+/// other scheme.
+fn scheme_extension(source: &str) -> Result<Option<&'static str>, QueryError> {
+    let Some((scheme, _)) = source.split_once("://") else {
+        return Ok(None);
+    };
+    match scheme.to_lowercase().as_str() {
+        "file" => Ok(None),
+        "s3" | "s3a" | "s3n" | "gs" | "gcs" | "r2" | "http" | "https" => Ok(Some("httpfs")),
+        "az" | "azure" | "abfss" => Ok(Some("azure")),
+        _ => Err(QueryError::InvalidFilesystem(format!(
+            "No DuckDB extension reads {}:// sources",
+            scheme
+        ))),
+    }
+}
+
+/// Installs and loads the extension `source`'s scheme needs, if any; see
+/// [`scheme_extension`].
+fn detect_and_install_extension(conn: &Connection, source: &str) -> Result<(), QueryError> {
+    match scheme_extension(source)? {
+        Some(extension) => load_extension(conn, extension),
+        None => Ok(()),
+    }
+}
+
+/// Maps a failed `read_blob` over `source` to the error callers can act on.
+fn listing_error(source: &str, err: duckdb::Error) -> QueryError {
+    let message = err.to_string();
+    if message.contains("No files found") {
+        QueryError::NoFilesMatched(source.to_string())
+    } else if is_access_denied(&message) {
+        QueryError::AccessDenied(vec![path_source(source)])
+    } else {
+        err.into()
+    }
+}
+
+/// The directory a glob starts from: everything before the last `/` ahead of
+/// its first wildcard.
+fn glob_root(source: &str) -> Option<&str> {
+    let literal = &source[..source.find(['*', '?', '[']).unwrap_or(source.len())];
+    literal.rfind('/').map(|slash| &source[..slash])
+}
+
+/// `directory`'s prefix when grouping `depth` directories below `root`.
+fn grouped_prefix(directory: &str, root: &str, depth: usize) -> String {
+    let Some(relative) = directory.strip_prefix(root) else {
+        return format!("{}/*", directory);
+    };
+    let segments: Vec<_> = relative.split('/').filter(|s| !s.is_empty()).collect();
+    if segments.len() < depth {
+        return format!("{}/*", directory);
+    }
+    let mut prefix = root.to_string();
+    for segment in &segments[..depth] {
+        prefix.push('/');
+        prefix.push_str(segment);
+    }
+    prefix.push_str("/**");
+    prefix
+}
+
+/// Recognises httpfs's wording for rejected requests and credentials.
+fn is_access_denied(message: &str) -> bool {
+    [
+        "HTTP 403",
+        "403 (Forbidden)",
+        "AccessDenied",
+        "Access Denied",
+        "InvalidAccessKeyId",
+        "SignatureDoesNotMatch",
+    ]
+    .iter()
+    .any(|needle| message.contains(needle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty() -> ScanConfig {
+        ScanConfig {
+            region: None,
+            endpoint: None,
+            access_key_id: None,
+            secret_access_key: None,
+            session_token: None,
+            url_style: None,
+            timeout: DEFAULT_PREFIX_SCAN_TIMEOUT,
+            order: PrefixOrder::Prefix,
+            depth: None,
+            extensions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_unknown_extension_is_reported() {
+        assert_eq!(
+            parse_extensions(" httpfs, JSON,,spatial "),
+            ["httpfs", "json", "spatial"]
+        );
+
+        let mut conn = ScanConnection::new(Connection::open_in_memory().unwrap());
+        for name in ["httpfz", "json; DROP TABLE t"] {
+            let remote = empty().extensions([name]).remote_setup();
+            match conn.prepare_remote(&remote) {
+                Err(QueryError::Other(message)) => {
+                    assert_eq!(message, format!("Unknown DuckDB extension: {}", name))
+                }
+                other => panic!("expected an unknown extension, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_secret_statement() {
+        assert_eq!(empty().secret_statement(), None);
+
+        let config = empty()
+            .region("eu-west-1")
+            .endpoint("http://localhost:9000/")
+            .access_key_id("minio")
+            .secret_access_key("it's-secret")
+            .url_style(UrlStyle::Path);
+        assert_eq!(
+            config.secret_statement().unwrap(),
+            "CREATE OR REPLACE SECRET pond_scan (TYPE S3, KEY_ID 'minio', SECRET 'it''s-secret', \
+             REGION 'eu-west-1', ENDPOINT 'localhost:9000', URL_STYLE 'path', USE_SSL false)"
+        );
+        assert!(empty()
+            .endpoint("storage.example.com")
+            .secret_statement()
+            .unwrap()
+            .ends_with("ENDPOINT 'storage.example.com', USE_SSL true)"));
+    }
+
+    #[test]
+    fn test_debug_redacts_credentials() {
+        let config = empty()
+            .access_key_id("AKIAEXAMPLE")
+            .secret_access_key("wJalrXUtnFEMI");
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("AKIAEXAMPLE"));
+        assert!(!debug.contains("wJalrXUtnFEMI"));
+        assert!(debug.contains("<redacted>"));
+    }
+
+    #[test]
+    fn test_access_denied_messages() {
+        assert!(is_access_denied(
+            "IO Error: HTTP GET error on 'https://b.s3.amazonaws.com/?prefix=x' (HTTP 403)"
+        ));
+        assert!(is_access_denied("<Code>InvalidAccessKeyId</Code>"));
+        assert!(!is_access_denied(
+            "IO Error: HTTP GET error on 'https://b.s3.amazonaws.com/' (HTTP 404)"
+        ));
+    }
+
+    #[test]
+    fn test_scheme_extension() {
+        for (source, extension) in [
+            ("/data/*.parquet", None),
+            ("file:///data/*.parquet", None),
+            ("s3://bucket/data/*", Some("httpfs")),
+            ("GS://bucket/data/*", Some("httpfs")),
+            ("gcs://bucket/data/*", Some("httpfs")),
+            ("https://example.com/a.parquet", Some("httpfs")),
+            ("az://container/data/*", Some("azure")),
+            (
+                "abfss://container@account.dfs.core.windows.net/*",
+                Some("azure"),
+            ),
+        ] {
+            assert_eq!(scheme_extension(source).unwrap(), extension, "{}", source);
+        }
+        assert!(matches!(
+            scheme_extension("ftp://host/data/*"),
+            Err(QueryError::InvalidFilesystem(_))
+        ));
+    }
+
+    #[test]
+    fn test_unknown_scheme_fails_before_globbing() {
+        let conn = Connection::open_in_memory().unwrap();
+        detect_and_install_extension(&conn, "/data/*.parquet").unwrap();
+        assert!(matches!(
+            detect_and_install_extension(&conn, "ftp://host/data/*"),
+            Err(QueryError::InvalidFilesystem(_))
+        ));
+
+        let mut scan = ScanConnection::new(conn);
+        assert!(matches!(
+            scan.glob_directories("ftp://host/data/*", &empty().remote_setup()),
+            Err(QueryError::InvalidFilesystem(_))
+        ));
+    }
+
+    fn temp_tree(name: &str) -> std::path::PathBuf {
+        let root = std::env::temp_dir().join(format!("pond-{}-{}", name, std::process::id()));
+        for file in ["data/a.parquet", "data/2024/b.parquet"] {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"PAR1").unwrap();
+        }
+        root
+    }
+
+    #[tokio::test]
+    async fn test_scanner_reuses_its_connection() {
+        let root = temp_tree("scanner");
+        let query = format!("SELECT * FROM '{}/data/**/*.parquet'", root.display());
+        let wrapper = QueryWrapper::parse(&query).unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE marker (id INTEGER)")
+            .unwrap();
+        let scanner = PrefixScanner::with_connection(conn);
+        for _ in 0..2 {
+            let mut prefixes = scanner.scan(&wrapper, &empty()).await.unwrap();
+            prefixes.sort();
+            assert_eq!(
+                prefixes,
+                vec![
+                    format!("{}/data/*", root.display()),
+                    format!("{}/data/2024/*", root.display()),
+                ]
+            );
+        }
+        {
+            let state = scanner.state.lock().unwrap();
+            let conn = &state.as_ref().unwrap().conn;
+            assert!(conn.execute_batch("SELECT * FROM marker").is_ok());
+        }
+
+        let fresh = PrefixScanner::new();
+        assert!(fresh.state.lock().unwrap().is_none());
+        fresh.scan(&wrapper, &empty()).await.unwrap();
+        assert!(fresh.state.lock().unwrap().is_some());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_prefix_stats() {
+        let root = std::env::temp_dir().join(format!("pond-stats-{}", std::process::id()));
+        for (file, size) in [
+            ("data/2023/a.parquet", 10),
+            ("data/2024/b.parquet", 20),
+            ("data/2024/c.parquet", 30),
+            ("data/2025/empty.parquet", 0),
+        ] {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, vec![b'x'; size]).unwrap();
+        }
+        let wrapper = |pattern: &str| {
+            QueryWrapper::parse(&format!("SELECT * FROM '{}/{}'", root.display(), pattern)).unwrap()
+        };
+        let stats = |prefix: &str, file_count, total_bytes| PrefixStats {
+            prefix: format!("{}/{}", root.display(), prefix),
+            file_count,
+            total_bytes,
+        };
+        let scanner = PrefixScanner::new();
+
+        let by_prefix = scanner
+            .scan_stats(&wrapper("data/*/*.parquet"), &empty())
+            .await
+            .unwrap();
+        assert_eq!(
+            by_prefix,
+            vec![stats("data/2023/*", 1, 10), stats("data/2024/*", 2, 50)]
+        );
+
+        let largest_first = empty().order(PrefixOrder::LargestFirst);
+        let by_size = scanner
+            .scan_stats(&wrapper("data/*/*.parquet"), &largest_first)
+            .await
+            .unwrap();
+        assert_eq!(
+            by_size,
+            vec![stats("data/2024/*", 2, 50), stats("data/2023/*", 1, 10)]
+        );
+
+        let none = scanner
+            .scan_stats(&wrapper("missing/*.parquet"), &empty())
+            .await
+            .unwrap();
+        assert!(none.is_empty());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_grouping_depth() {
+        let root = std::env::temp_dir().join(format!("pond-depth-{}", std::process::id()));
+        for file in [
+            "events/year=2023/month=12/day=31/part-0.parquet",
+            "events/year=2024/month=01/day=01/part-0.parquet",
+            "events/year=2024/month=01/day=01/part-1.parquet",
+            "events/year=2024/month=01/day=02/part-0.parquet",
+            "events/year=2024/month=02/day=01/part-0.parquet",
+        ] {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"PAR1").unwrap();
+        }
+        let query = format!(
+            "SELECT * FROM '{}/events/year=*/month=*/day=*/*.parquet'",
+            root.display()
+        );
+        let wrapper = QueryWrapper::parse(&query).unwrap();
+        let scanner = PrefixScanner::new();
+        let events = format!("{}/events/", root.display());
+        let grouped = |stats: Vec<PrefixStats>| {
+            stats
+                .into_iter()
+                .map(|stats| {
+                    let prefix = stats.prefix.strip_prefix(&events).unwrap();
+                    (prefix.to_string(), stats.file_count)
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut by_depth = Vec::new();
+        for config in [empty()]
+            .into_iter()
+            .chain((0..=4).map(|depth| empty().group_by_segments(depth)))
+        {
+            by_depth.push(grouped(
+                scanner.scan_stats(&wrapper, &config).await.unwrap(),
+            ));
+        }
+        let owned = |expected: &[(&str, u64)]| {
+            expected
+                .iter()
+                .map(|(prefix, count)| (prefix.to_string(), *count))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            by_depth[0],
+            owned(&[
+                ("year=2023/month=12/day=31/*", 1),
+                ("year=2024/month=01/day=01/*", 2),
+                ("year=2024/month=01/day=02/*", 1),
+                ("year=2024/month=02/day=01/*", 1),
+            ])
+        );
+        assert_eq!(by_depth[1], owned(&[("**", 5)]));
+        assert_eq!(
+            by_depth[2],
+            owned(&[("year=2023/**", 1), ("year=2024/**", 4)])
+        );
+        assert_eq!(
+            by_depth[3],
+            owned(&[
+                ("year=2023/month=12/**", 1),
+                ("year=2024/month=01/**", 3),
+                ("year=2024/month=02/**", 1),
+            ])
+        );
+        assert_eq!(by_depth[4].len(), 4);
+        // Deeper than the layout: every file is in a shallower directory.
+        assert_eq!(by_depth[5], by_depth[0]);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_list_files() {
+        let root = temp_tree("files");
+        let scanner = PrefixScanner::new();
+        let query = format!(
+            "SELECT * FROM read_parquet(['{root}/data/*.parquet', '{root}/data/**/*.parquet'])",
+            root = root.display()
+        );
+        let wrapper = QueryWrapper::parse(&query).unwrap();
+
+        let files = scanner.list_files(&wrapper, &empty(), None).await.unwrap();
+        let paths: Vec<_> = files.iter().map(|file| file.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                format!("{}/data/2024/b.parquet", root.display()),
+                format!("{}/data/a.parquet", root.display()),
+            ]
+        );
+        assert!(files.iter().all(|file| file.size_bytes == 4));
+        let age = SystemTime::now()
+            .duration_since(files[0].last_modified)
+            .unwrap();
+        assert!(age < Duration::from_secs(600));
+
+        let limited = scanner
+            .list_files(&wrapper, &empty(), Some(1))
+            .await
+            .unwrap();
+        assert_eq!(limited.len(), 1);
+
+        let missing = format!("{}/missing/*.parquet", root.display());
+        let wrapper = QueryWrapper::parse(&format!("SELECT * FROM '{}'", missing)).unwrap();
+        match scanner.list_files(&wrapper, &empty(), None).await {
+            Err(QueryError::NoFilesMatched(source)) => assert_eq!(source, missing),
+            other => panic!("expected NoFilesMatched, got {:?}", other),
+        }
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quotes_in_paths() {
+        let root = std::env::temp_dir().join(format!("pond-quotes-{}", std::process::id()));
+        let dir = root.join("o'brien");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.parquet"), b"PAR1").unwrap();
+        let source = format!("{}/*.parquet", dir.display());
+        let wrapper =
+            QueryWrapper::parse(&format!("SELECT * FROM '{}'", source.replace('\'', "''")))
+                .unwrap();
+        let scanner = PrefixScanner::new();
+
+        let files = scanner.list_files(&wrapper, &empty(), None).await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, format!("{}/a.parquet", dir.display()));
+        let stats = scanner.scan_stats(&wrapper, &empty()).await.unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].total_bytes, 4);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    /// Compares a fresh connection per scan with one reused connection. Each
+    /// scan also loads httpfs when it can be installed; offline, only the
+    /// connection setup is compared. Run with
+    /// `cargo test -p pond-parser bench_connection_reuse -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_connection_reuse() {
+        const SCANS: u32 = 20;
+        let root = temp_tree("bench");
+        let source = format!("{}/data/**/*.parquet", root.display());
+        let httpfs = empty().extensions(["httpfs"]).remote_setup();
+        let remote = ScanConnection::new(Connection::open_in_memory().unwrap())
+            .prepare_remote(&httpfs)
+            .is_ok();
+
+        let started = std::time::Instant::now();
+        for _ in 0..SCANS {
+            let mut conn = ScanConnection::new(Connection::open_in_memory().unwrap());
+            if remote {
+                conn.prepare_remote(&httpfs).unwrap();
+            }
+            conn.glob_directories(&source, &httpfs).unwrap();
+        }
+        let fresh = started.elapsed() / SCANS;
+
+        let mut conn = ScanConnection::new(Connection::open_in_memory().unwrap());
+        let started = std::time::Instant::now();
+        for _ in 0..SCANS {
+            if remote {
+                conn.prepare_remote(&httpfs).unwrap();
+            }
+            conn.glob_directories(&source, &httpfs).unwrap();
+        }
+        let reused = started.elapsed() / SCANS;
+
+        println!(
+            "per scan (httpfs loaded: {}): fresh connection {:?}, reused {:?}",
+            remote, fresh, reused
+        );
+        assert!(reused < fresh);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    /// Needs a MinIO (or other S3-compatible) server described by
+    /// `POND_TEST_S3_ENDPOINT`, `POND_TEST_S3_ACCESS_KEY_ID`,
+    /// `POND_TEST_S3_SECRET_ACCESS_KEY` and `POND_TEST_S3_BUCKET`, with at least
+    /// one object under `data/` in the bucket.
+    #[cfg(feature = "minio")]
+    #[tokio::test]
+    async fn test_scan_against_s3_compatible_endpoint() {
+        let var =
+            |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("{} is not set", name));
+        let bucket = var("POND_TEST_S3_BUCKET");
+        let config = empty()
+            .region("us-east-1")
+            .endpoint(var("POND_TEST_S3_ENDPOINT"))
+            .access_key_id(var("POND_TEST_S3_ACCESS_KEY_ID"))
+            .secret_access_key(var("POND_TEST_S3_SECRET_ACCESS_KEY"))
+            .url_style(UrlStyle::Path)
+            .timeout(Duration::from_secs(30));
+
+        let query = format!("SELECT * FROM 's3://{}/data/*'", bucket);
+        let wrapper = QueryWrapper::parse(&query).unwrap();
+        let prefixes = wrapper
+            .scan_source_for_prefixes_with_config(&config)
+            .await
+            .unwrap();
+        assert_eq!(prefixes, vec![format!("s3://{}/data/*", bucket)]);
+
+        let wrong_key = config.secret_access_key("not-the-secret");
+        match wrapper
+            .scan_source_for_prefixes_with_config(&wrong_key)
+            .await
+        {
+            Err(QueryError::AccessDenied(sources)) => {
+                assert_eq!(sources, vec![format!("s3://{}", bucket)])
+            }
+            other => panic!("expected AccessDenied, got {:?}", other),
+        }
+    }
+}
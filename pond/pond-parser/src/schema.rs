@@ -0,0 +1,695 @@
+use crate::columns::{column_name, relation_name};
+use crate::{
+    normalized_ident, FileEntry, FileFormat, PrefixScanner, QueryError, QueryWrapper, ScanConfig,
+};
+use arrow_schema::{DataType, TimeUnit};
+use parquet::arrow::arrow_reader::{ArrowReaderMetadata, ArrowReaderOptions};
+use sqlparser::ast::{
+    DataType as SqlType, ExcludeSelectItem, Expr, FunctionArg, FunctionArgExpr, Ident,
+    Query as SqlQuery, SelectItem, SetExpr, TableFactor, Value, Visit, Visitor,
+    WildcardAdditionalOptions,
+};
+use std::collections::{HashMap, HashSet};
+use std::ops::ControlFlow;
+
+/// Column names and Arrow types, in file order.
+pub type Columns = Vec<(String, DataType)>;
+
+impl QueryWrapper {
+    /// The columns of each parquet file source, read from the footers of the
+    /// files it matches, using [`ScanConfig::from_env`] and the shared
+    /// [`PrefixScanner`].
+    pub async fn source_schema(&self) -> Result<HashMap<String, Columns>, QueryError> {
+        self.source_schema_with_config(&ScanConfig::from_env())
+            .await
+    }
+
+    /// Like [`source_schema`](Self::source_schema), reaching object storage as
+    /// `config` describes.
+    ///
+    /// Every matched file's footer is read, one small request per remote
+    /// file, and all of them must agree: a file whose columns differ from the
+    /// first one's fails with [`QueryError::SchemaMismatch`] instead of being
+    /// unioned. Remote footers are read with `object_store`, so `s3://`
+    /// sources need the `object-store` feature. Sources that aren't parquet
+    /// are left out.
+    pub async fn source_schema_with_config(
+        &self,
+        config: &ScanConfig,
+    ) -> Result<HashMap<String, Columns>, QueryError> {
+        let mut schemas = HashMap::new();
+        for (source, format) in self.file_sources() {
+            if format != FileFormat::Parquet || schemas.contains_key(&source) {
+                continue;
+            }
+            let files = PrefixScanner::shared()
+                .list_source_files(source.clone(), config)
+                .await?;
+            let mut expected: Option<(String, Columns)> = None;
+            for file in files {
+                let columns = read_footer(&file, config).await?;
+                match &expected {
+                    None => expected = Some((file.path, columns)),
+                    Some((first, expected)) => {
+                        if let Some(message) = difference(first, expected, &columns) {
+                            return Err(QueryError::SchemaMismatch {
+                                glob: source,
+                                file: file.path,
+                                message,
+                            });
+                        }
+                    }
+                }
+            }
+            if let Some((_, columns)) = expected {
+                schemas.insert(source, columns);
+            }
+        }
+        Ok(schemas)
+    }
+
+    /// The outer SELECT's output columns, resolved against `sources` as
+    /// returned by [`source_schema`](Self::source_schema).
+    ///
+    /// `*` expands to every relation's columns in FROM order and `t.*` to
+    /// those of `t`, less any it EXCLUDEs and retyped by any it REPLACEs;
+    /// columns are matched case-insensitively and named as
+    /// written unless aliased. Besides plain and qualified columns, only casts
+    /// to common types and `COUNT` have a known type; anything else, a column
+    /// no source has and relations without a schema (tables, subqueries)
+    /// fail with [`QueryError::Other`].
+    pub fn projected_schema(
+        &self,
+        sources: &HashMap<String, Columns>,
+    ) -> Result<Columns, QueryError> {
+        let Some(select) = self.outer_select() else {
+            return Err(QueryError::Other(
+                "Only a single SELECT has a projected schema".to_string(),
+            ));
+        };
+        let relations: Vec<Relation> = self
+            .tables()
+            .into_iter()
+            .map(|table| Relation {
+                name: relation_name(table),
+                columns: relation_path(table).and_then(|path| sources.get(&path)),
+                text: table.to_string(),
+            })
+            .collect();
+        let resolver = Resolver {
+            relations: &relations,
+        };
+
+        let mut projected = Vec::new();
+        for item in &select.projection {
+            match item {
+                SelectItem::Wildcard(options) => {
+                    for relation in &relations {
+                        let columns = relation.columns()?;
+                        projected.extend(resolver.wildcard(columns, options)?);
+                    }
+                }
+                SelectItem::QualifiedWildcard(name, options) => {
+                    let qualifier = name.0.last().map_or("", |ident| ident.value.as_str());
+                    let columns = resolver.relation(qualifier)?.columns()?;
+                    projected.extend(resolver.wildcard(columns, options)?);
+                }
+                SelectItem::UnnamedExpr(expr) => {
+                    projected.push((column_name(expr), resolver.expr(expr)?));
+                }
+                SelectItem::ExprWithAlias { expr, alias } => {
+                    projected.push((alias.value.clone(), resolver.expr(expr)?));
+                }
+            }
+        }
+        Ok(projected)
+    }
+
+    /// Checks the columns the query references against `schema`, the column
+    /// names of each table, and returns a warning for each one its table
+    /// doesn't have, to catch typos before the query runs.
+    ///
+    /// Tables are named as in [`QueryAnalysis::tables`](crate::QueryAnalysis::tables),
+    /// qualifiers are resolved through aliases and names are matched
+    /// case-insensitively. Wildcards, columns of tables `schema` doesn't
+    /// list, and unqualified columns that name a SELECT alias or could come
+    /// from a relation it doesn't list (a subquery, a CTE, UNNEST) aren't
+    /// checked, so an empty result only means no typo was found.
+    pub fn validate_against_schema(&self, schema: &HashMap<String, Vec<String>>) -> Vec<String> {
+        let analysis = self.analyze();
+        let columns_of = |table: &str| {
+            schema
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(table))
+                .map(|(_, columns)| columns)
+        };
+        let has = |columns: &[String], column: &str| {
+            columns
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(column))
+        };
+        let spelled = |name: &str| {
+            analysis
+                .spellings()
+                .get(name)
+                .cloned()
+                .unwrap_or_else(|| name.to_string())
+        };
+
+        let mut relations: Vec<&String> = analysis
+            .tables()
+            .iter()
+            .chain(analysis.aliases().values())
+            .collect();
+        relations.sort();
+        relations.dedup();
+        // `None` when some relation's columns are unknown.
+        let known: Option<Vec<&Vec<String>>> = relations
+            .iter()
+            .map(|relation| columns_of(relation))
+            .collect();
+        let aliases = select_aliases(&self.ast);
+
+        let mut columns: Vec<&String> = analysis.columns().iter().collect();
+        columns.sort();
+        let mut warnings = Vec::new();
+        for column in columns {
+            if column == "*" || column.ends_with(".*") {
+                continue;
+            }
+            // Paths have dots of their own, so match whole relation names.
+            let qualified = relations
+                .iter()
+                .filter(|relation| {
+                    column.len() > relation.len() + 1
+                        && column.starts_with(relation.as_str())
+                        && column.as_bytes()[relation.len()] == b'.'
+                })
+                .max_by_key(|relation| relation.len());
+            match qualified {
+                Some(relation) => {
+                    let name = &column[relation.len() + 1..];
+                    if let Some(columns) = columns_of(relation) {
+                        if !has(columns, name) {
+                            warnings.push(format!(
+                                "column `{}` not found in `{}`",
+                                spelled(name),
+                                relation
+                            ));
+                        }
+                    }
+                }
+                // A struct field or an unknown qualifier.
+                None if column.contains('.') => {}
+                None => {
+                    let Some(known) = &known else {
+                        continue;
+                    };
+                    if !known.is_empty()
+                        && !aliases.contains(column)
+                        && !known.iter().any(|columns| has(columns, column))
+                    {
+                        let mut tables: Vec<&String> = analysis.tables().iter().collect();
+                        tables.sort();
+                        warnings.push(format!(
+                            "column `{}` not found in {}",
+                            spelled(column),
+                            tables
+                                .iter()
+                                .map(|table| format!("`{}`", table))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ));
+                    }
+                }
+            }
+        }
+        warnings
+    }
+}
+
+/// The normalized aliases SELECT items anywhere in `node` are given, which
+/// clauses such as HAVING may reference like columns.
+fn select_aliases<V: Visit>(node: &V) -> HashSet<String> {
+    let mut aliases = SelectAliases::default();
+    let _ = node.visit(&mut aliases);
+    aliases.0
+}
+
+#[derive(Default)]
+struct SelectAliases(HashSet<String>);
+
+impl Visitor for SelectAliases {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &SqlQuery) -> ControlFlow<()> {
+        if let SetExpr::Select(select) = query.body.as_ref() {
+            for item in &select.projection {
+                if let SelectItem::ExprWithAlias { alias, .. } = item {
+                    self.0.insert(normalized_ident(alias));
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// The columns stored in `file`'s parquet footer.
+async fn read_footer(file: &FileEntry, config: &ScanConfig) -> Result<Columns, QueryError> {
+    let metadata = read_metadata(file, config).await?;
+    Ok(metadata
+        .schema()
+        .fields()
+        .iter()
+        .map(|field| (field.name().clone(), field.data_type().clone()))
+        .collect())
+}
+
+/// `file`'s parquet footer, fetched from object storage for remote paths.
+pub(crate) async fn read_metadata(
+    file: &FileEntry,
+    config: &ScanConfig,
+) -> Result<ArrowReaderMetadata, QueryError> {
+    if file.path.contains("://") {
+        remote_metadata(&file.path, config).await
+    } else {
+        let path = file.path.clone();
+        tokio::task::spawn_blocking(move || {
+            let file = std::fs::File::open(&path)
+                .map_err(|err| QueryError::Other(format!("Cannot open {}: {}", path, err)))?;
+            ArrowReaderMetadata::load(&file, ArrowReaderOptions::new())
+                .map_err(|err| footer_error(&path, err))
+        })
+        .await
+        .map_err(|err| QueryError::Other(format!("footer read failed: {}", err)))?
+    }
+}
+
+#[cfg(feature = "object-store")]
+async fn remote_metadata(
+    path: &str,
+    config: &ScanConfig,
+) -> Result<ArrowReaderMetadata, QueryError> {
+    use object_store::path::Path as ObjectPath;
+    use parquet::arrow::async_reader::ParquetObjectReader;
+    use std::sync::Arc;
+
+    let (store, _, key) = crate::listing::open_store(path, config)?;
+    let store: Arc<dyn object_store::ObjectStore> = Arc::from(store);
+    let meta = store
+        .head(&ObjectPath::from(key))
+        .await
+        .map_err(|err| QueryError::Other(format!("Cannot read {}: {}", path, err)))?;
+    let mut reader = ParquetObjectReader::new(store, meta);
+    ArrowReaderMetadata::load_async(&mut reader, ArrowReaderOptions::new())
+        .await
+        .map_err(|err| footer_error(path, err))
+}
+
+#[cfg(not(feature = "object-store"))]
+async fn remote_metadata(
+    path: &str,
+    _config: &ScanConfig,
+) -> Result<ArrowReaderMetadata, QueryError> {
+    Err(QueryError::InvalidFilesystem(format!(
+        "Reading the parquet footer of {} needs the object-store feature",
+        crate::access::path_source(path)
+    )))
+}
+
+fn footer_error(path: &str, err: parquet::errors::ParquetError) -> QueryError {
+    QueryError::Other(format!("Invalid parquet footer in {}: {}", path, err))
+}
+
+/// How `columns` differ from `expected`, read from `first`, if they do.
+fn difference(first: &str, expected: &Columns, columns: &Columns) -> Option<String> {
+    for (name, data_type) in columns {
+        match expected.iter().find(|(expected, _)| expected == name) {
+            Some((_, expected_type)) if expected_type != data_type => {
+                return Some(format!(
+                    "column {} is {}, but {} in {}",
+                    name, data_type, expected_type, first
+                ));
+            }
+            Some(_) => {}
+            None => return Some(format!("column {} is not in {}", name, first)),
+        }
+    }
+    let names: Vec<&String> = columns.iter().map(|(name, _)| name).collect();
+    let expected_names: Vec<&String> = expected.iter().map(|(name, _)| name).collect();
+    if let Some((missing, _)) = expected.iter().find(|(name, _)| !names.contains(&name)) {
+        return Some(format!("column {} from {} is missing", missing, first));
+    }
+    (names != expected_names).then(|| format!("columns are in a different order than in {}", first))
+}
+
+/// The path a relation reads: a quoted path, or the first string passed to a
+/// reader such as `read_parquet`, or the first of a list of them.
+pub(crate) fn relation_path(table: &TableFactor) -> Option<String> {
+    let TableFactor::Table { name, args, .. } = table else {
+        return None;
+    };
+    let Some(args) = args else {
+        return match name.0.as_slice() {
+            [ident] if ident.quote_style == Some('\'') => Some(ident.value.clone()),
+            _ => None,
+        };
+    };
+    let first = args.args.first()?;
+    let FunctionArg::Unnamed(FunctionArgExpr::Expr(expr)) = first else {
+        return None;
+    };
+    match expr {
+        Expr::Value(Value::SingleQuotedString(path)) => Some(path.clone()),
+        Expr::Array(array) => match array.elem.first() {
+            Some(Expr::Value(Value::SingleQuotedString(path))) => Some(path.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+struct Relation<'a> {
+    /// Lowercased alias or table name.
+    name: Option<String>,
+    columns: Option<&'a Columns>,
+    text: String,
+}
+
+impl Relation<'_> {
+    fn columns(&self) -> Result<&Columns, QueryError> {
+        self.columns.ok_or_else(|| {
+            QueryError::Other(format!("No parquet schema for relation {}", self.text))
+        })
+    }
+
+    fn column(&self, name: &str) -> Option<&DataType> {
+        self.columns?
+            .iter()
+            .find(|(column, _)| column.eq_ignore_ascii_case(name))
+            .map(|(_, data_type)| data_type)
+    }
+}
+
+struct Resolver<'a> {
+    relations: &'a [Relation<'a>],
+}
+
+impl Resolver<'_> {
+    fn relation(&self, qualifier: &str) -> Result<&Relation<'_>, QueryError> {
+        let qualifier = qualifier.to_lowercase();
+        self.relations
+            .iter()
+            .find(|relation| relation.name.as_deref() == Some(qualifier.as_str()))
+            .ok_or_else(|| QueryError::Other(format!("Unknown relation {}", qualifier)))
+    }
+
+    /// `columns` as a wildcard with `options` projects them: without the
+    /// ones it EXCLUDEs, and with the type of the expression replacing each
+    /// one it REPLACEs.
+    fn wildcard(
+        &self,
+        columns: &Columns,
+        options: &WildcardAdditionalOptions,
+    ) -> Result<Columns, QueryError> {
+        let excluded: Vec<&Ident> = match &options.opt_exclude {
+            Some(ExcludeSelectItem::Single(ident)) => vec![ident],
+            Some(ExcludeSelectItem::Multiple(idents)) => idents.iter().collect(),
+            None => Vec::new(),
+        };
+        let replaced = options
+            .opt_replace
+            .iter()
+            .flat_map(|replace| &replace.items);
+
+        let mut projected = Columns::new();
+        for (name, data_type) in columns {
+            if excluded
+                .iter()
+                .any(|ident| ident.value.eq_ignore_ascii_case(name))
+            {
+                continue;
+            }
+            let data_type = match replaced
+                .clone()
+                .find(|element| element.column_name.value.eq_ignore_ascii_case(name))
+            {
+                Some(element) => self.expr(&element.expr)?,
+                None => data_type.clone(),
+            };
+            projected.push((name.clone(), data_type));
+        }
+        Ok(projected)
+    }
+
+    fn expr(&self, expr: &Expr) -> Result<DataType, QueryError> {
+        match expr {
+            Expr::Identifier(ident) => {
+                let mut found = self
+                    .relations
+                    .iter()
+                    .filter_map(|relation| relation.column(&ident.value));
+                match (found.next(), found.next()) {
+                    (Some(data_type), None) => Ok(data_type.clone()),
+                    (Some(_), Some(_)) => Err(QueryError::Other(format!(
+                        "Column {} is ambiguous",
+                        ident.value
+                    ))),
+                    (None, _) => Err(QueryError::Other(format!("Unknown column {}", ident.value))),
+                }
+            }
+            Expr::CompoundIdentifier(idents) if idents.len() >= 2 => {
+                let column = &idents[idents.len() - 1].value;
+                let relation = self.relation(&idents[idents.len() - 2].value)?;
+                relation.columns()?;
+                relation
+                    .column(column)
+                    .cloned()
+                    .ok_or_else(|| QueryError::Other(format!("Unknown column {}", expr)))
+            }
+            Expr::Nested(expr) => self.expr(expr),
+            Expr::Cast { data_type, .. } => arrow_type(data_type)
+                .ok_or_else(|| QueryError::Other(format!("Cannot infer the type of {}", expr))),
+            Expr::Function(func) if func.name.to_string().eq_ignore_ascii_case("count") => {
+                Ok(DataType::Int64)
+            }
+            _ => Err(QueryError::Other(format!(
+                "Cannot infer the type of {}",
+                expr
+            ))),
+        }
+    }
+}
+
+/// The Arrow type DuckDB returns for a cast to `data_type`.
+fn arrow_type(data_type: &SqlType) -> Option<DataType> {
+    Some(match data_type {
+        SqlType::Boolean | SqlType::Bool => DataType::Boolean,
+        SqlType::TinyInt(_) | SqlType::Int8(_) => DataType::Int8,
+        SqlType::SmallInt(_) | SqlType::Int2(_) | SqlType::Int16 => DataType::Int16,
+        SqlType::Int(_) | SqlType::Integer(_) | SqlType::Int4(_) | SqlType::Int32 => {
+            DataType::Int32
+        }
+        SqlType::BigInt(_) | SqlType::Int64 => DataType::Int64,
+        SqlType::Real | SqlType::Float4 | SqlType::Float32 => DataType::Float32,
+        SqlType::Double | SqlType::DoublePrecision | SqlType::Float8 | SqlType::Float64 => {
+            DataType::Float64
+        }
+        SqlType::Varchar(_) | SqlType::Text | SqlType::String(_) => DataType::Utf8,
+        SqlType::Date => DataType::Date32,
+        SqlType::Timestamp(_, _) => DataType::Timestamp(TimeUnit::Microsecond, None),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray};
+    use parquet::arrow::ArrowWriter;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    fn write_parquet(path: &Path, columns: Vec<(&str, ArrayRef)>) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let batch = RecordBatch::try_from_iter(columns).unwrap();
+        let mut writer =
+            ArrowWriter::try_new(std::fs::File::create(path).unwrap(), batch.schema(), None)
+                .unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+    }
+
+    fn ids() -> ArrayRef {
+        Arc::new(Int64Array::from(vec![1, 2]))
+    }
+
+    fn names() -> ArrayRef {
+        Arc::new(StringArray::from(vec!["a", "b"]))
+    }
+
+    #[test]
+    fn test_validate_against_schema() {
+        let schema = HashMap::from([
+            (
+                "sales".to_string(),
+                vec![
+                    "Region".to_string(),
+                    "amount".to_string(),
+                    "user_id".to_string(),
+                ],
+            ),
+            (
+                "users".to_string(),
+                vec!["id".to_string(), "name".to_string()],
+            ),
+            (
+                "s3://b/events.parquet".to_string(),
+                vec!["user_id".to_string(), "kind".to_string()],
+            ),
+        ]);
+        let validate = |sql: &str| {
+            QueryWrapper::parse(sql)
+                .unwrap()
+                .validate_against_schema(&schema)
+        };
+
+        assert!(validate(
+            "SELECT s.region, u.*, SUM(amount) AS total FROM sales s \
+             JOIN users u ON s.user_id = u.id GROUP BY 1, 2 HAVING total > 10"
+        )
+        .is_empty());
+        assert_eq!(
+            validate(
+                "SELECT s.regoin, u.Nmae, amont FROM Sales s JOIN users u ON s.user_id = u.id"
+            ),
+            [
+                "column `amont` not found in `sales`, `users`",
+                "column `regoin` not found in `sales`",
+                "column `Nmae` not found in `users`",
+            ]
+        );
+        assert_eq!(
+            validate("SELECT e.kind, e.typ FROM 's3://b/events.parquet' e"),
+            ["column `typ` not found in `s3://b/events.parquet`"]
+        );
+
+        // Columns of unknown relations could be anything.
+        assert!(validate(
+            "SELECT o.anything, whatever FROM orders o JOIN sales s ON o.id = s.user_id"
+        )
+        .is_empty());
+        assert!(validate("SELECT d.x FROM (SELECT amount AS x FROM sales) d").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_source_and_projected_schema() {
+        let root = std::env::temp_dir().join(format!("pond-schema-{}", std::process::id()));
+        for day in ["01", "02"] {
+            write_parquet(
+                &root.join(format!("sales/{}.parquet", day)),
+                vec![
+                    ("id", ids()),
+                    ("amount", Arc::new(Float64Array::from(vec![1.5, 2.5]))),
+                ],
+            );
+        }
+        write_parquet(
+            &root.join("users.parquet"),
+            vec![("id", ids()), ("name", names())],
+        );
+
+        let sales = format!("{}/sales/*.parquet", root.display());
+        let users = format!("{}/users.parquet", root.display());
+        let wrapper = QueryWrapper::parse(&format!(
+            "SELECT s.*, u.NAME AS customer, CAST(s.id AS VARCHAR) AS label, count(*) \
+             FROM '{}' s JOIN read_parquet('{}') u ON s.id = u.id",
+            sales, users
+        ))
+        .unwrap();
+
+        let schemas = wrapper.source_schema().await.unwrap();
+        assert_eq!(
+            schemas[&sales],
+            vec![
+                ("id".to_string(), DataType::Int64),
+                ("amount".to_string(), DataType::Float64)
+            ]
+        );
+        assert_eq!(schemas[&users][1], ("name".to_string(), DataType::Utf8));
+
+        let projected = wrapper.projected_schema(&schemas).unwrap();
+        let projected: Vec<(&str, &DataType)> = projected
+            .iter()
+            .map(|(name, data_type)| (name.as_str(), data_type))
+            .collect();
+        assert_eq!(
+            projected,
+            [
+                ("id", &DataType::Int64),
+                ("amount", &DataType::Float64),
+                ("customer", &DataType::Utf8),
+                ("label", &DataType::Utf8),
+                ("count", &DataType::Int64),
+            ]
+        );
+
+        let reshaped = QueryWrapper::parse(&format!(
+            "SELECT * EXCLUDE (AMOUNT) REPLACE (CAST(id AS VARCHAR) AS id) FROM '{}'",
+            sales
+        ))
+        .unwrap();
+        assert_eq!(
+            reshaped.projected_schema(&schemas).unwrap(),
+            [("id".to_string(), DataType::Utf8)]
+        );
+
+        // Unqualified `id` is in both relations.
+        let ambiguous = QueryWrapper::parse(&format!(
+            "SELECT id FROM '{}' s JOIN read_parquet('{}') u ON s.id = u.id",
+            sales, users
+        ))
+        .unwrap();
+        assert!(matches!(
+            ambiguous.projected_schema(&schemas),
+            Err(QueryError::Other(message)) if message == "Column id is ambiguous"
+        ));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_type_mismatch_is_reported() {
+        let root =
+            std::env::temp_dir().join(format!("pond-schema-mismatch-{}", std::process::id()));
+        write_parquet(
+            &root.join("a.parquet"),
+            vec![("id", ids()), ("name", names())],
+        );
+        write_parquet(
+            &root.join("b.parquet"),
+            vec![("id", names()), ("name", names())],
+        );
+
+        let source = format!("{}/*.parquet", root.display());
+        let wrapper = QueryWrapper::parse(&format!("SELECT * FROM '{}'", source)).unwrap();
+        match wrapper.source_schema().await {
+            Err(QueryError::SchemaMismatch {
+                glob,
+                file,
+                message,
+            }) => {
+                assert_eq!(glob, source);
+                assert!(file.ends_with("b.parquet"));
+                assert!(
+                    message.starts_with("column id is Utf8, but Int64 in"),
+                    "{}",
+                    message
+                );
+            }
+            other => panic!("expected a schema mismatch, got {:?}", other.map(|_| ())),
+        }
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+use crate::QueryWrapper;
+use sqlparser::ast::{Query, SetExpr, SetOperator, SetQuantifier, Statement};
+
+impl QueryWrapper {
+    /// The branches of a top-level `UNION ALL`, nested ones flattened, each
+    /// as a query of its own that can run independently: concatenating their
+    /// results gives the union's rows, in some order. Branches keep the
+    /// query's WITH clause but not its ORDER BY, LIMIT or OFFSET, which apply
+    /// to the concatenation.
+    ///
+    /// `None` for anything else, including UNION, INTERSECT and EXCEPT, whose
+    /// duplicate handling spans branches, and `UNION ALL BY NAME`, which lines
+    /// up columns by name rather than position. A parenthesized branch with
+    /// its own ORDER BY or LIMIT is kept whole.
+    pub fn split_set_operation(&self) -> Option<Vec<QueryWrapper>> {
+        let Statement::Query(query) = &self.ast else {
+            return None;
+        };
+        if !self.trailing.is_empty() || !is_union_all(&query.body) {
+            return None;
+        }
+        let mut branches = Vec::new();
+        flatten(&query.body, &mut branches);
+        branches
+            .into_iter()
+            .map(|body| {
+                let branch = Query {
+                    body: Box::new(body.clone()),
+                    order_by: None,
+                    limit: None,
+                    limit_by: Vec::new(),
+                    offset: None,
+                    fetch: None,
+                    ..query.as_ref().clone()
+                };
+                QueryWrapper::parse(&branch.to_string()).ok()
+            })
+            .collect()
+    }
+}
+
+fn is_union_all(body: &SetExpr) -> bool {
+    matches!(
+        body,
+        SetExpr::SetOperation {
+            op: SetOperator::Union,
+            set_quantifier: SetQuantifier::All,
+            ..
+        }
+    )
+}
+
+/// Collects the branches of `body`, looking through nested `UNION ALL`s and
+/// parentheses that add nothing of their own.
+fn flatten<'a>(body: &'a SetExpr, branches: &mut Vec<&'a SetExpr>) {
+    match body {
+        SetExpr::SetOperation { left, right, .. } if is_union_all(body) => {
+            flatten(left, branches);
+            flatten(right, branches);
+        }
+        SetExpr::Query(query)
+            if query.with.is_none()
+                && query.order_by.is_none()
+                && query.limit.is_none()
+                && query.limit_by.is_empty()
+                && query.offset.is_none()
+                && query.fetch.is_none() =>
+        {
+            flatten(&query.body, branches);
+        }
+        _ => branches.push(body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use duckdb::Connection;
+
+    fn branch_sql(sql: &str) -> Option<Vec<String>> {
+        let branches = QueryWrapper::parse(sql).unwrap().split_set_operation()?;
+        Some(
+            branches
+                .iter()
+                .map(|branch| branch.sql().to_string())
+                .collect(),
+        )
+    }
+
+    fn rows(conn: &Connection, sql: &str) -> Vec<String> {
+        let mut statement = conn
+            .prepare(&format!(
+                "SELECT concat_ws('|', COLUMNS(*)) FROM ({}) AS t",
+                sql
+            ))
+            .unwrap();
+        let mut rows: Vec<String> = statement
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        rows.sort();
+        rows
+    }
+
+    #[test]
+    fn test_split_union_all() {
+        let sql = "SELECT id, amount FROM 's3://b/2023/*.parquet' \
+                   UNION ALL (SELECT id, amount FROM 's3://b/2024/*.parquet' \
+                   UNION ALL SELECT id, amount FROM 's3://b/2025/*.parquet') \
+                   ORDER BY amount DESC LIMIT 10";
+        let branches = QueryWrapper::parse(sql)
+            .unwrap()
+            .split_set_operation()
+            .unwrap();
+        let sources: Vec<_> = branches
+            .iter()
+            .map(|branch| branch.source().unwrap())
+            .collect();
+        assert_eq!(
+            sources,
+            [
+                "s3://b/2023/*.parquet",
+                "s3://b/2024/*.parquet",
+                "s3://b/2025/*.parquet"
+            ]
+        );
+        assert_eq!(
+            branches[0].sql(),
+            "SELECT id, amount FROM 's3://b/2023/*.parquet'"
+        );
+        assert_ne!(branches[0].hash(), branches[1].hash());
+
+        assert_eq!(
+            branch_sql(
+                "WITH big AS (SELECT * FROM t WHERE amount > 5) \
+                 SELECT id FROM big UNION ALL (SELECT id FROM u ORDER BY id LIMIT 1)"
+            )
+            .unwrap(),
+            [
+                "WITH big AS (SELECT * FROM t WHERE amount > 5) SELECT id FROM big",
+                "WITH big AS (SELECT * FROM t WHERE amount > 5) (SELECT id FROM u ORDER BY id LIMIT 1)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_operations_that_do_not_split() {
+        for sql in [
+            "SELECT id FROM a UNION SELECT id FROM b",
+            "SELECT id FROM a INTERSECT SELECT id FROM b",
+            "SELECT id FROM a EXCEPT SELECT id FROM b",
+            "SELECT id FROM a UNION ALL BY NAME SELECT id FROM b",
+            "SELECT id FROM a",
+            "INSERT INTO a SELECT id FROM b",
+        ] {
+            assert!(branch_sql(sql).is_none(), "{}", sql);
+        }
+        // A distinct UNION nested under UNION ALL is one branch.
+        assert_eq!(
+            branch_sql("(SELECT 1 UNION SELECT 1) UNION ALL SELECT 2").unwrap(),
+            ["SELECT 1 UNION SELECT 1", "SELECT 2"]
+        );
+    }
+
+    #[test]
+    fn test_branches_concatenate_to_the_union() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE sales_2023 AS SELECT * FROM (VALUES (1, 10), (2, 20), (2, 20)) t(id, amount);
+             CREATE TABLE sales_2024 AS SELECT * FROM (VALUES (2, 20), (3, 5)) t(id, amount);",
+        )
+        .unwrap();
+        let sql = "WITH recent AS (SELECT * FROM sales_2024 WHERE amount > 1) \
+                   SELECT id, amount FROM sales_2023 \
+                   UNION ALL (SELECT id, amount FROM recent UNION ALL SELECT 9, 90)";
+        let branches = QueryWrapper::parse(sql)
+            .unwrap()
+            .split_set_operation()
+            .unwrap();
+        assert_eq!(branches.len(), 3);
+
+        let mut concatenated: Vec<String> = branches
+            .iter()
+            .flat_map(|branch| rows(&conn, branch.sql()))
+            .collect();
+        concatenated.sort();
+        assert_eq!(concatenated, rows(&conn, sql));
+        assert_eq!(concatenated.len(), 6);
+    }
+}
@@ -0,0 +1,383 @@
+use crate::QueryWrapper;
+use chrono::{DateTime, Months, NaiveDate, NaiveDateTime, TimeDelta, Utc};
+use sqlparser::ast::{BinaryOperator, Expr, FunctionArguments, Interval, Value};
+use std::ops::Bound;
+
+/// The range of times a query's WHERE clause selects for a column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeBounds {
+    pub lower: Bound<DateTime<Utc>>,
+    pub upper: Bound<DateTime<Utc>>,
+    /// Whether ORed ranges were merged into the smallest range covering them
+    /// all, which may take in times between them the query doesn't select.
+    pub approximate: bool,
+}
+
+impl QueryWrapper {
+    /// The range the outer WHERE clause restricts `column` to, for picking the
+    /// partitions a query needs. `None` when nothing bounds it.
+    ///
+    /// Comparisons and BETWEEN against timestamp and date literals count, as
+    /// do `now()`, `current_timestamp` and `current_date` plus or minus an
+    /// INTERVAL, which are evaluated at call time. Literals without a time
+    /// zone are taken as UTC. Conditions ANDed together narrow the range;
+    /// ORed ones widen it to cover every branch, making it
+    /// [`approximate`](TimeBounds::approximate). Anything else leaves the
+    /// range as it is under AND and unbounded under OR.
+    pub fn time_bounds(&self, column: &str) -> Option<TimeBounds> {
+        self.time_bounds_at(column, Utc::now())
+    }
+
+    /// Like [`time_bounds`](Self::time_bounds), evaluating `now()` as `now`.
+    pub fn time_bounds_at(&self, column: &str, now: DateTime<Utc>) -> Option<TimeBounds> {
+        let bounds = bounds(self.where_clause()?, column, now);
+        let unbounded = matches!(
+            (&bounds.lower, &bounds.upper),
+            (Bound::Unbounded, Bound::Unbounded)
+        );
+        (!unbounded).then_some(bounds)
+    }
+}
+
+fn unbounded() -> TimeBounds {
+    TimeBounds {
+        lower: Bound::Unbounded,
+        upper: Bound::Unbounded,
+        approximate: false,
+    }
+}
+
+fn bounds(expr: &Expr, column: &str, now: DateTime<Utc>) -> TimeBounds {
+    match expr {
+        Expr::Nested(expr) => bounds(expr, column, now),
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => intersect(bounds(left, column, now), bounds(right, column, now)),
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::Or,
+            right,
+        } => hull(bounds(left, column, now), bounds(right, column, now)),
+        Expr::BinaryOp { left, op, right } => {
+            let (op, value) = if is_column(left, column) {
+                (op.clone(), right)
+            } else if is_column(right, column) {
+                (flip(op), left)
+            } else {
+                return unbounded();
+            };
+            let Some(time) = timestamp(value, now) else {
+                return unbounded();
+            };
+            let (lower, upper) = match op {
+                BinaryOperator::Eq => (Bound::Included(time), Bound::Included(time)),
+                BinaryOperator::Gt => (Bound::Excluded(time), Bound::Unbounded),
+                BinaryOperator::GtEq => (Bound::Included(time), Bound::Unbounded),
+                BinaryOperator::Lt => (Bound::Unbounded, Bound::Excluded(time)),
+                BinaryOperator::LtEq => (Bound::Unbounded, Bound::Included(time)),
+                _ => return unbounded(),
+            };
+            TimeBounds {
+                lower,
+                upper,
+                approximate: false,
+            }
+        }
+        Expr::Between {
+            expr,
+            negated: false,
+            low,
+            high,
+        } if is_column(expr, column) => TimeBounds {
+            lower: timestamp(low, now).map_or(Bound::Unbounded, Bound::Included),
+            upper: timestamp(high, now).map_or(Bound::Unbounded, Bound::Included),
+            approximate: false,
+        },
+        _ => unbounded(),
+    }
+}
+
+/// Whether `expr` names `column`, qualified or not.
+fn is_column(expr: &Expr, column: &str) -> bool {
+    match expr {
+        Expr::Identifier(ident) => ident.value.eq_ignore_ascii_case(column),
+        Expr::CompoundIdentifier(idents) => idents
+            .last()
+            .is_some_and(|ident| ident.value.eq_ignore_ascii_case(column)),
+        Expr::Nested(expr) => is_column(expr, column),
+        _ => false,
+    }
+}
+
+/// The operator with its operands swapped, so `'2024-01-01' < ts` reads as
+/// `ts > '2024-01-01'`.
+fn flip(op: &BinaryOperator) -> BinaryOperator {
+    match op {
+        BinaryOperator::Gt => BinaryOperator::Lt,
+        BinaryOperator::GtEq => BinaryOperator::LtEq,
+        BinaryOperator::Lt => BinaryOperator::Gt,
+        BinaryOperator::LtEq => BinaryOperator::GtEq,
+        op => op.clone(),
+    }
+}
+
+/// The time `expr` evaluates to, for the literals and `now()` arithmetic
+/// [`QueryWrapper::time_bounds`] understands.
+fn timestamp(expr: &Expr, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    match expr {
+        Expr::Nested(expr) | Expr::Cast { expr, .. } => timestamp(expr, now),
+        Expr::Value(Value::SingleQuotedString(text)) | Expr::TypedString { value: text, .. } => {
+            parse_timestamp(text)
+        }
+        Expr::Function(func)
+            if matches!(func.args, FunctionArguments::None)
+                || matches!(&func.args, FunctionArguments::List(list) if list.args.is_empty()) =>
+        {
+            match func.name.to_string().to_lowercase().as_str() {
+                "now" | "current_timestamp" | "get_current_timestamp" => Some(now),
+                "current_date" | "today" => Some(now.date_naive().and_hms_opt(0, 0, 0)?.and_utc()),
+                _ => None,
+            }
+        }
+        Expr::BinaryOp {
+            left,
+            op: op @ (BinaryOperator::Plus | BinaryOperator::Minus),
+            right,
+        } => {
+            let time = timestamp(left, now)?;
+            let Expr::Interval(interval) = right.as_ref() else {
+                return None;
+            };
+            let (amount, unit) = interval_parts(interval)?;
+            let amount = if *op == BinaryOperator::Minus {
+                amount.checked_neg()?
+            } else {
+                amount
+            };
+            shift(time, amount, &unit)
+        }
+        _ => None,
+    }
+}
+
+/// Timestamps with or without a UTC offset, and dates as their midnight.
+fn parse_timestamp(text: &str) -> Option<DateTime<Utc>> {
+    let text = text.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Some(time.to_utc());
+    }
+    for format in [
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%d %H:%M",
+    ] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(text, format) {
+            return Some(time.and_utc());
+        }
+    }
+    let date = NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc())
+}
+
+/// The count and singular, lowercased unit of `INTERVAL 1 DAY`,
+/// `INTERVAL '1' DAY` or `INTERVAL '1 day'`.
+fn interval_parts(interval: &Interval) -> Option<(i64, String)> {
+    let text = match interval.value.as_ref() {
+        Expr::Value(Value::Number(number, _)) => number.clone(),
+        Expr::Value(Value::SingleQuotedString(text)) => text.clone(),
+        _ => return None,
+    };
+    let (amount, unit) = match &interval.leading_field {
+        Some(field) => (text.trim().to_string(), field.to_string()),
+        None => {
+            let (amount, unit) = text.trim().split_once(char::is_whitespace)?;
+            (amount.to_string(), unit.trim().to_string())
+        }
+    };
+    let unit = unit.to_lowercase();
+    let unit = unit.strip_suffix('s').unwrap_or(&unit).to_string();
+    Some((amount.parse().ok()?, unit))
+}
+
+fn shift(time: DateTime<Utc>, amount: i64, unit: &str) -> Option<DateTime<Utc>> {
+    let delta = match unit {
+        "second" => TimeDelta::try_seconds(amount)?,
+        "minute" => TimeDelta::try_minutes(amount)?,
+        "hour" => TimeDelta::try_hours(amount)?,
+        "day" => TimeDelta::try_days(amount)?,
+        "week" => TimeDelta::try_weeks(amount)?,
+        "month" | "year" => {
+            let months = if unit == "year" {
+                amount.checked_mul(12)?
+            } else {
+                amount
+            };
+            let magnitude = Months::new(u32::try_from(months.unsigned_abs()).ok()?);
+            return if months < 0 {
+                time.checked_sub_months(magnitude)
+            } else {
+                time.checked_add_months(magnitude)
+            };
+        }
+        _ => return None,
+    };
+    time.checked_add_signed(delta)
+}
+
+/// The times both `a` and `b` select.
+fn intersect(a: TimeBounds, b: TimeBounds) -> TimeBounds {
+    TimeBounds {
+        lower: tighter(a.lower, b.lower, |x, y| x > y),
+        upper: tighter(a.upper, b.upper, |x, y| x < y),
+        approximate: a.approximate || b.approximate,
+    }
+}
+
+/// The smallest range covering both `a` and `b`.
+fn hull(a: TimeBounds, b: TimeBounds) -> TimeBounds {
+    TimeBounds {
+        lower: looser(a.lower, b.lower, |x, y| x < y),
+        upper: looser(a.upper, b.upper, |x, y| x > y),
+        approximate: true,
+    }
+}
+
+/// Of two lower (or upper) bounds, the one admitting fewer times; `beyond`
+/// says whether one time is past the other in that direction.
+fn tighter<T: PartialEq>(a: Bound<T>, b: Bound<T>, beyond: impl Fn(&T, &T) -> bool) -> Bound<T> {
+    match (a, b) {
+        (Bound::Unbounded, bound) | (bound, Bound::Unbounded) => bound,
+        (a, b) => {
+            let (x, y) = (endpoint(&a), endpoint(&b));
+            if beyond(x, y) || (x == y && matches!(a, Bound::Excluded(_))) {
+                a
+            } else {
+                b
+            }
+        }
+    }
+}
+
+/// Of two lower (or upper) bounds, the one admitting more times.
+fn looser<T: PartialEq>(a: Bound<T>, b: Bound<T>, beyond: impl Fn(&T, &T) -> bool) -> Bound<T> {
+    match (a, b) {
+        (Bound::Unbounded, _) | (_, Bound::Unbounded) => Bound::Unbounded,
+        (a, b) => {
+            let (x, y) = (endpoint(&a), endpoint(&b));
+            if beyond(x, y) || (x == y && matches!(a, Bound::Included(_))) {
+                a
+            } else {
+                b
+            }
+        }
+    }
+}
+
+fn endpoint<T>(bound: &Bound<T>) -> &T {
+    match bound {
+        Bound::Included(value) | Bound::Excluded(value) => value,
+        Bound::Unbounded => unreachable!("unbounded ends are handled by the caller"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(y: i32, m: u32, d: u32, h: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, 0, 0).unwrap()
+    }
+
+    fn time_bounds(sql: &str) -> Option<TimeBounds> {
+        QueryWrapper::parse(sql)
+            .unwrap()
+            .time_bounds_at("ts", at(2024, 5, 10, 12))
+    }
+
+    #[test]
+    fn test_between_and_two_sided_ranges() {
+        let expected = TimeBounds {
+            lower: Bound::Included(at(2024, 5, 1, 0)),
+            upper: Bound::Included(at(2024, 5, 7, 0)),
+            approximate: false,
+        };
+        assert_eq!(
+            time_bounds(
+                "SELECT * FROM t WHERE ts BETWEEN '2024-05-01' AND TIMESTAMP '2024-05-07 00:00:00'"
+            ),
+            Some(expected)
+        );
+
+        // Conjuncts intersect, and an equal excluded bound wins.
+        assert_eq!(
+            time_bounds(
+                "SELECT * FROM t WHERE e.ts >= '2024-05-01' AND region = 'eu' \
+                 AND '2024-05-07' > ts AND (ts > '2024-04-01' AND ts <= '2024-05-07')"
+            ),
+            Some(TimeBounds {
+                lower: Bound::Included(at(2024, 5, 1, 0)),
+                upper: Bound::Excluded(at(2024, 5, 7, 0)),
+                approximate: false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_one_sided_and_unbounded() {
+        assert_eq!(
+            time_bounds("SELECT * FROM t WHERE TS > '2024-05-01T06:00:00Z'"),
+            Some(TimeBounds {
+                lower: Bound::Excluded(at(2024, 5, 1, 6)),
+                upper: Bound::Unbounded,
+                approximate: false,
+            })
+        );
+        assert_eq!(time_bounds("SELECT * FROM t WHERE region = 'eu'"), None);
+        assert_eq!(time_bounds("SELECT * FROM t"), None);
+        assert_eq!(
+            time_bounds("SELECT * FROM t WHERE ts > '2024-05-01' OR region = 'eu'"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_interval_arithmetic() {
+        assert_eq!(
+            time_bounds("SELECT * FROM t WHERE ts >= now() - INTERVAL 1 DAY"),
+            Some(TimeBounds {
+                lower: Bound::Included(at(2024, 5, 9, 12)),
+                upper: Bound::Unbounded,
+                approximate: false,
+            })
+        );
+        assert_eq!(
+            time_bounds(
+                "SELECT * FROM t WHERE ts >= current_date - INTERVAL '1 month' \
+                 AND ts < current_timestamp + INTERVAL '2' HOUR"
+            ),
+            Some(TimeBounds {
+                lower: Bound::Included(at(2024, 4, 10, 0)),
+                upper: Bound::Excluded(at(2024, 5, 10, 14)),
+                approximate: false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_ored_ranges_are_approximate() {
+        assert_eq!(
+            time_bounds(
+                "SELECT * FROM t WHERE ts BETWEEN '2024-01-01' AND '2024-01-31' \
+                 OR ts BETWEEN '2024-03-01' AND '2024-03-31'"
+            ),
+            Some(TimeBounds {
+                lower: Bound::Included(at(2024, 1, 1, 0)),
+                upper: Bound::Included(at(2024, 3, 31, 0)),
+                approximate: true,
+            })
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use sqlparser::ast::{
+    BinaryOperator, Expr, JoinConstraint, JoinOperator, Select, TableFactor, Value,
+};
+use std::fmt;
+
+/// A join that multiplies rows instead of matching them, found by
+/// [`QueryWrapper::analyze`](crate::QueryWrapper::analyze).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnalysisWarning {
+    /// An explicit `CROSS JOIN`.
+    CrossJoin(Vec<String>),
+    /// `FROM a, b` with no WHERE equality relating the relations listed.
+    UncorrelatedCommaJoin(Vec<String>),
+    /// An inner join `ON TRUE`.
+    TrivialJoinCondition(Vec<String>),
+}
+
+impl AnalysisWarning {
+    /// The relations whose rows are multiplied, named as in
+    /// [`QueryAnalysis::tables`](crate::QueryAnalysis::tables): for a join,
+    /// everything joined before it and the relation it adds.
+    pub fn tables(&self) -> &[String] {
+        match self {
+            Self::CrossJoin(tables)
+            | Self::UncorrelatedCommaJoin(tables)
+            | Self::TrivialJoinCondition(tables) => tables,
+        }
+    }
+}
+
+impl fmt::Display for AnalysisWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tables = self.tables().join(", ");
+        match self {
+            Self::CrossJoin(_) => write!(f, "cross join of {}", tables),
+            Self::UncorrelatedCommaJoin(_) => {
+                write!(f, "no WHERE equality relates comma-joined {}", tables)
+            }
+            Self::TrivialJoinCondition(_) => write!(f, "join of {} is always true", tables),
+        }
+    }
+}
+
+/// The cartesian products in `select`, given the names of each FROM item's
+/// relations, its joins' in order after the first.
+pub(crate) fn join_warnings(select: &Select, names: &[Vec<String>]) -> Vec<AnalysisWarning> {
+    let mut warnings = Vec::new();
+    for (from, names) in select.from.iter().zip(names) {
+        for (i, join) in from.joins.iter().enumerate() {
+            let tables = names[..i + 2].to_vec();
+            match &join.join_operator {
+                JoinOperator::CrossJoin => warnings.push(AnalysisWarning::CrossJoin(tables)),
+                JoinOperator::Inner(JoinConstraint::On(expr)) if is_true(expr) => {
+                    warnings.push(AnalysisWarning::TrivialJoinCondition(tables));
+                }
+                _ => {}
+            }
+        }
+    }
+    if select.from.len() > 1 && !comma_joins_related(select) {
+        let tables = names.iter().flatten().cloned().collect();
+        warnings.push(AnalysisWarning::UncorrelatedCommaJoin(tables));
+    }
+    warnings
+}
+
+fn is_true(expr: &Expr) -> bool {
+    match expr {
+        Expr::Value(Value::Boolean(value)) => *value,
+        Expr::Nested(expr) => is_true(expr),
+        _ => false,
+    }
+}
+
+/// Whether WHERE equalities between columns connect every FROM item. An
+/// equality with an unqualified side could relate any of them and is taken
+/// to; table functions such as `unnest(a.list)` and lateral subqueries read
+/// the items before them and count as related.
+fn comma_joins_related(select: &Select) -> bool {
+    let qualifiers: Vec<Vec<String>> = select
+        .from
+        .iter()
+        .map(|from| {
+            std::iter::once(&from.relation)
+                .chain(from.joins.iter().map(|join| &join.relation))
+                .filter_map(qualifier)
+                .collect()
+        })
+        .collect();
+    let mut components: Vec<usize> = (0..select.from.len()).collect();
+    for (i, from) in select.from.iter().enumerate() {
+        if reads_earlier_items(&from.relation) {
+            merge(&mut components, 0, i);
+        }
+    }
+
+    let mut conjuncts: Vec<&Expr> = select.selection.iter().collect();
+    while let Some(expr) = conjuncts.pop() {
+        match expr {
+            Expr::BinaryOp {
+                left,
+                op: BinaryOperator::And,
+                right,
+            } => conjuncts.extend([left.as_ref(), right.as_ref()]),
+            Expr::Nested(expr) => conjuncts.push(expr),
+            Expr::BinaryOp {
+                left,
+                op: BinaryOperator::Eq,
+                right,
+            } => {
+                let item = |expr: &Expr| match expr {
+                    Expr::Identifier(_) => Some(None),
+                    Expr::CompoundIdentifier(idents) if idents.len() >= 2 => {
+                        let table = idents[idents.len() - 2].value.to_lowercase();
+                        Some(qualifiers.iter().position(|names| names.contains(&table)))
+                    }
+                    _ => None,
+                };
+                match (item(left), item(right)) {
+                    (Some(Some(left)), Some(Some(right))) => merge(&mut components, left, right),
+                    (Some(_), Some(_)) => return true,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    (0..components.len()).all(|i| find(&components, i) == find(&components, 0))
+}
+
+/// The name columns of `relation` are qualified with.
+fn qualifier(relation: &TableFactor) -> Option<String> {
+    let name = match relation {
+        TableFactor::Table {
+            alias: Some(alias), ..
+        }
+        | TableFactor::Derived {
+            alias: Some(alias), ..
+        }
+        | TableFactor::Function {
+            alias: Some(alias), ..
+        }
+        | TableFactor::UNNEST {
+            alias: Some(alias), ..
+        } => &alias.name.value,
+        TableFactor::Table { name, .. } => &name.0.last()?.value,
+        _ => return None,
+    };
+    Some(name.to_lowercase())
+}
+
+fn reads_earlier_items(relation: &TableFactor) -> bool {
+    match relation {
+        TableFactor::Derived { lateral, .. } | TableFactor::Function { lateral, .. } => *lateral,
+        TableFactor::UNNEST { .. } => true,
+        // `unnest(a.list)` parses as a table function.
+        TableFactor::Table { args, .. } => args.is_some(),
+        _ => false,
+    }
+}
+
+fn find(components: &[usize], mut i: usize) -> usize {
+    while components[i] != i {
+        i = components[i];
+    }
+    i
+}
+
+fn merge(components: &mut [usize], a: usize, b: usize) {
+    let (a, b) = (find(components, a), find(components, b));
+    components[a.max(b)] = a.min(b);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::QueryWrapper;
+
+    fn warnings(sql: &str) -> Vec<AnalysisWarning> {
+        QueryWrapper::parse(sql)
+            .unwrap()
+            .analyze()
+            .warnings()
+            .to_vec()
+    }
+
+    fn tables(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_comma_join_with_filter() {
+        assert!(
+            warnings("SELECT * FROM orders o, customers c WHERE o.customer_id = c.id").is_empty()
+        );
+        assert!(warnings(
+            "SELECT * FROM a, b, c WHERE (a.x = 1 AND b.id = c.b_id) AND c.a_id = a.id"
+        )
+        .is_empty());
+        // Unqualified names could relate either side.
+        assert!(warnings("SELECT * FROM a, b WHERE id = b_id").is_empty());
+        assert!(warnings("SELECT * FROM t, unnest(t.tags) AS u(tag)").is_empty());
+    }
+
+    #[test]
+    fn test_comma_join_without_filter() {
+        assert_eq!(
+            warnings("SELECT * FROM orders o, 's3://b/customers.parquet'"),
+            [AnalysisWarning::UncorrelatedCommaJoin(tables(&[
+                "orders",
+                "s3://b/customers.parquet"
+            ]))]
+        );
+        // Filters on each side alone, or relating only two of three.
+        assert_eq!(
+            warnings("SELECT * FROM a, b JOIN c ON b.id = c.id WHERE a.x = 1 AND b.y = 2").len(),
+            1
+        );
+        assert_eq!(
+            warnings("SELECT * FROM a, b, c WHERE a.id = b.id OR b.id = c.id").len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_cross_join() {
+        let found = warnings(
+            "SELECT * FROM a JOIN b ON a.id = b.id CROSS JOIN c JOIN d ON TRUE LEFT JOIN e ON TRUE",
+        );
+        assert_eq!(
+            found,
+            [
+                AnalysisWarning::CrossJoin(tables(&["a", "b", "c"])),
+                AnalysisWarning::TrivialJoinCondition(tables(&["a", "b", "c", "d"])),
+            ]
+        );
+        assert_eq!(found[0].to_string(), "cross join of a, b, c");
+        assert!(warnings("SELECT * FROM a JOIN b ON a.id = b.id").is_empty());
+    }
+}
@@ -1,24 +1,120 @@
-use arrow::array::{Int64Array, StringArray};
-use arrow::datatypes::{DataType, Field, Schema};
+use arrow::array::{
+    new_empty_array, Array, ArrayRef, AsArray, Float64Array, Int64Array, UInt32Array,
+};
+use arrow::compute::kernels::numeric::div;
+use arrow::compute::{
+    cast, cast_with_options, concat, lexsort_to_indices, take, take_record_batch, CastOptions,
+    SortOptions,
+};
+use arrow::datatypes::{DataType, Field, Float64Type, Int64Type, Schema, SchemaRef};
+use arrow::error::ArrowError;
+use arrow::ipc::reader::StreamReader;
 use arrow::ipc::writer::StreamWriter;
 use arrow::record_batch::RecordBatch;
+use arrow::row::{RowConverter, SortField};
 use aws_config::BehaviorVersion;
+use aws_sdk_lambda::error::SdkError;
+use aws_sdk_lambda::operation::invoke::builders::InvokeFluentBuilder;
+use aws_sdk_lambda::operation::invoke::{InvokeError, InvokeOutput};
 use aws_sdk_lambda::primitives::Blob;
 use aws_sdk_lambda::{types::InvocationType, Client as LambdaClient};
-use futures::future::join_all;
-use lambda_runtime::{service_fn, Error, LambdaEvent};
+use duckdb::Connection;
+use futures::future::BoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use lambda_runtime::{service_fn, Error as LambdaError, LambdaEvent};
+use pond_parser::{renamed_function, QueryError, QueryKind, QueryWrapper, Strategy};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use sqlparser::ast::{Expr, GroupByExpr, Query, Select, SelectItem, SetExpr, Statement};
+use sqlparser::ast::{visit_expressions, Expr, GroupByExpr, SelectItem, Value};
 use sqlparser::dialect::DuckDbDialect;
-use sqlparser::parser::Parser;
+use sqlparser::parser::{Parser, ParserError};
+use sqlparser::tokenizer::Token;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::io::Cursor;
-use std::sync::Arc;
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::Semaphore;
+
+/// Worker Lambda invoked for each partition when neither the request nor
+/// `POND_WORKER_FUNCTION` names one.
+const DEFAULT_WORKER_FUNCTION: &str = "pond-duckling";
+
+/// Columns the workers return for AVG, merged into the average by the planner.
+const AVG_SUM_COLUMN: &str = "__pond_avg_sum";
+const AVG_COUNT_COLUMN: &str = "__pond_avg_count";
+
+/// Aggregates [`DistributedPlan::merge`] can combine from per-partition results.
+const MERGEABLE_AGGREGATES: &[&str] = &["count", "sum", "min", "max", "avg"];
+
+/// The media type of an Arrow IPC stream, which workers are asked for and
+/// the planner answers with.
+const ARROW_STREAM_CONTENT_TYPE: &str = "application/vnd.apache.arrow.stream";
+
+/// Worker invocations allowed in flight at once unless the request says otherwise.
+const DEFAULT_MAX_CONCURRENT: usize = 10;
+
+/// How long one partition's invocation, retries included, may take unless
+/// overridden by the request or `POND_INVOKE_TIMEOUT_SECS`.
+const DEFAULT_INVOKE_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[derive(Deserialize)]
 struct Request {
     query: String,
+    /// Worker function name or ARN for this query, overriding `POND_WORKER_FUNCTION`.
+    worker_function: Option<String>,
+    /// How to handle failed partitions for this query, overriding `POND_FAILURE_MODE`.
+    failure_mode: Option<FailureMode>,
+    /// Cap on concurrent worker invocations for this query.
+    max_concurrent: Option<usize>,
+    /// Per-partition invocation timeout in seconds, overriding `POND_INVOKE_TIMEOUT_SECS`.
+    invoke_timeout_secs: Option<u64>,
+    /// A predicate every worker applies on top of the query's WHERE, e.g. to
+    /// restrict a shared query to one tenant; see [`DistributedPlan::with_filter`].
+    filter: Option<String>,
+}
+
+/// What to do when some partitions fail while others succeed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum FailureMode {
+    /// Any failed partition fails the whole query.
+    #[default]
+    Strict,
+    /// Return the rows from the partitions that succeeded and list the
+    /// failures in the `X-Pond-Failed-Partitions` header.
+    Lenient,
+}
+
+impl FailureMode {
+    /// Reads `POND_FAILURE_MODE` (`strict` or `lenient`), falling back to strict.
+    fn from_env() -> Self {
+        match std::env::var("POND_FAILURE_MODE").as_deref() {
+            Ok("lenient") => Self::Lenient,
+            _ => Self::Strict,
+        }
+    }
+}
+
+/// A partition whose worker could not produce results.
+#[derive(Debug, Serialize)]
+struct PartitionFailure {
+    partition: String,
+    error: String,
+}
+
+/// The merged result of a plan, plus the partitions left out in lenient mode.
+struct PlanResults {
+    /// One `(group, aggregate)` row per group, ordered and limited.
+    batch: RecordBatch,
+    failures: Vec<PartitionFailure>,
+    /// Partitions whose worker was throttled at least once, successful or not.
+    throttled: Vec<String>,
 }
 
+/// The envelope the planner returns its Arrow IPC in.
 #[derive(Serialize)]
 struct ArrowIpcResponse {
     status_code: u16,
@@ -27,183 +123,1498 @@ struct ArrowIpcResponse {
     body: Vec<u8>,
 }
 
+/// The payload each worker is invoked with.
+#[derive(Debug, Serialize)]
+struct WorkerRequest {
+    /// The SQL the worker runs; see [`DistributedPlan::worker_query`].
+    query: String,
+    table: String,
+    group_column: Option<String>,
+    agg_function: String,
+    /// The SELECT items computing the partial aggregate; see
+    /// [`DistributedPlan::partial_aggregates`].
+    aggregates: Vec<String>,
+    where_clause: Option<String>,
+    /// See [`DistributedPlan::push_down_filter`].
+    push_down_filter: Option<String>,
+    /// The prefix or source this worker reads.
+    partition: String,
+    /// Always `arrow`, so the batches can be merged without re-parsing
+    /// whatever format the worker would default to.
+    response_format: &'static str,
+}
+
+/// The envelope a worker returns: Arrow IPC on success, otherwise a JSON
+/// [`WorkerError`] or some other text.
+#[derive(Deserialize)]
+struct WorkerResponse {
+    status_code: u16,
+    #[serde(default)]
+    headers: serde_json::Value,
+    #[serde(with = "serde_bytes")]
+    body: Vec<u8>,
+}
+
+/// The JSON body of a worker's error response.
+#[derive(Deserialize)]
+struct WorkerError {
+    error_type: String,
+    message: String,
+}
+
+/// Why a query could not be planned or executed.
+#[derive(Error, Debug)]
+enum PlannerError {
+    /// The SQL doesn't parse, or the parser rejected it.
+    #[error(transparent)]
+    Query(#[from] QueryError),
+    /// Valid SQL the planner can't distribute.
+    #[error("{0}")]
+    Unsupported(String),
+    /// Valid SQL that has to run whole on one node, such as a lateral join
+    /// evaluated per row of everything before it.
+    #[error("Query must run on a single node: {0}")]
+    SingleNode(String),
+    /// Listing the source's prefixes failed.
+    #[error("Partition discovery failed: {0}")]
+    Discovery(QueryError),
+    /// A worker failed, timed out or returned an unreadable response.
+    #[error("Partition {partition} failed: {message}")]
+    Worker { partition: String, message: String },
+    /// The workers' results could not be combined.
+    #[error("Failed to merge partition results: {0}")]
+    Merge(String),
+    #[error("Arrow error: {0}")]
+    Arrow(#[from] ArrowError),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+impl PlannerError {
+    /// 400 when the query is at fault, 502 when storage or a worker is, 500
+    /// for everything else.
+    fn status_code(&self) -> u16 {
+        match self {
+            Self::Query(_) | Self::Unsupported(_) | Self::SingleNode(_) => 400,
+            Self::Discovery(QueryError::AccessDenied(_)) => 403,
+            Self::Discovery(_) | Self::Worker { .. } => 502,
+            Self::Merge(_) | Self::Arrow(_) | Self::Serialization(_) => 500,
+        }
+    }
+
+    fn error_type(&self) -> &'static str {
+        match self {
+            Self::Query(_) => "InvalidQuery",
+            Self::Unsupported(_) => "UnsupportedQuery",
+            Self::SingleNode(_) => "SingleNodeRequired",
+            Self::Discovery(QueryError::AccessDenied(_)) => "AccessDenied",
+            Self::Discovery(_) => "PartitionDiscoveryFailed",
+            Self::Worker { .. } => "WorkerFailed",
+            Self::Merge(_) | Self::Arrow(_) | Self::Serialization(_) => "InternalError",
+        }
+    }
+}
+
+/// JSON body returned in place of Arrow IPC when a query cannot be planned or executed.
+#[derive(Serialize)]
+struct ErrorResponse {
+    status_code: u16,
+    error_type: String,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<ErrorLocation>,
+}
+
+/// Where in the submitted SQL a syntax error was found.
+#[derive(Serialize)]
+struct ErrorLocation {
+    line: usize,
+    column: usize,
+    snippet: String,
+}
+
+impl From<PlannerError> for ErrorResponse {
+    fn from(err: PlannerError) -> Self {
+        let location = match &err {
+            PlannerError::Query(QueryError::ParseFailed {
+                line,
+                column,
+                snippet,
+                ..
+            }) => Some(ErrorLocation {
+                line: *line,
+                column: *column,
+                snippet: snippet.clone(),
+            }),
+            _ => None,
+        };
+        Self {
+            status_code: err.status_code(),
+            error_type: err.error_type().to_string(),
+            message: err.to_string(),
+            location,
+        }
+    }
+}
+
+impl ErrorResponse {
+    fn into_response(self) -> Result<ArrowIpcResponse, LambdaError> {
+        Ok(ArrowIpcResponse {
+            status_code: self.status_code,
+            headers: serde_json::json!({
+                "Content-Type": "application/json",
+            }),
+            body: serde_json::to_vec(&self)?,
+        })
+    }
+}
+
 struct QueryPlanner {
-    lambda_client: LambdaClient,
+    executor: Box<dyn PartitionExecutor>,
+    failure_mode: FailureMode,
+    max_concurrent: usize,
+    invoke_timeout: Duration,
+}
+
+/// Backoff settings for worker invocations that fail with a retryable error.
+#[derive(Clone, Copy, Debug)]
+struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Reads `POND_INVOKE_MAX_ATTEMPTS`, falling back to the default when unset or invalid.
+    fn from_env() -> Self {
+        let mut policy = Self::default();
+        if let Some(max_attempts) = std::env::var("POND_INVOKE_MAX_ATTEMPTS")
+            .ok()
+            .and_then(|value| value.parse::<u32>().ok())
+            .filter(|value| *value > 0)
+        {
+            policy.max_attempts = max_attempts;
+        }
+        policy
+    }
+
+    /// Full-jitter exponential backoff for the given (zero-based) retry number.
+    fn backoff(&self, retry: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay);
+        let millis = ceiling.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+    }
+}
+
+/// Lambda reports throttling (`ThrottlingException`, surfaced by the SDK as
+/// `TooManyRequestsException`) when account or function concurrency runs out.
+fn is_throttled(err: &SdkError<InvokeError>) -> bool {
+    matches!(err, SdkError::ServiceError(service_err)
+        if service_err.err().is_too_many_requests_exception())
+}
+
+fn is_retryable(err: &SdkError<InvokeError>) -> bool {
+    match err {
+        SdkError::ServiceError(service_err) => {
+            let err = service_err.err();
+            err.is_too_many_requests_exception() || err.is_service_exception()
+        }
+        SdkError::TimeoutError(_) => true,
+        _ => false,
+    }
+}
+
+/// The outcome of invoking one worker, possibly after retries.
+struct Invocation {
+    result: Result<InvokeOutput, SdkError<InvokeError>>,
+    /// How many attempts were throttled.
+    throttles: u32,
+}
+
+async fn invoke_with_retry(req: InvokeFluentBuilder, policy: RetryPolicy) -> Invocation {
+    let mut attempt = 1;
+    let mut throttles = 0;
+    loop {
+        let result = req.clone().send().await;
+        if matches!(&result, Err(err) if is_throttled(err)) {
+            throttles += 1;
+        }
+        match result {
+            Err(err) if attempt < policy.max_attempts && is_retryable(&err) => {
+                tokio::time::sleep(policy.backoff(attempt - 1)).await;
+                attempt += 1;
+            }
+            result => return Invocation { result, throttles },
+        }
+    }
+}
+
+/// What running one partition's worker query produced.
+struct Execution {
+    /// The partition's schema and batches, or why there are none.
+    result: Result<(SchemaRef, Vec<RecordBatch>), String>,
+    /// How many attempts were throttled.
+    throttles: u32,
+}
+
+/// Runs the worker query for one partition.
+trait PartitionExecutor: Send + Sync {
+    fn execute<'a>(&'a self, payload: &'a WorkerRequest) -> BoxFuture<'a, Execution>;
+}
+
+/// Invokes the worker Lambda, retrying as `retry_policy` allows.
+struct LambdaExecutor {
+    client: LambdaClient,
+    worker_function: String,
+    retry_policy: RetryPolicy,
+}
+
+impl PartitionExecutor for LambdaExecutor {
+    fn execute<'a>(&'a self, payload: &'a WorkerRequest) -> BoxFuture<'a, Execution> {
+        Box::pin(async move {
+            let payload = match serde_json::to_vec(payload) {
+                Ok(payload) => payload,
+                Err(err) => {
+                    return Execution {
+                        result: Err(format!("Invalid worker request: {}", err)),
+                        throttles: 0,
+                    }
+                }
+            };
+            let req = self
+                .client
+                .invoke()
+                .function_name(&self.worker_function)
+                .invocation_type(InvocationType::RequestResponse)
+                .payload(Blob::new(payload));
+            let Invocation { result, throttles } = invoke_with_retry(req, self.retry_policy).await;
+            Execution {
+                result: result
+                    .map_err(|err| format!("Lambda invocation error: {:?}", err))
+                    .and_then(decode_batches),
+                throttles,
+            }
+        })
+    }
+}
+
+/// Runs worker queries in process on an embedded DuckDB, so a plan can be
+/// executed without Lambda. Selected with `POND_EXECUTOR=local`.
+struct DuckDbExecutor {
+    /// Cloned per partition; the clones share its database.
+    conn: Mutex<Connection>,
+}
+
+impl DuckDbExecutor {
+    fn new(conn: Connection) -> Self {
+        Self {
+            conn: Mutex::new(conn),
+        }
+    }
+
+    fn open_in_memory() -> Result<Self, duckdb::Error> {
+        Connection::open_in_memory().map(Self::new)
+    }
+}
+
+impl PartitionExecutor for DuckDbExecutor {
+    fn execute<'a>(&'a self, payload: &'a WorkerRequest) -> BoxFuture<'a, Execution> {
+        let conn = self.conn.lock().unwrap().try_clone();
+        let query = payload.query.clone();
+        Box::pin(async move {
+            let result = match conn {
+                Ok(conn) => tokio::task::spawn_blocking(move || query_batches(&conn, &query))
+                    .await
+                    .unwrap_or_else(|err| Err(format!("Worker query panicked: {}", err))),
+                Err(err) => Err(format!("DuckDB error: {}", err)),
+            };
+            Execution {
+                result,
+                throttles: 0,
+            }
+        })
+    }
+}
+
+/// Runs `query`, taking the schema from the statement so an empty result
+/// still has one.
+fn query_batches(conn: &Connection, query: &str) -> Result<(SchemaRef, Vec<RecordBatch>), String> {
+    let mut stmt = conn
+        .prepare(query)
+        .map_err(|err| format!("DuckDB error: {}", err))?;
+    let arrow = stmt
+        .query_arrow([])
+        .map_err(|err| format!("DuckDB error: {}", err))?;
+    Ok((arrow.get_schema(), arrow.collect()))
 }
 
 #[derive(Default)]
 struct DistributedPlan {
+    /// The FROM relation as written, alias included.
     table: String,
+    /// The path or table name the relation reads; see [`QueryWrapper::source`].
+    source: String,
     group_column: String,
     agg_function: String,
-    where_clause: Option<Expr>,
+    /// The aggregate columns each worker computes, as SELECT items. Usually
+    /// the query's aggregate itself; AVG is split into a SUM and a COUNT.
+    partial_aggregates: Vec<String>,
+    /// Output name of the aggregate: its alias, or its SQL text when unaliased.
+    agg_alias: String,
+    where_clause: Option<String>,
+    /// A predicate applied by the workers along with `where_clause`.
+    push_down_filter: Option<String>,
+    /// Applied to the merged rows, in order of precedence.
+    order_by: Vec<SortKey>,
+    limit: Option<usize>,
+    offset: usize,
+    /// What each worker reads in place of `source`: one prefix per worker for
+    /// file sources, or the source itself.
     partitions: Vec<String>,
 }
 
-impl QueryPlanner {
-    async fn new() -> Result<Self, Error> {
-        let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-        let lambda_client = LambdaClient::new(&config);
-        Ok(Self { lambda_client })
+/// One ORDER BY term of the merged result.
+#[derive(Clone, Copy, Debug)]
+struct SortKey {
+    column: SortColumn,
+    descending: bool,
+    nulls_first: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SortColumn {
+    Group,
+    Aggregate,
+}
+
+impl DistributedPlan {
+    /// Has every worker also filter its rows by `filter_sql`, a boolean SQL
+    /// expression over the source's columns, so fewer rows are aggregated and
+    /// sent back. It is ANDed with the query's own WHERE clause.
+    fn with_filter(mut self, filter_sql: &str) -> Self {
+        self.push_down_filter = Some(filter_sql.to_string());
+        self
     }
 
-    async fn plan_and_execute(&self, query: &str) -> Result<ArrowIpcResponse, Error> {
-        let plan = self.analyze_query(query)?;
-        let results = self.execute_plan(plan).await?;
-        self.create_arrow_response(results)
+    /// The SQL the worker for `partition` runs: the aggregate grouped by the
+    /// group column, reading the partition instead of the whole source.
+    fn worker_query(&self, partition: &str) -> Result<String, PlannerError> {
+        let mut sql = format!(
+            "SELECT {}, {} FROM {}",
+            quote_ident(&self.group_column),
+            self.partial_aggregates.join(", "),
+            self.table
+        );
+        match (&self.where_clause, &self.push_down_filter) {
+            (Some(where_clause), Some(filter)) => {
+                sql.push_str(&format!(" WHERE ({}) AND ({})", where_clause, filter));
+            }
+            (Some(filter), None) | (None, Some(filter)) => {
+                sql.push_str(" WHERE ");
+                sql.push_str(filter);
+            }
+            (None, None) => {}
+        }
+        sql.push_str(" GROUP BY ");
+        sql.push_str(&quote_ident(&self.group_column));
+        if partition == self.source {
+            return Ok(sql);
+        }
+
+        let mut wrapper = QueryWrapper::parse(&sql)?;
+        wrapper.normalize_table_names(&HashMap::from([(
+            self.source.clone(),
+            partition.to_string(),
+        )]))?;
+        Ok(wrapper.sql().to_string())
     }
 
-    fn analyze_query(&self, query: &str) -> Result<DistributedPlan, Error> {
-        let dialect = DuckDbDialect {};
-        let ast = Parser::parse_sql(&dialect, query)?;
-
-        if let Statement::Query(query) = &ast[0] {
-            let Query { body, .. } = query.as_ref();
-            if let SetExpr::Select(select) = body.as_ref() {
-                let Select {
-                    projection,
-                    from,
-                    selection,
-                    group_by,
-                    ..
-                } = select.as_ref();
-
-                let table_name = &from[0].relation.to_string();
-
-                let group_column = match group_by {
-                    GroupByExpr::Expressions(exprs, _) if !exprs.is_empty() => {
-                        if let Expr::Identifier(ident) = &exprs[0] {
-                            ident.value.clone()
-                        } else {
-                            return Err("Unsupported GROUP BY expression".into());
-                        }
-                    }
-                    GroupByExpr::All(_) => return Err("GROUP BY ALL is not supported".into()),
-                    GroupByExpr::Expressions(_, _) => return Err("GROUP BY clause is empty".into()),
-                };
-
-                let agg_function =
-                    if let SelectItem::UnnamedExpr(Expr::Function(func)) = &projection[0] {
-                        func.name.to_string()
-                    } else {
-                        return Err("Unsupported aggregation".into());
-                    };
-
-                let where_clause = selection.clone();
-
-                let partitions = vec![
-                    "A".to_string(),
-                    "B".to_string(),
-                    "C".to_string(),
-                    "D".to_string(),
-                ];
-
-                Ok(DistributedPlan {
-                    table: table_name.clone(),
-                    group_column,
-                    agg_function,
-                    where_clause,
-                    partitions,
-                })
+    /// Combines the `(group, aggregate)` batches returned by the partitions.
+    ///
+    /// COUNT and SUM are added and MIN and MAX re-applied. AVG divides the
+    /// summed SUM by the summed COUNT, giving NULL for groups with no values.
+    /// Any other aggregate can't be merged from per-partition values alone
+    /// and fails with [`PlannerError::Merge`].
+    fn merge(&self, batches: &[RecordBatch]) -> Result<RecordBatch, PlannerError> {
+        let Some(first) = batches.first() else {
+            let value_type = if self.agg_function.eq_ignore_ascii_case("avg") {
+                DataType::Float64
             } else {
-                Err("Unsupported query type".into())
+                DataType::Int64
+            };
+            return self.batch(
+                new_empty_array(&DataType::Utf8),
+                new_empty_array(&value_type),
+            );
+        };
+        let expected = 1 + self.partial_aggregates.len();
+        if let Some(batch) = batches.iter().find(|batch| batch.num_columns() != expected) {
+            return Err(PlannerError::Merge(format!(
+                "worker returned {} columns, expected {}",
+                batch.num_columns(),
+                expected
+            )));
+        }
+
+        let function = self.agg_function.to_lowercase();
+        let keys = concat_column(batches, 0, first.column(0).data_type())?;
+        if function == "avg" {
+            let sums = concat_column(batches, 1, &DataType::Float64)?;
+            let counts = concat_column(batches, 2, &DataType::Int64)?;
+            let (firsts, groups) = group_rows(&keys)?;
+            let sums = add_values(&sums, &groups, firsts.len())?;
+            let counts = cast(
+                &add_values(&counts, &groups, firsts.len())?,
+                &DataType::Float64,
+            )?;
+            let keys = take(&keys, &UInt32Array::from(firsts), None)?;
+            return self.batch(keys, div(&sums, &counts)?);
+        }
+
+        let value_type = match function.as_str() {
+            "count" | "sum"
+                if batches
+                    .iter()
+                    .all(|batch| is_integral(batch.column(1).data_type())) =>
+            {
+                DataType::Int64
+            }
+            "count" | "sum" => DataType::Float64,
+            _ => first.column(1).data_type().clone(),
+        };
+        let values = concat_column(batches, 1, &value_type)?;
+
+        let wanted = match function.as_str() {
+            "count" | "sum" => None,
+            "min" => Some(Ordering::Less),
+            "max" => Some(Ordering::Greater),
+            _ => {
+                return Err(PlannerError::Merge(format!(
+                    "{} can't be merged across partitions",
+                    self.agg_function
+                )))
             }
+        };
+        let (firsts, groups) = group_rows(&keys)?;
+        let values = match wanted {
+            None => add_values(&values, &groups, firsts.len())?,
+            Some(wanted) => pick_values(&values, &groups, firsts.len(), wanted)?,
+        };
+        let keys = take(&keys, &UInt32Array::from(firsts), None)?;
+        self.batch(keys, values)
+    }
+
+    fn batch(&self, keys: ArrayRef, values: ArrayRef) -> Result<RecordBatch, PlannerError> {
+        let schema = Schema::new(vec![
+            Field::new(&self.group_column, keys.data_type().clone(), true),
+            Field::new(&self.agg_alias, values.data_type().clone(), true),
+        ]);
+        Ok(RecordBatch::try_new(Arc::new(schema), vec![keys, values])?)
+    }
+
+    /// Sorts by the query's ORDER BY and applies OFFSET and LIMIT.
+    ///
+    /// With a LIMIT only the first `offset + limit` rows are fully sorted.
+    fn order_and_limit(&self, batch: RecordBatch) -> Result<RecordBatch, PlannerError> {
+        let rows = batch.num_rows();
+        let end = self
+            .limit
+            .map(|limit| self.offset.saturating_add(limit))
+            .unwrap_or(rows)
+            .min(rows);
+
+        let batch = if self.order_by.is_empty() {
+            batch
         } else {
-            Err("Unsupported statement type".into())
+            let columns: Vec<_> = self
+                .order_by
+                .iter()
+                .map(|key| arrow::compute::SortColumn {
+                    values: batch
+                        .column(match key.column {
+                            SortColumn::Group => 0,
+                            SortColumn::Aggregate => 1,
+                        })
+                        .clone(),
+                    options: Some(SortOptions {
+                        descending: key.descending,
+                        nulls_first: key.nulls_first,
+                    }),
+                })
+                .collect();
+            let indices = lexsort_to_indices(&columns, Some(end))?;
+            take_record_batch(&batch, &indices)?
+        };
+
+        let offset = self.offset.min(end);
+        Ok(batch.slice(offset, end - offset))
+    }
+}
+
+/// Integers and scale-0 decimals (DuckDB's HUGEINT sums) add up exactly as `Int64`.
+fn is_integral(data_type: &DataType) -> bool {
+    data_type.is_integer() || matches!(data_type, DataType::Decimal128(_, 0))
+}
+
+/// Concatenates column `index` of every batch, cast to `data_type`.
+fn concat_column(
+    batches: &[RecordBatch],
+    index: usize,
+    data_type: &DataType,
+) -> Result<ArrayRef, PlannerError> {
+    // Fail on values that don't fit rather than turning them into NULLs.
+    let options = CastOptions {
+        safe: false,
+        ..Default::default()
+    };
+    let columns = batches
+        .iter()
+        .map(|batch| cast_with_options(batch.column(index), data_type, &options))
+        .collect::<Result<Vec<_>, _>>()?;
+    let columns: Vec<&dyn Array> = columns.iter().map(|column| column.as_ref()).collect();
+    Ok(concat(&columns)?)
+}
+
+/// Numbers each row by its key, returning the first row of every group and
+/// the group of every row.
+fn group_rows(keys: &ArrayRef) -> Result<(Vec<u32>, Vec<usize>), PlannerError> {
+    let converter = RowConverter::new(vec![SortField::new(keys.data_type().clone())])?;
+    let rows = converter.convert_columns(&[Arc::clone(keys)])?;
+    let mut index = HashMap::new();
+    let mut firsts = Vec::new();
+    let groups = rows
+        .iter()
+        .enumerate()
+        .map(|(row, key)| {
+            *index.entry(key).or_insert_with(|| {
+                firsts.push(row as u32);
+                firsts.len() - 1
+            })
+        })
+        .collect();
+    Ok((firsts, groups))
+}
+
+/// Sums `values` per group. A group whose values are all NULL stays NULL.
+fn add_values(
+    values: &ArrayRef,
+    groups: &[usize],
+    group_count: usize,
+) -> Result<ArrayRef, PlannerError> {
+    if let Some(ints) = values.as_primitive_opt::<Int64Type>() {
+        let mut sums: Vec<Option<i64>> = vec![None; group_count];
+        for (value, &group) in ints.iter().zip(groups) {
+            if let Some(value) = value {
+                let sum = sums[group]
+                    .unwrap_or(0)
+                    .checked_add(value)
+                    .ok_or_else(|| PlannerError::Merge("Int64 overflow".to_string()))?;
+                sums[group] = Some(sum);
+            }
+        }
+        Ok(Arc::new(Int64Array::from(sums)))
+    } else {
+        let mut sums: Vec<Option<f64>> = vec![None; group_count];
+        for (value, &group) in values.as_primitive::<Float64Type>().iter().zip(groups) {
+            if let Some(value) = value {
+                sums[group] = Some(sums[group].unwrap_or(0.0) + value);
+            }
+        }
+        Ok(Arc::new(Float64Array::from(sums)))
+    }
+}
+
+/// Keeps, per group, the value that compares as `wanted` against the others;
+/// NULLs lose.
+fn pick_values(
+    values: &ArrayRef,
+    groups: &[usize],
+    group_count: usize,
+    wanted: Ordering,
+) -> Result<ArrayRef, PlannerError> {
+    let converter = RowConverter::new(vec![SortField::new(values.data_type().clone())])?;
+    let rows = converter.convert_columns(&[Arc::clone(values)])?;
+    let mut best: Vec<Option<u32>> = vec![None; group_count];
+    for (row, &group) in groups.iter().enumerate() {
+        if values.is_null(row) {
+            continue;
         }
+        match best[group] {
+            Some(current) if rows.row(row).cmp(&rows.row(current as usize)) != wanted => {}
+            _ => best[group] = Some(row as u32),
+        }
+    }
+    Ok(take(values, &UInt32Array::from(best), None)?)
+}
+
+/// Double-quotes an identifier for the worker query.
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+impl QueryPlanner {
+    async fn new(
+        worker_function: Option<String>,
+        failure_mode: Option<FailureMode>,
+        max_concurrent: Option<usize>,
+        invoke_timeout_secs: Option<u64>,
+    ) -> Result<Self, LambdaError> {
+        // `POND_EXECUTOR=local` runs every partition in process instead.
+        let executor: Box<dyn PartitionExecutor> = match std::env::var("POND_EXECUTOR").as_deref() {
+            Ok("local") => Box::new(DuckDbExecutor::open_in_memory()?),
+            _ => {
+                let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+                Box::new(LambdaExecutor {
+                    client: LambdaClient::new(&config),
+                    worker_function: worker_function
+                        .or_else(|| std::env::var("POND_WORKER_FUNCTION").ok())
+                        .unwrap_or_else(|| DEFAULT_WORKER_FUNCTION.to_string()),
+                    retry_policy: RetryPolicy::from_env(),
+                })
+            }
+        };
+        Ok(Self::with_executor(
+            executor,
+            failure_mode,
+            max_concurrent,
+            invoke_timeout_secs,
+        ))
     }
 
-    async fn execute_plan(&self, plan: DistributedPlan) -> Result<Vec<(String, i64)>, Error> {
-        let mut tasks = Vec::new();
+    fn with_executor(
+        executor: Box<dyn PartitionExecutor>,
+        failure_mode: Option<FailureMode>,
+        max_concurrent: Option<usize>,
+        invoke_timeout_secs: Option<u64>,
+    ) -> Self {
+        Self {
+            executor,
+            failure_mode: failure_mode.unwrap_or_else(FailureMode::from_env),
+            max_concurrent: max_concurrent
+                .filter(|max| *max > 0)
+                .unwrap_or(DEFAULT_MAX_CONCURRENT),
+            invoke_timeout: invoke_timeout_secs
+                .or_else(|| {
+                    std::env::var("POND_INVOKE_TIMEOUT_SECS")
+                        .ok()
+                        .and_then(|value| value.parse().ok())
+                })
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_INVOKE_TIMEOUT),
+        }
+    }
 
-        for partition in plan.partitions {
-            let payload = serde_json::json!({
-                "table": plan.table,
-                "group_column": plan.group_column,
-                "agg_function": plan.agg_function,
-                "where_clause": plan.where_clause.as_ref().map(|expr| expr.to_string()),
-                "partition": partition
+    async fn plan_and_execute(
+        &self,
+        query: &str,
+        filter: Option<&str>,
+    ) -> Result<ArrowIpcResponse, ErrorResponse> {
+        self.try_plan_and_execute(query, filter)
+            .await
+            .map_err(ErrorResponse::from)
+    }
+
+    async fn try_plan_and_execute(
+        &self,
+        query: &str,
+        filter: Option<&str>,
+    ) -> Result<ArrowIpcResponse, PlannerError> {
+        let mut wrapper = QueryWrapper::parse(query)?;
+        let mut plan = self.analyze_query(&wrapper)?;
+        if let Some(filter) = filter {
+            plan = plan.with_filter(&push_down_filter(filter)?);
+        }
+        plan.partitions = partitions(&mut wrapper).await?;
+        let results = self.execute_plan(&plan).await?;
+        self.create_arrow_response(results)
+    }
+
+    fn analyze_query(&self, wrapper: &QueryWrapper) -> Result<DistributedPlan, PlannerError> {
+        if wrapper.contains_lateral_join() {
+            return Err(PlannerError::SingleNode(
+                "it contains a lateral join".to_string(),
+            ));
+        }
+        match wrapper.query_kind() {
+            QueryKind::Aggregate => {}
+            QueryKind::Scalar => {
+                return Err(unsupported("Query has no FROM clause to distribute"));
+            }
+            QueryKind::Rows => return Err(unsupported("Query has no aggregation to distribute")),
+        }
+        match wrapper.distributability() {
+            Ok(Strategy::PartialAggregate) => {}
+            Ok(Strategy::BroadcastJoin { .. }) => {
+                return Err(unsupported("Joins are not supported"))
+            }
+            Ok(Strategy::ParallelScan { .. }) => {
+                return Err(unsupported("Query has no aggregation to distribute"))
+            }
+            Err(blockers) => {
+                let reasons: Vec<_> = blockers.iter().map(ToString::to_string).collect();
+                return Err(PlannerError::SingleNode(reasons.join(", ")));
+            }
+        }
+        // The merge step only re-aggregates; it can't filter the merged groups.
+        if wrapper.having().is_some() {
+            return Err(unsupported("HAVING is not supported"));
+        }
+        let table = match wrapper.tables().as_slice() {
+            [relation] => relation.to_string(),
+            [] => return Err(unsupported("Unsupported query type")),
+            // Workers would only read the first relation.
+            _ => return Err(unsupported("Joins are not supported")),
+        };
+
+        let aliases = wrapper.table_aliases();
+        let group_column = match wrapper.group_by() {
+            Some(GroupByExpr::Expressions(exprs, _)) if exprs.len() > 1 => {
+                return Err(unsupported("Only one GROUP BY column is supported"))
+            }
+            Some(GroupByExpr::Expressions(exprs, _)) if !exprs.is_empty() => match &exprs[0] {
+                Expr::Identifier(ident) => ident.value.clone(),
+                // `GROUP BY t.region`, with `t` the relation's alias.
+                Expr::CompoundIdentifier(idents)
+                    if idents.len() == 2
+                        && aliases.contains_key(&idents[0].value.to_lowercase()) =>
+                {
+                    idents[1].value.clone()
+                }
+                _ => return Err(unsupported("Unsupported GROUP BY expression")),
+            },
+            Some(GroupByExpr::All(_)) => return Err(unsupported("GROUP BY ALL is not supported")),
+            Some(GroupByExpr::Expressions(_, _)) => {
+                return Err(unsupported("GROUP BY clause is empty"))
+            }
+            None => return Err(unsupported("Unsupported query type")),
+        };
+
+        // Workers return the group column and one aggregate; anything else
+        // selected would be dropped from the merged result.
+        if wrapper.projection().len() > 2 {
+            return Err(unsupported("Only one aggregate per query is supported"));
+        }
+        let (func, agg_alias) = match wrapper.aggregate_columns().as_slice() {
+            [(func, alias)] => (*func, alias.clone()),
+            [] => return Err(unsupported("Unsupported aggregation")),
+            _ => return Err(unsupported("Only one aggregate per query is supported")),
+        };
+        let agg_function = func.name.to_string();
+        if !MERGEABLE_AGGREGATES.contains(&agg_function.to_lowercase().as_str()) {
+            return Err(PlannerError::Unsupported(format!(
+                "{} can't be merged across partitions",
+                agg_function
+            )));
+        }
+        let partial_aggregates = if agg_function.eq_ignore_ascii_case("avg") {
+            vec![
+                format!(
+                    "{} AS {}",
+                    renamed_function(func, "SUM"),
+                    quote_ident(AVG_SUM_COLUMN)
+                ),
+                format!(
+                    "{} AS {}",
+                    renamed_function(func, "COUNT"),
+                    quote_ident(AVG_COUNT_COLUMN)
+                ),
+            ]
+        } else {
+            vec![format!("{} AS {}", func, quote_ident(&agg_alias))]
+        };
+
+        let projection = wrapper.projection();
+        let mut order_by = Vec::new();
+        for order in wrapper.order_by() {
+            let column = match &order.expr {
+                Expr::Value(Value::Number(n, _)) => {
+                    n.parse::<usize>()
+                        .ok()
+                        .and_then(|position| projection.get(position.checked_sub(1)?))
+                        .and_then(|item| match item {
+                            SelectItem::UnnamedExpr(expr)
+                            | SelectItem::ExprWithAlias { expr, .. } => Some(expr),
+                            _ => None,
+                        })
+                        .and_then(|expr| sort_column(expr, &group_column, &agg_alias))
+                }
+                expr => sort_column(expr, &group_column, &agg_alias),
+            };
+            let column = column.ok_or_else(|| {
+                PlannerError::Unsupported(format!(
+                    "Unsupported ORDER BY expression: {}",
+                    order.expr
+                ))
+            })?;
+            let descending = order.asc == Some(false);
+            order_by.push(SortKey {
+                column,
+                descending,
+                nulls_first: order.nulls_first.unwrap_or(false),
             });
+        }
+        let analysis = wrapper.analyze();
 
-            let payload_string = serde_json::to_string(&payload)?;
-            let payload_bytes = payload_string.into_bytes();
-            let blob = Blob::new(payload_bytes);
+        // Every worker applies the full WHERE filter, including any
+        // predicate on the partition column itself.
+        let where_clause = wrapper.where_clause().map(|expr| expr.to_string());
 
-            let req = self
-                .lambda_client
-                .invoke()
-                .function_name("pond-duckling")
-                .invocation_type(InvocationType::RequestResponse)
-                .payload(blob);
+        Ok(DistributedPlan {
+            table,
+            source: wrapper.source()?,
+            group_column,
+            agg_function,
+            partial_aggregates,
+            agg_alias,
+            where_clause,
+            push_down_filter: None,
+            order_by,
+            limit: analysis.limit().map(|limit| limit as usize),
+            offset: analysis.offset().unwrap_or(0) as usize,
+            partitions: Vec::new(),
+        })
+    }
+
+    async fn execute_plan(&self, plan: &DistributedPlan) -> Result<PlanResults, PlannerError> {
+        let semaphore = Semaphore::new(self.max_concurrent);
+        let mut tasks = FuturesUnordered::new();
 
-            tasks.push(tokio::spawn(async move { req.send().await }));
+        for partition in plan.partitions.iter().cloned() {
+            let payload = WorkerRequest {
+                query: plan.worker_query(&partition)?,
+                table: plan.table.clone(),
+                group_column: Some(plan.group_column.clone()).filter(|column| !column.is_empty()),
+                agg_function: plan.agg_function.clone(),
+                aggregates: plan.partial_aggregates.clone(),
+                where_clause: plan.where_clause.clone(),
+                push_down_filter: plan.push_down_filter.clone(),
+                partition: partition.clone(),
+                response_format: "arrow",
+            };
+
+            let executor = self.executor.as_ref();
+            let timeout = self.invoke_timeout;
+            let semaphore = &semaphore;
+            tasks.push(async move {
+                // The semaphore is never closed, so acquiring only waits.
+                let _permit = semaphore.acquire().await;
+                // Time spent waiting for a permit doesn't count against the timeout.
+                let execution = tokio::time::timeout(timeout, executor.execute(&payload)).await;
+                (partition, execution)
+            });
         }
 
-        let results = join_all(tasks).await;
-        let mut final_result = Vec::new();
+        let mut batches = Vec::new();
+        let mut schemas = Vec::new();
+        let mut failures = Vec::new();
+        let mut throttled = Vec::new();
 
-        for result in results {
-            match result {
-                Ok(Ok(output)) => {
-                    if let Some(payload) = output.payload {
-                        let payload_vec: Vec<u8> = payload.into_inner();
-                        let partial: serde_json::Value = serde_json::from_slice(&payload_vec)?;
-                        for (key, value) in partial.as_object().unwrap() {
-                            final_result.push((key.clone(), value.as_i64().unwrap_or(0)));
-                        }
+        while let Some((partition, execution)) = tasks.next().await {
+            let partition_batches = match execution {
+                Ok(execution) => {
+                    if execution.throttles > 0 {
+                        throttled.push(partition.clone());
                     }
+                    execution.result
+                }
+                Err(_) => Err(format!("timed out after {:?}", self.invoke_timeout)),
+            };
+            match partition_batches {
+                Ok((schema, partition_batches)) => {
+                    schemas.push((partition, schema));
+                    batches.extend(partition_batches);
+                }
+                Err(err) if self.failure_mode == FailureMode::Lenient => {
+                    failures.push(PartitionFailure {
+                        partition,
+                        error: err,
+                    });
                 }
-                Ok(Err(err)) => return Err(format!("Lambda invocation error: {:?}", err).into()),
-                Err(err) => return Err(format!("Task join error: {:?}", err).into()),
+                Err(message) => return Err(PlannerError::Worker { partition, message }),
             }
         }
 
-        Ok(final_result)
+        validate_worker_schemas(&schemas)?;
+        let batch = plan.order_and_limit(plan.merge(&batches)?)?;
+        Ok(PlanResults {
+            batch,
+            failures,
+            throttled,
+        })
     }
 
     fn create_arrow_response(
         &self,
-        results: Vec<(String, i64)>,
-    ) -> Result<ArrowIpcResponse, Error> {
-        let schema = Schema::new(vec![
-            Field::new("category", DataType::Utf8, false),
-            Field::new("count", DataType::Int64, false),
-        ]);
-
-        let categories: Vec<_> = results.iter().map(|(cat, _)| cat.as_str()).collect();
-        let counts: Vec<_> = results.iter().map(|(_, count)| *count).collect();
-
-        let batch = RecordBatch::try_new(
-            Arc::new(schema.clone()),
-            vec![
-                Arc::new(StringArray::from(categories)),
-                Arc::new(Int64Array::from(counts)),
-            ],
-        )?;
+        results: PlanResults,
+    ) -> Result<ArrowIpcResponse, PlannerError> {
+        let PlanResults {
+            batch,
+            failures,
+            throttled,
+        } = results;
 
         let mut buffer = Cursor::new(Vec::new());
         {
-            let mut writer = StreamWriter::try_new(&mut buffer, &schema)?;
+            let mut writer = StreamWriter::try_new(&mut buffer, &batch.schema())?;
             writer.write(&batch)?;
             writer.finish()?;
         }
 
+        let mut headers = serde_json::json!({
+            "Content-Type": ARROW_STREAM_CONTENT_TYPE,
+        });
+        if !failures.is_empty() {
+            headers["X-Pond-Partial-Results"] = "true".into();
+            headers["X-Pond-Failed-Partitions"] = serde_json::to_string(&failures)?.into();
+        }
+        if !throttled.is_empty() {
+            headers["X-Pond-Throttled-Partitions"] = throttled.join(",").into();
+        }
+
         Ok(ArrowIpcResponse {
             status_code: 200,
-            headers: serde_json::json!({
-                "Content-Type": "application/vnd.apache.arrow.stream",
-            }),
+            headers,
             body: buffer.into_inner(),
         })
     }
 }
 
-async fn function_handler(event: LambdaEvent<Request>) -> Result<ArrowIpcResponse, Error> {
-    let planner = QueryPlanner::new().await?;
-    planner.plan_and_execute(&event.payload.query).await
+/// The prefixes under a file source, each scanned by its own worker. Any other
+/// source, such as a table name, is read whole by a single worker.
+async fn partitions(wrapper: &mut QueryWrapper) -> Result<Vec<String>, PlannerError> {
+    let source = wrapper.source()?;
+    if !source.contains("://") && !source.contains('/') {
+        return Ok(vec![source]);
+    }
+    match wrapper.list_of_prefixes().await {
+        Ok(prefixes) => Ok(prefixes.clone()),
+        Err(err) => Err(PlannerError::Discovery(err)),
+    }
+}
+
+/// Parses a request's `filter` as one SQL expression and renders it back for
+/// [`DistributedPlan::with_filter`].
+///
+/// Each worker evaluates the filter over its own partition, so subqueries,
+/// aggregates and window functions, which would see only that partition,
+/// are refused.
+fn push_down_filter(filter: &str) -> Result<String, PlannerError> {
+    let mut parser = Parser::new(&DuckDbDialect {})
+        .try_with_sql(filter)
+        .map_err(QueryError::from)?;
+    let expr = parser.parse_expr().map_err(QueryError::from)?;
+    let next = parser.next_token();
+    if next.token != Token::EOF {
+        return Err(QueryError::from(ParserError::ParserError(format!(
+            "Expected end of filter, found: {}",
+            next
+        )))
+        .into());
+    }
+
+    let refused = visit_expressions(&expr, |expr| match expr {
+        Expr::Subquery(_) | Expr::Exists { .. } | Expr::InSubquery { .. } => {
+            ControlFlow::Break("a subquery")
+        }
+        Expr::Function(func) if func.over.is_some() => ControlFlow::Break("a window function"),
+        _ => ControlFlow::Continue(()),
+    });
+    let refused = match refused {
+        ControlFlow::Break(reason) => Some(reason),
+        ControlFlow::Continue(())
+            if QueryWrapper::parse(&format!("SELECT {}", expr))?.has_aggregation() =>
+        {
+            Some("an aggregate")
+        }
+        ControlFlow::Continue(()) => None,
+    };
+    if let Some(reason) = refused {
+        return Err(PlannerError::Unsupported(format!(
+            "Filter `{}` can't contain {}",
+            expr, reason
+        )));
+    }
+    Ok(expr.to_string())
+}
+
+fn unsupported(reason: &str) -> PlannerError {
+    PlannerError::Unsupported(reason.to_string())
+}
+
+/// Decodes the Arrow IPC stream in a worker's response into its schema and
+/// batches.
+fn decode_batches(output: InvokeOutput) -> Result<(SchemaRef, Vec<RecordBatch>), String> {
+    let payload = output.payload.map(Blob::into_inner).unwrap_or_default();
+    if let Some(function_error) = output.function_error {
+        return Err(format!(
+            "Worker error ({}): {}",
+            function_error,
+            String::from_utf8_lossy(&payload)
+        ));
+    }
+
+    let response: WorkerResponse = serde_json::from_slice(&payload)
+        .map_err(|err| format!("Invalid worker response: {}", err))?;
+    if response.status_code != 200 {
+        return Err(
+            match serde_json::from_slice::<WorkerError>(&response.body) {
+                Ok(WorkerError {
+                    error_type,
+                    message,
+                }) => format!(
+                    "Worker returned status {} ({}): {}",
+                    response.status_code, error_type, message
+                ),
+                Err(_) => format!(
+                    "Worker returned status {}: {}",
+                    response.status_code,
+                    String::from_utf8_lossy(&response.body)
+                ),
+            },
+        );
+    }
+    // Workers that predate `response_format` may not say what they sent.
+    let content_type = response
+        .headers
+        .as_object()
+        .and_then(|headers| {
+            headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+        })
+        .and_then(|(_, value)| value.as_str());
+    if let Some(content_type) = content_type {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        if !media_type.eq_ignore_ascii_case(ARROW_STREAM_CONTENT_TYPE) {
+            return Err(format!(
+                "Worker returned {}, expected {}",
+                content_type, ARROW_STREAM_CONTENT_TYPE
+            ));
+        }
+    }
+    StreamReader::try_new(Cursor::new(response.body), None)
+        .and_then(|reader| Ok((reader.schema(), reader.collect::<Result<_, _>>()?)))
+        .map_err(|err| format!("Invalid Arrow IPC from worker: {}", err))
+}
+
+/// Checks that every partition returned the field names and types the first
+/// did, so a misconfigured worker can't be silently merged into the result.
+/// Nullability and metadata may differ.
+fn validate_worker_schemas(schemas: &[(String, SchemaRef)]) -> Result<(), PlannerError> {
+    let Some((first_partition, first)) = schemas.first() else {
+        return Ok(());
+    };
+    let fields = |schema: &SchemaRef| {
+        schema
+            .fields()
+            .iter()
+            .map(|field| format!("{}: {}", field.name(), field.data_type()))
+            .collect::<Vec<_>>()
+    };
+    let expected = fields(first);
+    for (partition, schema) in &schemas[1..] {
+        let actual = fields(schema);
+        if actual != expected {
+            return Err(PlannerError::Worker {
+                partition: partition.clone(),
+                message: format!(
+                    "schema mismatch: returned ({}) but {} returned ({})",
+                    actual.join(", "),
+                    first_partition,
+                    expected.join(", ")
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Maps an ORDER BY expression onto the group column or the aggregate, by name
+/// or by the aggregate's SQL text.
+fn sort_column(expr: &Expr, group_column: &str, agg_alias: &str) -> Option<SortColumn> {
+    match expr {
+        Expr::Identifier(ident) if ident.value == group_column => Some(SortColumn::Group),
+        Expr::Identifier(ident) if ident.value == agg_alias => Some(SortColumn::Aggregate),
+        Expr::Function(func) if func.to_string() == agg_alias => Some(SortColumn::Aggregate),
+        _ => None,
+    }
+}
+
+async fn function_handler(event: LambdaEvent<Request>) -> Result<ArrowIpcResponse, LambdaError> {
+    let Request {
+        query,
+        worker_function,
+        failure_mode,
+        max_concurrent,
+        invoke_timeout_secs,
+        filter,
+    } = event.payload;
+    let planner = QueryPlanner::new(
+        worker_function,
+        failure_mode,
+        max_concurrent,
+        invoke_timeout_secs,
+    )
+    .await?;
+    planner
+        .plan_and_execute(&query, filter.as_deref())
+        .await
+        .or_else(ErrorResponse::into_response)
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Error> {
+async fn main() -> Result<(), LambdaError> {
     lambda_runtime::run(service_fn(function_handler)).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Float64Array, StringArray};
+
+    fn local_planner(conn: Connection) -> QueryPlanner {
+        QueryPlanner::with_executor(
+            Box::new(DuckDbExecutor::new(conn)),
+            Some(FailureMode::Strict),
+            None,
+            None,
+        )
+    }
+
+    #[test]
+    fn test_decode_worker_batches() {
+        let batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("region", DataType::Utf8, true),
+                Field::new("total", DataType::Int64, true),
+            ])),
+            vec![
+                Arc::new(StringArray::from(vec!["eu", "us"])),
+                Arc::new(Int64Array::from(vec![30, 5])),
+            ],
+        )
+        .unwrap();
+        let mut stream = Vec::new();
+        {
+            let mut writer = StreamWriter::try_new(&mut stream, &batch.schema()).unwrap();
+            writer.write(&batch).unwrap();
+            writer.finish().unwrap();
+        }
+        let output = |content_type: Option<&str>, body: &[u8]| {
+            let mut response = serde_json::json!({ "status_code": 200, "body": body });
+            if let Some(content_type) = content_type {
+                response["headers"] = serde_json::json!({ "Content-Type": content_type });
+            }
+            InvokeOutput::builder()
+                .payload(Blob::new(serde_json::to_vec(&response).unwrap()))
+                .build()
+        };
+
+        for content_type in [Some(ARROW_STREAM_CONTENT_TYPE), None] {
+            let (schema, batches) = decode_batches(output(content_type, &stream)).unwrap();
+            assert_eq!(schema, batch.schema());
+            assert_eq!(batches, std::slice::from_ref(&batch));
+        }
+        let err = decode_batches(output(Some("application/json"), b"[]")).unwrap_err();
+        assert!(
+            err.contains("expected application/vnd.apache.arrow.stream"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_worker_schema_mismatch() {
+        let schema = |total: DataType| {
+            Arc::new(Schema::new(vec![
+                Field::new("region", DataType::Utf8, true),
+                Field::new("total", total, true),
+            ]))
+        };
+        let mut schemas = vec![
+            ("day=1".to_string(), schema(DataType::Int64)),
+            ("day=2".to_string(), schema(DataType::Int64)),
+        ];
+        validate_worker_schemas(&schemas).unwrap();
+
+        schemas.push(("day=3".to_string(), schema(DataType::Utf8)));
+        let err = validate_worker_schemas(&schemas).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Partition day=3 failed: schema mismatch: returned (region: Utf8, total: Utf8) \
+             but day=1 returned (region: Utf8, total: Int64)"
+        );
+        let response = ErrorResponse::from(err);
+        assert_eq!(response.status_code, 502);
+        assert_eq!(response.error_type, "WorkerFailed");
+    }
+
+    #[test]
+    fn test_lateral_joins_stay_on_one_node() {
+        let planner = local_planner(Connection::open_in_memory().unwrap());
+        let wrapper = QueryWrapper::parse(
+            "SELECT u.region, SUM(l.amount) FROM users u \
+             JOIN LATERAL (SELECT amount FROM sales s WHERE s.user_id = u.id) l ON TRUE \
+             GROUP BY u.region",
+        )
+        .unwrap();
+        let Err(err) = planner.analyze_query(&wrapper) else {
+            panic!("a lateral join should not be distributed");
+        };
+        assert!(matches!(err, PlannerError::SingleNode(_)));
+        let response = ErrorResponse::from(err);
+        assert_eq!(response.status_code, 400);
+        assert_eq!(response.error_type, "SingleNodeRequired");
+    }
+
+    #[test]
+    fn test_refuses_queries_it_would_answer_wrongly() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE sales AS SELECT * FROM (VALUES \
+             ('eu', 1, 10), ('eu', 1, 20), ('us', 2, 5)) t(region, user_id, amount)",
+        )
+        .unwrap();
+        let planner = local_planner(conn);
+
+        for (query, error_type) in [
+            (
+                "SELECT region, COUNT(DISTINCT user_id) FROM sales GROUP BY region",
+                "SingleNodeRequired",
+            ),
+            (
+                "SELECT region, MEDIAN(amount) FROM sales GROUP BY region",
+                "SingleNodeRequired",
+            ),
+            (
+                "SELECT region, SUM(amount) FROM sales GROUP BY region HAVING SUM(amount) > 10",
+                "UnsupportedQuery",
+            ),
+            (
+                "SELECT region, SUM(amount), COUNT(*) FROM sales GROUP BY region",
+                "UnsupportedQuery",
+            ),
+            (
+                "SELECT region, user_id, SUM(amount) FROM sales GROUP BY region, user_id",
+                "UnsupportedQuery",
+            ),
+        ] {
+            let wrapper = QueryWrapper::parse(query).unwrap();
+            let Err(err) = planner.analyze_query(&wrapper) else {
+                panic!("{} should not be planned", query);
+            };
+            let response = ErrorResponse::from(err);
+            assert_eq!(response.status_code, 400, "{}", query);
+            assert_eq!(response.error_type, error_type, "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_merge_refuses_other_aggregates() {
+        let plan = DistributedPlan {
+            group_column: "region".to_string(),
+            agg_function: "MEDIAN".to_string(),
+            partial_aggregates: vec!["MEDIAN(amount)".to_string()],
+            agg_alias: "median".to_string(),
+            ..Default::default()
+        };
+        let batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("region", DataType::Utf8, true),
+                Field::new("median", DataType::Float64, true),
+            ])),
+            vec![
+                Arc::new(StringArray::from(vec!["eu", "eu"])),
+                Arc::new(Float64Array::from(vec![10.0, 20.0])),
+            ],
+        )
+        .unwrap();
+        let Err(err) = plan.merge(&[batch]) else {
+            panic!("MEDIAN results should not be merged");
+        };
+        assert!(matches!(err, PlannerError::Merge(_)));
+    }
+
+    /// The merged result and the number of partitions it was split into.
+    async fn plan_and_execute(planner: &QueryPlanner, query: &str) -> (RecordBatch, usize) {
+        plan_and_execute_filtered(planner, query, None).await
+    }
+
+    async fn plan_and_execute_filtered(
+        planner: &QueryPlanner,
+        query: &str,
+        filter: Option<&str>,
+    ) -> (RecordBatch, usize) {
+        let mut wrapper = QueryWrapper::parse(query).unwrap();
+        let mut plan = planner.analyze_query(&wrapper).unwrap();
+        if let Some(filter) = filter {
+            plan = plan.with_filter(&push_down_filter(filter).unwrap());
+        }
+        plan.partitions = partitions(&mut wrapper).await.unwrap();
+        let results = planner.execute_plan(&plan).await.unwrap();
+        (results.batch, plan.partitions.len())
+    }
+
+    fn rows(batch: &RecordBatch) -> Vec<(String, f64)> {
+        let groups = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        let values = cast(batch.column(1), &DataType::Float64).unwrap();
+        let values = values.as_any().downcast_ref::<Float64Array>().unwrap();
+        (0..batch.num_rows())
+            .map(|row| (groups.value(row).to_string(), values.value(row)))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_local_executor_runs_the_plan() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE sales AS SELECT * FROM (VALUES \
+             ('eu', 10), ('eu', 20), ('us', 5), ('apac', 1)) t(region, amount)",
+        )
+        .unwrap();
+        let planner = local_planner(conn);
+
+        let (batch, partitions) = plan_and_execute(
+            &planner,
+            "SELECT region, SUM(amount) AS total FROM sales GROUP BY region \
+             ORDER BY total DESC LIMIT 2",
+        )
+        .await;
+        assert_eq!(partitions, 1);
+        assert_eq!(
+            rows(&batch),
+            [("eu".to_string(), 30.0), ("us".to_string(), 5.0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_push_down_filter() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE sales AS SELECT * FROM (VALUES \
+             ('eu', 10, 'a'), ('eu', 20, 'b'), ('us', 5, 'a'), ('us', 7, 'a')) \
+             t(region, amount, tenant)",
+        )
+        .unwrap();
+        let planner = local_planner(conn);
+        let query = "SELECT region, SUM(amount) FROM sales WHERE amount > 5 GROUP BY region \
+                     ORDER BY region";
+
+        let wrapper = QueryWrapper::parse(query).unwrap();
+        let plan = planner
+            .analyze_query(&wrapper)
+            .unwrap()
+            .with_filter("tenant = 'a'");
+        assert!(plan
+            .worker_query("sales")
+            .unwrap()
+            .contains("WHERE (amount > 5) AND (tenant = 'a')"));
+
+        let (batch, _) = plan_and_execute_filtered(&planner, query, Some("tenant = 'a'")).await;
+        assert_eq!(
+            rows(&batch),
+            [("eu".to_string(), 10.0), ("us".to_string(), 7.0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_local_executor_merges_partitions() {
+        let dir = std::env::temp_dir().join(format!("pond-planner-{}", std::process::id()));
+        for (partition, rows) in [("day=1", "eu,10\nus,4\n"), ("day=2", "eu,20\nus,6\n")] {
+            std::fs::create_dir_all(dir.join(partition)).unwrap();
+            std::fs::write(
+                dir.join(partition).join("sales.csv"),
+                format!("region,amount\n{}", rows),
+            )
+            .unwrap();
+        }
+        let planner = local_planner(Connection::open_in_memory().unwrap());
+
+        let (batch, partitions) = plan_and_execute(
+            &planner,
+            &format!(
+                "SELECT region, AVG(amount) FROM read_csv('{}/*/*.csv') GROUP BY region \
+                 ORDER BY region",
+                dir.display()
+            ),
+        )
+        .await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(partitions, 2);
+        assert_eq!(
+            rows(&batch),
+            [("eu".to_string(), 15.0), ("us".to_string(), 5.0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_push_down_filter_across_partitions() {
+        let dir = std::env::temp_dir().join(format!("pond-planner-filter-{}", std::process::id()));
+        for (partition, rows) in [
+            ("day=1", "eu,10,a\neu,30,b\nus,4,a\n"),
+            ("day=2", "eu,20,a\nus,6,b\n"),
+        ] {
+            std::fs::create_dir_all(dir.join(partition)).unwrap();
+            std::fs::write(
+                dir.join(partition).join("sales.csv"),
+                format!("region,amount,tenant\n{}", rows),
+            )
+            .unwrap();
+        }
+        let planner = local_planner(Connection::open_in_memory().unwrap());
+        let query = format!(
+            "SELECT region, SUM(amount) FROM read_csv('{}/*/*.csv') GROUP BY region \
+             ORDER BY region",
+            dir.display()
+        );
+
+        assert_eq!(
+            push_down_filter("tenant='a'  AND amount > 0").unwrap(),
+            "tenant = 'a' AND amount > 0"
+        );
+        let (batch, partitions) =
+            plan_and_execute_filtered(&planner, &query, Some("tenant='a'  AND amount > 0")).await;
+        assert_eq!(partitions, 2);
+        assert_eq!(
+            rows(&batch),
+            [("eu".to_string(), 30.0), ("us".to_string(), 4.0)]
+        );
+
+        for (filter, error_type) in [
+            (
+                "amount > (SELECT AVG(amount) FROM sales)",
+                "UnsupportedQuery",
+            ),
+            ("amount > AVG(amount) OVER ()", "UnsupportedQuery"),
+            ("SUM(amount) > 10", "UnsupportedQuery"),
+            ("tenant = 'a' GROUP BY region", "InvalidQuery"),
+            ("tenant = ", "InvalidQuery"),
+        ] {
+            let Err(err) = planner.try_plan_and_execute(&query, Some(filter)).await else {
+                panic!("{} should be refused", filter);
+            };
+            let response = ErrorResponse::from(err);
+            assert_eq!(response.status_code, 400, "{}", filter);
+            assert_eq!(response.error_type, error_type, "{}", filter);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}